@@ -0,0 +1,103 @@
+//! Times looking up the git history of every chapter in a synthetic repository, once with a separate walk of the history for each chapter (the way the per-chapter fields used to do it) and once with the single shared walk `GitRepo::histories` does.
+
+use gix::objs::tree::{Entry, EntryKind};
+use mdbook_buildtime_annotations::git::GitRepo;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const CHAPTERS: usize = 100;
+const COMMITS: usize = 2000;
+/// One commit in this many changes a chapter, the rest change the code next to the book.
+const DOCS_EVERY: usize = 10;
+
+fn write_tree(
+    repo: &gix::Repository,
+    entries: &BTreeMap<String, (EntryKind, gix::ObjectId)>,
+) -> gix::ObjectId {
+    let tree = gix::objs::Tree {
+        entries: entries
+            .iter()
+            .map(|(name, (kind, oid))| Entry {
+                mode: (*kind).into(),
+                filename: name.as_str().into(),
+                oid: *oid,
+            })
+            .collect(),
+    };
+    repo.write_object(&tree)
+        .expect("Failed to write tree")
+        .detach()
+}
+
+/// A repository with `COMMITS` commits, each changing one file in `code/` or (every `DOCS_EVERY` commits) one chapter in `docs/src/`.
+fn synthetic_repo(dir: &Path) {
+    let repo = gix::init(dir).expect("Failed to create repo");
+    let mut chapters = BTreeMap::new();
+    let mut code = BTreeMap::new();
+    let mut parent = None;
+    for index in 0..COMMITS {
+        let blob = repo
+            .write_blob(format!("change {index}\n"))
+            .expect("Failed to write blob")
+            .detach();
+        if index % DOCS_EVERY == 0 {
+            let chapter = (index / DOCS_EVERY) % CHAPTERS;
+            chapters.insert(format!("chapter_{chapter}.md"), (EntryKind::Blob, blob));
+        } else {
+            code.insert(format!("file_{}.rs", index % 50), (EntryKind::Blob, blob));
+        }
+
+        let src = write_tree(&repo, &chapters);
+        let docs = write_tree(
+            &repo,
+            &BTreeMap::from([("src".to_string(), (EntryKind::Tree, src))]),
+        );
+        let mut root = BTreeMap::from([("docs".to_string(), (EntryKind::Tree, docs))]);
+        if !code.is_empty() {
+            root.insert(
+                "code".to_string(),
+                (EntryKind::Tree, write_tree(&repo, &code)),
+            );
+        }
+        let root = write_tree(&repo, &root);
+
+        let time = format!("{} +0000", 1_700_000_000 + index * 60);
+        let signature = gix::actor::SignatureRef {
+            name: "Author".into(),
+            email: "author@example.com".into(),
+            time: &time,
+        };
+        parent = Some(
+            repo.commit_as(signature, signature, "HEAD", "change", root, parent)
+                .expect("Failed to commit")
+                .detach(),
+        );
+    }
+}
+
+fn main() {
+    let dir = tempfile::tempdir().expect("Failed to create tempdir");
+    synthetic_repo(dir.path());
+    let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+    let paths: Vec<PathBuf> = (0..CHAPTERS)
+        .map(|chapter| PathBuf::from(format!("docs/src/chapter_{chapter}.md")))
+        .collect();
+    let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+
+    let start = Instant::now();
+    let separate: Vec<usize> = paths
+        .iter()
+        .map(|path| repo.histories(&[path], None)[0].len())
+        .collect();
+    let separate_time = start.elapsed();
+
+    let start = Instant::now();
+    let shared: Vec<usize> = repo.histories(&paths, None).iter().map(Vec::len).collect();
+    let shared_time = start.elapsed();
+
+    assert_eq!(separate, shared);
+    println!(
+        "history of {CHAPTERS} chapters over {COMMITS} commits: {separate_time:?} walking it for each chapter, {shared_time:?} walking it once"
+    );
+}
@@ -0,0 +1,61 @@
+//! Times annotating a synthetic 500 chapter book with `insert_before_heading` set, so every chapter has to be scanned. Compare `cargo bench` with `cargo bench --features parallel` to see what the `parallel` feature buys.
+
+use mdbook_buildtime_annotations::{Config, Processor};
+use mdbook_preprocessor::book::{Book, Chapter};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const CHAPTERS: usize = 500;
+const ITERATIONS: u32 = 20;
+
+fn large_book() -> Book {
+    let paragraph = "Some text with `code`, *emphasis* and a [link](https://example.com).\n\n";
+    let mut book = Book::new();
+    for number in 0..CHAPTERS {
+        let mut content = format!("# Chapter {number}\n\n");
+        for section in 0..50 {
+            content.push_str(&format!("## Section {section}\n\n"));
+            content.push_str(&paragraph.repeat(10));
+            content.push_str("```rust\n## Feedback\nfn main() {}\n```\n\n");
+        }
+        content.push_str("## Feedback\n\nTell us what you think!\n");
+        book.push_item(Chapter::new(
+            &format!("Chapter {number}"),
+            content,
+            format!("chapter_{number}.md"),
+            vec![],
+        ));
+    }
+    book
+}
+
+fn main() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let cfg = Config::builder()
+        .root_dir(root)
+        .git_commit(false)
+        .insert_before_heading("Feedback")
+        .build();
+    let book = large_book();
+    let processor = Processor::default();
+
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let book = book.clone();
+        let start = Instant::now();
+        let book = processor
+            .run_with_config(&cfg, root, book)
+            .expect("Failed to annotate the book");
+        total += start.elapsed();
+        std::hint::black_box(book);
+    }
+    println!(
+        "annotated {CHAPTERS} chapters in {:?} on average ({}), over {ITERATIONS} runs",
+        total / ITERATIONS,
+        if cfg!(feature = "parallel") {
+            "parallel"
+        } else {
+            "serial"
+        }
+    );
+}
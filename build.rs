@@ -0,0 +1,66 @@
+//! Embeds where and when the preprocessor itself was built, for `--version`. Everything falls back to "unknown" so building from a tarball without git still works.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!(
+        "cargo:rustc-env=BUILD_GIT_HASH={}",
+        command_output("git", &["rev-parse", "--short", "HEAD"]).unwrap_or_else(unknown)
+    );
+    println!(
+        "cargo:rustc-env=BUILD_DATE={}",
+        build_date().unwrap_or_else(unknown)
+    );
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    println!(
+        "cargo:rustc-env=BUILD_RUSTC={}",
+        command_output(&rustc, &["--version"])
+            // "rustc 1.78.0 (9b00956e5 2024-04-29)" is more than we need
+            .and_then(|version| version.split_whitespace().nth(1).map(str::to_string))
+            .map(|version| format!("rustc {version}"))
+            .unwrap_or_else(|| "rustc unknown".to_string())
+    );
+
+    for path in [".git/HEAD", ".git/refs"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+fn unknown() -> String {
+    "unknown".to_string()
+}
+
+/// The trimmed stdout of the command, if it ran successfully and printed something.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !stdout.trim().is_empty()).then(|| stdout.trim().to_string())
+}
+
+/// Today's date as YYYY-MM-DD, or `SOURCE_DATE_EPOCH`'s for reproducible builds.
+fn build_date() -> Option<String> {
+    let seconds = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.trim().parse::<u64>().ok()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs(),
+    };
+    // civil-from-days, see <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    Some(format!("{year:04}-{month:02}-{day:02}"))
+}
@@ -0,0 +1,205 @@
+//! The `annotate` subcommand, which stamps standalone Markdown files with the same footer the preprocessor adds to chapters.
+
+use crate::{Processor, insert_annotation};
+use anyhow::Context;
+use mdbook_preprocessor::errors::Result;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Where `annotate` writes each annotated file.
+#[derive(Debug)]
+pub enum Destination {
+    /// Print the annotated files to stdout.
+    Stdout,
+    /// Replace each file, atomically.
+    InPlace,
+    /// Write each file into this directory, under its original file name. Two files with the same name are an error, rather than one overwriting the other.
+    Dir(PathBuf),
+}
+
+/// Resolves the annotation from the config, manifest and git repository for `root` (a book.toml there is used if it exists, otherwise the defaults are), then adds it to each of `files`, replacing any annotation they already have. In dry-run mode the changes are printed and nothing is written.
+pub fn annotate_files(
+    processor: &Processor,
+    root: &Path,
+    files: &[PathBuf],
+    destination: &Destination,
+) -> Result<()> {
+    if let Destination::Dir(dir) = destination {
+        check_file_names(files, dir)?;
+    }
+    let cfg = processor.standalone_config(root)?;
+    let footer = processor
+        .collect(&cfg, root)?
+        .footer(&cfg)
+        .context("No annotation data found")?;
+
+    for file in files {
+        let mut content = std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {}", file.display()))?;
+        let original = processor.dry_run.then(|| content.clone());
+        insert_annotation(&mut content, &footer, &cfg);
+
+        if let Some(original) = original {
+            if let Some(change) =
+                crate::dry_run::describe_change(&file.display().to_string(), &original, &content)
+            {
+                println!("{change}");
+            }
+            continue;
+        }
+        match destination {
+            Destination::Stdout => print!("{content}"),
+            Destination::InPlace => write_atomic(file, &content)?,
+            Destination::Dir(dir) => {
+                let file_name = file
+                    .file_name()
+                    .with_context(|| format!("{} isn't a file", file.display()))?;
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+                let target = dir.join(file_name);
+                std::fs::write(&target, content)
+                    .with_context(|| format!("Failed to write {}", target.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails if any of `files` doesn't have a file name, or two of them have the same one, so they'd be written to the same place in `dir`.
+fn check_file_names(files: &[PathBuf], dir: &Path) -> Result<()> {
+    let mut seen = std::collections::HashMap::new();
+    for file in files {
+        let file_name = file
+            .file_name()
+            .with_context(|| format!("{} isn't a file", file.display()))?;
+        if let Some(first) = seen.insert(file_name, file) {
+            anyhow::bail!(
+                "{} and {} would both be written to {}, annotate them into different directories",
+                first.display(),
+                file.display(),
+                dir.join(file_name).display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Writes to a temporary file next to `path` and renames it over the top, so a failed write can't leave a half-written file behind.
+pub(crate) fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut temp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create a temporary file in {}", dir.display()))?;
+    temp.write_all(content.as_bytes())
+        .with_context(|| format!("Failed to write {}", temp.path().display()))?;
+    temp.persist(path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[test]
+    fn test_annotate_in_place_and_output_dir() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let notes = dir.path().join("notes.md");
+        std::fs::write(&notes, "# Release notes\n").expect("Failed to write notes.md");
+        let processor = Processor::with_overrides(crate::cli::ConfigOverrides {
+            root_dir: Some(env!("CARGO_MANIFEST_DIR").into()),
+            ..Default::default()
+        });
+
+        let output = dir.path().join("out");
+        annotate_files(
+            &processor,
+            dir.path(),
+            std::slice::from_ref(&notes),
+            &Destination::Dir(output.clone()),
+        )
+        .expect("annotate failed");
+        let written =
+            std::fs::read_to_string(output.join("notes.md")).expect("Failed to read output");
+        assert!(written.starts_with("# Release notes\n"));
+        assert!(written.contains("<footer id=\"buildtime-annotations\">"));
+        assert_eq!(
+            std::fs::read_to_string(&notes).expect("Failed to read notes.md"),
+            "# Release notes\n"
+        );
+
+        for _ in 0..2 {
+            annotate_files(
+                &processor,
+                dir.path(),
+                std::slice::from_ref(&notes),
+                &Destination::InPlace,
+            )
+            .expect("annotate failed");
+        }
+        let annotated = std::fs::read_to_string(&notes).expect("Failed to read notes.md");
+        assert_eq!(annotated, written);
+        assert_eq!(annotated.matches("<footer").count(), 1);
+    }
+
+    #[test]
+    fn test_output_dir_name_clash() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let files = ["guide", "reference"].map(|sub| {
+            let sub = dir.path().join(sub);
+            std::fs::create_dir(&sub).expect("Failed to create directory");
+            let file = sub.join("intro.md");
+            std::fs::write(&file, "# Intro\n").expect("Failed to write intro.md");
+            file
+        });
+        let processor = Processor::with_overrides(crate::cli::ConfigOverrides {
+            root_dir: Some(env!("CARGO_MANIFEST_DIR").into()),
+            ..Default::default()
+        });
+
+        let output = dir.path().join("out");
+        let err = annotate_files(
+            &processor,
+            dir.path(),
+            &files,
+            &Destination::Dir(output.clone()),
+        )
+        .expect_err("the second intro.md would overwrite the first")
+        .to_string();
+        for file in &files {
+            assert!(err.contains(&file.display().to_string()), "{err}");
+        }
+        // nothing was written
+        assert!(!output.exists());
+
+        // they don't clash anywhere else
+        annotate_files(&processor, dir.path(), &files, &Destination::InPlace)
+            .expect("annotate failed");
+    }
+
+    #[test]
+    fn test_annotate_uses_book_config() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            dir.path().join("book.toml"),
+            format!(
+                "[preprocessor.build-annotations]\nroot_dir = {:?}\nformat = \"markdown\"\ngit_commit = false\n",
+                env!("CARGO_MANIFEST_DIR")
+            ),
+        )
+        .expect("Failed to write book.toml");
+        let cfg = Processor::default()
+            .standalone_config(dir.path())
+            .expect("Failed to load config");
+        assert!(!cfg.git_commit);
+        assert!(matches!(cfg.format, crate::Format::Markdown));
+
+        let cfg = Processor::default()
+            .standalone_config(&dir.path().join("no-book-here"))
+            .expect("Failed to load config");
+        assert_eq!(cfg.commit_characters, Config::default().commit_characters);
+    }
+}
@@ -0,0 +1,244 @@
+//! The `write_build_info` file, which has the build information as JSON for tools that want it without scraping the rendered book. It looks like this, with fields that couldn't be found set to `null`:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "name": "mdbook-buildtime-annotations",
+//!   "version": "0.0.1",
+//!   "commit": "0f6ad02d1c4be7f3d3c2a1e0b9f8e7d6c5b4a392",
+//!   "branch": "main",
+//!   "commit_time": "2024-05-12T09:30:00+10:00",
+//!   "built_at": "2024-05-13T01:02:03Z",
+//!   "extras": { "label": "nightly" }
+//! }
+//! ```
+//!
+//! `commit` is always the full commit id, whatever `commit_characters` is, and both timestamps are RFC 3339. With `deterministic` set, `built_at` is `SOURCE_DATE_EPOCH`, or `null` if that isn't set. `extras` has the fields from any other providers. [`SCHEMA_VERSION`] goes up whenever a field is renamed, removed or changes meaning, adding fields doesn't change it.
+//!
+//! The `write_js` file has the same object, as a script for theme scripts to read: `window.MDBOOK_BUILD_INFO = {…};`.
+
+use crate::Annotation;
+use anyhow::Context;
+use mdbook_preprocessor::errors::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// What the `write_js` file's object is assigned to.
+const JS_PREFIX: &str = "window.MDBOOK_BUILD_INFO = ";
+
+/// The version of the file's shape, written as `schema_version`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// What's written to the `write_build_info` file, see the module docs for what it looks like.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub schema_version: u32,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// The full HEAD commit id.
+    pub commit: Option<String>,
+    pub branch: Option<String>,
+    /// The committer time of the HEAD commit, in RFC 3339 format.
+    pub commit_time: Option<String>,
+    /// When the book was built, in RFC 3339 format (UTC), or `SOURCE_DATE_EPOCH` if that's set so reproducible builds stay reproducible. `None` for [`BuildInfo::reproducible`] without `SOURCE_DATE_EPOCH`.
+    pub built_at: Option<String>,
+    pub extras: BTreeMap<String, String>,
+}
+
+impl BuildInfo {
+    pub fn new(annotation: &Annotation) -> Self {
+        BuildInfo::built_at(annotation, Some(build_time()))
+    }
+
+    /// [`BuildInfo::new`] without anything that changes from one build to the next, for `deterministic`: `built_at` is only set from `SOURCE_DATE_EPOCH`.
+    pub fn reproducible(annotation: &Annotation) -> Self {
+        BuildInfo::built_at(annotation, source_date_epoch())
+    }
+
+    fn built_at(annotation: &Annotation, built_at: Option<SystemTime>) -> Self {
+        BuildInfo {
+            schema_version: SCHEMA_VERSION,
+            name: annotation.name.clone(),
+            version: annotation.version.clone(),
+            commit: annotation.commit_id.clone(),
+            branch: annotation.branch.clone(),
+            commit_time: annotation.commit_time.clone(),
+            built_at: built_at
+                .map(|built_at| humantime::format_rfc3339_seconds(built_at).to_string()),
+            extras: annotation.extras.iter().cloned().collect(),
+        }
+    }
+
+    /// Writes the build information to `path`, atomically so nothing reading it can see a half-written file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        write_file(path, &json).with_context(|| {
+            format!(
+                "Failed to write the build information to {}",
+                path.display()
+            )
+        })
+    }
+
+    /// Writes the build information to `path` as a script that sets `window.MDBOOK_BUILD_INFO`, atomically like [`BuildInfo::write`]. The object is JSON, so no value can break out of it. The file's only rewritten when something other than `built_at` has changed, since it's usually in the theme directory and `mdbook serve` rebuilds the book whenever that changes.
+    pub fn write_js(&self, path: &Path) -> Result<()> {
+        if std::fs::read_to_string(path).is_ok_and(|existing| self.same_script(&existing)) {
+            debug!("{} is already up to date", path.display());
+            return Ok(());
+        }
+        let script = format!("{JS_PREFIX}{};\n", serde_json::to_string_pretty(self)?);
+        write_file(path, &script).with_context(|| {
+            format!(
+                "Failed to write the build information script to {}",
+                path.display()
+            )
+        })
+    }
+
+    /// Whether `script`, a `write_js` file, has the same build information as this, apart from `built_at`.
+    fn same_script(&self, script: &str) -> bool {
+        let Some(json) = script
+            .strip_prefix(JS_PREFIX)
+            .and_then(|rest| rest.trim_end().strip_suffix(';'))
+        else {
+            return false;
+        };
+        let (Ok(mut existing), Ok(mut current)) = (
+            serde_json::from_str::<serde_json::Value>(json),
+            serde_json::to_value(self),
+        ) else {
+            return false;
+        };
+        for info in [&mut existing, &mut current] {
+            if let Some(info) = info.as_object_mut() {
+                info.remove("built_at");
+            }
+        }
+        existing == current
+    }
+}
+
+/// Writes `content` to `path` atomically, creating its directory if need be.
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    crate::annotate::write_atomic(path, content)
+}
+
+/// Now, unless `SOURCE_DATE_EPOCH` says otherwise.
+pub(crate) fn build_time() -> SystemTime {
+    source_date_epoch().unwrap_or_else(SystemTime::now)
+}
+
+/// The time in `SOURCE_DATE_EPOCH`, if it's set to a number of seconds.
+pub(crate) fn source_date_epoch() -> Option<SystemTime> {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info_json() {
+        let annotation = Annotation {
+            name: Some("demo".to_string()),
+            version: Some("1.0.0".to_string()),
+            commit: Some("0f6ad02d1c".to_string()),
+            commit_id: Some("0f6ad02d1c4be7f3d3c2a1e0b9f8e7d6c5b4a392".to_string()),
+            commit_time: Some("2024-05-12T09:30:00+10:00".to_string()),
+            extras: vec![("label".to_string(), "nightly".to_string())],
+            ..Default::default()
+        };
+        let info = BuildInfo::built_at(
+            &annotation,
+            Some(UNIX_EPOCH + Duration::from_secs(1_715_562_123)),
+        );
+        assert_eq!(
+            serde_json::to_value(&info).expect("Failed to serialize"),
+            serde_json::json!({
+                "schema_version": 1,
+                "name": "demo",
+                "version": "1.0.0",
+                "commit": "0f6ad02d1c4be7f3d3c2a1e0b9f8e7d6c5b4a392",
+                "branch": null,
+                "commit_time": "2024-05-12T09:30:00+10:00",
+                "built_at": "2024-05-13T01:02:03Z",
+                "extras": { "label": "nightly" }
+            })
+        );
+
+        let json = serde_json::to_value(BuildInfo::built_at(&annotation, None))
+            .expect("Failed to serialize");
+        assert!(json["built_at"].is_null());
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = dir.path().join("out").join("build-info.json");
+        for _ in 0..2 {
+            info.write(&path).expect("Failed to write build info");
+        }
+        let written: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(&path).expect("Failed to read build info"),
+        )
+        .expect("Failed to parse build info");
+        assert_eq!(
+            written["commit"],
+            "0f6ad02d1c4be7f3d3c2a1e0b9f8e7d6c5b4a392"
+        );
+    }
+
+    #[test]
+    fn test_write_js() {
+        let annotation = Annotation {
+            name: Some("</script><script>alert(\"hi\")</script>".to_string()),
+            version: Some("1.0.0".to_string()),
+            ..Default::default()
+        };
+        let at = |seconds| {
+            BuildInfo::built_at(&annotation, Some(UNIX_EPOCH + Duration::from_secs(seconds)))
+        };
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let path = dir.path().join("theme").join("buildinfo.js");
+        at(1_715_562_123)
+            .write_js(&path)
+            .expect("Failed to write the script");
+        let read = || std::fs::read_to_string(&path).expect("Failed to read the script");
+        let script = read();
+        let json = script
+            .strip_prefix("window.MDBOOK_BUILD_INFO = ")
+            .and_then(|rest| rest.strip_suffix(";\n"))
+            .expect("not an assignment");
+        let written: serde_json::Value = serde_json::from_str(json).expect("Failed to parse");
+        assert_eq!(
+            written["name"],
+            annotation.name.as_deref().unwrap_or_default()
+        );
+        assert_eq!(written["built_at"], "2024-05-13T01:02:03Z");
+
+        // only built_at changing doesn't rewrite it
+        at(1_715_600_000)
+            .write_js(&path)
+            .expect("Failed to write the script");
+        assert_eq!(read(), script);
+        let annotation = Annotation {
+            version: Some("1.0.1".to_string()),
+            ..annotation
+        };
+        BuildInfo::built_at(&annotation, None)
+            .write_js(&path)
+            .expect("Failed to write the script");
+        assert!(read().contains("\"1.0.1\""));
+    }
+}
@@ -1,49 +1,1100 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+
+/// The crate version along with the commit, date and compiler the binary was built with, which come from `build.rs`.
+pub const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("BUILD_GIT_HASH"),
+    " ",
+    env!("BUILD_DATE"),
+    ", ",
+    env!("BUILD_RUSTC"),
+    ")"
+);
 
 #[derive(Parser)]
-#[command(about, version)]
+#[command(about, version = VERSION)]
 pub struct CliOpts {
     #[command(subcommand)]
     pub cmd: Option<Cmd>,
+    /// Print what would change in each chapter instead of changing it, the book is passed through to mdbook untouched
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    /// How to format log lines, overrides MDBOOK_LOG_FORMAT
+    #[arg(long, global = true, value_enum)]
+    pub log_format: Option<LogFormat>,
+    /// Only log errors, MDBOOK_LOG takes precedence if it's set
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Log more, -v for debug and -vv for trace, MDBOOK_LOG takes precedence if it's set
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+}
+
+/// Flags mirroring the book.toml options, these take precedence over `MDBOOK_BUILDTIME_*` environment variables and book.toml.
+#[derive(Args, Debug, Default)]
+pub struct ConfigOverrides {
+    /// Also read config from [preprocessor.<NAME>] if [preprocessor.build-annotations] isn't there, can be repeated
+    #[arg(long = "config-table", value_name = "NAME")]
+    pub config_tables: Vec<String>,
+    /// How many characters of the git commit hash to include
+    #[arg(long)]
+    pub commit_characters: Option<usize>,
+    /// The case to show the commit hash in, "lower" or "upper"
+    #[arg(long)]
+    pub hash_case: Option<String>,
+    /// What goes in front of the commit hash
+    #[arg(long)]
+    pub hash_prefix: Option<String>,
+    /// Where to look for the Cargo.toml
+    #[arg(long)]
+    pub workspace_dir: Option<PathBuf>,
+    /// Where to look for the .git directory
+    #[arg(long)]
+    pub git_dir: Option<PathBuf>,
+    /// Where to look for both the Cargo.toml and the .git directory
+    #[arg(long)]
+    pub root_dir: Option<PathBuf>,
+    /// Where to look for the Cargo.toml of a second project to annotate
+    #[arg(long)]
+    pub code_workspace_dir: Option<PathBuf>,
+    /// Where to look for the .git directory of a second project to annotate
+    #[arg(long)]
+    pub code_git_dir: Option<PathBuf>,
+    /// Shown in place of the package name in the book's segment
+    #[arg(long)]
+    pub book_label: Option<String>,
+    /// Shown in place of the second project's package name in its segment
+    #[arg(long)]
+    pub code_label: Option<String>,
+    /// What goes between the book's and the second project's segments
+    #[arg(long)]
+    pub segment_separator: Option<String>,
+    /// The annotation's text, with {name}, {version}, {commit} and other fields in braces replaced
+    #[arg(long)]
+    pub template: Option<String>,
+    /// An environment variable (or pattern) the template can read, on top of the CI systems' ones, can be repeated
+    #[arg(long = "env-allow")]
+    pub env_allowlist: Vec<String>,
+    /// Include the package name
+    #[arg(long, overrides_with = "no_package_name")]
+    pub package_name: bool,
+    /// Don't include the package name
+    #[arg(long)]
+    pub no_package_name: bool,
+    /// Include the package version
+    #[arg(long, overrides_with = "no_package_version")]
+    pub package_version: bool,
+    /// Don't include the package version
+    #[arg(long)]
+    pub no_package_version: bool,
+    /// Include the git commit
+    #[arg(long, overrides_with = "no_git_commit")]
+    pub git_commit: bool,
+    /// Don't include the git commit
+    #[arg(long)]
+    pub no_git_commit: bool,
+    /// Skip chapters with no content
+    #[arg(long, overrides_with = "no_skip_empty")]
+    pub skip_empty: bool,
+    /// Annotate chapters with no content
+    #[arg(long)]
+    pub no_skip_empty: bool,
+    /// Only annotate chapters nested at most this deep
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+    /// Only annotate chapters under this SUMMARY.md part, can be repeated
+    #[arg(long = "part")]
+    pub parts: Vec<String>,
+    /// Only annotate chapters whose source path matches this pattern, can be repeated
+    #[arg(long = "chapter")]
+    pub chapters: Vec<String>,
+    /// Add the build information appendix chapter
+    #[arg(long, overrides_with = "no_appendix")]
+    pub appendix: bool,
+    /// Don't add the build information appendix chapter
+    #[arg(long)]
+    pub no_appendix: bool,
+    /// The title of the build information appendix chapter
+    #[arg(long)]
+    pub appendix_title: Option<String>,
+    /// Add the revision history appendix chapter
+    #[arg(long, overrides_with = "no_history_appendix")]
+    pub history_appendix: bool,
+    /// Don't add the revision history appendix chapter
+    #[arg(long)]
+    pub no_history_appendix: bool,
+    /// The title of the revision history appendix chapter
+    #[arg(long)]
+    pub history_appendix_title: Option<String>,
+    /// Add the changelog chapter
+    #[arg(long, overrides_with = "no_changelog_chapter")]
+    pub changelog_chapter: bool,
+    /// Don't add the changelog chapter
+    #[arg(long)]
+    pub no_changelog_chapter: bool,
+    /// The title of the changelog chapter
+    #[arg(long)]
+    pub changelog_chapter_title: Option<String>,
+    /// How many tagged releases the changelog chapter lists
+    #[arg(long)]
+    pub changelog_tags: Option<usize>,
+    /// Only list commits in the changelog chapter that changed a file matching this pattern, can be repeated
+    #[arg(long = "changelog-path-filter")]
+    pub changelog_path_filter: Vec<String>,
+    /// Only follow the first parent of each commit in the changelog chapter
+    #[arg(long, overrides_with = "no_changelog_first_parent")]
+    pub changelog_first_parent: bool,
+    /// Follow every parent of each commit in the changelog chapter
+    #[arg(long)]
+    pub no_changelog_first_parent: bool,
+    /// Add the "What's new" section to the first chapter
+    #[arg(long, overrides_with = "no_whats_new")]
+    pub whats_new: bool,
+    /// Don't add the "What's new" section to the first chapter
+    #[arg(long)]
+    pub no_whats_new: bool,
+    /// The heading of the "What's new" section, with {tag} for the last tag
+    #[arg(long)]
+    pub whats_new_title: Option<String>,
+    /// The most commits the "What's new" section lists
+    #[arg(long)]
+    pub whats_new_max: Option<usize>,
+    /// Only list commits in the "What's new" section that changed a file matching this pattern, can be repeated
+    #[arg(long = "whats-new-path")]
+    pub whats_new_paths: Vec<String>,
+    /// Append the version to the first chapter's title
+    #[arg(long, overrides_with = "no_title_suffix")]
+    pub title_suffix: bool,
+    /// Don't append the version to the first chapter's title
+    #[arg(long)]
+    pub no_title_suffix: bool,
+    /// What to append to the chapter titles, with the same fields as --template
+    #[arg(long)]
+    pub title_suffix_template: Option<String>,
+    /// Append the title suffix to chapters whose source path matches this pattern instead of the first chapter, can be repeated
+    #[arg(long = "title-suffix-chapter")]
+    pub title_suffix_chapters: Vec<String>,
+    /// A translation directory to strip from chapter paths, can be repeated
+    #[arg(long = "language-dir")]
+    pub language_dirs: Vec<String>,
+    /// Insert the annotation above the first heading with this text
+    #[arg(long)]
+    pub insert_before_heading: Option<String>,
+    /// Put the annotation after a comment that tells mdbook-xgettext to skip it
+    #[arg(long)]
+    pub gettext_skip: bool,
+    /// How the annotation is written into the chapter, "html", "epub", "markdown", "comment" or "frontmatter"
+    #[arg(long)]
+    pub format: Option<String>,
+    /// Only annotate the book for this renderer, can be repeated
+    #[arg(long = "renderer")]
+    pub renderers: Vec<String>,
+    /// Leave the book untouched for this renderer (instead of "test"), can be repeated
+    #[arg(long = "skip-renderer")]
+    pub skip_renderers: Vec<String>,
+    /// The table under profiles to override the main table with, eg "ci" or "local"
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// Only annotate the book on a branch matching this pattern (using * and ?), can be repeated
+    #[arg(long = "branch")]
+    pub branches: Vec<String>,
+    /// Annotate the book on every branch except the --branch ones
+    #[arg(long)]
+    pub branches_invert: bool,
+    /// The template for branches matching a pattern, as PATTERN=TEMPLATE (eg 'main={name} v{version}'), can be repeated and the first match wins
+    #[arg(long = "variant", value_parser = parse_variant)]
+    pub variants: Vec<(String, String)>,
+    /// When to warn about mdbook's version, "strict", "compatible" or "off"
+    #[arg(long)]
+    pub version_check: Option<String>,
+    /// Resolve relative workspace_dir and git_dir paths against the book root
+    #[arg(long)]
+    pub resolve_from_book_root: bool,
+    /// How many parent directories above the book root to search for a Cargo.toml
+    #[arg(long)]
+    pub discovery_levels: Option<usize>,
+    /// The workspace member to read the name and version from, when the Cargo.toml is a virtual manifest
+    #[arg(long)]
+    pub member_path: Option<PathBuf>,
+    /// Write the build information as JSON to this file, relative to the book root
+    #[arg(long)]
+    pub write_build_info: Option<PathBuf>,
+    /// Write a JSON report of what was annotated, skipped and how long it took to this file, relative to the book root
+    #[arg(long)]
+    pub report_file: Option<PathBuf>,
+    /// Leave out everything that changes between builds of the same sources
+    #[arg(long)]
+    pub deterministic: bool,
+    /// Fail the build on problems that are otherwise only warnings
+    #[arg(long)]
+    pub strict: bool,
+    /// A field (package_name, package_version or git_commit) that fails the build if it can't be found, can be repeated
+    #[arg(long)]
+    pub required: Vec<String>,
+    /// Write the annotation as an HTML fragment to this file, relative to the book root, for the theme to include
+    #[arg(long)]
+    pub write_theme_partial: Option<PathBuf>,
+    /// Write the build information as a script setting window.MDBOOK_BUILD_INFO to this file, relative to the book root
+    #[arg(long)]
+    pub write_js: Option<PathBuf>,
+    /// Don't add the annotation to the chapters
+    #[arg(long)]
+    pub skip_chapters: bool,
+    /// End each chapter's annotation with the date its file was last changed in git
+    #[arg(long)]
+    pub chapter_dates: bool,
+    /// The strftime format for chapter dates
+    #[arg(long)]
+    pub date_format: Option<String>,
+    /// How chapter dates are shown, "absolute" or "relative"
+    #[arg(long)]
+    pub chapter_date_style: Option<String>,
+    /// What to show instead of the date for chapters that haven't been committed yet
+    #[arg(long)]
+    pub chapter_dates_fallback: Option<String>,
+    /// Note chapters that have changed since the last commit
+    #[arg(long)]
+    pub note_local_changes: bool,
+    /// End each chapter's annotation with the authors of the commits that changed it
+    #[arg(long)]
+    pub chapter_contributors: bool,
+    /// Add the date each chapter's file was added in git to its annotation
+    #[arg(long)]
+    pub chapter_created: bool,
+    /// End each chapter's annotation with the number of commits that changed it
+    #[arg(long)]
+    pub chapter_revisions: bool,
+    /// The most revisions to count for each chapter
+    #[arg(long)]
+    pub revisions_max: Option<usize>,
+    /// The most commits to look back through for the per-chapter fields
+    #[arg(long)]
+    pub history_limit: Option<usize>,
+    /// Only look at commits from this date on (eg 2022-01-01) for the per-chapter fields
+    #[arg(long)]
+    pub history_since: Option<String>,
+    /// The most contributors to list for each chapter
+    #[arg(long)]
+    pub contributors_max: Option<usize>,
+    /// Count the authors of merge commits as contributors
+    #[arg(long)]
+    pub contributors_merges: bool,
+    /// An author (or a pattern using * and ?) to leave out of the contributors, can be repeated
+    #[arg(long = "exclude-author")]
+    pub exclude_authors: Vec<String>,
+    /// End each chapter's annotation with its owners from the CODEOWNERS file
+    #[arg(long, overrides_with = "no_codeowners")]
+    pub codeowners: bool,
+    /// Don't show the chapters' owners
+    #[arg(long)]
+    pub no_codeowners: bool,
+    /// The URL of a commit on your forge, with {commit} where the commit id goes
+    #[arg(long)]
+    pub commit_url_template: Option<String>,
+    /// End the annotation with a "Changelog" link to this URL
+    #[arg(long)]
+    pub changelog_url: Option<String>,
+    /// Added to the end of the changelog URL, with {version} where the version goes
+    #[arg(long)]
+    pub changelog_anchor_template: Option<String>,
+    /// Link the version to its release page when it's tagged
+    #[arg(long, overrides_with = "no_release_link")]
+    pub release_link: bool,
+    /// Don't link the version to its release page
+    #[arg(long)]
+    pub no_release_link: bool,
+    /// The URL of a release on your forge, with {tag} where the tag goes
+    #[arg(long)]
+    pub release_url_template: Option<String>,
+    /// End each chapter's annotation with an "Edit this page" link
+    #[arg(long)]
+    pub edit_link: bool,
+    /// The URL to edit a chapter, with {path} and {branch} where the chapter's path and the branch go
+    #[arg(long)]
+    pub edit_url_template: Option<String>,
+    /// The branch edit and history links point at, instead of the current branch
+    #[arg(long)]
+    pub edit_branch: Option<String>,
+    /// End each chapter's annotation with a link to its file's history on your forge
+    #[arg(long)]
+    pub history_link: bool,
+    /// The URL of a chapter's history, with {path} and {branch} where the chapter's path and the branch go
+    #[arg(long)]
+    pub history_url_template: Option<String>,
+    /// The text of the history link
+    #[arg(long)]
+    pub history_link_text: Option<String>,
+    /// A trailer to read from the HEAD commit's message and add to the annotation, can be repeated
+    #[arg(long = "trailer")]
+    pub trailers: Vec<String>,
+    /// What goes between the values of a trailer that's in the commit more than once
+    #[arg(long)]
+    pub trailer_separator: Option<String>,
+    /// Take email addresses out of contributors and trailers
+    #[arg(long, overrides_with = "no_redact_emails")]
+    pub redact_emails: bool,
+    /// Leave email addresses in contributors and trailers
+    #[arg(long)]
+    pub no_redact_emails: bool,
+    /// How to take email addresses out, "strip" or "hash"
+    #[arg(long)]
+    pub email_redaction: Option<String>,
+    /// Start each annotated chapter with a warning when the working tree has uncommitted changes
+    #[arg(long)]
+    pub dirty_banner: bool,
+    /// The text of the warning about uncommitted changes
+    #[arg(long)]
+    pub dirty_banner_text: Option<String>,
+    /// The class of the warning about uncommitted changes
+    #[arg(long)]
+    pub dirty_banner_class: Option<String>,
+    /// Start each annotated chapter with a banner when the version is a prerelease
+    #[arg(long)]
+    pub prerelease_banner: bool,
+    /// The prerelease banner, with {version} in place of the version
+    #[arg(long)]
+    pub prerelease_banner_template: Option<String>,
+    /// End the annotation with a notice when HEAD is ahead of the closest tag
+    #[arg(long)]
+    pub unreleased_notice: bool,
+    /// The unreleased notice, with {tag}, {count} and {commits} in place of the tag and commits since it
+    #[arg(long)]
+    pub unreleased_notice_template: Option<String>,
+    /// Cut field values longer than this many characters short, except the commit and URLs
+    #[arg(long)]
+    pub max_field_length: Option<usize>,
+    /// The longest one field's value can be, as FIELD=LENGTH (eg branch=20), can be repeated
+    #[arg(long = "field-max-length", value_parser = parse_field_length)]
+    pub field_max_lengths: Vec<(String, usize)>,
+    /// What goes on the end of a value that was cut short
+    #[arg(long)]
+    pub ellipsis: Option<String>,
+}
+
+/// Parses a `--field-max-length` value, eg `branch=20`.
+fn parse_field_length(value: &str) -> Result<(String, usize), String> {
+    let (field, length) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected FIELD=LENGTH, found {value:?}"))?;
+    let length = length
+        .trim()
+        .parse()
+        .map_err(|err| format!("invalid length {length:?}: {err}"))?;
+    Ok((field.trim().to_string(), length))
+}
+
+/// Parses a `--variant` value, eg `main={name} v{version}`.
+fn parse_variant(value: &str) -> Result<(String, String), String> {
+    let (pattern, template) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected PATTERN=TEMPLATE, found {value:?}"))?;
+    Ok((pattern.trim().to_string(), template.to_string()))
+}
+
+impl ConfigOverrides {
+    /// The flags that were actually set, keyed the same way as the book.toml table.
+    pub fn to_table(&self) -> toml::Table {
+        let mut table = toml::Table::new();
+        let mut set = |key: &str, value: Option<toml::Value>| {
+            if let Some(value) = value {
+                table.insert(key.to_string(), value);
+            }
+        };
+        let flag = |yes: bool, no: bool| match (yes, no) {
+            (true, _) => Some(toml::Value::Boolean(true)),
+            (_, true) => Some(toml::Value::Boolean(false)),
+            _ => None,
+        };
+        let path = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|path| toml::Value::String(path.display().to_string()))
+        };
+        let list = |items: &[String]| {
+            (!items.is_empty()).then(|| {
+                toml::Value::Array(items.iter().cloned().map(toml::Value::String).collect())
+            })
+        };
+
+        set(
+            "commit_characters",
+            self.commit_characters
+                .map(|v| toml::Value::Integer(v as i64)),
+        );
+        set("hash_case", self.hash_case.clone().map(toml::Value::String));
+        set(
+            "hash_prefix",
+            self.hash_prefix.clone().map(toml::Value::String),
+        );
+        set("workspace_dir", path(&self.workspace_dir));
+        set("git_dir", path(&self.git_dir));
+        set("root_dir", path(&self.root_dir));
+        set("code_workspace_dir", path(&self.code_workspace_dir));
+        set("code_git_dir", path(&self.code_git_dir));
+        set(
+            "book_label",
+            self.book_label.clone().map(toml::Value::String),
+        );
+        set(
+            "code_label",
+            self.code_label.clone().map(toml::Value::String),
+        );
+        set(
+            "segment_separator",
+            self.segment_separator.clone().map(toml::Value::String),
+        );
+        set("template", self.template.clone().map(toml::Value::String));
+        set("env_allowlist", list(&self.env_allowlist));
+        set(
+            "package_name",
+            flag(self.package_name, self.no_package_name),
+        );
+        set(
+            "package_version",
+            flag(self.package_version, self.no_package_version),
+        );
+        set("git_commit", flag(self.git_commit, self.no_git_commit));
+        set("skip_empty", flag(self.skip_empty, self.no_skip_empty));
+        set(
+            "max_depth",
+            self.max_depth.map(|v| toml::Value::Integer(v as i64)),
+        );
+        set("parts", list(&self.parts));
+        set("chapters", list(&self.chapters));
+        set("appendix", flag(self.appendix, self.no_appendix));
+        set(
+            "appendix_title",
+            self.appendix_title.clone().map(toml::Value::String),
+        );
+        set(
+            "history_appendix",
+            flag(self.history_appendix, self.no_history_appendix),
+        );
+        set(
+            "history_appendix_title",
+            self.history_appendix_title.clone().map(toml::Value::String),
+        );
+        set(
+            "changelog_chapter",
+            flag(self.changelog_chapter, self.no_changelog_chapter),
+        );
+        set(
+            "changelog_chapter_title",
+            self.changelog_chapter_title
+                .clone()
+                .map(toml::Value::String),
+        );
+        set(
+            "changelog_tags",
+            self.changelog_tags.map(|v| toml::Value::Integer(v as i64)),
+        );
+        set("changelog_path_filter", list(&self.changelog_path_filter));
+        set(
+            "changelog_first_parent",
+            flag(self.changelog_first_parent, self.no_changelog_first_parent),
+        );
+        set("whats_new", flag(self.whats_new, self.no_whats_new));
+        set(
+            "whats_new_title",
+            self.whats_new_title.clone().map(toml::Value::String),
+        );
+        set(
+            "whats_new_max",
+            self.whats_new_max.map(|v| toml::Value::Integer(v as i64)),
+        );
+        set("whats_new_paths", list(&self.whats_new_paths));
+        set(
+            "title_suffix",
+            flag(self.title_suffix, self.no_title_suffix),
+        );
+        set(
+            "title_suffix_template",
+            self.title_suffix_template.clone().map(toml::Value::String),
+        );
+        set("title_suffix_chapters", list(&self.title_suffix_chapters));
+        set("language_dirs", list(&self.language_dirs));
+        set(
+            "insert_before_heading",
+            self.insert_before_heading.clone().map(toml::Value::String),
+        );
+        set("format", self.format.clone().map(toml::Value::String));
+        set("renderers", list(&self.renderers));
+        set("skip_renderers", list(&self.skip_renderers));
+        set("profile", self.profile.clone().map(toml::Value::String));
+        set("branches", list(&self.branches));
+        set(
+            "branches_invert",
+            self.branches_invert.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "variants",
+            (!self.variants.is_empty()).then(|| {
+                toml::Value::Table(
+                    self.variants
+                        .iter()
+                        .map(|(pattern, template)| {
+                            (pattern.clone(), toml::Value::String(template.clone()))
+                        })
+                        .collect(),
+                )
+            }),
+        );
+        set(
+            "version_check",
+            self.version_check.clone().map(toml::Value::String),
+        );
+        set(
+            "gettext_skip",
+            self.gettext_skip.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "resolve_from_book_root",
+            self.resolve_from_book_root
+                .then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "discovery_levels",
+            self.discovery_levels
+                .map(|v| toml::Value::Integer(v as i64)),
+        );
+        set("member_path", path(&self.member_path));
+        set("write_build_info", path(&self.write_build_info));
+        set("report_file", path(&self.report_file));
+        set(
+            "deterministic",
+            self.deterministic.then_some(toml::Value::Boolean(true)),
+        );
+        set("strict", self.strict.then_some(toml::Value::Boolean(true)));
+        set("required", list(&self.required));
+        set("write_theme_partial", path(&self.write_theme_partial));
+        set("write_js", path(&self.write_js));
+        set(
+            "skip_chapters",
+            self.skip_chapters.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "chapter_dates",
+            self.chapter_dates.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "date_format",
+            self.date_format.clone().map(toml::Value::String),
+        );
+        set(
+            "chapter_date_style",
+            self.chapter_date_style.clone().map(toml::Value::String),
+        );
+        set(
+            "chapter_dates_fallback",
+            self.chapter_dates_fallback.clone().map(toml::Value::String),
+        );
+        set(
+            "note_local_changes",
+            self.note_local_changes
+                .then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "chapter_contributors",
+            self.chapter_contributors
+                .then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "chapter_created",
+            self.chapter_created.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "chapter_revisions",
+            self.chapter_revisions.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "revisions_max",
+            self.revisions_max.map(|v| toml::Value::Integer(v as i64)),
+        );
+        set(
+            "history_limit",
+            self.history_limit.map(|v| toml::Value::Integer(v as i64)),
+        );
+        set(
+            "history_since",
+            self.history_since.clone().map(toml::Value::String),
+        );
+        set(
+            "contributors_max",
+            self.contributors_max
+                .map(|v| toml::Value::Integer(v as i64)),
+        );
+        set(
+            "contributors_merges",
+            self.contributors_merges
+                .then_some(toml::Value::Boolean(true)),
+        );
+        set("exclude_authors", list(&self.exclude_authors));
+        set("codeowners", flag(self.codeowners, self.no_codeowners));
+        set(
+            "commit_url_template",
+            self.commit_url_template.clone().map(toml::Value::String),
+        );
+        set(
+            "changelog_url",
+            self.changelog_url.clone().map(toml::Value::String),
+        );
+        set(
+            "changelog_anchor_template",
+            self.changelog_anchor_template
+                .clone()
+                .map(toml::Value::String),
+        );
+        set(
+            "release_link",
+            flag(self.release_link, self.no_release_link),
+        );
+        set(
+            "release_url_template",
+            self.release_url_template.clone().map(toml::Value::String),
+        );
+        set(
+            "edit_link",
+            self.edit_link.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "edit_url_template",
+            self.edit_url_template.clone().map(toml::Value::String),
+        );
+        set(
+            "edit_branch",
+            self.edit_branch.clone().map(toml::Value::String),
+        );
+        set(
+            "history_link",
+            self.history_link.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "history_url_template",
+            self.history_url_template.clone().map(toml::Value::String),
+        );
+        set(
+            "history_link_text",
+            self.history_link_text.clone().map(toml::Value::String),
+        );
+        set("trailers", list(&self.trailers));
+        set(
+            "trailer_separator",
+            self.trailer_separator.clone().map(toml::Value::String),
+        );
+        set(
+            "redact_emails",
+            flag(self.redact_emails, self.no_redact_emails),
+        );
+        set(
+            "email_redaction",
+            self.email_redaction.clone().map(toml::Value::String),
+        );
+        set(
+            "dirty_banner",
+            self.dirty_banner.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "dirty_banner_text",
+            self.dirty_banner_text.clone().map(toml::Value::String),
+        );
+        set(
+            "dirty_banner_class",
+            self.dirty_banner_class.clone().map(toml::Value::String),
+        );
+        set(
+            "prerelease_banner",
+            self.prerelease_banner.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "prerelease_banner_template",
+            self.prerelease_banner_template
+                .clone()
+                .map(toml::Value::String),
+        );
+        set(
+            "unreleased_notice",
+            self.unreleased_notice.then_some(toml::Value::Boolean(true)),
+        );
+        set(
+            "unreleased_notice_template",
+            self.unreleased_notice_template
+                .clone()
+                .map(toml::Value::String),
+        );
+        set(
+            "max_field_length",
+            self.max_field_length
+                .map(|v| toml::Value::Integer(v as i64)),
+        );
+        set(
+            "field_max_lengths",
+            (!self.field_max_lengths.is_empty()).then(|| {
+                toml::Value::Table(
+                    self.field_max_lengths
+                        .iter()
+                        .map(|(field, length)| {
+                            (field.clone(), toml::Value::Integer(*length as i64))
+                        })
+                        .collect(),
+                )
+            }),
+        );
+        set("ellipsis", self.ellipsis.clone().map(toml::Value::String));
+        table
+    }
 }
 
 #[derive(Subcommand)]
 pub enum Cmd {
-    /// Check whether a renderer is supported by this preprocessor
-    Supports { renderer: String },
+    /// Check whether a renderer is supported by this preprocessor, or list the supported renderers if none is given
+    Supports {
+        renderer: Option<String>,
+        /// How to list the supported renderers, when no renderer is given
+        #[arg(long, value_enum, default_value_t = PrintFormat::Text)]
+        format: PrintFormat,
+    },
+    /// Run the preprocessor like mdbook does, optionally reading the payload from and writing the book to files
+    Preprocess {
+        /// Read mdbook's JSON payload from this file instead of stdin
+        #[arg(long)]
+        input: Option<PathBuf>,
+        /// Write the processed book to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Print a shell completion script
+    Completions { shell: clap_complete::Shell },
+    /// Write man pages for the command and each subcommand
+    #[command(hide = true)]
+    Mangen {
+        /// The directory to write the pages to, which is created if it doesn't exist
+        #[arg(long, default_value = ".")]
+        out: PathBuf,
+    },
+    /// Print a JSON Schema describing the book.toml options
+    ConfigSchema,
+    /// Print the annotation for a book, without building it
+    Print {
+        /// The book directory, containing book.toml
+        #[arg(long, default_value = ".")]
+        root: PathBuf,
+        #[arg(long, value_enum, default_value_t = PrintFormat::Text)]
+        format: PrintFormat,
+    },
+    /// Check that the book.toml, Cargo.toml and git repository can all be found, printing what was tried
+    Check {
+        /// The book directory, containing book.toml
+        #[arg(long, default_value = ".")]
+        root: PathBuf,
+        /// Also print what annotating each chapter would change
+        #[arg(long)]
+        diff: bool,
+    },
+    /// Add the annotation to standalone Markdown files, printing them unless --in-place or --output is given
+    Annotate {
+        /// The Markdown files to annotate
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Replace each file with its annotated version
+        #[arg(long, conflicts_with = "output")]
+        in_place: bool,
+        /// Write the annotated files into this directory instead, under their own file names, which have to be different
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// The directory to read the config (book.toml, if there is one), Cargo.toml and git repository relative to
+        #[arg(long, default_value = ".")]
+        root: PathBuf,
+    },
+    /// Add the preprocessor to a book's book.toml
+    Install {
+        /// The book directory, containing book.toml
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+        /// Replace the existing config if the book already has it
+        #[arg(long)]
+        force: bool,
+    },
 }
 
-// Grabbed from <https://github.com/rust-lang/mdBook/blob/master/src/main.rs#L93>
-pub fn init_logger() {
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum PrintFormat {
+    /// Plain text: the annotation as it appears in the footer, or one renderer per line
+    Text,
+    /// JSON: each field of the annotation as an object, or an array of renderers
+    Json,
+}
+
+/// Writes the completion script for `shell`, generated from [`CliOpts`] so every subcommand and flag is covered.
+pub fn write_completions(shell: clap_complete::Shell, out: &mut impl std::io::Write) {
+    clap_complete::generate(shell, &mut CliOpts::command(), env!("CARGO_PKG_NAME"), out);
+}
+
+/// Writes `mdbook-buildtime-annotations.1` and a page for each (visible) subcommand into `out_dir`, returning the paths written.
+pub fn generate_man_pages(out_dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    fn generate(
+        cmd: clap::Command,
+        out_dir: &Path,
+        written: &mut Vec<PathBuf>,
+    ) -> std::io::Result<()> {
+        for subcommand in cmd.get_subcommands().filter(|s| !s.is_hide_set()) {
+            generate(subcommand.clone(), out_dir, written)?;
+        }
+        written.push(clap_mangen::Man::new(cmd).generate_to(out_dir)?);
+        Ok(())
+    }
+
+    std::fs::create_dir_all(out_dir)?;
+    let mut cmd = CliOpts::command().disable_help_subcommand(true);
+    // building fills in the subcommand display names, which become the page names
+    cmd.build();
+    let mut written = Vec::new();
+    generate(cmd, out_dir, &mut written)?;
+    Ok(written)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines, the default
+    Pretty,
+    /// One JSON object per line, including the level and target
+    Json,
+}
+
+impl LogFormat {
+    /// The `--log-format` flag if it was given, otherwise the `MDBOOK_LOG_FORMAT` environment variable, falling back to pretty if that's unset or unrecognised.
+    pub fn select(flag: Option<LogFormat>, env: Option<&str>) -> LogFormat {
+        flag.or_else(|| env.and_then(|value| LogFormat::from_str(value.trim(), true).ok()))
+            .unwrap_or(LogFormat::Pretty)
+    }
+}
+
+/// Builds the log filter from `log_env` (the value of `MDBOOK_LOG`) if it has any directives, otherwise from `--quiet`/`--verbose`, which move the default level from info.
+pub fn log_filter(
+    quiet: bool,
+    verbose: u8,
+    log_env: Option<&str>,
+) -> tracing_subscriber::EnvFilter {
+    use tracing_subscriber::filter::LevelFilter;
+    let default_level = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
     let filter = tracing_subscriber::EnvFilter::builder()
-        .with_env_var("MDBOOK_LOG")
-        .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
-        .from_env_lossy();
-    let log_env = std::env::var("MDBOOK_LOG");
+        .with_default_directive(default_level.into())
+        .parse_lossy(log_env.unwrap_or_default());
     // Silence some particularly noisy dependencies unless the user
     // specifically asks for them.
     let silence_unless_specified = |filter: tracing_subscriber::EnvFilter, target| {
-        if !log_env
-            .as_ref()
-            .is_ok_and(|s| s.split(',').any(|directive| directive.starts_with(target)))
-        {
+        if !log_env.is_some_and(|s| s.split(',').any(|directive| directive.starts_with(target))) {
             filter.add_directive(format!("{target}=warn").parse().unwrap())
         } else {
             filter
         }
     };
     let filter = silence_unless_specified(filter, "handlebars");
-    let filter = silence_unless_specified(filter, "html5ever");
+    silence_unless_specified(filter, "html5ever")
+}
+
+// Grabbed from <https://github.com/rust-lang/mdBook/blob/master/src/main.rs#L93>
+pub fn init_logger(format: LogFormat, quiet: bool, verbose: u8) {
+    let log_env = std::env::var("MDBOOK_LOG");
+    let filter = log_filter(quiet, verbose, log_env.as_deref().ok());
 
     // Don't show the target by default, since it generally isn't useful
     // unless you are overriding the level.
     let with_target = log_env.is_ok();
 
-    tracing_subscriber::fmt()
-        .without_time()
-        .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
-        .with_writer(std::io::stderr)
-        .with_env_filter(filter)
-        .with_target(with_target)
-        .init();
+    match format {
+        LogFormat::Pretty => tracing_subscriber::fmt()
+            .without_time()
+            .with_ansi(std::io::IsTerminal::is_terminal(&std::io::stderr()))
+            .with_writer(std::io::stderr)
+            .with_env_filter(filter)
+            .with_target(with_target)
+            .init(),
+        // structured logs are for machines, so always include the target
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_writer(std::io::stderr)
+            .with_env_filter(filter)
+            .with_target(true)
+            .init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides_to_table() {
+        let opts = CliOpts::parse_from([
+            "mdbook-buildtime-annotations",
+            "--commit-characters",
+            "7",
+            "--no-package-version",
+            "--part",
+            "Reference",
+            "--part",
+            "Internals",
+        ]);
+        let table = opts.overrides.to_table();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table["commit_characters"].as_integer(), Some(7));
+        assert_eq!(table["package_version"].as_bool(), Some(false));
+        assert_eq!(table["parts"].as_array().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn test_field_max_lengths() {
+        let opts = CliOpts::parse_from([
+            "mdbook-buildtime-annotations",
+            "--field-max-length",
+            "branch=20",
+            "--field-max-length",
+            "trailer.Ticket = 12",
+        ]);
+        let table = opts.overrides.to_table();
+        assert_eq!(table["field_max_lengths"]["branch"].as_integer(), Some(20));
+        assert_eq!(
+            table["field_max_lengths"]["trailer.Ticket"].as_integer(),
+            Some(12)
+        );
+        assert!(
+            CliOpts::try_parse_from([
+                "mdbook-buildtime-annotations",
+                "--field-max-length",
+                "branch",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_variants() {
+        let opts = CliOpts::parse_from([
+            "mdbook-buildtime-annotations",
+            "--variant",
+            "main={name} v{version}",
+            "--variant",
+            "*=PREVIEW {branch} = @{commit}",
+        ]);
+        let table = opts.overrides.to_table();
+        let variants: Vec<(&str, Option<&str>)> = table["variants"]
+            .as_table()
+            .expect("variants should be a table")
+            .iter()
+            .map(|(pattern, template)| (pattern.as_str(), template.as_str()))
+            .collect();
+        assert_eq!(
+            variants,
+            [
+                ("main", Some("{name} v{version}")),
+                ("*", Some("PREVIEW {branch} = @{commit}"))
+            ]
+        );
+        assert!(
+            CliOpts::try_parse_from(["mdbook-buildtime-annotations", "--variant", "main"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_overrides_last_flag_wins() {
+        let opts = CliOpts::parse_from([
+            "mdbook-buildtime-annotations",
+            "--no-git-commit",
+            "--git-commit",
+        ]);
+        assert_eq!(
+            opts.overrides.to_table()["git_commit"].as_bool(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn test_supports_still_parses() {
+        let opts = CliOpts::parse_from(["mdbook-buildtime-annotations", "supports", "html"]);
+        assert!(
+            matches!(opts.cmd, Some(Cmd::Supports { renderer, .. }) if renderer.as_deref() == Some("html"))
+        );
+        let opts = CliOpts::parse_from(["mdbook-buildtime-annotations", "supports"]);
+        assert!(matches!(
+            opts.cmd,
+            Some(Cmd::Supports { renderer: None, .. })
+        ));
+    }
+
+    #[test]
+    fn test_bash_completions() {
+        let mut out = Vec::new();
+        write_completions(clap_complete::Shell::Bash, &mut out);
+        let script = String::from_utf8(out).expect("completions should be utf-8");
+        assert!(script.contains("supports"));
+        assert!(script.contains("--commit-characters"));
+    }
+
+    #[test]
+    fn test_man_pages() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let out = dir.path().join("man");
+        let written = generate_man_pages(&out).expect("Failed to write man pages");
+        let main_page = out.join("mdbook-buildtime-annotations.1");
+        assert!(written.contains(&main_page));
+        assert!(written.contains(&out.join("mdbook-buildtime-annotations-supports.1")));
+        assert!(!written.contains(&out.join("mdbook-buildtime-annotations-mangen.1")));
+
+        let roff = std::fs::read_to_string(main_page).expect("Failed to read man page");
+        assert!(roff.contains("mdbook\\-buildtime\\-annotations"));
+        assert!(roff.contains("commit\\-characters"));
+    }
+
+    #[test]
+    fn test_log_format_select() {
+        assert_eq!(LogFormat::select(None, None), LogFormat::Pretty);
+        assert_eq!(LogFormat::select(None, Some("json")), LogFormat::Json);
+        assert_eq!(LogFormat::select(None, Some(" JSON ")), LogFormat::Json);
+        assert_eq!(LogFormat::select(None, Some("xml")), LogFormat::Pretty);
+        assert_eq!(
+            LogFormat::select(Some(LogFormat::Pretty), Some("json")),
+            LogFormat::Pretty
+        );
+        let opts = CliOpts::parse_from(["mdbook-buildtime-annotations", "--log-format", "json"]);
+        assert_eq!(LogFormat::select(opts.log_format, None), LogFormat::Json);
+    }
+
+    #[test]
+    fn test_log_filter() {
+        assert_eq!(
+            log_filter(false, 0, None).to_string(),
+            "handlebars=warn,html5ever=warn,info"
+        );
+        assert!(log_filter(true, 0, None).to_string().ends_with(",error"));
+        assert!(log_filter(false, 1, None).to_string().ends_with(",debug"));
+        assert!(log_filter(false, 3, None).to_string().ends_with(",trace"));
+        // MDBOOK_LOG wins over the flags
+        assert!(
+            log_filter(true, 0, Some("debug"))
+                .to_string()
+                .ends_with(",debug")
+        );
+        assert_eq!(
+            log_filter(false, 2, Some("handlebars=debug")).to_string(),
+            "handlebars=debug,html5ever=warn"
+        );
+        // an empty MDBOOK_LOG is the same as it not being set
+        assert!(
+            log_filter(false, 1, Some(""))
+                .to_string()
+                .ends_with(",debug")
+        );
+
+        let opts = CliOpts::parse_from(["mdbook-buildtime-annotations", "-vv", "print"]);
+        assert_eq!(opts.verbose, 2);
+        assert!(CliOpts::try_parse_from(["mdbook-buildtime-annotations", "-q", "-v"]).is_err());
+    }
 }
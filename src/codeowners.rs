@@ -0,0 +1,219 @@
+//! The repository's CODEOWNERS file, for `codeowners`. Its patterns work the way GitHub documents them, which is gitignore's syntax without `!` negation, `[ ]` ranges or `\#` escapes:
+//!
+//! - `*` matches anything but `/`, `?` any one character but `/`, and `**` any number of directories, including none.
+//! - A pattern with a `/` at the start or in the middle is relative to the top of the repository, otherwise it matches at any depth.
+//! - A pattern that matches a directory matches everything in it, and one ending in `/` only matches directories.
+//! - `docs/*` only matches the files directly in `docs`, not the ones in directories under it.
+//!
+//! The last rule that matches a path is the one that counts, so a rule without any owners after a broader one leaves those paths unowned.
+
+use tracing::warn;
+
+/// Where GitHub looks for the file, relative to the top of the repository, in the order it looks.
+pub(crate) const LOCATIONS: &[&str] = &[".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+/// The rules from a CODEOWNERS file, in the order they're in it.
+#[derive(Debug, Default)]
+pub(crate) struct CodeOwners {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// The pattern's `/`-separated parts, starting with `**` if it matches at any depth.
+    segments: Vec<String>,
+    /// Whether it ended in `/`, so it only matches directories.
+    directory: bool,
+    owners: Vec<String>,
+}
+
+impl CodeOwners {
+    /// Parses a CODEOWNERS file: a pattern and the owners of what it matches (`@user`, `@org/team` or an email address) on each line, separated by whitespace. Blank lines and comments (from a `#` to the end of the line) are skipped, and so are lines using syntax GitHub doesn't support, with a warning.
+    pub(crate) fn parse(text: &str) -> CodeOwners {
+        let mut rules = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line);
+            let mut words = line.split_whitespace();
+            let Some(pattern) = words.next() else {
+                continue;
+            };
+            let unsupported = match pattern {
+                _ if pattern.starts_with('!') => Some("! negation"),
+                _ if pattern.contains(['[', ']']) => Some("[ ] character ranges"),
+                _ if pattern.contains('\\') => Some("\\ escapes"),
+                _ => None,
+            };
+            if let Some(unsupported) = unsupported {
+                warn!(
+                    "Line {} of CODEOWNERS uses {unsupported}, which CODEOWNERS doesn't support, skipping it",
+                    index + 1
+                );
+                continue;
+            }
+            rules.push(Rule::new(pattern, words.map(str::to_string).collect()));
+        }
+        CodeOwners { rules }
+    }
+
+    /// The owners of the file at `path` (relative to the top of the repository, with `/` between directories) from the last rule matching it. `None` if no rule does, or that rule doesn't have any owners.
+    pub(crate) fn owners(&self, path: &str) -> Option<&[String]> {
+        let path: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(&path))
+            .map(|rule| rule.owners.as_slice())
+            .filter(|owners| !owners.is_empty())
+    }
+}
+
+impl Rule {
+    fn new(pattern: &str, owners: Vec<String>) -> Rule {
+        let (pattern, directory) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let anchored = pattern.contains('/');
+        let mut segments: Vec<String> = pattern
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(str::to_string)
+            .collect();
+        if !anchored {
+            segments.insert(0, "**".to_string());
+        }
+        Rule {
+            segments,
+            directory,
+            owners,
+        }
+    }
+
+    /// Whether the rule matches the file at `path`, or one of the directories it's in.
+    fn matches(&self, path: &[&str]) -> bool {
+        // `docs/*` is only the files in `docs`, not everything under it
+        if self.segments.len() > 1 && self.segments.last().is_some_and(|last| last == "*") {
+            return !self.directory && segments_match(&self.segments, path);
+        }
+        let files = usize::from(!self.directory);
+        (1..path.len() + files).any(|len| segments_match(&self.segments, &path[..len]))
+    }
+}
+
+/// Whether `path` matches all of `pattern`, a part at a time.
+fn segments_match(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| segments_match(rest, &path[skip..]))
+        }
+        Some((first, rest)) => path.split_first().is_some_and(|(name, path)| {
+            crate::wildcard::matches(first, name) && segments_match(rest, path)
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(codeowners: &CodeOwners, path: &str) -> Option<String> {
+        codeowners.owners(path).map(|owners| owners.join(" "))
+    }
+
+    #[test]
+    fn test_parse() {
+        let codeowners = CodeOwners::parse(
+            "# Owners\n\n*       @org/everyone\n/docs/ @org/docs   @alice # the docs team\n!/docs/drafts/ @bob\n[Dd]ocs/ @carol\n/docs/archive/\n",
+        );
+        assert_eq!(codeowners.rules.len(), 3);
+        assert_eq!(
+            owner(&codeowners, "docs/intro.md").as_deref(),
+            Some("@org/docs @alice")
+        );
+        assert_eq!(
+            owner(&codeowners, "src/main.rs").as_deref(),
+            Some("@org/everyone")
+        );
+        // the last match has no owners
+        assert_eq!(owner(&codeowners, "docs/archive/old.md"), None);
+        assert_eq!(owner(&CodeOwners::parse(""), "docs/intro.md"), None);
+    }
+
+    #[test]
+    fn test_patterns() {
+        for (pattern, path, matches) in [
+            // no slash matches at any depth
+            ("*.md", "intro.md", true),
+            ("*.md", "docs/guide/intro.md", true),
+            ("*.md", "docs/intro.rs", false),
+            ("*", "docs/guide/intro.md", true),
+            ("intro.md", "docs/intro.md", true),
+            ("int?o.md", "docs/intro.md", true),
+            ("int?o.md", "docs/int/o.md", false),
+            // a directory matches everything in it, wherever it is without a slash
+            ("docs", "docs/guide/intro.md", true),
+            ("guide", "docs/guide/intro.md", true),
+            ("apps/", "apps/intro.md", true),
+            ("apps/", "docs/apps/guide/intro.md", true),
+            // a trailing slash only matches directories
+            ("intro.md/", "docs/intro.md", false),
+            ("guide/", "docs/guide", false),
+            // a slash at the start or in the middle is from the top
+            ("/docs/", "docs/guide/intro.md", true),
+            ("/docs/", "book/docs/intro.md", false),
+            ("/intro.md", "intro.md", true),
+            ("/intro.md", "docs/intro.md", false),
+            ("docs/guide", "docs/guide/intro.md", true),
+            ("docs/guide", "book/docs/guide/intro.md", false),
+            ("/build/logs/", "build/logs/today/out.md", true),
+            // `*` stops at a slash
+            ("docs/*", "docs/intro.md", true),
+            ("docs/*", "docs/guide/intro.md", false),
+            ("docs/*.md", "docs/intro.md", true),
+            ("docs/*.md", "docs/guide/intro.md", false),
+            ("/*/intro.md", "docs/intro.md", true),
+            ("/*/intro.md", "book/docs/intro.md", false),
+            // `**` doesn't
+            ("docs/**", "docs/guide/intro.md", true),
+            ("docs/**/intro.md", "docs/intro.md", true),
+            ("docs/**/intro.md", "docs/a/b/intro.md", true),
+            ("docs/**/intro.md", "book/docs/a/intro.md", false),
+            ("**/logs", "build/logs/out.md", true),
+            ("**/logs", "deeply/nested/logs/out.md", true),
+            ("**/logs", "logs", true),
+            ("**/logs", "catalogs/out.md", false),
+            // case matters
+            ("Docs/", "docs/intro.md", false),
+        ] {
+            let codeowners = CodeOwners::parse(&format!("{pattern} @owner"));
+            assert_eq!(
+                codeowners.owners(path).is_some(),
+                matches,
+                "{pattern} against {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_last_match_wins() {
+        let codeowners = CodeOwners::parse(
+            "*.md @writers\n/apps/ @octocat\n/apps/github\n/docs/ @org/docs\ndocs/guide/*.md @alice\n",
+        );
+        assert_eq!(owner(&codeowners, "readme.md").as_deref(), Some("@writers"));
+        assert_eq!(
+            owner(&codeowners, "apps/intro.md").as_deref(),
+            Some("@octocat")
+        );
+        assert_eq!(owner(&codeowners, "apps/github/intro.md"), None);
+        assert_eq!(
+            owner(&codeowners, "docs/guide/intro.md").as_deref(),
+            Some("@alice")
+        );
+        assert_eq!(
+            owner(&codeowners, "docs/guide/deep/intro.md").as_deref(),
+            Some("@org/docs")
+        );
+        assert_eq!(owner(&codeowners, "src/main.rs"), None);
+    }
+}
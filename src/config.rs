@@ -0,0 +1,2624 @@
+//! Parsing of the `[preprocessor.build-annotations]` table in book.toml.
+
+use crate::cli::ConfigOverrides;
+use crate::error::{Error, Result};
+use mdbook_preprocessor::PreprocessorContext;
+use mdbook_preprocessor::book::Chapter;
+use schemars::JsonSchema;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// The name of our table under `[preprocessor]` in book.toml.
+pub(crate) const CONFIG_TABLE: &str = "build-annotations";
+
+/// Older names for our table, still read (with a deprecation warning) so books don't all have to be updated at once.
+const LEGACY_CONFIG_TABLES: &[&str] = &["buildtime-annotations"];
+
+/// Prefix for environment variables overriding config keys, eg `MDBOOK_BUILDTIME_COMMIT_CHARACTERS=7`.
+const ENV_PREFIX: &str = "MDBOOK_BUILDTIME_";
+
+/// Environment variables that can always be read, on top of `env_allowlist`: the ones CI systems set to describe the build, which say nothing about who ran it.
+pub const DEFAULT_ENV_ALLOWLIST: &[&str] = &[
+    "CI",
+    "GITHUB_ACTIONS",
+    "GITHUB_HEAD_REF",
+    "GITHUB_REF_NAME",
+    "GITHUB_REF_TYPE",
+    "GITHUB_REPOSITORY",
+    "GITHUB_RUN_ID",
+    "GITHUB_RUN_NUMBER",
+    "GITHUB_SERVER_URL",
+    "GITHUB_SHA",
+    "GITHUB_WORKFLOW",
+    "GITLAB_CI",
+    "CI_COMMIT_BRANCH",
+    "CI_COMMIT_SHA",
+    "CI_COMMIT_TAG",
+    "CI_JOB_ID",
+    "CI_MERGE_REQUEST_SOURCE_BRANCH_NAME",
+    "CI_PIPELINE_ID",
+    "CI_PIPELINE_URL",
+    "CI_PROJECT_PATH",
+    "BUILDKITE",
+    "BUILDKITE_BRANCH",
+    "BUILDKITE_BUILD_NUMBER",
+    "BUILDKITE_BUILD_URL",
+    "BUILDKITE_COMMIT",
+    "CIRCLECI",
+    "CIRCLE_BRANCH",
+    "CIRCLE_BUILD_NUM",
+    "CIRCLE_BUILD_URL",
+    "CIRCLE_SHA1",
+    "BRANCH_NAME",
+    "BUILD_NUMBER",
+];
+
+/// Keys mdbook itself reads from every preprocessor table, which we shouldn't warn about.
+const MDBOOK_KEYS: &[&str] = &["command", "before", "after", "optional"];
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// How many characters of the git commit hash to include in the annotation. If unset it's worked out the way git abbreviates commit hashes: the repository's `core.abbrev` if that's set, otherwise enough characters to tell the repository's objects apart, and at least 7.
+    pub commit_characters: Option<usize>,
+    /// Defaults to "lower" if unset, and is the case the commit hash is shown in, "lower" or "upper". Links to the commit always use the lowercase hash.
+    pub hash_case: HashCase,
+    /// Defaults to "@" if unset, and goes in front of the commit hash in the annotation, eg "g" like `git describe`, or "" for nothing.
+    #[schemars(extend("default" = "@"))]
+    pub hash_prefix: String,
+    /// Where to look for the Cargo.toml. If unset we walk up from the book root and use the first Cargo.toml found, the same way cargo finds a workspace. This should typically be set to the root of the workspace, so that it works correctly in both workspace and non-workspace projects.
+    pub workspace_dir: Option<PathBuf>,
+    /// Defaults to `root_dir` or "../" if unset, and is used to determine where to look for the .git directory. This should typically be set to the root of the workspace, so that it works correctly in both workspace and non-workspace projects.
+    pub git_dir: Option<PathBuf>,
+    /// Sets both `workspace_dir` and `git_dir`, which are nearly always the same directory. Either of those keys still wins over this one if it's set.
+    pub root_dir: Option<PathBuf>,
+    /// Not annotated if unset, otherwise the directory of the Cargo.toml of a second project (eg the software the book documents), whose name and version are added to the annotation in a segment of their own, after the book's.
+    pub code_workspace_dir: Option<PathBuf>,
+    /// Not annotated if unset, otherwise the directory of the .git directory of a second project, whose HEAD commit is added to its segment of the annotation.
+    pub code_git_dir: Option<PathBuf>,
+    /// The package name if unset, otherwise shown in place of the package name in the book's segment of the annotation, eg "docs".
+    pub book_label: Option<String>,
+    /// The second project's package name if unset, otherwise shown in its place in the `code_workspace_dir`/`code_git_dir` segment, eg "engine".
+    pub code_label: Option<String>,
+    /// Defaults to " · " if unset, and goes between the book's and the second project's segments of the annotation.
+    #[schemars(extend("default" = " · "))]
+    pub segment_separator: String,
+    /// Uses the built-in layout if unset, otherwise the annotation's text, with each field's name in braces replaced by its value, eg "{name} {version} @{commit}". The fields are the ones in the `write_build_info` file (`name`, `version`, `commit`, `commit_id`, `branch`, `commit_date`, `commit_time`, `code.name`, `trailer.Ticket` and so on), and `env.NAME` for the environment variable `NAME` if it's allowed by `env_allowlist`. Fields that weren't found are left empty, and anything in braces that isn't a field is left as it is. `package_name`, `git_commit`, `package_version`, the labels and `hash_prefix` only apply to the built-in layout.
+    pub template: Option<String>,
+    /// Environment variables (or patterns like `branches`, eg "DEPLOY_*") that can be read, on top of the CI systems' well-known ones in [`DEFAULT_ENV_ALLOWLIST`]. Anything else is never read, even if a `template` asks for it with `{env.NAME}`, so nothing unexpected ends up in the book.
+    pub env_allowlist: Vec<String>,
+    /// Defaults to true if unset, and determines whether to include the package name in the annotation
+    pub package_name: bool,
+    /// Defaults to true if unset, and determines whether to include the package version in the annotation
+    pub package_version: bool,
+    /// Defaults to true if unset, and determines whether to include the git commit in the annotation
+    pub git_commit: bool,
+    /// Defaults to true if unset, and skips adding the annotation to chapters with no content
+    pub skip_empty: bool,
+    /// Unlimited if unset, otherwise only chapters whose section number has at most this many components are annotated. Unnumbered chapters aren't affected.
+    pub max_depth: Option<usize>,
+    /// Annotates every chapter if unset, otherwise only chapters under the named SUMMARY.md parts (eg `# Reference`) are annotated.
+    pub parts: Option<Vec<String>>,
+    /// Annotates every chapter if unset, otherwise only chapters whose source path (relative to the book's `src` directory, after `language_dirs`, with `/` between directories) matches one of these patterns are annotated, eg `["index.md", "guide/*"]`. The patterns work like `branches`. Draft chapters don't have a path, so they're left out.
+    pub chapters: Vec<String>,
+    /// Defaults to false if unset, and adds a generated chapter to the end of the book listing the build information: the package, version, commit, branch, tag, commit date, build time and CI run link, and the `code_workspace_dir` project's and other providers' fields
+    pub appendix: bool,
+    /// Defaults to "Build information" if unset, and is the title of the generated appendix chapter
+    pub appendix_title: String,
+    /// Defaults to false if unset, and adds a generated chapter to the end of the book with a table of every annotated chapter, when it was last changed and by whom, most recent first. Needs the `git` feature.
+    pub history_appendix: bool,
+    /// Defaults to "Revision history" if unset, and is the title of the generated revision history chapter
+    pub history_appendix_title: String,
+    /// Defaults to false if unset, and adds a generated chapter to the end of the book listing the commits in each of the last `changelog_tags` releases, newest first, linked with `commit_url_template`. Needs the `git` feature, and is left out of shallow clones and repositories without tags.
+    pub changelog_chapter: bool,
+    /// Defaults to "Changelog" if unset, and is the title of the generated changelog chapter
+    pub changelog_chapter_title: String,
+    /// Defaults to 3 if unset, and is how many tagged releases the changelog chapter lists, on top of the commits since the last one
+    #[schemars(extend("default" = 3))]
+    pub changelog_tags: usize,
+    /// Lists every commit in the changelog chapter if unset, otherwise only commits that changed a file whose path (relative to the top of the repository) matches one of these patterns, eg `["src/**"]`. The patterns work like `branches`.
+    pub changelog_path_filter: Vec<String>,
+    /// Defaults to false if unset, and when true the changelog chapter only follows the first parent of each commit, like `git log --first-parent`, so a merged branch is just its merge commit
+    pub changelog_first_parent: bool,
+    /// Defaults to false if unset, and when true a section titled `whats_new_title` listing the subjects of the commits since the last tag that changed the book is added to the book's first chapter, where it has a `<!-- buildtime-whats-new -->` comment or at the end. It's left out if there aren't any. Needs the `git` feature.
+    pub whats_new: bool,
+    /// Defaults to "What's new since {tag}" if unset, and is the heading of the `whats_new` section, with `{tag}` the last tag
+    #[schemars(extend("default" = "What's new since {tag}"))]
+    pub whats_new_title: String,
+    /// Defaults to 5 if unset, and is the most commits the `whats_new` section lists, any more are counted in an "…and 12 more" line
+    #[schemars(extend("default" = 5))]
+    pub whats_new_max: usize,
+    /// Lists commits that changed the book's `src` directory in the `whats_new` section if unset, otherwise commits that changed a file whose path (relative to the top of the repository) matches one of these patterns, like `changelog_path_filter`
+    pub whats_new_paths: Vec<String>,
+    /// Defaults to false if unset, and when true `title_suffix_template` is appended to the title of the book's first chapter (or of the chapters matching `title_suffix_chapters`), which mdbook uses in the sidebar and the page's `<title>`. It isn't added to a title that already ends with it.
+    pub title_suffix: bool,
+    /// Defaults to " — v{version}" if unset, and is what `title_suffix` appends, with the same `{field}`s as `template`. It's left off if any of its fields can't be found.
+    #[schemars(extend("default" = " — v{version}"))]
+    pub title_suffix_template: String,
+    /// Only the book's first chapter if unset, otherwise the chapters whose source path (like `chapters`) matches one of these patterns get the `title_suffix`.
+    pub title_suffix_chapters: Vec<String>,
+    /// Empty if unset, and lists translation directories (eg `["en", "de"]`) that are stripped from the start of chapter source paths before they're matched against `chapters` and `title_suffix_chapters`, so `de/reference/intro.md` is treated as `reference/intro.md`.
+    pub language_dirs: Vec<String>,
+    /// Appends the annotation if unset, otherwise the annotation is inserted just above the first heading with this exact text (at any level), falling back to appending if there's no such heading.
+    pub insert_before_heading: Option<String>,
+    /// Defaults to false if unset, and when true the annotation is a block of its own after an `<!-- i18n:skip -->` comment, so mdbook-i18n-helpers' `mdbook-xgettext` leaves it out of the POT file. It's always appended to the end of the chapter, so `insert_before_heading` is ignored and no message in the chapter moves.
+    pub gettext_skip: bool,
+    /// Defaults to "html" for the html renderer, "epub" for the epub renderer and "markdown" for every other renderer if unset, and determines how the annotation is written into the chapter. "html" wraps it in a `<footer>` tag, "epub" in a `<div class="buildtime-annotations">` that's valid XHTML, and "markdown" adds it as a plain paragraph. "comment" makes it an HTML comment, which isn't shown but is there for tools reading the built pages. "frontmatter" puts the fields under a `buildtime` key in a YAML frontmatter block at the top of the chapter, merged into the chapter's own if it has one, for renderers that read it. It only has the fields the built-in layout shows, so `template`, the per-chapter fields and the links are left out.
+    #[schemars(extend("default" = "html"))]
+    pub format: Format,
+    /// Supports every renderer if unset, otherwise the book is only annotated for the listed renderers (eg `["html", "markdown"]`). mdbook also reads this key to decide which renderers to run the preprocessor for.
+    pub renderers: Option<Vec<String>>,
+    /// Defaults to `["test"]` if unset, and the book is returned untouched for these renderers even if `renderers` would annotate it, so `mdbook test` (which runs preprocessors as the "test" renderer) doesn't test the annotation. Set it to `[]` to annotate for every renderer.
+    #[schemars(extend("default" = ["test"]))]
+    pub skip_renderers: Vec<String>,
+    /// Not used if unset, otherwise the name of the table in `profiles` whose keys override the main table's (and are overridden by a per-renderer table's). It's usually set with `MDBOOK_BUILDTIME_PROFILE` rather than in book.toml, and defaults to "ci" when the `CI` environment variable is true and there's a `ci` profile.
+    pub profile: Option<String>,
+    /// Empty if unset, otherwise tables of keys to override the main table's with (eg `[preprocessor.build-annotations.profiles.ci]`), one of which is picked by `profile`.
+    #[schemars(with = "BTreeMap<String, BTreeMap<String, serde_json::Value>>")]
+    pub profiles: BTreeMap<String, toml::Table>,
+    /// Annotates the book on every branch if unset, otherwise only when it's built from a branch matching one of these patterns (eg `["main", "release/*"]`), and returns it untouched on any other branch or when the branch can't be found. `*` matches any run of characters (including `/`) and `?` any one character. The branch is the one HEAD is on, or the one the CI system says it's building when HEAD is detached.
+    pub branches: Vec<String>,
+    /// Defaults to false if unset, and when true `branches` lists the branches the book isn't annotated on instead, and books built from anywhere else (including when the branch can't be found) are annotated.
+    pub branches_invert: bool,
+    /// Empty if unset, otherwise branch patterns (like `branches`) with the settings to use when the book's built from a matching branch, eg `{ "main" = "{name} {version}", "*" = { template = "PREVIEW BUILD {branch} @{commit}", hash_case = "upper" } }`. A string is just the `template`, a table can override any other key. The first pattern that matches the branch, in the order they're written, is used, and on branches none of them match the rest of the config is used as it is. Keys that change where the fields are read from (`workspace_dir`, `git_dir`, `trailers` and so on) are read before the branch is known, so they can't be overridden here.
+    #[serde(with = "ordered_map")]
+    #[schemars(with = "BTreeMap<String, Variant>")]
+    pub variants: Vec<(String, Variant)>,
+    /// Empty if unset, in which case the rest of the table describes the one annotation added to each chapter. Otherwise each entry is an annotation of its own, added in order, with any keys of the main table overridden, eg `[{ format = "comment" }, { chapters = ["index.md"], template = "Built from {branch}" }]`. The fields are only collected once and shared by all of them, so keys that change where they're read from (`workspace_dir`, `git_dir`, `trailers`, `history_limit` and so on) can't be overridden, and neither can the ones that aren't about the chapters' annotations (`write_build_info`, `appendix`, the banners and so on), which always come from the main table. The banners go on the chapters the first annotation is added to.
+    #[schemars(with = "Vec<BTreeMap<String, serde_json::Value>>")]
+    pub annotations: Vec<toml::Table>,
+    /// Defaults to "compatible" if unset, which warns when the mdbook running the preprocessor isn't semver-compatible with the one it was built against. "strict" warns whenever the versions differ at all, and "off" never warns.
+    #[schemars(extend("default" = "compatible"))]
+    pub version_check: VersionCheck,
+    /// Defaults to false if unset, in which case relative `workspace_dir` and `git_dir` paths are resolved against the directory mdbook was run from. When true they're resolved against the book root (where book.toml is), which doesn't depend on how mdbook was invoked, and will become the default in a future release.
+    pub resolve_from_book_root: bool,
+    /// Unlimited if unset, otherwise the number of parent directories above the book root to search for a Cargo.toml when `workspace_dir` isn't set.
+    pub discovery_levels: Option<usize>,
+    /// Uses the only member if unset, otherwise the workspace member (eg "crates/core") whose name and version go in the annotation when the Cargo.toml is a virtual workspace manifest, one with `[workspace]` and no `[package]`. Relative to the directory that Cargo.toml is in.
+    pub member_path: Option<PathBuf>,
+    /// Not written if unset, otherwise the build information is written to this file as JSON (see the `build_info` module for its shape) every time the book is built. Relative paths are resolved against the book root.
+    pub write_build_info: Option<PathBuf>,
+    /// Not written if unset, otherwise a report of the run is written to this file as JSON (see the `report` module for its shape) after the book's been annotated: the resolved config, the annotation's fields, which chapters were annotated or skipped and why, and how long each phase took. Relative paths are resolved against the book root. Failing to write it is only logged, even with `strict`.
+    pub report_file: Option<PathBuf>,
+    /// Defaults to false if unset, in which case problems that don't stop the book being annotated, like failing to write `write_build_info` or the git repository not being found, are only logged. When true they fail the build.
+    pub strict: bool,
+    /// Nothing is required if unset, otherwise the fields (`package_name`, `package_version` or `git_commit`) that fail the build when they can't be found, instead of being logged and left out.
+    pub required: Vec<RequiredField>,
+    /// Not written if unset, otherwise the annotation is written to this file as an HTML fragment (a `<footer>` element) every time the book is built, for a theme to include with eg `{{> buildinfo}}`. Relative paths are resolved against the book root.
+    pub write_theme_partial: Option<PathBuf>,
+    /// Not written if unset, otherwise the build information is written to this file as a script setting `window.MDBOOK_BUILD_INFO` to the same object as the `write_build_info` file, for theme scripts. Relative paths are resolved against the book root.
+    pub write_js: Option<PathBuf>,
+    /// Defaults to false if unset, and when true the annotation isn't added to the chapters at all, for when it's only wanted in the `write_theme_partial`, `write_js` or `write_build_info` file or the appendix.
+    pub skip_chapters: bool,
+    /// Defaults to false if unset, and when true each chapter's annotation ends with "Last updated" and the date of the most recent commit that changed the chapter's file. Needs the `git` feature.
+    pub chapter_dates: bool,
+    /// Defaults to "%Y-%m-%d" if unset, and is the strftime format `chapter_dates` are written in.
+    #[schemars(extend("default" = "%Y-%m-%d"))]
+    pub date_format: String,
+    /// Defaults to "absolute" if unset, which shows `chapter_dates` as eg "Last updated 2024-05-12" in `date_format`. "relative" shows them as eg "Updated 2 weeks ago" instead, relative to when the book is built (or `SOURCE_DATE_EPOCH`).
+    #[schemars(extend("default" = "absolute"))]
+    pub chapter_date_style: DateStyle,
+    /// Shows nothing if unset, otherwise this is shown in place of the date for chapters no commit has changed yet (eg "Unreleased").
+    pub chapter_dates_fallback: Option<String>,
+    /// Defaults to false if unset, and when true `chapter_dates` notes chapters whose file has changed since the last commit with " (modified locally)".
+    pub note_local_changes: bool,
+    /// Defaults to false if unset, and when true each chapter's annotation ends with "Contributors:" and the authors of the commits that changed the chapter's file, most commits first. Needs the `git` feature.
+    pub chapter_contributors: bool,
+    /// Defaults to false if unset, and when true each chapter's annotation includes "First published" and the author date of the commit that added the chapter's file. Needs the `git` feature.
+    pub chapter_created: bool,
+    /// Defaults to false if unset, and when true each chapter's annotation ends with "rev" and the number of commits that changed the chapter's file, eg "rev 14". Needs the `git` feature.
+    pub chapter_revisions: bool,
+    /// Defaults to 500 if unset, and is the most `chapter_revisions` are counted up to, more are shown as eg "rev 500+". This bounds how much history is walked for each chapter.
+    #[schemars(extend("default" = 500))]
+    pub revisions_max: usize,
+    /// Unlimited if unset, otherwise the most commits the per-chapter fields look back through. Chapters whose history goes back further show what was found, marked as approximate, eg "rev 14+".
+    pub history_limit: Option<usize>,
+    /// Unlimited if unset, otherwise the per-chapter fields only look at commits from this date on, eg "2022-01-01" (the start of that day in UTC) or "2022-01-01 12:00:00" (in UTC). Chapters whose history goes back further are marked as approximate like with `history_limit`.
+    pub history_since: Option<String>,
+    /// Unlimited if unset, otherwise at most this many `chapter_contributors` are listed.
+    pub contributors_max: Option<usize>,
+    /// Defaults to false if unset, in which case the authors of merge commits aren't counted as `chapter_contributors`.
+    pub contributors_merges: bool,
+    /// Authors to leave out of `chapter_contributors`, eg `["dependabot[bot]", "*-bot"]`. `*` matches any run of characters and `?` any one character, everything else (including `[`) has to match exactly, and case matters. Names are matched after they've been through the `.mailmap`.
+    pub exclude_authors: Vec<String>,
+    /// Defaults to false if unset, and when true each chapter's annotation ends with "Owners:" and the owners of the chapter's file from the repository's CODEOWNERS file (in `.github/`, the top of the repository or `docs/`, the first GitHub finds), eg "Owners: @org/docs, @alice". Chapters no rule owns don't get it. Needs the `git` feature.
+    pub codeowners: bool,
+    /// Trailers to read from the HEAD commit's message (eg `["Reviewed-by", "Ticket"]`) and add to the end of the annotation, as the `trailer.<name>` fields. Commits without a trailer just leave it out. Needs the `git` feature.
+    pub trailers: Vec<String>,
+    /// Defaults to ", " if unset, and goes between the values of a trailer that's in the commit more than once.
+    #[schemars(extend("default" = ", "))]
+    pub trailer_separator: String,
+    /// Defaults to true if unset, and takes email addresses out of `chapter_contributors` and the `trailers` (eg `Reviewed-by: Bob <bob@example.com>`), so turning those on doesn't publish anyone's address. How is up to `email_redaction`.
+    pub redact_emails: bool,
+    /// Defaults to "strip" if unset, which leaves `redact_emails`' addresses out completely ("Bob <bob@example.com>" becomes "Bob"), or "hash", which replaces them with a short token made from the address, eg "Bob <user-1a2b3c4d>". The same address always gets the same token, so people can still be told apart.
+    pub email_redaction: EmailRedaction,
+    /// Defaults to false if unset, and when true nothing that changes from one build of the same sources to the next is written, so builds are byte-for-byte reproducible: `chapter_date_style = "relative"` shows absolute dates instead, the `write_build_info` file's `built_at` and the `appendix`'s build time are `SOURCE_DATE_EPOCH` (or left out if that isn't set), the `appendix` has no CI link, the `report_file` leaves out the timings and `{env.NAME}` template fields are left empty. What was left out is logged.
+    pub deterministic: bool,
+    /// Defaults to false if unset, and when true every annotated chapter starts with `dirty_banner_text` when the git working tree has uncommitted changes, so a book built from local experiments can't be mistaken for a published one. Needs the `git` feature.
+    pub dirty_banner: bool,
+    /// Defaults to "⚠ This book was built from a modified working tree and may not match any committed state" if unset, and is the `dirty_banner`'s text.
+    #[schemars(extend("default" = "⚠ This book was built from a modified working tree and may not match any committed state"))]
+    pub dirty_banner_text: String,
+    /// Defaults to "buildtime-dirty" if unset, and is the class of the `dirty_banner`'s `<div>`, for styling it.
+    #[schemars(extend("default" = "buildtime-dirty"))]
+    pub dirty_banner_class: String,
+    /// Defaults to false if unset, and when true every annotated chapter starts with `prerelease_banner_template` when the version is a semver prerelease, eg `2.0.0-alpha.3`.
+    pub prerelease_banner: bool,
+    /// Defaults to "You are reading documentation for an unreleased version ({version})." if unset, and is the `prerelease_banner`, with the version in place of `{version}`.
+    #[schemars(extend("default" = "You are reading documentation for an unreleased version ({version})."))]
+    pub prerelease_banner_template: String,
+    /// Defaults to false if unset, and when true the annotation ends with `unreleased_notice_template` when HEAD is ahead of the closest tag in its history, so readers know the book might not match the latest release. Needs the `git` feature.
+    pub unreleased_notice: bool,
+    /// Defaults to "contains unreleased changes ({commits} since {tag})" if unset, and is the `unreleased_notice`, with the tag in place of `{tag}`, the number of commits since it in place of `{count}` and eg "7 commits" (or "1 commit") in place of `{commits}`.
+    #[schemars(extend("default" = "contains unreleased changes ({commits} since {tag})"))]
+    pub unreleased_notice_template: String,
+    /// Unlimited if unset, otherwise field values with more characters than this are cut short and end with `ellipsis`, so a long trailer or branch name can't take over the annotation. The commit (a hash) and values that are URLs are never cut, unless they're in `field_max_lengths`.
+    pub max_field_length: Option<usize>,
+    /// Per-field overrides of `max_field_length`, by field name (eg `{ branch = 20, "trailer.Ticket" = 12 }`). The field names are the ones in the `write_build_info` file.
+    pub field_max_lengths: BTreeMap<String, usize>,
+    /// Defaults to "…" if unset, and goes on the end of values cut short by `max_field_length` or `field_max_lengths`.
+    #[schemars(extend("default" = "…"))]
+    pub ellipsis: String,
+    /// Unlinked if unset, otherwise the URL of a commit on your forge with `{commit}` where the full commit id goes, eg "https://github.com/org/repo/commit/{commit}". Each chapter's `chapter_dates` date links to the commit that last changed it.
+    pub commit_url_template: Option<String>,
+    /// Not linked if unset (or blank), otherwise the annotation ends with a "Changelog" link to this URL. Relative URLs (eg "changelog.md") are left as they are, so they're resolved from each page like any other link.
+    pub changelog_url: Option<String>,
+    /// Not added if unset, otherwise it's added to the end of `changelog_url` with the version in place of `{version}`, eg "#v{version}", so the link goes to the current version's section.
+    pub changelog_anchor_template: Option<String>,
+    /// Defaults to true if unset, and links the version to its release page when there's a tag for it (eg `v1.4.2`), for repositories on GitHub, GitLab or Gitea or with a `release_url_template`. Needs the `git` feature.
+    pub release_link: bool,
+    /// Worked out from the `origin` remote for GitHub, GitLab and Gitea if unset, otherwise the URL of a release on your forge with `{tag}` where the tag goes, eg "https://git.example.com/org/repo/releases/{tag}".
+    pub release_url_template: Option<String>,
+    /// Defaults to false if unset, and when true each chapter's annotation ends with an "Edit this page" link, made from `edit_url_template`. Chapters without a source file don't get one.
+    pub edit_link: bool,
+    /// The URL to edit a chapter on your forge, with `{path}` where the chapter's path (relative to the book's `src` directory) goes and `{branch}` where the branch goes, eg "https://github.com/org/repo/edit/{branch}/docs/src/{path}". Both are URL-encoded.
+    pub edit_url_template: Option<String>,
+    /// Defaults to the current branch if unset, and is the branch `edit_url_template` and `history_url_template` link to.
+    pub edit_branch: Option<String>,
+    /// Defaults to false if unset, and when true each chapter's annotation ends with a link to the page listing the commits that changed the chapter's file on your forge, made from `history_url_template` or worked out from the `origin` remote for GitHub, GitLab and Gitea. Chapters without a source file don't get one.
+    pub history_link: bool,
+    /// Worked out from the `origin` remote if unset (needs the `git` feature), otherwise the URL of a chapter's history on your forge, with `{path}` and `{branch}` like `edit_url_template`, eg "https://github.com/org/repo/commits/{branch}/docs/src/{path}".
+    pub history_url_template: Option<String>,
+    /// Defaults to "View history" if unset, and is the text of the `history_link`
+    #[schemars(extend("default" = "View history"))]
+    pub history_link_text: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Html,
+    Markdown,
+    /// A `<div>` rather than a `<footer>`, which epubcheck accepts wherever the chapter puts it.
+    Epub,
+    /// An HTML comment, which isn't shown, for tools that read the built pages.
+    Comment,
+    /// A `buildtime` key in a YAML frontmatter block at the top of the chapter, for renderers that read it.
+    Frontmatter,
+}
+
+/// The case commit hashes are shown in, see `hash_case`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashCase {
+    #[default]
+    Lower,
+    Upper,
+}
+
+impl HashCase {
+    /// `hash` in this case.
+    pub fn apply(self, hash: &str) -> String {
+        match self {
+            HashCase::Lower => hash.to_ascii_lowercase(),
+            HashCase::Upper => hash.to_ascii_uppercase(),
+        }
+    }
+}
+
+/// How `redact_emails` takes email addresses out.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailRedaction {
+    #[default]
+    Strip,
+    Hash,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateStyle {
+    #[default]
+    Absolute,
+    Relative,
+}
+
+/// The settings for the branches matching one of the `variants` patterns.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Variant {
+    /// Just the `template`.
+    Template(String),
+    /// Any keys of the main table, with the values to use instead.
+    Overrides(#[schemars(with = "BTreeMap<String, serde_json::Value>")] toml::Table),
+}
+
+impl Variant {
+    /// The keys this variant overrides, and their values.
+    pub fn overrides(&self) -> toml::Table {
+        match self {
+            Variant::Template(template) => {
+                toml::Table::from_iter([("template".to_string(), template.clone().into())])
+            }
+            Variant::Overrides(table) => table.clone(),
+        }
+    }
+}
+
+impl From<&str> for Variant {
+    fn from(template: &str) -> Self {
+        Variant::Template(template.to_string())
+    }
+}
+
+impl From<toml::Table> for Variant {
+    fn from(overrides: toml::Table) -> Self {
+        Variant::Overrides(overrides)
+    }
+}
+
+/// (De)serializes `variants` as a table, keeping the order its keys are written in.
+mod ordered_map {
+    use super::Variant;
+    use serde::de::{MapAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        variants: &[(String, Variant)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(variants.iter().map(|(pattern, variant)| (pattern, variant)))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(String, Variant)>, D::Error> {
+        struct OrderedMap;
+
+        impl<'de> Visitor<'de> for OrderedMap {
+            type Value = Vec<(String, Variant)>;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a table of branch patterns")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut variants = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    variants.push(entry);
+                }
+                Ok(variants)
+            }
+        }
+
+        deserializer.deserialize_map(OrderedMap)
+    }
+}
+
+/// A field that `required` can list.
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequiredField {
+    PackageName,
+    PackageVersion,
+    GitCommit,
+}
+
+impl RequiredField {
+    /// The config key the field is named after.
+    pub fn key(self) -> &'static str {
+        match self {
+            RequiredField::PackageName => "package_name",
+            RequiredField::PackageVersion => "package_version",
+            RequiredField::GitCommit => "git_commit",
+        }
+    }
+}
+
+/// When to warn that mdbook's version doesn't match the one we were built against, see `version_check`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionCheck {
+    Strict,
+    #[default]
+    Compatible,
+    Off,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            commit_characters: None,
+            hash_case: Default::default(),
+            hash_prefix: "@".to_string(),
+            workspace_dir: None,
+            git_dir: None,
+            root_dir: None,
+            code_workspace_dir: None,
+            code_git_dir: None,
+            book_label: None,
+            code_label: None,
+            segment_separator: " · ".to_string(),
+            template: None,
+            env_allowlist: Vec::new(),
+            package_name: true,
+            package_version: true,
+            git_commit: true,
+            skip_empty: true,
+            max_depth: None,
+            parts: None,
+            chapters: Vec::new(),
+            appendix: false,
+            appendix_title: "Build information".to_string(),
+            history_appendix: false,
+            history_appendix_title: "Revision history".to_string(),
+            changelog_chapter: false,
+            changelog_chapter_title: "Changelog".to_string(),
+            changelog_tags: 3,
+            changelog_path_filter: Vec::new(),
+            changelog_first_parent: false,
+            whats_new: false,
+            whats_new_title: "What's new since {tag}".to_string(),
+            whats_new_max: 5,
+            whats_new_paths: Vec::new(),
+            title_suffix: false,
+            title_suffix_template: " — v{version}".to_string(),
+            title_suffix_chapters: Vec::new(),
+            language_dirs: Vec::new(),
+            insert_before_heading: None,
+            gettext_skip: false,
+            format: Default::default(),
+            renderers: None,
+            skip_renderers: vec!["test".to_string()],
+            profile: None,
+            profiles: BTreeMap::new(),
+            branches: Vec::new(),
+            branches_invert: false,
+            variants: Vec::new(),
+            annotations: Vec::new(),
+            version_check: Default::default(),
+            resolve_from_book_root: false,
+            discovery_levels: None,
+            member_path: None,
+            write_build_info: None,
+            report_file: None,
+            strict: false,
+            required: Vec::new(),
+            write_theme_partial: None,
+            write_js: None,
+            skip_chapters: false,
+            chapter_dates: false,
+            date_format: "%Y-%m-%d".to_string(),
+            chapter_date_style: Default::default(),
+            chapter_dates_fallback: None,
+            note_local_changes: false,
+            chapter_contributors: false,
+            chapter_created: false,
+            chapter_revisions: false,
+            revisions_max: 500,
+            history_limit: None,
+            history_since: None,
+            contributors_max: None,
+            contributors_merges: false,
+            exclude_authors: Vec::new(),
+            codeowners: false,
+            trailers: Vec::new(),
+            trailer_separator: ", ".to_string(),
+            redact_emails: true,
+            email_redaction: Default::default(),
+            deterministic: false,
+            dirty_banner: false,
+            dirty_banner_text: "⚠ This book was built from a modified working tree and may not match any committed state".to_string(),
+            dirty_banner_class: "buildtime-dirty".to_string(),
+            prerelease_banner: false,
+            prerelease_banner_template:
+                "You are reading documentation for an unreleased version ({version}).".to_string(),
+            unreleased_notice: false,
+            unreleased_notice_template: "contains unreleased changes ({commits} since {tag})"
+                .to_string(),
+            max_field_length: None,
+            field_max_lengths: BTreeMap::new(),
+            ellipsis: "…".to_string(),
+            commit_url_template: None,
+            changelog_url: None,
+            changelog_anchor_template: None,
+            release_link: true,
+            release_url_template: None,
+            edit_link: false,
+            edit_url_template: None,
+            edit_branch: None,
+            history_link: false,
+            history_url_template: None,
+            history_link_text: "View history".to_string(),
+        }
+    }
+}
+
+/// Builds a [`Config`] in code, starting from the defaults, for when you're not going via book.toml.
+///
+/// ```
+/// use mdbook_buildtime_annotations::Config;
+///
+/// let cfg = Config::builder().commit_characters(7).git_commit(false).build();
+/// assert_eq!(cfg.commit_characters, Some(7));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn commit_characters(mut self, commit_characters: usize) -> Self {
+        self.config.commit_characters = Some(commit_characters);
+        self
+    }
+
+    pub fn hash_case(mut self, hash_case: HashCase) -> Self {
+        self.config.hash_case = hash_case;
+        self
+    }
+
+    pub fn hash_prefix(mut self, hash_prefix: impl Into<String>) -> Self {
+        self.config.hash_prefix = hash_prefix.into();
+        self
+    }
+
+    pub fn workspace_dir(mut self, workspace_dir: impl Into<PathBuf>) -> Self {
+        self.config.workspace_dir = Some(workspace_dir.into());
+        self
+    }
+
+    pub fn git_dir(mut self, git_dir: impl Into<PathBuf>) -> Self {
+        self.config.git_dir = Some(git_dir.into());
+        self
+    }
+
+    pub fn root_dir(mut self, root_dir: impl Into<PathBuf>) -> Self {
+        self.config.root_dir = Some(root_dir.into());
+        self
+    }
+
+    pub fn code_workspace_dir(mut self, code_workspace_dir: impl Into<PathBuf>) -> Self {
+        self.config.code_workspace_dir = Some(code_workspace_dir.into());
+        self
+    }
+
+    pub fn code_git_dir(mut self, code_git_dir: impl Into<PathBuf>) -> Self {
+        self.config.code_git_dir = Some(code_git_dir.into());
+        self
+    }
+
+    pub fn book_label(mut self, book_label: impl Into<String>) -> Self {
+        self.config.book_label = Some(book_label.into());
+        self
+    }
+
+    pub fn code_label(mut self, code_label: impl Into<String>) -> Self {
+        self.config.code_label = Some(code_label.into());
+        self
+    }
+
+    pub fn segment_separator(mut self, segment_separator: impl Into<String>) -> Self {
+        self.config.segment_separator = segment_separator.into();
+        self
+    }
+
+    pub fn template(mut self, template: impl Into<String>) -> Self {
+        self.config.template = Some(template.into());
+        self
+    }
+
+    pub fn env_allowlist(
+        mut self,
+        env_allowlist: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.env_allowlist = env_allowlist.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn package_name(mut self, package_name: bool) -> Self {
+        self.config.package_name = package_name;
+        self
+    }
+
+    pub fn package_version(mut self, package_version: bool) -> Self {
+        self.config.package_version = package_version;
+        self
+    }
+
+    pub fn git_commit(mut self, git_commit: bool) -> Self {
+        self.config.git_commit = git_commit;
+        self
+    }
+
+    pub fn skip_empty(mut self, skip_empty: bool) -> Self {
+        self.config.skip_empty = skip_empty;
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.config.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn parts(mut self, parts: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.parts = Some(parts.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn chapters(mut self, chapters: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.chapters = chapters.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn appendix(mut self, appendix: bool) -> Self {
+        self.config.appendix = appendix;
+        self
+    }
+
+    pub fn appendix_title(mut self, appendix_title: impl Into<String>) -> Self {
+        self.config.appendix_title = appendix_title.into();
+        self
+    }
+
+    pub fn history_appendix(mut self, history_appendix: bool) -> Self {
+        self.config.history_appendix = history_appendix;
+        self
+    }
+
+    pub fn history_appendix_title(mut self, history_appendix_title: impl Into<String>) -> Self {
+        self.config.history_appendix_title = history_appendix_title.into();
+        self
+    }
+
+    pub fn changelog_chapter(mut self, changelog_chapter: bool) -> Self {
+        self.config.changelog_chapter = changelog_chapter;
+        self
+    }
+
+    pub fn changelog_chapter_title(mut self, changelog_chapter_title: impl Into<String>) -> Self {
+        self.config.changelog_chapter_title = changelog_chapter_title.into();
+        self
+    }
+
+    pub fn changelog_tags(mut self, changelog_tags: usize) -> Self {
+        self.config.changelog_tags = changelog_tags;
+        self
+    }
+
+    pub fn changelog_path_filter(mut self, changelog_path_filter: Vec<String>) -> Self {
+        self.config.changelog_path_filter = changelog_path_filter;
+        self
+    }
+
+    pub fn changelog_first_parent(mut self, changelog_first_parent: bool) -> Self {
+        self.config.changelog_first_parent = changelog_first_parent;
+        self
+    }
+
+    pub fn whats_new(mut self, whats_new: bool) -> Self {
+        self.config.whats_new = whats_new;
+        self
+    }
+
+    pub fn whats_new_title(mut self, whats_new_title: impl Into<String>) -> Self {
+        self.config.whats_new_title = whats_new_title.into();
+        self
+    }
+
+    pub fn whats_new_max(mut self, whats_new_max: usize) -> Self {
+        self.config.whats_new_max = whats_new_max;
+        self
+    }
+
+    pub fn whats_new_paths(mut self, whats_new_paths: Vec<String>) -> Self {
+        self.config.whats_new_paths = whats_new_paths;
+        self
+    }
+
+    pub fn title_suffix(mut self, title_suffix: bool) -> Self {
+        self.config.title_suffix = title_suffix;
+        self
+    }
+
+    pub fn title_suffix_template(mut self, title_suffix_template: impl Into<String>) -> Self {
+        self.config.title_suffix_template = title_suffix_template.into();
+        self
+    }
+
+    pub fn title_suffix_chapters(
+        mut self,
+        title_suffix_chapters: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.title_suffix_chapters =
+            title_suffix_chapters.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn language_dirs(
+        mut self,
+        language_dirs: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.language_dirs = language_dirs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn insert_before_heading(mut self, insert_before_heading: impl Into<String>) -> Self {
+        self.config.insert_before_heading = Some(insert_before_heading.into());
+        self
+    }
+
+    pub fn gettext_skip(mut self, gettext_skip: bool) -> Self {
+        self.config.gettext_skip = gettext_skip;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    pub fn renderers(mut self, renderers: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.renderers = Some(renderers.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn skip_renderers(
+        mut self,
+        skip_renderers: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.skip_renderers = skip_renderers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn branches(mut self, branches: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.branches = branches.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn branches_invert(mut self, branches_invert: bool) -> Self {
+        self.config.branches_invert = branches_invert;
+        self
+    }
+
+    /// Adds a variant for branches matching `pattern`, after any that are already set.
+    pub fn variant(mut self, pattern: impl Into<String>, variant: impl Into<Variant>) -> Self {
+        self.config.variants.push((pattern.into(), variant.into()));
+        self
+    }
+
+    /// Adds an annotation of its own, with `overrides` replacing the main config's keys, see `annotations`.
+    pub fn annotation(mut self, overrides: toml::Table) -> Self {
+        self.config.annotations.push(overrides);
+        self
+    }
+
+    pub fn version_check(mut self, version_check: VersionCheck) -> Self {
+        self.config.version_check = version_check;
+        self
+    }
+
+    pub fn resolve_from_book_root(mut self, resolve_from_book_root: bool) -> Self {
+        self.config.resolve_from_book_root = resolve_from_book_root;
+        self
+    }
+
+    pub fn discovery_levels(mut self, discovery_levels: usize) -> Self {
+        self.config.discovery_levels = Some(discovery_levels);
+        self
+    }
+
+    pub fn member_path(mut self, member_path: impl Into<PathBuf>) -> Self {
+        self.config.member_path = Some(member_path.into());
+        self
+    }
+
+    pub fn write_build_info(mut self, write_build_info: impl Into<PathBuf>) -> Self {
+        self.config.write_build_info = Some(write_build_info.into());
+        self
+    }
+
+    pub fn report_file(mut self, report_file: impl Into<PathBuf>) -> Self {
+        self.config.report_file = Some(report_file.into());
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.strict = strict;
+        self
+    }
+
+    pub fn required(mut self, required: impl IntoIterator<Item = RequiredField>) -> Self {
+        self.config.required = required.into_iter().collect();
+        self
+    }
+
+    pub fn write_theme_partial(mut self, write_theme_partial: impl Into<PathBuf>) -> Self {
+        self.config.write_theme_partial = Some(write_theme_partial.into());
+        self
+    }
+
+    pub fn write_js(mut self, write_js: impl Into<PathBuf>) -> Self {
+        self.config.write_js = Some(write_js.into());
+        self
+    }
+
+    pub fn skip_chapters(mut self, skip_chapters: bool) -> Self {
+        self.config.skip_chapters = skip_chapters;
+        self
+    }
+
+    pub fn chapter_dates(mut self, chapter_dates: bool) -> Self {
+        self.config.chapter_dates = chapter_dates;
+        self
+    }
+
+    pub fn date_format(mut self, date_format: impl Into<String>) -> Self {
+        self.config.date_format = date_format.into();
+        self
+    }
+
+    pub fn chapter_date_style(mut self, chapter_date_style: DateStyle) -> Self {
+        self.config.chapter_date_style = chapter_date_style;
+        self
+    }
+
+    pub fn chapter_dates_fallback(mut self, chapter_dates_fallback: impl Into<String>) -> Self {
+        self.config.chapter_dates_fallback = Some(chapter_dates_fallback.into());
+        self
+    }
+
+    pub fn note_local_changes(mut self, note_local_changes: bool) -> Self {
+        self.config.note_local_changes = note_local_changes;
+        self
+    }
+
+    pub fn chapter_contributors(mut self, chapter_contributors: bool) -> Self {
+        self.config.chapter_contributors = chapter_contributors;
+        self
+    }
+
+    pub fn chapter_created(mut self, chapter_created: bool) -> Self {
+        self.config.chapter_created = chapter_created;
+        self
+    }
+
+    pub fn chapter_revisions(mut self, chapter_revisions: bool) -> Self {
+        self.config.chapter_revisions = chapter_revisions;
+        self
+    }
+
+    pub fn revisions_max(mut self, revisions_max: usize) -> Self {
+        self.config.revisions_max = revisions_max;
+        self
+    }
+
+    pub fn history_limit(mut self, history_limit: usize) -> Self {
+        self.config.history_limit = Some(history_limit);
+        self
+    }
+
+    pub fn history_since(mut self, history_since: impl Into<String>) -> Self {
+        self.config.history_since = Some(history_since.into());
+        self
+    }
+
+    pub fn contributors_max(mut self, contributors_max: usize) -> Self {
+        self.config.contributors_max = Some(contributors_max);
+        self
+    }
+
+    pub fn contributors_merges(mut self, contributors_merges: bool) -> Self {
+        self.config.contributors_merges = contributors_merges;
+        self
+    }
+
+    pub fn exclude_authors(
+        mut self,
+        exclude_authors: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.config.exclude_authors = exclude_authors.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn codeowners(mut self, codeowners: bool) -> Self {
+        self.config.codeowners = codeowners;
+        self
+    }
+
+    pub fn commit_url_template(mut self, commit_url_template: impl Into<String>) -> Self {
+        self.config.commit_url_template = Some(commit_url_template.into());
+        self
+    }
+
+    pub fn changelog_url(mut self, changelog_url: impl Into<String>) -> Self {
+        self.config.changelog_url = Some(changelog_url.into());
+        self
+    }
+
+    pub fn changelog_anchor_template(
+        mut self,
+        changelog_anchor_template: impl Into<String>,
+    ) -> Self {
+        self.config.changelog_anchor_template = Some(changelog_anchor_template.into());
+        self
+    }
+
+    pub fn release_link(mut self, release_link: bool) -> Self {
+        self.config.release_link = release_link;
+        self
+    }
+
+    pub fn release_url_template(mut self, release_url_template: impl Into<String>) -> Self {
+        self.config.release_url_template = Some(release_url_template.into());
+        self
+    }
+
+    pub fn edit_link(mut self, edit_link: bool) -> Self {
+        self.config.edit_link = edit_link;
+        self
+    }
+
+    pub fn edit_url_template(mut self, edit_url_template: impl Into<String>) -> Self {
+        self.config.edit_url_template = Some(edit_url_template.into());
+        self
+    }
+
+    pub fn edit_branch(mut self, edit_branch: impl Into<String>) -> Self {
+        self.config.edit_branch = Some(edit_branch.into());
+        self
+    }
+
+    pub fn history_link(mut self, history_link: bool) -> Self {
+        self.config.history_link = history_link;
+        self
+    }
+
+    pub fn history_url_template(mut self, history_url_template: impl Into<String>) -> Self {
+        self.config.history_url_template = Some(history_url_template.into());
+        self
+    }
+
+    pub fn history_link_text(mut self, history_link_text: impl Into<String>) -> Self {
+        self.config.history_link_text = history_link_text.into();
+        self
+    }
+
+    pub fn trailers(mut self, trailers: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.trailers = trailers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn trailer_separator(mut self, trailer_separator: impl Into<String>) -> Self {
+        self.config.trailer_separator = trailer_separator.into();
+        self
+    }
+
+    pub fn redact_emails(mut self, redact_emails: bool) -> Self {
+        self.config.redact_emails = redact_emails;
+        self
+    }
+
+    pub fn email_redaction(mut self, email_redaction: EmailRedaction) -> Self {
+        self.config.email_redaction = email_redaction;
+        self
+    }
+
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.config.deterministic = deterministic;
+        self
+    }
+
+    pub fn dirty_banner(mut self, dirty_banner: bool) -> Self {
+        self.config.dirty_banner = dirty_banner;
+        self
+    }
+
+    pub fn dirty_banner_text(mut self, dirty_banner_text: impl Into<String>) -> Self {
+        self.config.dirty_banner_text = dirty_banner_text.into();
+        self
+    }
+
+    pub fn dirty_banner_class(mut self, dirty_banner_class: impl Into<String>) -> Self {
+        self.config.dirty_banner_class = dirty_banner_class.into();
+        self
+    }
+
+    pub fn prerelease_banner(mut self, prerelease_banner: bool) -> Self {
+        self.config.prerelease_banner = prerelease_banner;
+        self
+    }
+
+    pub fn prerelease_banner_template(
+        mut self,
+        prerelease_banner_template: impl Into<String>,
+    ) -> Self {
+        self.config.prerelease_banner_template = prerelease_banner_template.into();
+        self
+    }
+
+    pub fn unreleased_notice(mut self, unreleased_notice: bool) -> Self {
+        self.config.unreleased_notice = unreleased_notice;
+        self
+    }
+
+    pub fn unreleased_notice_template(
+        mut self,
+        unreleased_notice_template: impl Into<String>,
+    ) -> Self {
+        self.config.unreleased_notice_template = unreleased_notice_template.into();
+        self
+    }
+
+    pub fn max_field_length(mut self, max_field_length: usize) -> Self {
+        self.config.max_field_length = Some(max_field_length);
+        self
+    }
+
+    pub fn field_max_length(mut self, field: impl Into<String>, max_length: usize) -> Self {
+        self.config
+            .field_max_lengths
+            .insert(field.into(), max_length);
+        self
+    }
+
+    pub fn ellipsis(mut self, ellipsis: impl Into<String>) -> Self {
+        self.config.ellipsis = ellipsis.into();
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+fn default_git_dir() -> PathBuf {
+    "../".into()
+}
+
+impl Config {
+    /// A JSON Schema describing every option, with its type, default and documentation, generated from this struct.
+    pub fn json_schema() -> serde_json::Value {
+        let mut schema = schemars::schema_for!(Config);
+        schema.insert(
+            "title".to_string(),
+            format!("preprocessor.{CONFIG_TABLE}").into(),
+        );
+        schema.to_value()
+    }
+
+    /// Starts building a config from the defaults.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// The heading to insert the annotation above, `insert_before_heading` unless `gettext_skip` means it always goes at the end.
+    pub(crate) fn before_heading(&self) -> Option<&str> {
+        if self.gettext_skip {
+            return None;
+        }
+        self.insert_before_heading.as_deref()
+    }
+
+    /// Whether any of the per-chapter fields that need the chapter's git history are enabled.
+    pub(crate) fn chapter_history(&self) -> bool {
+        self.chapter_dates
+            || self.chapter_created
+            || self.chapter_revisions
+            || self.chapter_contributors
+    }
+
+    /// `history_since` as seconds since the epoch, or an error if it isn't a date or UTC time.
+    pub(crate) fn history_since_seconds(&self) -> Result<Option<i64>> {
+        let Some(since) = &self.history_since else {
+            return Ok(None);
+        };
+        let time = if since.len() == 10 {
+            humantime::parse_rfc3339_weak(&format!("{since} 00:00:00"))
+        } else {
+            humantime::parse_rfc3339_weak(since)
+        };
+        time.map(|time| {
+            Some(
+                time.duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs() as i64),
+            )
+        })
+        .map_err(|err| Error::ConfigInvalid {
+            key: format!("preprocessor.{CONFIG_TABLE}.history_since"),
+            reason: format!("{since:?} isn't a date like \"2022-01-01\": {err}"),
+        })
+    }
+
+    /// Whether we should annotate the book for the given renderer.
+    pub fn supports_renderer(&self, renderer: &str) -> bool {
+        !self.skips_renderer(renderer)
+            && self
+                .renderers
+                .as_ref()
+                .is_none_or(|renderers| renderers.iter().any(|r| r == renderer))
+    }
+
+    /// Whether the renderer is in `skip_renderers`.
+    pub fn skips_renderer(&self, renderer: &str) -> bool {
+        self.skip_renderers.iter().any(|r| r == renderer)
+    }
+
+    /// Whether the environment variable `name` can be read, going by [`DEFAULT_ENV_ALLOWLIST`] and `env_allowlist`.
+    pub fn allows_env(&self, name: &str) -> bool {
+        DEFAULT_ENV_ALLOWLIST
+            .iter()
+            .copied()
+            .chain(self.env_allowlist.iter().map(String::as_str))
+            .any(|pattern| crate::wildcard::matches(pattern, name))
+    }
+
+    /// The environment variable `name` from `var`, or `None` (with a warning) if it isn't allowed by [`Config::allows_env`].
+    pub fn env_var(&self, name: &str, var: impl Fn(&str) -> Option<String>) -> Option<String> {
+        if !self.allows_env(name) {
+            warn!("The environment variable {name} isn't in env_allowlist, leaving it out");
+            return None;
+        }
+        var(name)
+    }
+
+    /// `text` (a name or trailer) with any email addresses in it taken out, going by `redact_emails` and `email_redaction`.
+    pub fn redact(&self, text: &str) -> String {
+        match self.redact_emails {
+            true => crate::redact::redact(text, self.email_redaction),
+            false => text.to_string(),
+        }
+    }
+
+    /// Whether we should annotate the book when it's built from `branch` (`None` if it couldn't be found), going by `branches` and `branches_invert`.
+    pub fn annotates_branch(&self, branch: Option<&str>) -> bool {
+        if self.branches.is_empty() {
+            return true;
+        }
+        let listed = branch.is_some_and(|branch| {
+            self.branches
+                .iter()
+                .any(|pattern| crate::wildcard::matches(pattern, branch))
+        });
+        listed != self.branches_invert
+    }
+
+    /// This config without the settings `deterministic` turns off, which are logged. Unchanged if it isn't set.
+    pub fn for_determinism(&self) -> std::borrow::Cow<'_, Config> {
+        if !self.deterministic {
+            return std::borrow::Cow::Borrowed(self);
+        }
+        let mut cfg = self.clone();
+        let mut suppressed = Vec::new();
+        if cfg.chapter_date_style == DateStyle::Relative {
+            cfg.chapter_date_style = DateStyle::Absolute;
+            suppressed.push("relative chapter dates (showing absolute ones instead)");
+        }
+        if (cfg.write_build_info.is_some() || cfg.write_js.is_some() || cfg.appendix)
+            && crate::build_info::source_date_epoch().is_none()
+        {
+            suppressed.push(
+                "built_at in write_build_info and write_js and the appendix's build time (SOURCE_DATE_EPOCH isn't set)",
+            );
+        }
+        if cfg.appendix {
+            suppressed.push("the appendix's CI build link");
+        }
+        if cfg.report_file.is_some() {
+            suppressed.push("the timings in report_file");
+        }
+        let templates = [
+            cfg.template.as_deref(),
+            cfg.title_suffix
+                .then_some(cfg.title_suffix_template.as_str()),
+        ];
+        if templates
+            .iter()
+            .flatten()
+            .any(|template| template.contains("{env."))
+        {
+            suppressed.push("the env.* fields in template and title_suffix_template");
+        }
+        if suppressed.is_empty() {
+            debug!("deterministic is set, there's nothing to leave out");
+        } else {
+            info!(
+                "deterministic is set, leaving out {}",
+                suppressed.join(", ")
+            );
+        }
+        std::borrow::Cow::Owned(cfg)
+    }
+
+    /// This config as it applies to a book built from `branch`: with the overrides of the first of the `variants` whose pattern matches it, or unchanged if none do (or the branch couldn't be found).
+    pub fn for_branch(&self, branch: Option<&str>) -> Result<std::borrow::Cow<'_, Config>> {
+        let Some(branch) = branch else {
+            return Ok(std::borrow::Cow::Borrowed(self));
+        };
+        match self
+            .variants
+            .iter()
+            .find(|(pattern, _)| crate::wildcard::matches(pattern, branch))
+        {
+            Some((pattern, variant)) => {
+                debug!("The branch {branch} matches {pattern:?} in variants, using its settings");
+                self.with_variant(pattern, variant)
+                    .map(std::borrow::Cow::Owned)
+            }
+            None => Ok(std::borrow::Cow::Borrowed(self)),
+        }
+    }
+
+    /// The config for each of the annotations added to the chapters: just this one if `annotations` is empty, otherwise one for each of them, with its overrides replaced.
+    pub fn annotation_configs(&self) -> Result<Vec<std::borrow::Cow<'_, Config>>> {
+        if self.annotations.is_empty() {
+            return Ok(vec![std::borrow::Cow::Borrowed(self)]);
+        }
+        self.annotations
+            .iter()
+            .enumerate()
+            .map(|(index, overrides)| {
+                let key = format!("preprocessor.{CONFIG_TABLE}.annotations[{index}]");
+                let mut cfg = self.with_overrides(&key, overrides.clone())?;
+                cfg.annotations = Vec::new();
+                Ok(std::borrow::Cow::Owned(cfg))
+            })
+            .collect()
+    }
+
+    /// This config with the keys `variant` (the one for `pattern`) overrides replaced.
+    fn with_variant(&self, pattern: &str, variant: &Variant) -> Result<Config> {
+        let key = format!("preprocessor.{CONFIG_TABLE}.variants.{pattern:?}");
+        self.with_overrides(&key, variant.overrides())
+    }
+
+    /// This config with the keys in `overrides` replaced, which are from the table `key` for errors.
+    fn with_overrides(&self, key: &str, overrides: toml::Table) -> Result<Config> {
+        let invalid = |err: toml::ser::Error| Error::ConfigInvalid {
+            key: key.to_string(),
+            reason: err.to_string(),
+        };
+        let mut table = toml::Table::try_from(self).map_err(invalid)?;
+        table.extend(overrides);
+        toml::Value::Table(table)
+            .try_into::<Config>()
+            .map_err(|err| Error::ConfigInvalid {
+                key: key.to_string(),
+                reason: describe_error(err.message()),
+            })
+    }
+
+    /// Whether the chapter at `path` (see [`Config::chapter_path`]) is one of the `chapters` to annotate.
+    pub(crate) fn annotates_chapter(&self, path: Option<&Path>) -> bool {
+        if self.chapters.is_empty() {
+            return true;
+        }
+        let Some(path) = path.map(crate::paths::slash_path) else {
+            return false;
+        };
+        self.chapters
+            .iter()
+            .any(|pattern| crate::wildcard::matches(pattern, &path))
+    }
+
+    /// Where to look for the Cargo.toml, either the configured `workspace_dir` or `root_dir` (see `resolve_from_book_root`) or the nearest directory at or above the book root containing one.
+    pub fn workspace_path(&self, book_root: &Path) -> PathBuf {
+        if let Some(workspace_dir) = self.workspace_dir.as_ref().or(self.root_dir.as_ref()) {
+            return self.resolve_path(book_root, workspace_dir);
+        }
+        match discover_workspace(book_root, self.discovery_levels) {
+            Some(path) => {
+                debug!("Found Cargo.toml in {}", path.display());
+                path
+            }
+            None => {
+                warn!(
+                    "Couldn't find a Cargo.toml at or above {}, set workspace_dir to say where it is",
+                    book_root.display()
+                );
+                book_root.to_path_buf()
+            }
+        }
+    }
+
+    /// Where to look for the .git directory, `git_dir` then `root_dir` then "../", see `resolve_from_book_root`.
+    pub fn git_path(&self, book_root: &Path) -> PathBuf {
+        let git_dir = self
+            .git_dir
+            .clone()
+            .or_else(|| self.root_dir.clone())
+            .unwrap_or_else(default_git_dir);
+        self.resolve_path(book_root, &git_dir)
+    }
+
+    /// Where to look for the second project's Cargo.toml, `code_workspace_dir` resolved like `workspace_dir` is. `None` if it isn't set.
+    pub fn code_workspace_path(&self, book_root: &Path) -> Option<PathBuf> {
+        let dir = self.code_workspace_dir.as_ref()?;
+        Some(self.resolve_path(book_root, dir))
+    }
+
+    /// Where to look for the second project's .git directory, `code_git_dir` resolved like `git_dir` is. `None` if it isn't set.
+    pub fn code_git_path(&self, book_root: &Path) -> Option<PathBuf> {
+        let dir = self.code_git_dir.as_ref()?;
+        Some(self.resolve_path(book_root, dir))
+    }
+
+    fn resolve_path(&self, book_root: &Path, path: &Path) -> PathBuf {
+        if self.resolve_from_book_root && path.is_relative() {
+            book_root.join(path)
+        } else {
+            path.to_path_buf()
+        }
+    }
+
+    /// The chapter's source path relative to the book's source directory, with any leading language directory from `language_dirs` removed. Returns `None` for chapters without a source file.
+    pub fn chapter_path(&self, chapter: &Chapter) -> Option<PathBuf> {
+        let source_path = chapter.source_path.as_ref()?;
+        for language_dir in &self.language_dirs {
+            if let Ok(stripped) = source_path.strip_prefix(language_dir) {
+                return Some(stripped.to_path_buf());
+            }
+        }
+        Some(source_path.clone())
+    }
+
+    /// The names of every key we read from the config table, straight from the `Deserialize` implementation so it can't get out of sync with the struct.
+    pub fn keys() -> &'static [&'static str] {
+        struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+        impl<'de> de::Deserializer<'de> for FieldNames<'_> {
+            type Error = de::value::Error;
+
+            fn deserialize_any<V: Visitor<'de>>(
+                self,
+                _visitor: V,
+            ) -> Result<V::Value, Self::Error> {
+                Err(de::Error::custom("only structs are supported"))
+            }
+
+            fn deserialize_struct<V: Visitor<'de>>(
+                self,
+                _name: &'static str,
+                fields: &'static [&'static str],
+                _visitor: V,
+            ) -> Result<V::Value, Self::Error> {
+                *self.0 = fields;
+                Err(de::Error::custom("only the field names are needed"))
+            }
+
+            serde::forward_to_deserialize_any! {
+                bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+                bytes byte_buf option unit unit_struct newtype_struct seq tuple
+                tuple_struct map enum identifier ignored_any
+            }
+        }
+
+        let mut fields: &'static [&'static str] = &[];
+        // this always "fails", we only want the side effect of capturing the field names
+        let _ = Config::deserialize(FieldNames(&mut fields));
+        fields
+    }
+}
+
+impl TryFrom<&PreprocessorContext> for Config {
+    type Error = Error;
+
+    fn try_from(ctx: &PreprocessorContext) -> Result<Self> {
+        Config::resolve(ctx, &ConfigOverrides::default())
+    }
+}
+
+impl Config {
+    /// Resolves the config from (in increasing order of precedence) the defaults, book.toml, `MDBOOK_BUILDTIME_*` environment variables and the command line overrides.
+    pub fn resolve(ctx: &PreprocessorContext, overrides: &ConfigOverrides) -> Result<Self> {
+        let (table_name, table) = find_table(ctx, &overrides.config_tables)?;
+        Config::from_layers(
+            &table_name,
+            table,
+            &ctx.renderer,
+            std::env::vars(),
+            &overrides.to_table(),
+        )
+    }
+
+    fn from_layers(
+        table_name: &str,
+        mut table: toml::Table,
+        renderer: &str,
+        vars: impl Iterator<Item = (String, String)>,
+        cli_overrides: &toml::Table,
+    ) -> Result<Self> {
+        let vars: Vec<(String, String)> = vars.collect();
+        let profile = select_profile(&table, &vars, cli_overrides);
+        if let Some(toml::Value::Table(profiles)) = table.get("profiles") {
+            for (name, section) in profiles {
+                match section {
+                    toml::Value::Table(section) => {
+                        warn_unknown_keys(&format!("{table_name}.profiles.{name}"), section)
+                    }
+                    _ => warn!(
+                        "preprocessor.{table_name}.profiles.{name} isn't a table, ignoring it"
+                    ),
+                }
+            }
+        }
+        let profile_table = profile.as_ref().and_then(|profile| {
+            match table
+                .get("profiles")
+                .and_then(|profiles| profiles.get(profile))
+                .and_then(toml::Value::as_table)
+            {
+                Some(section) => Some(section.clone()),
+                None => {
+                    warn!(
+                        "The {profile} profile was selected but preprocessor.{table_name}.profiles.{profile} isn't set, using the main table"
+                    );
+                    None
+                }
+            }
+        });
+        if let Some(section) = profile_table {
+            overlay(&mut table, section, |key, value| {
+                debug!(
+                    "Overriding preprocessor.{table_name}.{key} for the {profile:?} profile = {value}"
+                )
+            });
+        }
+        if let Some(profile) = &profile {
+            table.insert("profile".to_string(), toml::Value::String(profile.clone()));
+        }
+
+        // any table that isn't one of our keys is a per-renderer section, eg [preprocessor.build-annotations.html]
+        let renderer_tables: Vec<String> = table
+            .iter()
+            .filter(|(key, value)| value.is_table() && !Config::keys().contains(&key.as_str()))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut renderer_table = None;
+        for name in renderer_tables {
+            if let Some(toml::Value::Table(section)) = table.remove(&name) {
+                warn_unknown_keys(&format!("{table_name}.{name}"), &section);
+                if name == renderer {
+                    renderer_table = Some(section);
+                }
+            }
+        }
+        warn_unknown_keys(table_name, &table);
+        if let Some(section) = renderer_table {
+            overlay(&mut table, section, |key, value| {
+                debug!(
+                    "Overriding preprocessor.{table_name}.{key} for the {renderer} renderer = {value}"
+                )
+            });
+        }
+
+        apply_env_overrides(&mut table, vars.into_iter())?;
+        for (key, value) in cli_overrides {
+            debug!("Overriding preprocessor.{CONFIG_TABLE}.{key} from the command line = {value}");
+            table.insert(key.clone(), value.clone());
+        }
+
+        // markup would at best be ignored by anything but the html and epub renderers, and might break it
+        if renderer != "html" && !table.contains_key("format") {
+            let format = if renderer == "epub" {
+                "epub"
+            } else {
+                "markdown"
+            };
+            debug!("format isn't set, using {format} for the {renderer} renderer");
+            table.insert(
+                "format".to_string(),
+                toml::Value::String(format.to_string()),
+            );
+        }
+
+        match toml::Value::Table(table.clone()).try_into::<Config>() {
+            Ok(cfg) => {
+                if let Some(dir) = &cfg.workspace_dir
+                    && cfg.root_dir.is_none()
+                    && cfg.git_dir.as_ref() == Some(dir)
+                {
+                    info!(
+                        "workspace_dir and git_dir are both set to {dir:?}, you can replace them with root_dir = {dir:?}"
+                    );
+                }
+                if cfg.gettext_skip && cfg.insert_before_heading.is_some() {
+                    warn!(
+                        "gettext_skip always appends the annotation to the end of the chapter, ignoring insert_before_heading"
+                    );
+                }
+                cfg.history_since_seconds()?;
+                for (pattern, variant) in &cfg.variants {
+                    warn_unknown_keys(
+                        &format!("{table_name}.variants.{pattern:?}"),
+                        &variant.overrides(),
+                    );
+                    cfg.with_variant(pattern, variant)?;
+                }
+                for (index, overrides) in cfg.annotations.iter().enumerate() {
+                    warn_unknown_keys(&format!("{table_name}.annotations[{index}]"), overrides);
+                }
+                cfg.annotation_configs()?;
+                Ok(cfg)
+            }
+            Err(err) => {
+                let errors = ConfigError::from_table(table_name, &table);
+                if errors.errors.is_empty() {
+                    Err(Error::ConfigInvalid {
+                        key: format!("preprocessor.{table_name}"),
+                        reason: describe_error(err.message()),
+                    })
+                } else {
+                    Err(errors.into())
+                }
+            }
+        }
+    }
+}
+
+/// Copies each key in `section` (a per-renderer table or a profile) over the top of `table`, telling `log` about each one.
+fn overlay(table: &mut toml::Table, section: toml::Table, log: impl Fn(&str, &toml::Value)) {
+    for (key, value) in section {
+        log(&key, &value);
+        table.insert(key, value);
+    }
+}
+
+/// Which of the `profiles` to use: the `profile` key from the command line, then `MDBOOK_BUILDTIME_PROFILE`, then book.toml, and otherwise "ci" when the `CI` environment variable is true (as most CI systems set it) and there's a profile by that name. `None` if none of them say.
+fn select_profile(
+    table: &toml::Table,
+    vars: &[(String, String)],
+    cli_overrides: &toml::Table,
+) -> Option<String> {
+    let var = |name: &str| {
+        vars.iter()
+            .find(|(var, _)| var == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let (profile, source) = if let Some(profile) = cli_overrides.get("profile") {
+        (profile.as_str()?.to_string(), "the command line")
+    } else if let Some(profile) = var(&format!("{ENV_PREFIX}PROFILE")) {
+        (profile.to_string(), "MDBOOK_BUILDTIME_PROFILE")
+    } else if let Some(profile) = table.get("profile") {
+        (profile.as_str()?.to_string(), "book.toml")
+    } else if var("CI").and_then(parse_bool) == Some(true)
+        && table
+            .get("profiles")
+            .is_some_and(|profiles| profiles.get("ci").is_some())
+    {
+        ("ci".to_string(), "CI being set")
+    } else {
+        return None;
+    };
+    let profile = profile.trim();
+    if profile.is_empty() {
+        return None;
+    }
+    info!("Using the {profile} config profile, from {source}");
+    Some(profile.to_string())
+}
+
+/// Walks up from `start` looking for a directory containing a Cargo.toml, checking at most `levels` parent directories.
+fn discover_workspace(start: &Path, levels: Option<usize>) -> Option<PathBuf> {
+    let start = std::path::absolute(start).ok()?;
+    start
+        .ancestors()
+        .take(levels.map_or(usize::MAX, |levels| levels.saturating_add(1)))
+        .find(|dir| dir.join("Cargo.toml").is_file())
+        .map(Path::to_path_buf)
+}
+
+/// Warns about keys we don't recognise, suggesting the key they probably meant.
+fn warn_unknown_keys(table_name: &str, table: &toml::Table) {
+    let known_keys = Config::keys();
+    for key in table.keys() {
+        if known_keys.contains(&key.as_str()) || MDBOOK_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        match suggest_key(key, known_keys) {
+            Some(suggestion) => warn!(
+                "Unknown config key preprocessor.{table_name}.{key}, did you mean {suggestion}?"
+            ),
+            None => warn!("Unknown config key preprocessor.{table_name}.{key}, ignoring it"),
+        }
+    }
+}
+
+/// Finds our table in book.toml, trying the primary name first and then the legacy and user-supplied aliases. Returns the name that was actually used along with the table, which is empty if none of them exist.
+fn find_table(ctx: &PreprocessorContext, extra_tables: &[String]) -> Result<(String, toml::Table)> {
+    let aliases = LEGACY_CONFIG_TABLES
+        .iter()
+        .map(|name| name.to_string())
+        .chain(extra_tables.iter().cloned());
+    let get = |name: &str| {
+        let key = format!("preprocessor.{name}");
+        ctx.config.get(&key).map_err(|err| Error::ConfigInvalid {
+            key,
+            reason: format!("{err:#}"),
+        })
+    };
+    if let Some(table) = get(CONFIG_TABLE)? {
+        return Ok((CONFIG_TABLE.to_string(), table));
+    }
+    for name in aliases {
+        if let Some(table) = get(&name)? {
+            warn!(
+                "Reading config from [preprocessor.{name}], which is deprecated, please rename it to [preprocessor.{CONFIG_TABLE}]"
+            );
+            return Ok((name, table));
+        }
+    }
+    Ok((CONFIG_TABLE.to_string(), toml::Table::new()))
+}
+
+/// Overrides config keys with any matching `MDBOOK_BUILDTIME_*` variables. Environment variables are just strings, so we try the ways a value could be read (boolean, integer, TOML literal, plain string) and keep the first one that's valid for the key.
+fn apply_env_overrides(
+    table: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    for (var, raw) in vars {
+        let Some(key) = var.strip_prefix(ENV_PREFIX).map(str::to_lowercase) else {
+            continue;
+        };
+        if !Config::keys().contains(&key.as_str()) {
+            continue;
+        }
+
+        let mut candidates = Vec::new();
+        if let Some(value) = parse_bool(&raw) {
+            candidates.push(toml::Value::Boolean(value));
+        }
+        if let Ok(value) = raw.trim().parse::<i64>() {
+            candidates.push(toml::Value::Integer(value));
+        }
+        if let Ok(literal) = format!("value = {raw}").parse::<toml::Table>()
+            && let Some(value) = literal.get("value")
+        {
+            candidates.push(value.clone());
+        }
+        candidates.push(toml::Value::String(raw.clone()));
+
+        let mut last_error = None;
+        let value = candidates.into_iter().find(|value| {
+            let single = toml::Table::from_iter([(key.clone(), value.clone())]);
+            match toml::Value::Table(single).try_into::<Config>() {
+                Ok(_) => true,
+                Err(err) => {
+                    last_error = Some(describe_error(err.message()));
+                    false
+                }
+            }
+        });
+        let Some(value) = value else {
+            return Err(Error::EnvOverride {
+                var,
+                reason: last_error.unwrap_or_else(|| "invalid value".to_string()),
+            });
+        };
+        debug!("Overriding preprocessor.{CONFIG_TABLE}.{key} from {var} = {value}");
+        table.insert(key, value);
+    }
+    Ok(())
+}
+
+/// The usual spellings of true and false, for environment variables.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// A config key that couldn't be parsed, and why.
+#[derive(Debug)]
+pub struct KeyError {
+    /// The name of the table under `[preprocessor]` the key was read from
+    pub table: String,
+    pub key: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "preprocessor.{}.{}: {}",
+            self.table, self.key, self.reason
+        )
+    }
+}
+
+/// Every invalid key in the config table, so users can fix them all in one go rather than one build at a time.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<KeyError>,
+}
+
+impl ConfigError {
+    /// Parses each known key on its own, so each failure can be pinned to the key that caused it.
+    fn from_table(table_name: &str, table: &toml::Table) -> Self {
+        let errors = table
+            .iter()
+            .filter(|(key, _)| Config::keys().contains(&key.as_str()))
+            .filter_map(|(key, value)| {
+                let single = toml::Table::from_iter([(key.clone(), value.clone())]);
+                toml::Value::Table(single)
+                    .try_into::<Config>()
+                    .err()
+                    .map(|err| KeyError {
+                        table: table_name.to_string(),
+                        key: key.clone(),
+                        reason: describe_error(err.message()),
+                    })
+            })
+            .collect();
+        ConfigError { errors }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for error in &self.errors {
+            writeln!(f, "  {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Turns serde's `invalid type: string "ten", expected usize` into the friendlier `expected an integer, found string "ten"`.
+pub(crate) fn describe_error(message: &str) -> String {
+    let message = message.lines().next().unwrap_or(message);
+    let Some((found, expected)) = message
+        .strip_prefix("invalid type: ")
+        .or_else(|| message.strip_prefix("invalid value: "))
+        .and_then(|rest| rest.split_once(", expected "))
+    else {
+        return message.to_string();
+    };
+    let expected = match expected {
+        "usize" | "u8" | "u16" | "u32" | "u64" | "i8" | "i16" | "i32" | "i64" => {
+            "a non-negative integer"
+        }
+        "path string" => "a path",
+        "a sequence" => "an array",
+        other => other,
+    };
+    format!("expected {expected}, found {found}")
+}
+
+/// Finds the known key closest to a misspelt one, if any are close enough to be a plausible typo.
+fn suggest_key<'a>(key: &str, known_keys: &[&'a str]) -> Option<&'a str> {
+    known_keys
+        .iter()
+        .map(|known| (strsim::jaro_winkler(key, known), *known))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, known)| known)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(config: &str) -> PreprocessorContext {
+        renderer_context(config, "html")
+    }
+
+    fn renderer_context(config: &str, renderer: &str) -> PreprocessorContext {
+        crate::testing::context()
+            .renderer(renderer)
+            .book_toml(config)
+            .build()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let cfg = Config::try_from(&context("")).expect("Failed to parse config");
+        assert!(cfg.commit_characters.is_none());
+        assert!(cfg.workspace_dir.is_none());
+        assert_eq!(cfg.git_path(Path::new("")), PathBuf::from("../"));
+        assert!(cfg.package_name && cfg.package_version && cfg.git_commit);
+        assert_eq!(cfg.appendix_title, "Build information");
+        assert!(cfg.max_depth.is_none());
+    }
+
+    #[test]
+    fn test_default_matches_empty_table() {
+        let parsed = Config::try_from(&context("")).expect("Failed to parse config");
+        assert_eq!(format!("{parsed:?}"), format!("{:?}", Config::default()));
+    }
+
+    #[test]
+    fn test_builder() {
+        let cfg = Config::builder()
+            .commit_characters(7)
+            .git_commit(false)
+            .root_dir("/src/repo")
+            .parts(["Reference"])
+            .build();
+        assert_eq!(cfg.commit_characters, Some(7));
+        assert!(!cfg.git_commit);
+        assert!(cfg.package_name);
+        assert_eq!(cfg.root_dir, Some(PathBuf::from("/src/repo")));
+        assert_eq!(cfg.parts, Some(vec!["Reference".to_string()]));
+    }
+
+    #[test]
+    fn test_table_values() {
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\ncommand = \"mdbook-buildtime-annotations\"\ncommit_characters = 6\ngit_commit = false\nparts = [\"Reference\"]\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(cfg.commit_characters, Some(6));
+        assert!(!cfg.git_commit);
+        assert_eq!(cfg.parts, Some(vec!["Reference".to_string()]));
+    }
+
+    #[test]
+    fn test_invalid_key_errors() {
+        let err = Config::try_from(&context(
+            "[preprocessor.build-annotations]\ncommit_characters = \"ten\"\ngit_commit = 1\nappendix = true\n",
+        ))
+        .expect_err("config should be invalid");
+        assert!(matches!(err, Error::Config(_)));
+        let message = err.to_string();
+        assert!(message.contains(
+            "preprocessor.build-annotations.commit_characters: expected a non-negative integer, found string \"ten\""
+        ));
+        assert!(message.contains(
+            "preprocessor.build-annotations.git_commit: expected a boolean, found integer `1`"
+        ));
+        assert!(!message.contains("appendix"));
+    }
+
+    #[test]
+    fn test_required() {
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nrequired = [\"git_commit\", \"package_version\"]\n",
+        ))
+        .expect("config should be valid");
+        assert_eq!(
+            cfg.required,
+            [RequiredField::GitCommit, RequiredField::PackageVersion]
+        );
+
+        let err = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nrequired = [\"git_comit\"]\n",
+        ))
+        .expect_err("config should be invalid");
+        assert!(matches!(err, Error::Config(_)));
+        assert!(
+            err.to_string()
+                .contains("preprocessor.build-annotations.required"),
+            "{err}"
+        );
+        assert!(err.to_string().contains("git_comit"), "{err}");
+    }
+
+    #[test]
+    fn test_history_since() {
+        for (since, seconds) in [
+            ("2022-01-01", 1_640_995_200),
+            ("2022-01-01 12:00:00", 1_641_038_400),
+            ("2022-01-01T12:00:00Z", 1_641_038_400),
+        ] {
+            let cfg = Config::builder().history_since(since).build();
+            assert_eq!(
+                cfg.history_since_seconds().expect("valid date"),
+                Some(seconds),
+                "{since}"
+            );
+        }
+        assert_eq!(Config::default().history_since_seconds().ok(), Some(None));
+
+        let err = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nhistory_since = \"last year\"\n",
+        ))
+        .expect_err("config should be invalid");
+        assert!(
+            matches!(&err, Error::ConfigInvalid { key, .. } if key == "preprocessor.build-annotations.history_since"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn test_table_not_a_table() {
+        let err = Config::try_from(&context("[preprocessor]\nbuild-annotations = 1\n"))
+            .expect_err("config should be invalid");
+        assert!(matches!(err, Error::ConfigInvalid { .. }));
+        assert!(
+            err.to_string()
+                .starts_with("preprocessor.build-annotations: ")
+        );
+    }
+
+    #[test]
+    fn test_describe_error() {
+        assert_eq!(
+            describe_error("invalid type: string \"x\", expected a sequence\nin `parts`\n"),
+            "expected an array, found string \"x\""
+        );
+        assert_eq!(
+            describe_error("invalid value: integer `-1`, expected usize"),
+            "expected a non-negative integer, found integer `-1`"
+        );
+        assert_eq!(describe_error("something else"), "something else");
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut table: toml::Table = "commit_characters = 10\ngit_commit = true\n"
+            .parse()
+            .expect("Failed to parse table");
+        apply_env_overrides(
+            &mut table,
+            env(&[
+                ("MDBOOK_BUILDTIME_COMMIT_CHARACTERS", "7"),
+                ("MDBOOK_BUILDTIME_GIT_COMMIT", "off"),
+                ("MDBOOK_BUILDTIME_SKIP_EMPTY", "0"),
+                ("MDBOOK_BUILDTIME_APPENDIX_TITLE", "About this build"),
+                ("MDBOOK_BUILDTIME_PARTS", "[\"Reference\"]"),
+                ("MDBOOK_BUILDTIME_NOT_A_KEY", "whatever"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .expect("Failed to apply overrides");
+        let cfg: Config = toml::Value::Table(table)
+            .try_into()
+            .expect("Failed to parse config");
+        assert_eq!(cfg.commit_characters, Some(7));
+        assert!(!cfg.git_commit);
+        assert!(!cfg.skip_empty);
+        assert_eq!(cfg.appendix_title, "About this build");
+        assert_eq!(cfg.parts, Some(vec!["Reference".to_string()]));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let book_toml: toml::Table =
+            "commit_characters = 12\nappendix_title = \"Book\"\ngit_commit = false\n"
+                .parse()
+                .expect("Failed to parse table");
+        let cli: toml::Table = "commit_characters = 6"
+            .parse()
+            .expect("Failed to parse table");
+        let vars = || {
+            env(&[
+                ("MDBOOK_BUILDTIME_COMMIT_CHARACTERS", "8"),
+                ("MDBOOK_BUILDTIME_APPENDIX_TITLE", "Env"),
+            ])
+        };
+
+        // defaults
+        let cfg = Config::from_layers(
+            CONFIG_TABLE,
+            toml::Table::new(),
+            "html",
+            env(&[]),
+            &toml::Table::new(),
+        )
+        .expect("Failed to resolve config");
+        assert!(cfg.commit_characters.is_none());
+        // book.toml beats the defaults
+        let cfg = Config::from_layers(
+            CONFIG_TABLE,
+            book_toml.clone(),
+            "html",
+            env(&[]),
+            &toml::Table::new(),
+        )
+        .expect("Failed to resolve config");
+        assert_eq!(cfg.commit_characters, Some(12));
+        assert!(!cfg.git_commit);
+        // env beats book.toml
+        let cfg = Config::from_layers(
+            CONFIG_TABLE,
+            book_toml.clone(),
+            "html",
+            vars(),
+            &toml::Table::new(),
+        )
+        .expect("Failed to resolve config");
+        assert_eq!(cfg.commit_characters, Some(8));
+        assert_eq!(cfg.appendix_title, "Env");
+        // cli beats env
+        let cfg = Config::from_layers(CONFIG_TABLE, book_toml, "html", vars(), &cli)
+            .expect("Failed to resolve config");
+        assert_eq!(cfg.commit_characters, Some(6));
+        assert_eq!(cfg.appendix_title, "Env");
+        assert!(!cfg.git_commit);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let book_toml: toml::Table = r#"
+git_commit = false
+commit_characters = 6
+
+[profiles.ci]
+git_commit = true
+chapter_dates = true
+
+[profiles.local]
+package_version = false
+
+[markdown]
+git_commit = false
+"#
+        .parse()
+        .expect("Failed to parse table");
+        let resolve = |renderer: &str, vars: &[(&str, &str)], cli: &str| {
+            Config::from_layers(
+                CONFIG_TABLE,
+                book_toml.clone(),
+                renderer,
+                env(vars),
+                &cli.parse().expect("Failed to parse table"),
+            )
+            .expect("Failed to resolve config")
+        };
+
+        let cfg = resolve("html", &[], "");
+        assert_eq!(cfg.profile, None);
+        assert!(!cfg.git_commit);
+        assert!(cfg.package_version);
+        assert_eq!(cfg.profiles.len(), 2);
+
+        let cfg = resolve("html", &[("CI", "true")], "");
+        assert_eq!(cfg.profile.as_deref(), Some("ci"));
+        assert!(cfg.git_commit);
+        assert!(cfg.chapter_dates);
+        assert_eq!(cfg.commit_characters, Some(6));
+        // the renderer table goes on top of the profile
+        assert!(!resolve("markdown", &[("CI", "true")], "").git_commit);
+        assert!(!resolve("html", &[("CI", "false")], "").git_commit);
+
+        let cfg = resolve(
+            "html",
+            &[("CI", "true"), ("MDBOOK_BUILDTIME_PROFILE", "local")],
+            "",
+        );
+        assert_eq!(cfg.profile.as_deref(), Some("local"));
+        assert!(!cfg.git_commit);
+        assert!(!cfg.package_version);
+        let cfg = resolve(
+            "html",
+            &[("MDBOOK_BUILDTIME_PROFILE", "local")],
+            "profile = \"ci\"",
+        );
+        assert_eq!(cfg.profile.as_deref(), Some("ci"));
+        // env beats the profile
+        let cfg = resolve(
+            "html",
+            &[("CI", "1"), ("MDBOOK_BUILDTIME_GIT_COMMIT", "false")],
+            "",
+        );
+        assert!(!cfg.git_commit);
+        assert!(cfg.chapter_dates);
+
+        // a profile that doesn't exist changes nothing
+        let cfg = resolve("html", &[("MDBOOK_BUILDTIME_PROFILE", "staging")], "");
+        assert_eq!(cfg.profile.as_deref(), Some("staging"));
+        assert!(!cfg.git_commit);
+
+        // without a ci profile, CI doesn't select one
+        let cfg = Config::from_layers(
+            CONFIG_TABLE,
+            toml::Table::new(),
+            "html",
+            env(&[("CI", "true")]),
+            &toml::Table::new(),
+        )
+        .expect("Failed to resolve config");
+        assert_eq!(cfg.profile, None);
+    }
+
+    #[test]
+    fn test_env_override_invalid() {
+        let mut table = toml::Table::new();
+        let err = apply_env_overrides(
+            &mut table,
+            env(&[("MDBOOK_BUILDTIME_COMMIT_CHARACTERS", "ten")]),
+        )
+        .expect_err("override should be invalid");
+        assert!(matches!(err, Error::EnvOverride { .. }));
+        assert!(
+            err.to_string()
+                .starts_with("MDBOOK_BUILDTIME_COMMIT_CHARACTERS: ")
+        );
+
+        let err = apply_env_overrides(&mut table, env(&[("MDBOOK_BUILDTIME_GIT_COMMIT", "maybe")]))
+            .expect_err("override should be invalid");
+        assert!(err.to_string().contains("expected a boolean"));
+    }
+
+    #[test]
+    fn test_renderer_sections() {
+        let toml = r#"
+[preprocessor.build-annotations]
+format = "html"
+commit_characters = 6
+
+[preprocessor.build-annotations.markdown]
+format = "markdown"
+"#;
+        let cfg =
+            Config::try_from(&renderer_context(toml, "markdown")).expect("Failed to parse config");
+        assert_eq!(cfg.format, Format::Markdown);
+        assert_eq!(cfg.commit_characters, Some(6));
+
+        let cfg =
+            Config::try_from(&renderer_context(toml, "html")).expect("Failed to parse config");
+        assert_eq!(cfg.format, Format::Html);
+        assert_eq!(cfg.commit_characters, Some(6));
+    }
+
+    #[test]
+    fn test_format_follows_renderer() {
+        for (renderer, format) in [
+            ("html", Format::Html),
+            ("markdown", Format::Markdown),
+            ("linkcheck", Format::Markdown),
+            ("epub", Format::Epub),
+            ("pdf", Format::Markdown),
+        ] {
+            let cfg =
+                Config::try_from(&renderer_context("", renderer)).expect("Failed to parse config");
+            assert_eq!(cfg.format, format, "{renderer}");
+        }
+
+        let toml = r#"
+[preprocessor.build-annotations]
+format = "html"
+
+[preprocessor.build-annotations.linkcheck]
+format = "markdown"
+"#;
+        for (renderer, format) in [
+            ("html", Format::Html),
+            ("epub", Format::Html),
+            ("linkcheck", Format::Markdown),
+        ] {
+            let cfg = Config::try_from(&renderer_context(toml, renderer))
+                .expect("Failed to parse config");
+            assert_eq!(cfg.format, format, "{renderer}");
+        }
+
+        let toml = "[preprocessor.build-annotations.pdf]\nformat = \"html\"\n";
+        let cfg = Config::try_from(&renderer_context(toml, "pdf")).expect("Failed to parse config");
+        assert_eq!(cfg.format, Format::Html);
+        let cfg =
+            Config::try_from(&renderer_context(toml, "typst")).expect("Failed to parse config");
+        assert_eq!(cfg.format, Format::Markdown);
+
+        let toml = "[preprocessor.build-annotations.epub]\nformat = \"markdown\"\n";
+        let cfg =
+            Config::try_from(&renderer_context(toml, "epub")).expect("Failed to parse config");
+        assert_eq!(cfg.format, Format::Markdown);
+    }
+
+    #[test]
+    fn test_supports_renderer() {
+        let cfg = Config::try_from(&context("")).expect("Failed to parse config");
+        assert!(cfg.supports_renderer("pdf"));
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nrenderers = [\"html\", \"markdown\"]\n",
+        ))
+        .expect("Failed to parse config");
+        assert!(cfg.supports_renderer("html"));
+        assert!(cfg.supports_renderer("markdown"));
+        assert!(!cfg.supports_renderer("pdf"));
+    }
+
+    #[test]
+    fn test_version_check() {
+        let cfg = Config::try_from(&context("")).expect("Failed to parse config");
+        assert_eq!(cfg.version_check, VersionCheck::Compatible);
+        for (value, check) in [
+            ("strict", VersionCheck::Strict),
+            ("compatible", VersionCheck::Compatible),
+            ("off", VersionCheck::Off),
+        ] {
+            let cfg = Config::try_from(&context(&format!(
+                "[preprocessor.build-annotations]\nversion_check = \"{value}\"\n"
+            )))
+            .expect("Failed to parse config");
+            assert_eq!(cfg.version_check, check);
+        }
+        assert!(
+            Config::try_from(&context(
+                "[preprocessor.build-annotations]\nversion_check = \"loose\"\n"
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_skip_renderers() {
+        let cfg = Config::try_from(&context("")).expect("Failed to parse config");
+        assert!(cfg.skips_renderer("test"));
+        assert!(!cfg.supports_renderer("test"));
+        assert!(cfg.supports_renderer("html"));
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nrenderers = [\"html\", \"linkcheck\"]\nskip_renderers = [\"test\", \"linkcheck\"]\n",
+        ))
+        .expect("Failed to parse config");
+        assert!(cfg.supports_renderer("html"));
+        assert!(!cfg.supports_renderer("linkcheck"));
+        assert!(!cfg.supports_renderer("test"));
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nskip_renderers = []\n",
+        ))
+        .expect("Failed to parse config");
+        assert!(cfg.supports_renderer("test"));
+    }
+
+    #[test]
+    fn test_annotates_branch() {
+        let cfg = Config::try_from(&context("")).expect("Failed to parse config");
+        assert!(cfg.annotates_branch(Some("feature/x")));
+        assert!(cfg.annotates_branch(None));
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nbranches = [\"main\", \"release/*\"]\n",
+        ))
+        .expect("Failed to parse config");
+        assert!(cfg.annotates_branch(Some("main")));
+        assert!(cfg.annotates_branch(Some("release/1.4")));
+        assert!(cfg.annotates_branch(Some("release/1.4/hotfix")));
+        assert!(!cfg.annotates_branch(Some("mainline")));
+        assert!(!cfg.annotates_branch(Some("feature/release/1.4")));
+        assert!(!cfg.annotates_branch(None));
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nbranches = [\"preview/*\"]\nbranches_invert = true\n",
+        ))
+        .expect("Failed to parse config");
+        assert!(cfg.annotates_branch(Some("main")));
+        assert!(!cfg.annotates_branch(Some("preview/new-theme")));
+        assert!(cfg.annotates_branch(None));
+    }
+
+    #[test]
+    fn test_env_var() {
+        let var = |name: &str| Some(format!("value of {name}"));
+        let cfg = Config::default();
+        assert_eq!(
+            cfg.env_var("GITHUB_RUN_ID", var).as_deref(),
+            Some("value of GITHUB_RUN_ID")
+        );
+        assert_eq!(cfg.env_var("AWS_SECRET_ACCESS_KEY", var), None);
+        assert_eq!(cfg.env_var("DEPLOY_TARGET", var), None);
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nenv_allowlist = [\"DEPLOY_*\"]\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(
+            cfg.env_var("DEPLOY_TARGET", var).as_deref(),
+            Some("value of DEPLOY_TARGET")
+        );
+        // the defaults still apply
+        assert!(cfg.allows_env("CI"));
+        assert!(!cfg.allows_env("HOME"));
+    }
+
+    #[test]
+    fn test_variants() {
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nhash_case = \"lower\"\n\
+             [preprocessor.build-annotations.variants]\n\
+             \"main\" = \"{name} {version}\"\n\
+             \"release/*\" = { template = \"{name} @{commit}\", hash_case = \"upper\" }\n\
+             \"*\" = \"PREVIEW BUILD {branch} @{commit}\"\n\
+             \"release/1.4\" = \"never used\"\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(
+            cfg.variants
+                .iter()
+                .map(|(pattern, _)| pattern.as_str())
+                .collect::<Vec<_>>(),
+            ["main", "release/*", "*", "release/1.4"]
+        );
+
+        let template = |branch: Option<&str>| {
+            cfg.for_branch(branch)
+                .expect("Failed to apply variant")
+                .template
+                .clone()
+        };
+        assert_eq!(template(Some("main")).as_deref(), Some("{name} {version}"));
+        assert_eq!(
+            template(Some("release/1.4")).as_deref(),
+            Some("{name} @{commit}")
+        );
+        assert_eq!(
+            template(Some("feature/x")).as_deref(),
+            Some("PREVIEW BUILD {branch} @{commit}")
+        );
+        assert_eq!(template(None), None);
+
+        let release = cfg
+            .for_branch(Some("release/1.4"))
+            .expect("Failed to apply variant");
+        assert_eq!(release.hash_case, HashCase::Upper);
+        // everything the variant doesn't set is unchanged
+        assert_eq!(release.variants, cfg.variants);
+        assert!(matches!(
+            cfg.for_branch(Some("main"))
+                .expect("Failed to apply variant"),
+            std::borrow::Cow::Owned(_)
+        ));
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations.variants]\n\"main\" = \"{name}\"\n",
+        ))
+        .expect("Failed to parse config");
+        assert!(matches!(
+            cfg.for_branch(Some("feature/x"))
+                .expect("Failed to apply variant"),
+            std::borrow::Cow::Borrowed(_)
+        ));
+
+        let err = Config::try_from(&context(
+            "[preprocessor.build-annotations.variants]\n\"*\" = { hash_case = \"sideways\" }\n",
+        ))
+        .expect_err("an invalid variant should fail");
+        assert!(err.to_string().contains("variants.\"*\""), "{err}");
+    }
+
+    #[test]
+    fn test_annotations() {
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nhash_case = \"upper\"\n\
+             [[preprocessor.build-annotations.annotations]]\nformat = \"comment\"\n\
+             [[preprocessor.build-annotations.annotations]]\nchapters = [\"index.md\"]\ntemplate = \"{branch}\"\n",
+        ))
+        .expect("Failed to parse config");
+        let annotations = cfg
+            .annotation_configs()
+            .expect("Failed to apply annotations");
+        assert_eq!(annotations.len(), 2);
+        assert_eq!(annotations[0].format, Format::Comment);
+        assert!(annotations[0].chapters.is_empty());
+        assert_eq!(annotations[1].format, Format::Html);
+        assert_eq!(annotations[1].chapters, ["index.md"]);
+        assert_eq!(annotations[1].template.as_deref(), Some("{branch}"));
+        // everything else comes from the main table
+        assert!(
+            annotations
+                .iter()
+                .all(|cfg| cfg.hash_case == HashCase::Upper)
+        );
+        assert!(annotations.iter().all(|cfg| cfg.annotations.is_empty()));
+
+        // without any, the table is the one annotation
+        let cfg = Config::default();
+        let annotations = cfg
+            .annotation_configs()
+            .expect("Failed to apply annotations");
+        assert!(matches!(
+            annotations.as_slice(),
+            [std::borrow::Cow::Borrowed(_)]
+        ));
+
+        let err = Config::try_from(&context(
+            "[[preprocessor.build-annotations.annotations]]\n\
+             [[preprocessor.build-annotations.annotations]]\nformat = \"sideways\"\n",
+        ))
+        .expect_err("an invalid annotation should fail");
+        assert!(err.to_string().contains("annotations[1]"), "{err}");
+    }
+
+    #[test]
+    fn test_annotates_chapter() {
+        let cfg = Config::default();
+        assert!(cfg.annotates_chapter(Some(Path::new("guide/intro.md"))));
+        assert!(cfg.annotates_chapter(None));
+
+        let cfg = Config::builder().chapters(["index.md", "guide/*"]).build();
+        assert!(cfg.annotates_chapter(Some(Path::new("index.md"))));
+        assert!(cfg.annotates_chapter(Some(Path::new("guide/intro.md"))));
+        assert!(cfg.annotates_chapter(Some(&Path::new("guide").join("deep").join("more.md"))));
+        assert!(!cfg.annotates_chapter(Some(Path::new("reference/index.md"))));
+        // drafts don't have a path
+        assert!(!cfg.annotates_chapter(None));
+    }
+
+    #[test]
+    fn test_resolve_from_book_root() {
+        let book_root = Path::new("/books/handbook");
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nworkspace_dir = \"../\"\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(cfg.workspace_path(book_root), PathBuf::from("../"));
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nworkspace_dir = \"../\"\nresolve_from_book_root = true\ngit_dir = \"/src/repo\"\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(
+            cfg.workspace_path(book_root),
+            PathBuf::from("/books/handbook/../")
+        );
+        assert_eq!(cfg.git_path(book_root), PathBuf::from("/src/repo"));
+    }
+
+    #[test]
+    fn test_root_dir() {
+        let book_root = Path::new("/books/handbook");
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nroot_dir = \"/src/repo\"\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(cfg.workspace_path(book_root), PathBuf::from("/src/repo"));
+        assert_eq!(cfg.git_path(book_root), PathBuf::from("/src/repo"));
+
+        // the specific keys win over root_dir
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nroot_dir = \"/src/repo\"\ngit_dir = \"/src/git\"\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(cfg.workspace_path(book_root), PathBuf::from("/src/repo"));
+        assert_eq!(cfg.git_path(book_root), PathBuf::from("/src/git"));
+
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nroot_dir = \"/src/repo\"\nworkspace_dir = \"/src/crate\"\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(cfg.workspace_path(book_root), PathBuf::from("/src/crate"));
+        assert_eq!(cfg.git_path(book_root), PathBuf::from("/src/repo"));
+    }
+
+    #[test]
+    fn test_discover_workspace() {
+        let repo = tempfile::tempdir().expect("Failed to create tempdir");
+        let book = repo.path().join("docs/book");
+        std::fs::create_dir_all(&book).expect("Failed to create book dir");
+        std::fs::write(repo.path().join("Cargo.toml"), "").expect("Failed to write Cargo.toml");
+
+        assert_eq!(
+            discover_workspace(&book, None),
+            Some(repo.path().to_path_buf())
+        );
+        assert_eq!(
+            discover_workspace(&book, Some(2)),
+            Some(repo.path().to_path_buf())
+        );
+        assert_eq!(discover_workspace(&book, Some(1)), None);
+
+        // an explicit workspace_dir disables discovery
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nworkspace_dir = \"/somewhere/else\"\n",
+        ))
+        .expect("Failed to parse config");
+        assert_eq!(cfg.workspace_path(&book), PathBuf::from("/somewhere/else"));
+        let cfg = Config::try_from(&context("")).expect("Failed to parse config");
+        assert_eq!(cfg.workspace_path(&book), repo.path());
+    }
+
+    #[test]
+    fn test_legacy_table() {
+        let toml = "[preprocessor.buildtime-annotations]\ncommit_characters = 6\n";
+        let cfg = Config::try_from(&context(toml)).expect("Failed to parse config");
+        assert_eq!(cfg.commit_characters, Some(6));
+
+        // the primary table wins over the legacy one
+        let cfg = Config::try_from(&context(&format!(
+            "{toml}[preprocessor.build-annotations]\ncommit_characters = 8\n"
+        )))
+        .expect("Failed to parse config");
+        assert_eq!(cfg.commit_characters, Some(8));
+    }
+
+    #[test]
+    fn test_extra_config_table() {
+        let ctx = context("[preprocessor.buildtime]\ncommit_characters = \"six\"\n");
+        let cfg = Config::try_from(&ctx).expect("Failed to parse config");
+        assert!(cfg.commit_characters.is_none());
+
+        let overrides = ConfigOverrides {
+            config_tables: vec!["buildtime".to_string()],
+            ..Default::default()
+        };
+        let err = Config::resolve(&ctx, &overrides).expect_err("config should be invalid");
+        assert!(
+            err.to_string()
+                .contains("preprocessor.buildtime.commit_characters: ")
+        );
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = Config::json_schema();
+        let properties = schema["properties"]
+            .as_object()
+            .expect("schema should have properties");
+        for key in Config::keys() {
+            assert!(properties.contains_key(*key), "{key} missing from schema");
+        }
+        let commit_characters = &properties["commit_characters"];
+        assert!(commit_characters["default"].is_null());
+        assert!(
+            commit_characters["description"]
+                .as_str()
+                .is_some_and(|d| d.contains("git commit hash"))
+        );
+    }
+
+    #[test]
+    fn test_keys() {
+        let keys = Config::keys();
+        assert!(keys.contains(&"commit_characters"));
+        assert!(keys.contains(&"insert_before_heading"));
+    }
+
+    #[test]
+    fn test_suggest_key() {
+        let keys = Config::keys();
+        assert_eq!(
+            suggest_key("commit_charcters", keys),
+            Some("commit_characters")
+        );
+        assert_eq!(suggest_key("gitcommit", keys), Some("git_commit"));
+        assert_eq!(suggest_key("zzz", keys), None);
+    }
+
+    #[test]
+    fn test_chapter_path_language_dirs() {
+        let cfg = Config::try_from(&context(
+            "[preprocessor.build-annotations]\nlanguage_dirs = [\"en\", \"de\"]\n",
+        ))
+        .expect("Failed to parse config");
+        let chapter = |path: &str| Chapter::new("Chapter", String::new(), path, vec![]);
+
+        for path in ["en/reference/intro.md", "de/reference/intro.md"] {
+            assert_eq!(
+                cfg.chapter_path(&chapter(path)),
+                Some(PathBuf::from("reference/intro.md"))
+            );
+        }
+        // only whole leading path components are stripped
+        assert_eq!(
+            cfg.chapter_path(&chapter("english/intro.md")),
+            Some(PathBuf::from("english/intro.md"))
+        );
+        assert_eq!(cfg.chapter_path(&Chapter::new_draft("Draft", vec![])), None);
+
+        let cfg = Config::try_from(&context("")).expect("Failed to parse config");
+        assert_eq!(
+            cfg.chapter_path(&chapter("de/reference/intro.md")),
+            Some(PathBuf::from("de/reference/intro.md"))
+        );
+    }
+}
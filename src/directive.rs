@@ -0,0 +1,343 @@
+//! The `<!-- buildtime: ... -->` comment a chapter can have to change its own annotation, eg `<!-- buildtime: fields=commit,date position=top -->`.
+//!
+//! The arguments are `key=value` pairs separated by whitespace. A value is a comma-separated list of items, each either bare (anything but whitespace, commas and quotes) or a quoted string, in which `\"` and `\\` are a quote and a backslash.
+
+use pulldown_cmark::{Event, Parser, Tag};
+
+/// The start of a directive.
+const DIRECTIVE_START: &str = "<!-- buildtime:";
+const DIRECTIVE_END: &str = "-->";
+
+/// The fields a directive's `fields` can list, on top of the annotation's `extras` (`trailer.Ticket` and so on).
+pub(crate) const FIELDS: &[&str] = &[
+    "name",
+    "version",
+    "commit",
+    "branch",
+    "commit_date",
+    "commit_time",
+    "code",
+    "changelog",
+    "date",
+    "created",
+    "revisions",
+    "contributors",
+    "owners",
+    "edit",
+    "history",
+];
+
+/// Where a directive moves the chapter's annotation to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Position {
+    /// The start of the chapter.
+    Top,
+    /// The end of the chapter, whatever `insert_before_heading` says.
+    Bottom,
+}
+
+/// What a chapter's directive changes about its annotation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Directive {
+    /// Only these fields are shown, if it's set.
+    pub fields: Option<Vec<String>>,
+    pub position: Option<Position>,
+}
+
+/// Why a directive couldn't be parsed: the argument that's wrong, and what's wrong with it.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub argument: String,
+    pub reason: String,
+}
+
+impl Directive {
+    /// Removes every directive from `content` (along with the line it was on, if that's all there was on it) and returns what they say, later ones overriding earlier ones. `None` if there aren't any. Directives in code blocks are left alone.
+    pub(crate) fn take(content: &mut String) -> Result<Option<Directive>, ParseError> {
+        let mut found = Vec::new();
+        // a block's HTML comes a line at a time, so the whole block is searched instead
+        for (event, range) in Parser::new(content).into_offset_iter() {
+            if !matches!(event, Event::Start(Tag::HtmlBlock) | Event::InlineHtml(_)) {
+                continue;
+            }
+            let html = &content[range.clone()];
+            let mut offset = 0;
+            while let Some(start) = html[offset..].find(DIRECTIVE_START) {
+                let start = offset + start;
+                let Some(end) = html[start..].find(DIRECTIVE_END) else {
+                    break;
+                };
+                let end = start + end + DIRECTIVE_END.len();
+                found.push(range.start + start..range.start + end);
+                offset = end;
+            }
+        }
+        let mut directive: Option<Directive> = None;
+        for range in &found {
+            let args =
+                &content[range.start + DIRECTIVE_START.len()..range.end - DIRECTIVE_END.len()];
+            let parsed = Directive::parse(args)?;
+            let directive = directive.get_or_insert_default();
+            if parsed.fields.is_some() {
+                directive.fields = parsed.fields;
+            }
+            if parsed.position.is_some() {
+                directive.position = parsed.position;
+            }
+        }
+        for range in found.into_iter().rev() {
+            let line_start = content[..range.start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = content[range.end..]
+                .find('\n')
+                .map_or(content.len(), |i| range.end + i + 1);
+            let alone = content[line_start..range.start].trim().is_empty()
+                && content[range.end..line_end].trim().is_empty();
+            match alone {
+                true => content.replace_range(line_start..line_end, ""),
+                false => content.replace_range(range, ""),
+            }
+        }
+        Ok(directive)
+    }
+
+    /// Parses the arguments between `<!-- buildtime:` and `-->`.
+    fn parse(args: &str) -> Result<Directive, ParseError> {
+        let mut directive = Directive::default();
+        let mut rest = args.trim_start();
+        while !rest.is_empty() {
+            let argument = argument(rest);
+            let error = |reason: String| ParseError {
+                argument: argument.to_string(),
+                reason,
+            };
+            let Some((key, value)) = rest.split_once('=').filter(|(key, _)| {
+                !key.is_empty()
+                    && key
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            }) else {
+                return Err(error("expected key=value".to_string()));
+            };
+            let (values, after) = parse_list(value).map_err(error)?;
+            match key {
+                "fields" if directive.fields.is_some() => {
+                    return Err(error("fields is given more than once".to_string()));
+                }
+                "fields" => {
+                    if values.iter().any(String::is_empty) {
+                        return Err(error("a field name is empty".to_string()));
+                    }
+                    directive.fields = Some(values);
+                }
+                "position" if directive.position.is_some() => {
+                    return Err(error("position is given more than once".to_string()));
+                }
+                "position" => {
+                    directive.position = Some(match values.as_slice() {
+                        [value] if value == "top" => Position::Top,
+                        [value] if value == "bottom" => Position::Bottom,
+                        _ => {
+                            return Err(error(
+                                "expected position=top or position=bottom".to_string(),
+                            ));
+                        }
+                    })
+                }
+                _ => {
+                    return Err(error(format!(
+                        "unknown argument {key}, expected fields or position"
+                    )));
+                }
+            }
+            rest = after.trim_start();
+        }
+        Ok(directive)
+    }
+}
+
+/// The argument at the start of `rest`, for errors: up to the first whitespace outside quotes.
+fn argument(rest: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return &rest[..index],
+            _ => {}
+        }
+    }
+    rest
+}
+
+/// Parses the comma-separated list at the start of `value`, returning its items and what's after it.
+fn parse_list(value: &str) -> Result<(Vec<String>, &str), String> {
+    let mut items = Vec::new();
+    let mut rest = value;
+    loop {
+        let (item, after) = match rest.strip_prefix('"') {
+            Some(quoted) => parse_quoted(quoted)?,
+            None => {
+                let end = rest
+                    .find(|c: char| c.is_whitespace() || c == ',' || c == '"')
+                    .unwrap_or(rest.len());
+                if rest[end..].starts_with('"') {
+                    return Err("a quote can only start a value".to_string());
+                }
+                (rest[..end].to_string(), &rest[end..])
+            }
+        };
+        items.push(item);
+        match after.strip_prefix(',') {
+            Some(after) => rest = after,
+            None if after.is_empty() || after.starts_with(char::is_whitespace) => {
+                return Ok((items, after));
+            }
+            None => return Err("expected a comma or a space after a quoted string".to_string()),
+        }
+    }
+}
+
+/// Parses a quoted string, whose opening quote has been removed from the start of `rest`, returning it and what's after its closing quote.
+fn parse_quoted(rest: &str) -> Result<(String, &str), String> {
+    let mut item = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Ok((item, &rest[index + 1..])),
+            '\\' => match chars.next() {
+                Some((_, c @ ('"' | '\\'))) => item.push(c),
+                _ => return Err("only \\\" and \\\\ can be escaped".to_string()),
+            },
+            c => item.push(c),
+        }
+    }
+    Err("the quoted string isn't closed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Directive, ParseError> {
+        Directive::parse(args)
+    }
+
+    fn fields(fields: &[&str]) -> Option<Vec<String>> {
+        Some(fields.iter().map(|field| field.to_string()).collect())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(" "), Ok(Directive::default()));
+        assert_eq!(
+            parse(" fields=commit,date position=top "),
+            Ok(Directive {
+                fields: fields(&["commit", "date"]),
+                position: Some(Position::Top),
+            })
+        );
+        assert_eq!(
+            parse(r#"fields="trailer.Reviewed by","say \"hi\" \\ bye",name"#),
+            Ok(Directive {
+                fields: fields(&["trailer.Reviewed by", "say \"hi\" \\ bye", "name"]),
+                position: None,
+            })
+        );
+        assert_eq!(
+            parse("\n  position=\"bottom\"\n"),
+            Ok(Directive {
+                fields: None,
+                position: Some(Position::Bottom),
+            })
+        );
+
+        let error = |args: &str| parse(args).expect_err(args);
+        for (args, argument, reason) in [
+            ("fields", "fields", "expected key=value"),
+            ("=commit", "=commit", "expected key=value"),
+            ("colour=red", "colour=red", "unknown argument colour"),
+            (
+                "position=middle",
+                "position=middle",
+                "expected position=top",
+            ),
+            (
+                "position=top,bottom",
+                "position=top,bottom",
+                "expected position=top",
+            ),
+            (
+                "fields=commit,,date",
+                "fields=commit,,date",
+                "a field name is empty",
+            ),
+            (
+                "fields=name fields=commit",
+                "fields=commit",
+                "fields is given more than once",
+            ),
+            (r#"fields="name"#, r#"fields="name"#, "isn't closed"),
+            (r#"fields="a b" position=top"#, r#"fields="a b""#, ""),
+            (
+                r#"fields=a"b""#,
+                r#"fields=a"b""#,
+                "a quote can only start a value",
+            ),
+            (
+                r#"fields="a"b"#,
+                r#"fields="a"b"#,
+                "expected a comma or a space",
+            ),
+            (r#"fields="\n""#, r#"fields="\n""#, "can be escaped"),
+        ] {
+            if reason.is_empty() {
+                assert!(parse(args).is_ok(), "{args}");
+                continue;
+            }
+            let err = error(args);
+            assert_eq!(err.argument, argument, "{args}");
+            assert!(err.reason.contains(reason), "{args}: {}", err.reason);
+        }
+    }
+
+    #[test]
+    fn test_take() {
+        let mut content =
+            "# Intro\n\n<!-- buildtime: fields=commit -->\n\nText <!-- buildtime: position=top --> here.\n\n```\n<!-- buildtime: fields=name -->\n```\n"
+                .to_string();
+        assert_eq!(
+            Directive::take(&mut content),
+            Ok(Some(Directive {
+                fields: fields(&["commit"]),
+                position: Some(Position::Top),
+            }))
+        );
+        assert_eq!(
+            content,
+            "# Intro\n\n\nText  here.\n\n```\n<!-- buildtime: fields=name -->\n```\n"
+        );
+
+        // later directives override earlier ones
+        let mut content =
+            "<!-- buildtime: fields=commit -->\n<!-- buildtime:\n  fields=name\n-->\n# Intro\n"
+                .to_string();
+        assert_eq!(
+            Directive::take(&mut content).map(|directive| directive.and_then(|d| d.fields)),
+            Ok(fields(&["name"]))
+        );
+        assert_eq!(content, "# Intro\n");
+
+        let mut content = "# Intro\n".to_string();
+        assert_eq!(Directive::take(&mut content), Ok(None));
+
+        let mut content = "<!-- buildtime: fields=commit colour=red -->\n".to_string();
+        assert_eq!(
+            Directive::take(&mut content)
+                .expect_err("should fail")
+                .argument,
+            "colour=red"
+        );
+    }
+}
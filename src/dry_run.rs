@@ -0,0 +1,161 @@
+//! Reporting for `--dry-run` and `check --diff`, which describe what annotating a book would change without changing it.
+
+use anyhow::Context;
+use mdbook_preprocessor::book::{Book, BookItem, Chapter};
+use mdbook_preprocessor::errors::Result;
+use mdbook_summary::SummaryItem;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// One line per chapter that differs between `before` and `after`, naming its source path and the exact text that changed. Chapters only in `after` (the appendix) are reported as new.
+pub(crate) fn report(before: &Book, after: &Book) -> Vec<String> {
+    let original: HashMap<_, _> = chapters(before)
+        .map(|chapter| (chapter_label(chapter), chapter.content.as_str()))
+        .collect();
+    chapters(after)
+        .filter_map(|chapter| {
+            let label = chapter_label(chapter);
+            match original.get(&label) {
+                Some(content) => describe_change(&label, content, &chapter.content),
+                None => Some(format!("{label}: new chapter {:?}", chapter.name)),
+            }
+        })
+        .collect()
+}
+
+/// Describes how `after` differs from `before`, by trimming the text they have in common off both ends. `None` if they're the same.
+pub(crate) fn describe_change(label: &str, before: &str, after: &str) -> Option<String> {
+    if before == after {
+        return None;
+    }
+    let mut prefix = before
+        .bytes()
+        .zip(after.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !before.is_char_boundary(prefix) || !after.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let max_suffix = before.len().min(after.len()) - prefix;
+    let mut suffix = before
+        .bytes()
+        .rev()
+        .zip(after.bytes().rev())
+        .take(max_suffix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !before.is_char_boundary(before.len() - suffix)
+        || !after.is_char_boundary(after.len() - suffix)
+    {
+        suffix -= 1;
+    }
+
+    let removed = &before[prefix..before.len() - suffix];
+    let added = &after[prefix..after.len() - suffix];
+    Some(if !removed.is_empty() {
+        format!("{label}: replace {removed:?} with {added:?}")
+    } else if prefix == before.len() {
+        format!("{label}: append {added:?}")
+    } else {
+        let line = before[..prefix].matches('\n').count() + 1;
+        format!("{label}: insert {added:?} at line {line}")
+    })
+}
+
+/// Every chapter in the book, depth-first.
+fn chapters(book: &Book) -> impl Iterator<Item = &Chapter> {
+    book.iter().filter_map(|item| match item {
+        BookItem::Chapter(chapter) => Some(chapter),
+        _ => None,
+    })
+}
+
+/// The chapter's source path (with `/` separators), or its name for drafts, which don't have one.
+pub(crate) fn chapter_label(chapter: &Chapter) -> String {
+    chapter
+        .source_path
+        .as_ref()
+        .or(chapter.path.as_ref())
+        .map(|path| crate::paths::slash_path(path))
+        .unwrap_or_else(|| chapter.name.clone())
+}
+
+/// Loads the book in `root`, whose book.toml is `config`, from its SUMMARY.md the same way mdbook does, for `check --diff` and [`Processor::run_on_dir`](crate::Processor::run_on_dir). No other preprocessors are run, and unlike mdbook missing chapter files are an error rather than being created.
+pub(crate) fn load_book(root: &Path, config: &mdbook_preprocessor::config::Config) -> Result<Book> {
+    let src = root.join(&config.book.src);
+    let summary_path = src.join("SUMMARY.md");
+    let summary = std::fs::read_to_string(&summary_path)
+        .with_context(|| format!("Failed to read {}", summary_path.display()))?;
+    let summary = mdbook_summary::parse_summary(&summary)
+        .with_context(|| format!("Failed to parse {}", summary_path.display()))?;
+
+    let mut items = Vec::new();
+    for section in [
+        &summary.prefix_chapters,
+        &summary.numbered_chapters,
+        &summary.suffix_chapters,
+    ] {
+        items.extend(load_items(section, &src, &[])?);
+    }
+    Ok(Book::new_with_items(items))
+}
+
+fn load_items(items: &[SummaryItem], src: &Path, parent_names: &[String]) -> Result<Vec<BookItem>> {
+    let mut loaded = Vec::new();
+    for item in items {
+        loaded.push(match item {
+            SummaryItem::Link(link) => {
+                let mut chapter = match &link.location {
+                    Some(location) => {
+                        let path = src.join(location);
+                        let content = std::fs::read_to_string(&path)
+                            .with_context(|| format!("Failed to read {}", path.display()))?;
+                        Chapter::new(&link.name, content, location, parent_names.to_vec())
+                    }
+                    None => Chapter::new_draft(&link.name, parent_names.to_vec()),
+                };
+                chapter.number = link.number.clone();
+                let mut names = parent_names.to_vec();
+                names.push(link.name.clone());
+                chapter.sub_items = load_items(&link.nested_items, src, &names)?;
+                BookItem::Chapter(chapter)
+            }
+            SummaryItem::Separator => BookItem::Separator,
+            SummaryItem::PartTitle(title) => BookItem::PartTitle(title.clone()),
+            _ => continue,
+        });
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_change() {
+        assert_eq!(describe_change("a.md", "same", "same"), None);
+        assert_eq!(
+            describe_change("a.md", "# A\n", "# A\n<footer>x</footer>").as_deref(),
+            Some("a.md: append \"<footer>x</footer>\"")
+        );
+        assert_eq!(
+            describe_change("a.md", "# A\n<m>old</m>\n", "# A\n<m>new</m>\n").as_deref(),
+            Some("a.md: replace \"old\" with \"new\"")
+        );
+        assert_eq!(
+            describe_change("a.md", "# A\n\n## B\n", "# A\n\nfooter\n\n## B\n").as_deref(),
+            Some("a.md: insert \"footer\\n\\n\" at line 3")
+        );
+    }
+
+    #[test]
+    fn test_load_book() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("book");
+        let config = mdbook_preprocessor::config::Config::from_disk(root.join("book.toml"))
+            .expect("Failed to load the test book.toml");
+        let book = load_book(&root, &config).expect("Failed to load the test book");
+        assert!(chapters(&book).next().is_some());
+        assert!(chapters(&book).all(|chapter| chapter.source_path.is_some()));
+    }
+}
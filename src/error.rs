@@ -0,0 +1,367 @@
+//! The ways resolving an annotation can fail. Everything inside the crate returns an [`Error`], which is converted into mdbook's error type (keeping its sources) at the `Preprocessor` boundary, so callers with a mdbook error can still `downcast_ref::<Error>()` to find out what went wrong.
+
+use std::path::PathBuf;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The Cargo.toml couldn't be read. `path` is as fully resolved as we could make it, and `setting` is the config key that pointed at it (`workspace_dir` or `root_dir`) and its value, `None` if we searched for it. `relative_to_cwd` is set when that was a relative path resolved against the current directory, which is the usual reason it's missing.
+    #[error("Failed to read {}{}", .path.display(), manifest_hint(.setting.as_ref(), *.relative_to_cwd))]
+    ManifestNotFound {
+        path: PathBuf,
+        setting: Option<(String, PathBuf)>,
+        relative_to_cwd: bool,
+        source: std::io::Error,
+    },
+    /// The Cargo.toml was read but isn't valid TOML, or a field we read from it has the wrong type. `location` is where in the file, if toml could tell us.
+    #[error("Failed to parse {}{}", .path.display(), toml_hint(.location.as_deref(), .source))]
+    ManifestParse {
+        path: PathBuf,
+        location: Option<Box<TomlLocation>>,
+        source: Box<toml::de::Error>,
+    },
+    /// The Cargo.toml isn't valid UTF-8 and a field we need is one of the parts that couldn't be read. `offset` is where the first invalid byte in the file is, which isn't necessarily in `field`.
+    #[error("{} isn't valid UTF-8 (the first invalid byte is at offset {offset}) and its {field} couldn't be read. Save it as UTF-8", .path.display())]
+    ManifestEncoding {
+        path: PathBuf,
+        field: &'static str,
+        offset: usize,
+    },
+    /// The directory the git repository should be in doesn't exist. `path` is where we looked, made absolute.
+    #[cfg(feature = "git")]
+    #[error("The git directory {} doesn't exist. Set git_dir (or root_dir) to the directory containing .git", .path.display())]
+    GitNotFound { path: PathBuf },
+    /// `path` exists but isn't a git repository, or it couldn't be opened.
+    #[cfg(feature = "git")]
+    #[error("{} exists but isn't a git repository that can be opened", .path.display())]
+    GitOpen {
+        path: PathBuf,
+        source: Box<gix::Error>,
+    },
+    /// The repository's `extensions.objectFormat` is one gix can't read, which is anything but `sha1` and `sha256`.
+    #[cfg(feature = "git")]
+    #[error("{} is a {format} repository, which can't be read. Only SHA-1 and SHA-256 repositories are supported", .path.display())]
+    GitObjectFormat { path: PathBuf, format: String },
+    /// The repository was opened but HEAD doesn't point at a commit, eg because there are no commits yet.
+    #[cfg(feature = "git")]
+    #[error("Failed to resolve HEAD to a commit in {}", .path.display())]
+    GitHead {
+        path: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A field listed in `required` couldn't be found.
+    #[error("{field} is required, but couldn't be found")]
+    RequiredMissing { field: &'static str },
+    /// There's no book.toml in `path`, so it isn't a book we can load.
+    #[error("{} isn't an mdbook directory, it has no book.toml", .path.display())]
+    NotABook { path: PathBuf },
+    /// The book.toml couldn't be read or parsed.
+    #[error("Failed to load {}", .path.display())]
+    BookToml {
+        path: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A config value that isn't tied to a single key in our table, eg `[preprocessor.build-annotations]` not being a table at all.
+    #[error("{key}: {reason}")]
+    ConfigInvalid { key: String, reason: String },
+    /// One or more keys in our table have the wrong type.
+    #[error(transparent)]
+    Config(#[from] crate::config::ConfigError),
+    /// A chapter's `<!-- buildtime: ... -->` directive couldn't be parsed. `chapter` is its source path (or name), and `argument` is the part of the directive that's wrong.
+    #[error("{chapter}: invalid buildtime directive argument {argument:?}, {reason}")]
+    Directive {
+        chapter: String,
+        argument: String,
+        reason: String,
+    },
+    /// A `MDBOOK_BUILDTIME_*` environment variable that isn't a valid value for its key.
+    #[error("{var}: {reason}")]
+    EnvOverride { var: String, reason: String },
+}
+
+/// Where in a TOML file parsing it failed, counting lines and columns from 1, and the dotted key (eg `package.version`) whose value was the problem if there was one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TomlLocation {
+    pub line: usize,
+    pub column: usize,
+    pub key: Option<String>,
+}
+
+impl TomlLocation {
+    /// Works out where `err` happened in `content`, the TOML it came from. `None` if toml didn't say.
+    pub(crate) fn find(content: &str, err: &toml::de::Error) -> Option<Box<Self>> {
+        let span = err.span()?;
+        let before = content.get(..span.start)?;
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Some(Box::new(TomlLocation {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            key: toml_edit::Document::parse(content)
+                .ok()
+                .and_then(|doc| key_at(doc.as_table(), span.start, "")),
+        }))
+    }
+}
+
+/// The dotted key of the deepest item in `table` (whose own key is `prefix`) that `offset` falls in.
+fn key_at(table: &toml_edit::Table, offset: usize, prefix: &str) -> Option<String> {
+    for (key, item) in table.iter() {
+        let key = if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{prefix}.{key}")
+        };
+        let deeper = match item {
+            toml_edit::Item::Table(table) => key_at(table, offset, &key),
+            toml_edit::Item::Value(toml_edit::Value::InlineTable(inline)) => {
+                key_at(&inline.clone().into_table(), offset, &key)
+            }
+            _ => None,
+        };
+        let own = item.span().filter(|span| span.contains(&offset));
+        if deeper.is_some() {
+            return deeper;
+        }
+        if own.is_some() {
+            return Some(key);
+        }
+    }
+    None
+}
+
+/// Where a TOML file went wrong and why, for the end of a "Failed to parse" message.
+fn toml_hint(location: Option<&TomlLocation>, err: &toml::de::Error) -> String {
+    let reason = crate::config::describe_error(err.message());
+    match location {
+        Some(TomlLocation {
+            line,
+            column,
+            key: Some(key),
+        }) => format!(" at line {line}, column {column}, `{key}`: {reason}"),
+        Some(TomlLocation { line, column, .. }) => {
+            format!(" at line {line}, column {column}: {reason}")
+        }
+        None => format!(": {reason}"),
+    }
+}
+
+/// Explains where the Cargo.toml path came from and how to point us at the right one.
+fn manifest_hint(setting: Option<&(String, PathBuf)>, relative_to_cwd: bool) -> String {
+    let Some((key, dir)) = setting else {
+        return ", workspace_dir isn't set and there's no Cargo.toml at or above the book root. \
+                Set workspace_dir to the directory containing it (or raise discovery_levels if you've set it)"
+            .to_string();
+    };
+    let mut hint = format!(", from {key} = {dir:?}");
+    if relative_to_cwd {
+        hint.push_str(
+            ", which was resolved against the current directory. \
+             Set resolve_from_book_root = true to resolve it against the book root instead, \
+             which will be the default in a future release",
+        );
+    }
+    hint.push_str(&format!(
+        ". Set {key} to the directory containing the Cargo.toml, or remove it to search for one at or above the book root"
+    ));
+    hint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let not_found = || std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(
+            Error::ManifestNotFound {
+                path: "/src/book/Cargo.toml".into(),
+                setting: None,
+                relative_to_cwd: false,
+                source: not_found(),
+            }
+            .to_string(),
+            "Failed to read /src/book/Cargo.toml, workspace_dir isn't set and there's no Cargo.toml at or above the book root. \
+             Set workspace_dir to the directory containing it (or raise discovery_levels if you've set it)"
+        );
+        let message = Error::ManifestNotFound {
+            path: "/src/Cargo.toml".into(),
+            setting: Some(("workspace_dir".to_string(), "..".into())),
+            relative_to_cwd: true,
+            source: not_found(),
+        }
+        .to_string();
+        assert!(message.starts_with(
+            "Failed to read /src/Cargo.toml, from workspace_dir = \"..\", which was resolved against the current directory."
+        ));
+        assert_eq!(
+            Error::ManifestNotFound {
+                path: "/repo/Cargo.toml".into(),
+                setting: Some(("root_dir".to_string(), "/repo".into())),
+                relative_to_cwd: false,
+                source: not_found(),
+            }
+            .to_string(),
+            "Failed to read /repo/Cargo.toml, from root_dir = \"/repo\". \
+             Set root_dir to the directory containing the Cargo.toml, or remove it to search for one at or above the book root"
+        );
+
+        let source = toml::from_str::<toml::Table>("[package").expect_err("should be invalid");
+        assert_eq!(
+            Error::ManifestParse {
+                path: "Cargo.toml".into(),
+                location: None,
+                source: Box::new(source),
+            }
+            .to_string(),
+            "Failed to parse Cargo.toml: unclosed table, expected `]`"
+        );
+
+        assert_eq!(
+            Error::BookToml {
+                path: "book/book.toml".into(),
+                source: "bad toml".into(),
+            }
+            .to_string(),
+            "Failed to load book/book.toml"
+        );
+        assert_eq!(
+            Error::ManifestEncoding {
+                path: "Cargo.toml".into(),
+                field: "package.name",
+                offset: 42,
+            }
+            .to_string(),
+            "Cargo.toml isn't valid UTF-8 (the first invalid byte is at offset 42) and its package.name couldn't be read. Save it as UTF-8"
+        );
+        assert_eq!(
+            Error::NotABook {
+                path: "docs".into(),
+            }
+            .to_string(),
+            "docs isn't an mdbook directory, it has no book.toml"
+        );
+        assert_eq!(
+            Error::ConfigInvalid {
+                key: "preprocessor.build-annotations".to_string(),
+                reason: "expected a table".to_string(),
+            }
+            .to_string(),
+            "preprocessor.build-annotations: expected a table"
+        );
+        assert_eq!(
+            Error::EnvOverride {
+                var: "MDBOOK_BUILDTIME_GIT_COMMIT".to_string(),
+                reason: "expected a boolean".to_string(),
+            }
+            .to_string(),
+            "MDBOOK_BUILDTIME_GIT_COMMIT: expected a boolean"
+        );
+    }
+
+    #[test]
+    fn test_toml_location() {
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Manifest {
+            package: Package,
+        }
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Package {
+            name: String,
+            version: String,
+        }
+
+        for (content, expected) in [
+            (
+                "[package\n",
+                " at line 1, column 9: unclosed table, expected `]`",
+            ),
+            (
+                "[package]\nname = \"example\"\nversion = { workspace = true }\n",
+                " at line 3, column 11, `package.version`: expected a string, found map",
+            ),
+            (
+                "package = { name = \"example\", version = 1 }\n",
+                " at line 1, column 41, `package.version`: expected a string, found integer `1`",
+            ),
+            (
+                "[package]\nname = \"example\"\n",
+                " at line 1, column 1, `package`: missing field `version`",
+            ),
+        ] {
+            let err = toml::from_str::<Manifest>(content).expect_err("should be invalid");
+            let location = TomlLocation::find(content, &err);
+            assert_eq!(
+                toml_hint(location.as_deref(), &err),
+                expected,
+                "{content:?}"
+            );
+        }
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_git_messages() {
+        let source = gix::open("does-not-exist").expect_err("shouldn't be a repository");
+        assert_eq!(
+            Error::GitOpen {
+                path: "does-not-exist".into(),
+                source: Box::new(source),
+            }
+            .to_string(),
+            "does-not-exist exists but isn't a git repository that can be opened"
+        );
+
+        assert_eq!(
+            Error::GitObjectFormat {
+                path: "repo".into(),
+                format: "sha512".to_string(),
+            }
+            .to_string(),
+            "repo is a sha512 repository, which can't be read. Only SHA-1 and SHA-256 repositories are supported"
+        );
+
+        assert_eq!(
+            Error::GitNotFound {
+                path: "/src/missing".into(),
+            }
+            .to_string(),
+            "The git directory /src/missing doesn't exist. Set git_dir (or root_dir) to the directory containing .git"
+        );
+
+        assert_eq!(
+            Error::GitHead {
+                path: "repo".into(),
+                source: "no commits".into(),
+            }
+            .to_string(),
+            "Failed to resolve HEAD to a commit in repo"
+        );
+    }
+
+    #[test]
+    fn test_chain_preserved() {
+        let err: mdbook_preprocessor::errors::Error = Error::ManifestNotFound {
+            path: "Cargo.toml".into(),
+            setting: Some(("workspace_dir".to_string(), ".".into())),
+            relative_to_cwd: false,
+            source: std::io::Error::from(std::io::ErrorKind::NotFound),
+        }
+        .into();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ManifestNotFound { .. })
+        ));
+        assert_eq!(
+            format!("{err:#}"),
+            format!(
+                "Failed to read Cargo.toml, from workspace_dir = \".\". \
+                 Set workspace_dir to the directory containing the Cargo.toml, or remove it to search for one at or above the book root: {}",
+                std::io::Error::from(std::io::ErrorKind::NotFound)
+            )
+        );
+    }
+}
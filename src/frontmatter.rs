@@ -0,0 +1,194 @@
+//! The YAML frontmatter block the `frontmatter` format writes the annotation into, for renderers that read it:
+//!
+//! ```yaml
+//! ---
+//! buildtime:
+//!   name: "mdbook-buildtime-annotations"
+//!   commit: "0f6ad02d1c"
+//!   version: "0.0.1"
+//! ---
+//! ```
+//!
+//! A chapter that already starts with a frontmatter block gets the key added to it, or replaced if it's already there, rather than a second block.
+
+use std::ops::Range;
+
+/// The mapping's entries for `fields`, one per line and indented under the key, with every value a double-quoted string.
+pub(crate) fn entries<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    fields
+        .into_iter()
+        .map(|(field, value)| format!("  {}: {}\n", key(field), string(value)))
+        .collect()
+}
+
+/// Sets `key` in the frontmatter block at the start of `content` to the mapping with `entries` (from [`entries`]), replacing whatever it was. The block is added if there isn't one.
+pub(crate) fn set(content: &mut String, key: &str, entries: &str) {
+    let value = match entries.is_empty() {
+        true => format!("{key}: {{}}\n"),
+        false => format!("{key}:\n{entries}"),
+    };
+    match body(content) {
+        Some(body) => {
+            let range = find_key(&content[body.clone()], key).map_or(body.end..body.end, |found| {
+                body.start + found.start..body.start + found.end
+            });
+            content.replace_range(range, &value);
+        }
+        None => content.insert_str(0, &format!("---\n{value}---\n\n")),
+    }
+}
+
+/// Where the chapter starts after the frontmatter block at the start of `content` (after its closing `---` line and any blank lines), or 0 if there isn't one, for anything that has to go at the top of the chapter.
+pub(crate) fn end(content: &str) -> usize {
+    let Some(body) = body(content) else {
+        return 0;
+    };
+    let mut end = content[body.end..]
+        .find('\n')
+        .map_or(content.len(), |newline| body.end + newline + 1);
+    while let Some(line) = content[end..].split_inclusive('\n').next()
+        && line.trim().is_empty()
+    {
+        end += line.len();
+    }
+    end
+}
+
+/// The lines between the `---` that opens the frontmatter block at the very start of `content` and the `---` (or `...`) that closes it. `None` if `content` doesn't start with one, or it isn't closed.
+fn body(content: &str) -> Option<Range<usize>> {
+    let (first, _) = content.split_once('\n')?;
+    if first.trim_end() != "---" {
+        return None;
+    }
+    let start = first.len() + 1;
+    let mut offset = start;
+    for line in content[start..].split_inclusive('\n') {
+        if matches!(line.trim_end(), "---" | "...") {
+            return Some(start..offset);
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// The lines of `body` that are `key` and its value: its own line, and the indented (or `- ` list) lines after it.
+fn find_key(body: &str, key: &str) -> Option<Range<usize>> {
+    let mut offset = 0;
+    let mut found: Option<Range<usize>> = None;
+    for line in body.split_inclusive('\n') {
+        let range = offset..offset + line.len();
+        offset += line.len();
+        match &mut found {
+            None => {
+                if line
+                    .strip_prefix(key)
+                    .and_then(|rest| rest.strip_prefix(':'))
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+                {
+                    found = Some(range);
+                }
+            }
+            Some(found) if line.starts_with([' ', '\t']) || line.starts_with("- ") => {
+                found.end = range.end;
+            }
+            // a blank line only belongs to the value if more of it follows
+            Some(_) if line.trim().is_empty() => {}
+            Some(_) => break,
+        }
+    }
+    found
+}
+
+/// `field` as a mapping key: as it is if it's plain letters, digits, `_`, `.` and `-`, otherwise quoted.
+fn key(field: &str) -> String {
+    let plain = !field.is_empty()
+        && !field.starts_with('-')
+        && field
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    match plain {
+        true => field.to_string(),
+        false => string(field),
+    }
+}
+
+/// `value` as a double-quoted YAML string, with quotes, backslashes, line breaks and other control characters escaped so it can't end early or add keys.
+fn string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() || matches!(c, '\u{2028}' | '\u{2029}' | '\u{feff}') => {
+                quoted.push_str(&format!("\\u{:04x}", u32::from(c)));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries() {
+        assert_eq!(
+            entries([
+                ("name", "demo"),
+                ("trailer.Reviewed by", "Bob \"B\" \\ Smith"),
+                ("branch", "a\nevil: true\u{7}"),
+            ]),
+            "  name: \"demo\"\n  \"trailer.Reviewed by\": \"Bob \\\"B\\\" \\\\ Smith\"\n  branch: \"a\\nevil: true\\u0007\"\n"
+        );
+    }
+
+    #[test]
+    fn test_set() {
+        let entries = "  commit: \"abc\"\n";
+
+        let mut content = "# Intro\n".to_string();
+        set(&mut content, "buildtime", entries);
+        assert_eq!(
+            content,
+            "---\nbuildtime:\n  commit: \"abc\"\n---\n\n# Intro\n"
+        );
+        assert_eq!(
+            end(&content),
+            "---\nbuildtime:\n  commit: \"abc\"\n---\n\n".len()
+        );
+        // setting it again replaces it
+        let again = content.clone();
+        set(&mut content, "buildtime", entries);
+        assert_eq!(content, again);
+
+        // merged into a block that's already there, keeping everything else
+        let mut content =
+            "---\ntitle: Intro\nbuildtime:\n  commit: \"old\"\n  version: \"1.0.0\"\n\ntags:\n- a\n---\n# Intro\n"
+                .to_string();
+        set(&mut content, "buildtime", entries);
+        assert_eq!(
+            content,
+            "---\ntitle: Intro\nbuildtime:\n  commit: \"abc\"\n\ntags:\n- a\n---\n# Intro\n"
+        );
+        let mut content = "---\ntitle: Intro\nbuildtimes: 2\n---\n# Intro\n".to_string();
+        set(&mut content, "buildtime", "");
+        assert_eq!(
+            content,
+            "---\ntitle: Intro\nbuildtimes: 2\nbuildtime: {}\n---\n# Intro\n"
+        );
+
+        // a thematic break that isn't closed isn't frontmatter
+        let mut content = "---\n# Intro\n".to_string();
+        assert_eq!(end(&content), 0);
+        set(&mut content, "buildtime", entries);
+        assert!(content.starts_with("---\nbuildtime:\n"), "{content}");
+        assert!(content.ends_with("---\n\n---\n# Intro\n"), "{content}");
+    }
+}
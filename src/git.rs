@@ -0,0 +1,1692 @@
+//! Access to the book's git repository. A [`GitHandle`] is created for each run and opens the repository the first time something asks for it, so however many fields (or chapters) need git, the repository is only opened once.
+
+use crate::error::{Error, Result};
+use std::cell::{OnceCell, RefCell};
+use std::path::{Path, PathBuf};
+use tracing::{debug, error, info, warn};
+
+/// A commit that changed a file, from [`GitRepo::history`].
+#[derive(Clone, Debug)]
+pub struct FileCommit {
+    pub id: gix::ObjectId,
+    /// The committer time.
+    pub time: gix::date::Time,
+    /// The author's name, after it's been through the `.mailmap`.
+    pub author: String,
+    /// The names from the commit's `Co-authored-by` trailers, after they've been through the `.mailmap`. Trailers that aren't `Name <email>` are ignored.
+    pub co_authors: Vec<String>,
+    /// When the author wrote it, which is earlier than `time` if it was rebased or cherry-picked.
+    pub author_time: gix::date::Time,
+    pub merge: bool,
+}
+
+/// How far back [`GitRepo::histories_within`] looks, unbounded by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HistoryBounds {
+    /// The most commits to walk.
+    pub max_commits: Option<usize>,
+    /// Commits older than this (in seconds since the epoch) aren't walked.
+    pub since: Option<i64>,
+}
+
+/// A commit in a [`Release`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogCommit {
+    pub id: gix::ObjectId,
+    /// The first line of its message.
+    pub subject: String,
+    pub merge: bool,
+}
+
+/// The commits of one release, from [`GitRepo::releases`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Release {
+    /// `None` for the commits since the most recent tag.
+    pub tag: Option<String>,
+    /// The committer time of the tagged commit.
+    pub time: Option<gix::date::Time>,
+    /// Newest first.
+    pub commits: Vec<LogCommit>,
+}
+
+/// The authors and co-authors of `commits`, most commits first (then by name), with authors who've used more than one email address only listed once. Merge commits are left out unless `merges` is set, and so are authors matching any of the `exclude` patterns (see [`matches_author`]), whose commits don't count towards the order either.
+pub fn contributors(commits: &[FileCommit], merges: bool, exclude: &[String]) -> Vec<String> {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for commit in commits.iter().filter(|commit| merges || !commit.merge) {
+        let mut names: Vec<&str> = std::iter::once(commit.author.as_str())
+            .chain(commit.co_authors.iter().map(String::as_str))
+            .filter(|name| !exclude.iter().any(|pattern| matches_author(pattern, name)))
+            .collect();
+        // someone who's credited twice on one commit only gets it counted once
+        names.sort_unstable();
+        names.dedup();
+        for name in names {
+            match counts.iter_mut().find(|(counted, _)| *counted == name) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((name, 1)),
+            }
+        }
+    }
+    counts.sort_by(|(a_name, a_count), (b_name, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_name.cmp(b_name))
+    });
+    counts
+        .into_iter()
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// Whether the author `name` matches `pattern`, where `*` matches any run of characters (including none) and `?` matches any one character. Everything else has to match exactly, including case, so `[bot]` is just text.
+pub fn matches_author(pattern: &str, name: &str) -> bool {
+    crate::wildcard::matches(pattern, name)
+}
+
+/// The web page of the repository a remote `url` points at, eg `https://github.com/org/repo` for `git@github.com:org/repo.git` or `https://user@github.com/org/repo`. `None` for remotes that aren't on a server, like a path on disk.
+pub fn web_url(url: &str) -> Option<String> {
+    let url = url.trim().trim_end_matches('/');
+    let url = url.strip_suffix(".git").unwrap_or(url);
+    let (host, path) = match url.split_once("://") {
+        Some(("https" | "http" | "ssh" | "git", rest)) => rest.split_once('/')?,
+        Some(_) => return None,
+        // scp-like, eg git@github.com:org/repo
+        None => url
+            .split_once(':')
+            .filter(|(host, _)| !host.contains('/'))?,
+    };
+    // leave out the user and port
+    let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+    let host = host.split_once(':').map_or(host, |(host, _)| host);
+    if host.is_empty() || path.is_empty() {
+        return None;
+    }
+    Some(format!("https://{host}/{}", path.trim_start_matches('/')))
+}
+
+/// The `extensions.objectFormat` of the repository in `git_dir` (eg `sha256`), read straight from its config so it can be reported for a repository gix can't open. `None` if it isn't set, which means SHA-1.
+fn object_format(git_dir: &Path) -> Option<String> {
+    [git_dir.join(".git").join("config"), git_dir.join("config")]
+        .into_iter()
+        .filter(|path| path.is_file())
+        .find_map(|path| {
+            gix::config::File::from_path_no_includes(path, gix::config::Source::Local).ok()
+        })?
+        .string("extensions.objectFormat")
+        .map(|format| format.to_string())
+}
+
+/// The abbreviated commit hash length for a repository with `objects` objects, the same as gix and git use: enough hex characters for twice as many bits as it takes to count the objects, so two of them are unlikely to share a prefix, and never fewer than 7.
+fn auto_abbrev_len(objects: u64) -> usize {
+    let bits = u64::BITS - objects.leading_zeros();
+    bits.div_ceil(2).max(7) as usize
+}
+
+/// The files [`GitRepo::histories`] is looking for, as a tree of path components so each directory is only looked at once.
+#[derive(Debug, Default)]
+struct PathTree {
+    children: std::collections::BTreeMap<String, PathTree>,
+    /// The file's index in the list of paths, if there's a file here.
+    file: Option<usize>,
+}
+
+impl PathTree {
+    fn insert(&mut self, path: &Path, index: usize) {
+        let mut node = self;
+        for component in path.components() {
+            node = node
+                .children
+                .entry(component.as_os_str().to_string_lossy().into_owned())
+                .or_default();
+        }
+        node.file = Some(index);
+    }
+}
+
+/// The git repository for one run, opened on first use. If it can't be opened that's logged once (or kept for [`GitHandle::take_error`] when it's `strict`) and every lookup after that finds nothing.
+#[derive(Debug)]
+pub struct GitHandle {
+    path: PathBuf,
+    strict: bool,
+    /// Used instead of this process's `GIT_DIR` and `GIT_WORK_TREE` when it's set.
+    env: Option<GitEnv>,
+    repo: OnceCell<Option<GitRepo>>,
+    error: RefCell<Option<Error>>,
+}
+
+impl GitHandle {
+    /// A handle for the repository in `path`, which isn't opened until it's needed.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        GitHandle {
+            path: path.into(),
+            strict: false,
+            env: None,
+            repo: OnceCell::new(),
+            error: RefCell::new(None),
+        }
+    }
+
+    /// Keeps the error instead of logging it if the repository can't be opened, for the caller to fail with.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Opens the repository with `env` instead of this process's environment, see [`GitRepo::open_with_env`].
+    pub fn env(mut self, env: GitEnv) -> Self {
+        self.env = Some(env);
+        self
+    }
+
+    /// The repository, opening it if this is the first time it's been asked for.
+    pub fn repo(&self) -> Option<&GitRepo> {
+        self.repo
+            .get_or_init(|| {
+                let opened = match &self.env {
+                    Some(env) => GitRepo::open_with_env(&self.path, env),
+                    None => GitRepo::open(&self.path),
+                };
+                match opened {
+                    Ok(repo) => Some(repo),
+                    Err(err) if self.strict => {
+                        self.error.replace(Some(err));
+                        None
+                    }
+                    Err(err) => {
+                        error!("{:#}, can't annotate it!", anyhow::Error::from(err));
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+
+    /// Why a strict handle's repository couldn't be opened, if it couldn't. Only returns it once.
+    pub fn take_error(&self) -> Option<Error> {
+        self.error.borrow_mut().take()
+    }
+}
+
+/// Finds the submodule each file in a working tree is in, by walking up from the file to the nearest directory with a `.git` in it. What's found for each directory is kept, so each one is only looked at once however many files are in it.
+#[derive(Debug)]
+pub struct Submodules {
+    workdir: PathBuf,
+    /// The submodule working tree each directory is in, `None` for the superproject's own.
+    dirs: std::collections::HashMap<PathBuf, Option<PathBuf>>,
+}
+
+impl Submodules {
+    /// Looks for submodules in the superproject working tree `workdir`.
+    pub fn new(workdir: impl Into<PathBuf>) -> Self {
+        Submodules {
+            workdir: workdir.into(),
+            dirs: std::collections::HashMap::new(),
+        }
+    }
+
+    /// The working tree of the submodule the file at `path` is in, or `None` if it's in the superproject (or isn't in its working tree at all). `path` has to be absolute, with its directories canonicalized like the `workdir` is.
+    pub fn find(&mut self, path: &Path) -> Option<PathBuf> {
+        let mut walked = Vec::new();
+        let mut found = None;
+        for dir in path.ancestors().skip(1) {
+            if let Some(cached) = self.dirs.get(dir) {
+                found = cached.clone();
+                break;
+            }
+            if dir == self.workdir || !dir.starts_with(&self.workdir) {
+                break;
+            }
+            walked.push(dir.to_path_buf());
+            // a submodule's .git is a file pointing into the superproject's, a nested repository's is a directory
+            if dir.join(".git").exists() {
+                found = Some(dir.to_path_buf());
+                break;
+            }
+        }
+        for dir in walked {
+            self.dirs.insert(dir, found.clone());
+        }
+        found
+    }
+}
+
+/// The `GIT_DIR` and `GIT_WORK_TREE` environment variables, which command-line git uses instead of looking in the directory it's run in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GitEnv {
+    /// The repository's `.git` directory (or a bare repository), used instead of `git_dir`.
+    pub git_dir: Option<PathBuf>,
+    /// The working tree, for a `GIT_DIR` that isn't inside it.
+    pub work_tree: Option<PathBuf>,
+}
+
+impl GitEnv {
+    /// Reads the variables from this process's environment. Empty values count as unset, like they do for git.
+    pub fn from_env() -> Self {
+        let var = |name| {
+            std::env::var_os(name)
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        };
+        GitEnv {
+            git_dir: var("GIT_DIR"),
+            work_tree: var("GIT_WORK_TREE"),
+        }
+    }
+}
+
+/// An open git repository, which remembers what HEAD resolved to and its `.mailmap`.
+#[derive(Debug)]
+pub struct GitRepo {
+    repo: gix::Repository,
+    head: OnceCell<gix::ObjectId>,
+    mailmap: OnceCell<gix::mailmap::Snapshot>,
+}
+
+impl GitRepo {
+    /// Opens the git repository in `git_dir`, which has to be the top of the working tree (or a bare repository), we don't search upwards for it. `GIT_DIR` and `GIT_WORK_TREE` take precedence when they're set, see [`GitRepo::open_with_env`].
+    pub fn open(git_dir: &Path) -> Result<Self> {
+        GitRepo::open_with_env(git_dir, &GitEnv::from_env())
+    }
+
+    /// [`GitRepo::open`], with `env` instead of this process's environment. Like git, `env.git_dir` is used instead of `git_dir` and `env.work_tree` replaces the repository's working tree.
+    pub fn open_with_env(git_dir: &Path, env: &GitEnv) -> Result<Self> {
+        #[cfg(test)]
+        tests::OPENS.with(|opens| opens.set(opens.get() + 1));
+        let git_dir = match &env.git_dir {
+            Some(env_dir) => {
+                info!(
+                    "Using GIT_DIR={} instead of {}",
+                    env_dir.display(),
+                    git_dir.display()
+                );
+                env_dir.as_path()
+            }
+            None => git_dir,
+        };
+        if !git_dir.exists() {
+            return Err(Error::GitNotFound {
+                path: std::path::absolute(git_dir).unwrap_or_else(|_| git_dir.to_path_buf()),
+            });
+        }
+        let mut repo = gix::open(git_dir).map_err(|source| match object_format(git_dir) {
+            Some(format)
+                if !format.eq_ignore_ascii_case("sha1")
+                    && !format.eq_ignore_ascii_case("sha256") =>
+            {
+                Error::GitObjectFormat {
+                    path: git_dir.to_path_buf(),
+                    format,
+                }
+            }
+            _ => Error::GitOpen {
+                path: git_dir.to_path_buf(),
+                source: Box::new(source),
+            },
+        })?;
+        if let Some(work_tree) = &env.work_tree {
+            info!("Using GIT_WORK_TREE={}", work_tree.display());
+            let work_tree = std::path::absolute(work_tree).unwrap_or_else(|_| work_tree.clone());
+            if let Err(err) = repo.set_workdir(work_tree.clone()) {
+                warn!(
+                    "Ignoring GIT_WORK_TREE, {} can't be used as the working tree: {err}",
+                    work_tree.display()
+                );
+            }
+        }
+        Ok(GitRepo {
+            repo,
+            head: OnceCell::new(),
+            mailmap: OnceCell::new(),
+        })
+    }
+
+    /// The repository's `.mailmap`, read the first time it's asked for. Empty if there isn't one, or it can't be read.
+    fn mailmap(&self) -> &gix::mailmap::Snapshot {
+        self.mailmap.get_or_init(|| self.repo.open_mailmap())
+    }
+
+    /// The commit HEAD points to, resolved the first time it's asked for.
+    fn head_id(&self) -> Result<gix::ObjectId> {
+        if let Some(id) = self.head.get() {
+            return Ok(*id);
+        }
+        let head_error = |source: Box<dyn std::error::Error + Send + Sync>| Error::GitHead {
+            path: self
+                .repo
+                .workdir()
+                .unwrap_or(self.repo.git_dir())
+                .to_path_buf(),
+            source,
+        };
+        let id = self
+            .repo
+            .head()
+            .map_err(|err| head_error(err.into()))?
+            .peel_to_commit()
+            .map_err(|err| head_error(err.into()))?
+            .id;
+        Ok(*self.head.get_or_init(|| id))
+    }
+
+    /// The commit id HEAD points to, trimmed to the first `commit_characters` characters.
+    pub fn head_commit(&self, commit_characters: usize) -> Result<String> {
+        let mut commit_id = self.head_id()?.to_string();
+        // Now we actually want to trim this to the first `commit_characters` chars
+        commit_id.truncate(commit_characters);
+        Ok(commit_id)
+    }
+
+    /// How many characters of a commit hash to show when `commit_characters` isn't set, worked out like git does: `core.abbrev` if it's set, otherwise enough characters to tell the repository's objects apart.
+    pub fn abbrev_len(&self) -> usize {
+        let config = self.repo.config_snapshot();
+        if let Some(value) = config.string("core.abbrev") {
+            match gix::config::tree::Core::ABBREV
+                .try_into_abbreviation(value, self.repo.object_hash())
+            {
+                Ok(Some(len)) => return len,
+                // "auto"
+                Ok(None) => {}
+                Err(err) => warn!("Ignoring core.abbrev: {err}"),
+            }
+        }
+        auto_abbrev_len(self.repo.objects.packed_object_count().unwrap_or_default())
+    }
+
+    /// The short name of the branch HEAD points to, if it isn't detached.
+    pub fn head_branch(&self) -> Option<String> {
+        let name = self.repo.head_name().ok()??;
+        Some(name.shorten().to_string())
+    }
+
+    /// [`GitRepo::head_branch`], or if HEAD is detached (as it usually is in a submodule) the branch `origin/HEAD` points to, which is the remote's default branch.
+    pub fn default_branch(&self) -> Option<String> {
+        if let Some(branch) = self.head_branch() {
+            return Some(branch);
+        }
+        let origin_head = self
+            .repo
+            .try_find_reference("refs/remotes/origin/HEAD")
+            .ok()??;
+        let target = origin_head.target().try_name()?.shorten().to_string();
+        target.strip_prefix("origin/").map(str::to_string)
+    }
+
+    /// The values of the HEAD commit's `token` trailers (eg `Reviewed-by`), in the order they appear. The token's matched case-insensitively, like git does.
+    pub fn head_trailer(&self, token: &str) -> Vec<String> {
+        let Some(commit) = self
+            .head_id()
+            .ok()
+            .and_then(|id| self.repo.find_commit(id).ok())
+        else {
+            return Vec::new();
+        };
+        let Ok(decoded) = commit.decode() else {
+            return Vec::new();
+        };
+        decoded
+            .message_trailers()
+            .filter(|trailer| trailer.token.to_string().eq_ignore_ascii_case(token))
+            .map(|trailer| trailer.value.to_string())
+            .collect()
+    }
+
+    /// The closest tag (annotated or not) in the HEAD commit's history and how many commits HEAD is ahead of it, like `git describe --tags`. It's 0 when HEAD is tagged. `None` if there's no tag in its history.
+    pub fn nearest_tag(&self) -> Option<(String, usize)> {
+        let commit = self.repo.find_commit(self.head_id().ok()?).ok()?;
+        let resolution = commit
+            .describe()
+            .names(gix::commit::describe::SelectRef::AllTags)
+            .try_resolve()
+            .ok()??;
+        let name = resolution.outcome.name?.to_string();
+        Some((name, resolution.outcome.depth as usize))
+    }
+
+    /// Whether there's a tag called `name`.
+    pub fn has_tag(&self, name: &str) -> bool {
+        self.repo
+            .try_find_reference(&format!("refs/tags/{name}"))
+            .is_ok_and(|tag| tag.is_some())
+    }
+
+    /// The commits reachable from HEAD grouped by release, newest first: the commits since the most recent tag (if there are any), then for each of the last `tags` tags, the tagged commit and the ones after the tag before it. A commit with more than one tag goes under the one that sorts last. With `paths` (patterns like `src/**`), only commits that changed a file matching one of them compared with their first parent are included. With `first_parent`, only the first parent of each commit is followed, like `git log --first-parent`, so a merged branch's commits are left out for its merge commit. `None` if there aren't any tags in HEAD's history.
+    pub fn releases(
+        &self,
+        tags: usize,
+        paths: &[String],
+        first_parent: bool,
+    ) -> Option<Vec<Release>> {
+        let mut tagged = std::collections::HashMap::<gix::ObjectId, String>::new();
+        for mut reference in self.repo.references().ok()?.tags().ok()?.flatten() {
+            let name = reference.name().shorten().to_string();
+            let Ok(commit) = reference.peel_to_commit() else {
+                continue;
+            };
+            let tag = tagged.entry(commit.id).or_default();
+            if name > *tag {
+                *tag = name;
+            }
+        }
+        let mut walk = self
+            .repo
+            .rev_walk([self.head_id().ok()?])
+            .sorting(gix::revision::walk::Sorting::ByCommitTime(
+                Default::default(),
+            ))
+            .use_commit_graph(true);
+        if first_parent {
+            walk = walk.first_parent_only();
+        }
+
+        let mut releases = vec![Release {
+            tag: None,
+            time: None,
+            commits: Vec::new(),
+        }];
+        let mut found_tag = false;
+        for info in walk.all().ok()? {
+            let Some(commit) = info.ok().and_then(|info| info.object().ok()) else {
+                break;
+            };
+            if let Some(tag) = tagged.get(&commit.id) {
+                found_tag = true;
+                if releases.len() > tags {
+                    break;
+                }
+                releases.push(Release {
+                    tag: Some(tag.clone()),
+                    time: commit.time().ok(),
+                    commits: Vec::new(),
+                });
+            }
+            let parent_tree = commit.parent_ids().next().and_then(|parent| {
+                Some(parent.object().ok()?.into_commit().tree_id().ok()?.detach())
+            });
+            if !paths.is_empty()
+                && !commit.tree_id().is_ok_and(|tree| {
+                    self.changes_matching(Some(tree.detach()), parent_tree, "", paths)
+                })
+            {
+                continue;
+            }
+            let subject = commit
+                .message()
+                .map(|message| message.summary().to_string())
+                .unwrap_or_default();
+            releases.last_mut()?.commits.push(LogCommit {
+                id: commit.id,
+                subject,
+                merge: commit.parent_ids().count() > 1,
+            });
+        }
+        if !found_tag {
+            return None;
+        }
+        if releases[0].commits.is_empty() {
+            releases.remove(0);
+        }
+        Some(releases)
+    }
+
+    /// Whether any file under `prefix` that's different in the trees `new` and `old` (either of which can be missing) matches one of `patterns`. Directories that are the same in both aren't looked into.
+    fn changes_matching(
+        &self,
+        new: Option<gix::ObjectId>,
+        old: Option<gix::ObjectId>,
+        prefix: &str,
+        patterns: &[String],
+    ) -> bool {
+        if new == old {
+            return false;
+        }
+        let entries = |id: Option<gix::ObjectId>| -> std::collections::BTreeMap<String, (gix::ObjectId, bool)> {
+            let Some(tree) = id.and_then(|id| self.repo.find_tree(id).ok()) else {
+                return Default::default();
+            };
+            let Ok(decoded) = tree.decode() else {
+                return Default::default();
+            };
+            decoded
+                .entries
+                .iter()
+                .map(|entry| {
+                    (
+                        entry.filename.to_string(),
+                        (entry.oid.to_owned(), entry.mode.is_tree()),
+                    )
+                })
+                .collect()
+        };
+        let new_entries = entries(new);
+        let old_entries = entries(old);
+        let names: std::collections::BTreeSet<&String> =
+            new_entries.keys().chain(old_entries.keys()).collect();
+        names.into_iter().any(|name| {
+            let new_entry = new_entries.get(name);
+            let old_entry = old_entries.get(name);
+            if new_entry == old_entry {
+                return false;
+            }
+            let path = format!("{prefix}{name}");
+            let subtree = |entry: Option<&(gix::ObjectId, bool)>| {
+                entry.filter(|(_, tree)| *tree).map(|(id, _)| *id)
+            };
+            let is_file =
+                |entry: Option<&(gix::ObjectId, bool)>| entry.is_some_and(|(_, tree)| !tree);
+            ((is_file(new_entry) || is_file(old_entry))
+                && patterns
+                    .iter()
+                    .any(|pattern| crate::wildcard::matches(pattern, &path)))
+                || self.changes_matching(
+                    subtree(new_entry),
+                    subtree(old_entry),
+                    &format!("{path}/"),
+                    patterns,
+                )
+        })
+    }
+
+    /// The URL of the `origin` remote, or of the only remote if there's just one.
+    pub fn remote_url(&self) -> Option<String> {
+        let config = self.repo.config_snapshot();
+        if let Some(url) = config.string("remote.origin.url") {
+            return Some(url.to_string());
+        }
+        let names = self.repo.remote_names();
+        let [name] = names.iter().collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        config
+            .string(format!("remote.{name}.url").as_str())
+            .map(|url| url.to_string())
+    }
+
+    /// The committer date of the HEAD commit, as YYYY-MM-DD.
+    pub fn head_commit_date(&self) -> Option<String> {
+        self.head_time(gix::date::time::format::SHORT)
+    }
+
+    /// The committer time of the HEAD commit, in RFC 3339 format with the committer's offset, eg `2024-05-12T09:30:00+10:00`.
+    pub fn head_commit_time(&self) -> Option<String> {
+        self.head_time(gix::date::time::format::ISO8601_STRICT)
+    }
+
+    /// The working tree the repository belongs to, `None` for a bare repository.
+    pub fn workdir(&self) -> Option<&Path> {
+        self.repo.workdir()
+    }
+
+    /// The committer time of the most recent commit (reachable from HEAD) that changed the file at `path`, which is relative to the top of the working tree. `None` if no commit has it, eg because it's a new file.
+    pub fn last_modified(&self, path: &Path) -> Option<gix::date::Time> {
+        self.history(path, Some(1))
+            .first()
+            .map(|commit| commit.time)
+    }
+
+    /// The commits reachable from HEAD that changed the file at `path` (relative to the top of the working tree), newest first, stopping after `limit` of them. See [`GitRepo::histories`] for what counts as changing it, which is better for more than one file.
+    pub fn history(&self, path: &Path, limit: Option<usize>) -> Vec<FileCommit> {
+        self.histories(&[path], limit).pop().unwrap_or_default()
+    }
+
+    /// The commits reachable from HEAD that changed each of the files in `paths` (relative to the top of the working tree), newest first, with at most `limit` for each file. The history's walked once for all of them (using the commit-graph if there is one), and each commit's tree is only compared with its parents' as far down as the directories the files are in, so commits that don't touch them are cheap.
+    ///
+    /// A commit changed a file if the file's different from in every parent, so merges only count when they changed it themselves (like `git log` does). The root commit counts as changing every file in it, and so does the oldest commit in a shallow clone, as its parents aren't there to compare with. Renames aren't followed, history from before a file was moved isn't included.
+    pub fn histories(&self, paths: &[&Path], limit: Option<usize>) -> Vec<Vec<FileCommit>> {
+        self.histories_within(paths, limit, HistoryBounds::default())
+            .0
+    }
+
+    /// [`GitRepo::histories`], only looking back as far as `bounds` allows. Also returns whether the walk was cut short by them before it found `limit` commits for every file, in which case files with fewer than that might have older ones.
+    pub fn histories_within(
+        &self,
+        paths: &[&Path],
+        limit: Option<usize>,
+        bounds: HistoryBounds,
+    ) -> (Vec<Vec<FileCommit>>, bool) {
+        let mut histories = vec![Vec::new(); paths.len()];
+        let mut tree = PathTree::default();
+        for (index, path) in paths.iter().enumerate() {
+            tree.insert(path, index);
+        }
+        let Some(walk) = self.head_id().ok().and_then(|head| {
+            self.repo
+                .rev_walk([head])
+                .sorting(gix::revision::walk::Sorting::ByCommitTime(
+                    Default::default(),
+                ))
+                .use_commit_graph(true)
+                .all()
+                .ok()
+        }) else {
+            return (histories, false);
+        };
+
+        let mut truncated = false;
+        for (walked, info) in walk.enumerate() {
+            if limit.is_some_and(|limit| histories.iter().all(|history| history.len() >= limit)) {
+                break;
+            }
+            if bounds.max_commits.is_some_and(|max| walked >= max) {
+                truncated = true;
+                break;
+            }
+            let Some(commit) = info.ok().and_then(|info| info.object().ok()) else {
+                break;
+            };
+            // the walk's newest first, so everything after this is older too (give or take clock skew)
+            if let Some(since) = bounds.since
+                && commit.time().is_ok_and(|time| time.seconds < since)
+            {
+                truncated = true;
+                break;
+            }
+            let Ok(tree_id) = commit.tree_id() else {
+                continue;
+            };
+            let parent_trees: Vec<Option<gix::ObjectId>> = commit
+                .parent_ids()
+                .map(|parent| {
+                    let parent = parent.object().ok()?.into_commit();
+                    Some(parent.tree_id().ok()?.detach())
+                })
+                .collect();
+
+            // the files that differ from every parent
+            let mut changed = Vec::new();
+            self.changed_paths(
+                &tree,
+                tree_id.detach(),
+                parent_trees.first().copied().flatten(),
+                &mut changed,
+            );
+            for parent_tree in parent_trees.iter().skip(1) {
+                let mut also_changed = Vec::new();
+                self.changed_paths(&tree, tree_id.detach(), *parent_tree, &mut also_changed);
+                changed.retain(|index| also_changed.contains(index));
+            }
+            changed.retain(|index| limit.is_none_or(|limit| histories[*index].len() < limit));
+            if changed.is_empty() {
+                continue;
+            }
+
+            let Ok(time) = commit.time() else {
+                continue;
+            };
+            let author = commit.author().ok();
+            let co_authors = commit
+                .decode()
+                .map(|decoded| {
+                    decoded
+                        .co_authored_by_trailers()
+                        .filter_map(|trailer| self.co_author(&trailer.value.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let file_commit = FileCommit {
+                id: commit.id,
+                time,
+                author: author
+                    .map(|author| self.mailmap().resolve_cow(author).name.to_string())
+                    .unwrap_or_default(),
+                co_authors,
+                author_time: author.and_then(|author| author.time().ok()).unwrap_or(time),
+                merge: parent_trees.len() > 1,
+            };
+            for index in changed {
+                histories[index].push(file_commit.clone());
+            }
+        }
+        (histories, truncated)
+    }
+
+    /// Adds the index of every file in `paths` that's in the tree `new` and isn't the same in the tree `old` to `changed`. Directories that are the same in both aren't looked into.
+    fn changed_paths(
+        &self,
+        paths: &PathTree,
+        new: gix::ObjectId,
+        old: Option<gix::ObjectId>,
+        changed: &mut Vec<usize>,
+    ) {
+        if old == Some(new) {
+            return;
+        }
+        let Ok(new_tree) = self.repo.find_tree(new) else {
+            return;
+        };
+        let old_tree = old.and_then(|old| self.repo.find_tree(old).ok());
+        for (name, child) in &paths.children {
+            let Some(entry) = new_tree.find_entry(name.as_bytes()) else {
+                continue;
+            };
+            let new_id = entry.oid().to_owned();
+            let old_id = old_tree
+                .as_ref()
+                .and_then(|old_tree| old_tree.find_entry(name.as_bytes()))
+                .map(|entry| entry.oid().to_owned());
+            if let Some(index) = child.file
+                && old_id != Some(new_id)
+            {
+                changed.push(index);
+            }
+            if !child.children.is_empty() && entry.mode().is_tree() {
+                self.changed_paths(child, new_id, old_id, changed);
+            }
+        }
+    }
+
+    /// The name from a `Co-authored-by` trailer's `Name <email>`, after it's been through the `.mailmap`. `None` if it isn't in that form.
+    fn co_author(&self, value: &str) -> Option<String> {
+        let (name, email) = value.trim().strip_suffix('>')?.split_once('<')?;
+        let name = name.trim();
+        if name.is_empty() || email.is_empty() || email.contains(['<', '>']) {
+            return None;
+        }
+        let signature = gix::actor::SignatureRef {
+            name: name.into(),
+            email: email.into(),
+            time: "",
+        };
+        Some(self.mailmap().resolve_cow(signature).name.to_string())
+    }
+
+    /// Whether this is a shallow clone, where history stops before the first commit.
+    pub fn is_shallow(&self) -> bool {
+        self.repo.is_shallow().unwrap_or(false)
+    }
+
+    /// Whether the file at `path` (relative to the top of the working tree) differs from the version in HEAD, going by its content. Files that aren't in HEAD count as modified, files that aren't in the working tree don't.
+    pub fn is_modified(&self, path: &Path) -> bool {
+        let Some(workdir) = self.repo.workdir() else {
+            return false;
+        };
+        let Ok(content) = std::fs::read(workdir.join(path)) else {
+            return false;
+        };
+        let head_entry = self
+            .head_id()
+            .ok()
+            .and_then(|id| self.repo.find_commit(id).ok())
+            .and_then(|commit| commit.tree().ok()?.lookup_entry_by_path(path).ok()?)
+            .map(|entry| entry.object_id());
+        let working_id =
+            gix::objs::compute_hash(self.repo.object_hash(), gix::object::Kind::Blob, &content)
+                .ok();
+        head_entry.is_none() || head_entry != working_id
+    }
+
+    /// Whether the working tree has changes that haven't been committed, like `git describe --dirty`: staged changes, merge conflicts, and tracked files that have been changed or deleted. Untracked files and submodules' own changes don't count. A file whose size and modification time match the index is taken to be unchanged without reading it, the same shortcut git takes. Always false for a bare repository.
+    pub fn is_dirty(&self) -> bool {
+        let Some(workdir) = self.repo.workdir() else {
+            return false;
+        };
+        let index = match self.repo.index_or_empty() {
+            Ok(index) => index,
+            Err(err) => {
+                warn!("Failed to read the git index, assuming the working tree is clean: {err}");
+                return false;
+            }
+        };
+        let head_index = self
+            .head_id()
+            .ok()
+            .and_then(|id| self.repo.find_commit(id).ok()?.tree_id().ok())
+            .and_then(|tree| self.repo.index_from_tree(&tree).ok());
+        let staged = |state: &gix::index::State| {
+            state
+                .entries()
+                .iter()
+                .map(|entry| {
+                    (
+                        entry.path(state).to_owned(),
+                        entry.id,
+                        entry.mode,
+                        entry.stage_raw(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let head_entries = head_index.as_deref().map(staged).unwrap_or_default();
+        if staged(&index) != head_entries {
+            debug!("The git index differs from HEAD");
+            return true;
+        }
+
+        for entry in index.entries() {
+            if entry.mode.is_submodule()
+                || entry
+                    .flags
+                    .contains(gix::index::entry::Flags::SKIP_WORKTREE)
+            {
+                continue;
+            }
+            let path = entry.path(&index);
+            let Ok(relative) = gix::path::from_bstr(path) else {
+                continue;
+            };
+            let file = workdir.join(relative);
+            if self.worktree_changed(entry, &file) {
+                debug!("{} has changed since it was committed", file.display());
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether the working tree's `file` doesn't match its index `entry`.
+    fn worktree_changed(&self, entry: &gix::index::Entry, file: &Path) -> bool {
+        let Ok(metadata) = std::fs::symlink_metadata(file) else {
+            return true;
+        };
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok());
+        if metadata.len() as u32 == entry.stat.size
+            && modified.is_some_and(|time| {
+                time.as_secs() as u32 == entry.stat.mtime.secs
+                    && time.subsec_nanos() == entry.stat.mtime.nsecs
+            })
+        {
+            return false;
+        }
+        let content: Option<Vec<u8>> = if entry.mode == gix::index::entry::Mode::SYMLINK {
+            std::fs::read_link(file).ok().and_then(|target| {
+                gix::path::into_bstr(target)
+                    .ok()
+                    .map(|target| target.into_owned().into())
+            })
+        } else {
+            std::fs::read(file).ok()
+        };
+        let Some(content) = content else {
+            return true;
+        };
+        gix::objs::compute_hash(self.repo.object_hash(), gix::object::Kind::Blob, &content)
+            .map_or(true, |id| id != entry.id)
+    }
+
+    fn head_time(&self, format: gix::date::time::CustomFormat) -> Option<String> {
+        let commit = self.repo.find_commit(self.head_id().ok()?).ok()?;
+        commit.time().ok()?.format(format).ok()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// How many times [`GitRepo::open`] has been called on this thread.
+        pub(crate) static OPENS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    #[test]
+    fn test_git_rev() {
+        let repo =
+            GitRepo::open(Path::new(env!("CARGO_MANIFEST_DIR"))).expect("Failed to open repo");
+        let rev = repo.head_commit(10).expect("Failed to resolve HEAD");
+        assert_eq!(rev.len(), 10);
+    }
+
+    #[test]
+    fn test_git_rev_too_long() {
+        let repo =
+            GitRepo::open(Path::new(env!("CARGO_MANIFEST_DIR"))).expect("Failed to open repo");
+        let rev = repo.head_commit(100).expect("Failed to resolve HEAD");
+        assert_eq!(rev.len(), 40);
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let repo =
+            GitRepo::open(Path::new(env!("CARGO_MANIFEST_DIR"))).expect("Failed to open repo");
+        let modified = repo
+            .last_modified(Path::new("Cargo.toml"))
+            .expect("Cargo.toml should have history");
+        let head = repo
+            .repo
+            .find_commit(repo.head_id().expect("Failed to find HEAD"))
+            .expect("Failed to find the HEAD commit");
+        assert!(modified.seconds <= head.time().expect("HEAD has no time").seconds);
+        assert!(repo.last_modified(Path::new("does-not-exist.md")).is_none());
+        // there's nothing in the working tree to differ
+        assert!(!repo.is_modified(Path::new("does-not-exist.md")));
+    }
+
+    #[test]
+    fn test_contributors() {
+        let commit = |author: &str, merge: bool| FileCommit {
+            id: gix::ObjectId::null(gix::hash::Kind::Sha1),
+            time: gix::date::Time::new(0, 0),
+            author: author.to_string(),
+            co_authors: Vec::new(),
+            author_time: gix::date::Time::new(0, 0),
+            merge,
+        };
+        let commits = [
+            commit("Carol", false),
+            commit("Bob", false),
+            commit("Alice", false),
+            // the same person with a different email
+            commit("Bob", false),
+            commit("Dave", true),
+            commit("Dave", true),
+            commit("Dave", true),
+        ];
+        assert_eq!(
+            contributors(&commits, false, &[]),
+            ["Bob", "Alice", "Carol"]
+        );
+        assert_eq!(
+            contributors(&commits, true, &[]),
+            ["Dave", "Bob", "Alice", "Carol"]
+        );
+        assert!(contributors(&[], false, &[]).is_empty());
+
+        // excluded authors' commits don't count towards the order
+        let commits = [
+            commit("renovate-bot", false),
+            commit("renovate-bot", false),
+            commit("dependabot[bot]", false),
+            commit("Carol", false),
+            commit("Alice", false),
+            commit("Alice", false),
+        ];
+        assert_eq!(
+            contributors(
+                &commits,
+                false,
+                &["dependabot[bot]".to_string(), "*-bot".to_string()]
+            ),
+            ["Alice", "Carol"]
+        );
+    }
+
+    /// Creates a repository in `dir` with a commit for each of `commits` (author name, email, file), each changing that file. The commits are a day apart.
+    pub(crate) fn fixture_repo(dir: &Path, commits: &[(&str, &str, &str)]) {
+        let commits: Vec<_> = commits
+            .iter()
+            .map(|(name, email, file)| (*name, *email, *file, "change"))
+            .collect();
+        fixture_repo_with_messages(dir, &commits);
+    }
+
+    /// [`fixture_repo`], with a commit message for each commit.
+    pub(crate) fn fixture_repo_with_messages(dir: &Path, commits: &[(&str, &str, &str, &str)]) {
+        let repo = gix::init(dir).expect("Failed to create repo");
+        write_commits(&repo, commits);
+    }
+
+    /// [`fixture_repo`] for a SHA-256 repository, like `git init --object-format=sha256` makes.
+    pub(crate) fn sha256_fixture_repo(dir: &Path, commits: &[(&str, &str, &str)]) {
+        let options = gix::create::Options {
+            object_hash: Some(gix::hash::Kind::Sha256),
+            ..Default::default()
+        };
+        let repo: gix::Repository =
+            gix::ThreadSafeRepository::init(dir, gix::create::Kind::WithWorktree, options)
+                .expect("Failed to create repo")
+                .into();
+        let commits: Vec<_> = commits
+            .iter()
+            .map(|(name, email, file)| (*name, *email, *file, "change"))
+            .collect();
+        write_commits(&repo, &commits);
+    }
+
+    /// Commits each of `commits` to `repo` in turn, see [`fixture_repo_with_messages`].
+    fn write_commits(repo: &gix::Repository, commits: &[(&str, &str, &str, &str)]) {
+        let mut files = std::collections::BTreeMap::new();
+        let mut parent = None;
+        for (index, (name, email, file, message)) in commits.iter().enumerate() {
+            let blob = repo
+                .write_blob(format!("change {index}\n"))
+                .expect("Failed to write blob");
+            files.insert(file.to_string(), blob.detach());
+            let tree = gix::objs::Tree {
+                entries: files
+                    .iter()
+                    .map(|(file, oid)| gix::objs::tree::Entry {
+                        mode: gix::objs::tree::EntryKind::Blob.into(),
+                        filename: file.as_str().into(),
+                        oid: *oid,
+                    })
+                    .collect(),
+            };
+            let tree = repo.write_object(&tree).expect("Failed to write tree");
+            let time = format!("{} +0000", 1_700_000_000 + index * 86_400);
+            let signature = gix::actor::SignatureRef {
+                name: (*name).into(),
+                email: (*email).into(),
+                time: &time,
+            };
+            let commit = repo
+                .commit_as(signature, signature, "HEAD", message, tree, parent)
+                .expect("Failed to commit");
+            parent = Some(commit.detach());
+        }
+    }
+
+    /// Writes HEAD's files into the working tree of the repository in `dir`, and the index to match, like a fresh clone.
+    pub(crate) fn checkout(dir: &Path) {
+        let repo = gix::open(dir).expect("Failed to open repo");
+        let tree = repo
+            .head_commit()
+            .expect("no HEAD")
+            .tree_id()
+            .expect("no tree");
+        let mut index = repo.index_from_tree(&tree).expect("Failed to build index");
+        for entry in index.entries() {
+            let path = dir.join(entry.path(&index).to_string());
+            let blob = repo.find_object(entry.id).expect("Failed to find blob");
+            std::fs::write(path, &blob.data).expect("Failed to write file");
+        }
+        index
+            .write(gix::index::write::Options::default())
+            .expect("Failed to write index");
+    }
+
+    #[test]
+    fn test_is_dirty() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "setup.md"),
+            ],
+        );
+        checkout(dir.path());
+        let dirty = || {
+            GitRepo::open(dir.path())
+                .expect("Failed to open repo")
+                .is_dirty()
+        };
+        assert!(!dirty());
+
+        // untracked files don't count
+        std::fs::write(dir.path().join("notes.md"), "scratch\n").expect("Failed to write");
+        assert!(!dirty());
+
+        std::fs::write(dir.path().join("intro.md"), "changed\n").expect("Failed to write");
+        assert!(dirty());
+        std::fs::write(dir.path().join("intro.md"), "change 0\n").expect("Failed to write");
+        assert!(!dirty());
+
+        std::fs::remove_file(dir.path().join("setup.md")).expect("Failed to remove");
+        assert!(dirty());
+        checkout(dir.path());
+        assert!(!dirty());
+
+        // staged, with the working tree matching the index
+        let repo = gix::open(dir.path()).expect("Failed to open repo");
+        let blob = repo
+            .write_blob("staged\n")
+            .expect("Failed to write blob")
+            .detach();
+        let mut index = repo.open_index().expect("Failed to open index");
+        for entry in index.entries_mut() {
+            entry.id = blob;
+        }
+        index
+            .write(gix::index::write::Options::default())
+            .expect("Failed to write index");
+        std::fs::write(dir.path().join("intro.md"), "staged\n").expect("Failed to write");
+        std::fs::write(dir.path().join("setup.md"), "staged\n").expect("Failed to write");
+        assert!(dirty());
+    }
+
+    #[test]
+    fn test_web_url() {
+        for (url, expected) in [
+            (
+                "git@github.com:org/repo.git",
+                Some("https://github.com/org/repo"),
+            ),
+            (
+                "https://github.com/org/repo",
+                Some("https://github.com/org/repo"),
+            ),
+            (
+                "https://user@gitlab.com/group/sub/repo.git/",
+                Some("https://gitlab.com/group/sub/repo"),
+            ),
+            (
+                "ssh://git@gitlab.example.com:2222/group/repo.git",
+                Some("https://gitlab.example.com/group/repo"),
+            ),
+            ("/srv/git/repo.git", None),
+            ("file:///srv/git/repo.git", None),
+            ("../repo", None),
+        ] {
+            assert_eq!(web_url(url).as_deref(), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn test_matches_author() {
+        for (pattern, name, matches) in [
+            ("dependabot[bot]", "dependabot[bot]", true),
+            ("dependabot[bot]", "dependabotb", false),
+            ("dependabot[bot]", "Dependabot[bot]", false),
+            ("*-bot", "merge-bot", true),
+            ("*-bot", "-bot", true),
+            ("*-bot", "merge-bot-2", false),
+            ("*-bot", "Merge-Bot", false),
+            ("*bot*", "renovatebot (self-hosted)", true),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYcZ", false),
+            ("?ob", "Bob", true),
+            ("?ob", "ob", false),
+            ("*", "", true),
+            ("Alice", "Alice Example", false),
+        ] {
+            assert_eq!(
+                matches_author(pattern, name),
+                matches,
+                "{pattern:?} against {name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_history_co_authors() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo_with_messages(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md", "Start the intro"),
+                (
+                    "Alice",
+                    "alice@example.com",
+                    "intro.md",
+                    "Squash merge of the intro rewrite\n\nLots of changes.\n\nCo-authored-by: Bob <bob@example.com>\nCo-authored-by: Carol <carol@example.com>\nCo-authored-by: not a person\nCo-authored-by: <nobody@example.com>\nCo-authored-by: Alice <alice@example.com>\n",
+                ),
+                (
+                    "Alice",
+                    "alice@example.com",
+                    "other.md",
+                    "Co-authored-by: Dave <dave@example.com>",
+                ),
+            ],
+        );
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        let history = repo.history(Path::new("intro.md"), None);
+        assert_eq!(history[0].co_authors, ["Bob", "Carol", "Alice"]);
+        assert_eq!(
+            contributors(&history, false, &[]),
+            ["Alice", "Bob", "Carol"]
+        );
+        assert_eq!(
+            contributors(&history, false, &["Carol".to_string()]),
+            ["Alice", "Bob"]
+        );
+    }
+
+    #[test]
+    fn test_history_mailmap() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@old.example", "intro.md"),
+                ("alice", "alice@laptop.example", "intro.md"),
+                ("Bob", "bob@example.com", "intro.md"),
+            ],
+        );
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert_eq!(
+            contributors(&repo.history(Path::new("intro.md"), None), false, &[]),
+            ["Alice", "Bob", "alice"]
+        );
+
+        std::fs::write(
+            dir.path().join(".mailmap"),
+            "Alice Example <alice@example.com> <alice@old.example>\nAlice Example <alice@example.com> <alice@laptop.example>\n",
+        )
+        .expect("Failed to write .mailmap");
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert_eq!(
+            contributors(&repo.history(Path::new("intro.md"), None), false, &[]),
+            ["Alice Example", "Bob"]
+        );
+    }
+
+    #[test]
+    fn test_histories_match_history() {
+        let repo =
+            GitRepo::open(Path::new(env!("CARGO_MANIFEST_DIR"))).expect("Failed to open repo");
+        let paths = [
+            Path::new("Cargo.toml"),
+            Path::new("src/lib.rs"),
+            Path::new("src/git.rs"),
+            Path::new("book/book.toml"),
+            Path::new("src/does-not-exist.rs"),
+        ];
+        let ids =
+            |history: &[FileCommit]| history.iter().map(|commit| commit.id).collect::<Vec<_>>();
+        for limit in [None, Some(1), Some(3)] {
+            let histories = repo.histories(&paths, limit);
+            for (path, history) in paths.iter().zip(&histories) {
+                assert_eq!(
+                    ids(history),
+                    ids(&repo.history(path, limit)),
+                    "{} with limit {limit:?}",
+                    path.display()
+                );
+            }
+            assert!(histories[4].is_empty());
+        }
+    }
+
+    #[test]
+    fn test_histories_within() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "other.md"),
+                ("Carol", "carol@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "other.md"),
+            ],
+        );
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        let paths = [Path::new("intro.md"), Path::new("other.md")];
+        let lengths = |(histories, truncated): (Vec<Vec<FileCommit>>, bool)| {
+            (
+                histories.iter().map(Vec::len).collect::<Vec<_>>(),
+                truncated,
+            )
+        };
+
+        let unbounded = HistoryBounds::default();
+        assert_eq!(
+            lengths(repo.histories_within(&paths, None, unbounded)),
+            (vec![2, 2], false)
+        );
+        let three_commits = HistoryBounds {
+            max_commits: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            lengths(repo.histories_within(&paths, None, three_commits)),
+            (vec![1, 2], true)
+        );
+        // the limit was reached before the bound was
+        assert_eq!(
+            lengths(repo.histories_within(&paths, Some(1), three_commits)),
+            (vec![1, 1], false)
+        );
+        let since_third = HistoryBounds {
+            since: Some(1_700_000_000 + 2 * 86_400),
+            ..Default::default()
+        };
+        assert_eq!(
+            lengths(repo.histories_within(&paths, None, since_third)),
+            (vec![1, 1], true)
+        );
+        // the whole history fits in the bound
+        let four_commits = HistoryBounds {
+            max_commits: Some(4),
+            since: Some(1_700_000_000),
+        };
+        assert_eq!(
+            lengths(repo.histories_within(&paths, None, four_commits)),
+            (vec![2, 2], false)
+        );
+    }
+
+    #[test]
+    fn test_nearest_tag() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "intro.md"),
+                ("Carol", "carol@example.com", "intro.md"),
+            ],
+        );
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert_eq!(repo.nearest_tag(), None);
+
+        let ids: Vec<_> = repo
+            .history(Path::new("intro.md"), None)
+            .iter()
+            .map(|commit| commit.id)
+            .collect();
+        use gix::refs::transaction::PreviousValue::MustNotExist;
+        repo.repo
+            .tag_reference("v1.0.0", ids[2], MustNotExist)
+            .expect("Failed to tag");
+        assert_eq!(repo.nearest_tag(), Some(("v1.0.0".to_string(), 2)));
+
+        let tagger = gix::actor::SignatureRef {
+            name: "Alice".into(),
+            email: "alice@example.com".into(),
+            time: "1700200000 +0000",
+        };
+        repo.repo
+            .tag(
+                "v1.1.0",
+                ids[0],
+                gix::object::Kind::Commit,
+                Some(tagger),
+                "Release 1.1.0",
+                MustNotExist,
+            )
+            .expect("Failed to tag");
+        assert_eq!(repo.nearest_tag(), Some(("v1.1.0".to_string(), 0)));
+    }
+
+    #[test]
+    fn test_releases() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo_with_messages(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md", "Add the intro"),
+                (
+                    "Bob",
+                    "bob@example.com",
+                    "main.rs",
+                    "Add main\n\nWith a body.",
+                ),
+                ("Carol", "carol@example.com", "intro.md", "Fix a typo"),
+                ("Alice", "alice@example.com", "main.rs", "Speed up main"),
+                ("Bob", "bob@example.com", "intro.md", "Reword the intro"),
+            ],
+        );
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert_eq!(repo.releases(3, &[], false), None);
+
+        let ids: Vec<_> = repo
+            .history(Path::new("intro.md"), None)
+            .iter()
+            .chain(&repo.history(Path::new("main.rs"), None))
+            .map(|commit| commit.id)
+            .collect();
+        // intro.md's commits (4, 2 and 0) then main.rs's (3 and 1)
+        use gix::refs::transaction::PreviousValue::MustNotExist;
+        for (name, id) in [("v0.1.0", ids[2]), ("v0.2.0", ids[1]), ("v0.3.0", ids[3])] {
+            repo.repo
+                .tag_reference(name, id, MustNotExist)
+                .expect("Failed to tag");
+        }
+        let summary = |releases: Vec<Release>| -> Vec<(Option<String>, Vec<String>)> {
+            releases
+                .into_iter()
+                .map(|release| {
+                    (
+                        release.tag,
+                        release
+                            .commits
+                            .into_iter()
+                            .map(|commit| commit.subject)
+                            .collect(),
+                    )
+                })
+                .collect()
+        };
+        let tag = |name: &str| Some(name.to_string());
+        let subjects = |subjects: &[&str]| subjects.iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            summary(repo.releases(2, &[], false).expect("should have tags")),
+            [
+                (None, subjects(&["Reword the intro"])),
+                (tag("v0.3.0"), subjects(&["Speed up main"])),
+                (tag("v0.2.0"), subjects(&["Fix a typo", "Add main"])),
+            ]
+        );
+        assert_eq!(
+            summary(
+                repo.releases(3, &["*.rs".to_string()], false)
+                    .expect("should have tags")
+            ),
+            [
+                (tag("v0.3.0"), subjects(&["Speed up main"])),
+                (tag("v0.2.0"), subjects(&["Add main"])),
+                (tag("v0.1.0"), subjects(&[])),
+            ]
+        );
+        // just the commits since the last tag
+        assert_eq!(
+            summary(repo.releases(0, &[], false).expect("should have tags")),
+            [(None, subjects(&["Reword the intro"]))]
+        );
+        let release = &repo.releases(1, &[], false).expect("should have tags")[1];
+        assert_eq!(
+            release.time.map(|time| time.seconds),
+            Some(1_700_000_000 + 3 * 86_400)
+        );
+        assert!(!release.commits[0].merge);
+    }
+
+    #[test]
+    fn test_history() {
+        let repo =
+            GitRepo::open(Path::new(env!("CARGO_MANIFEST_DIR"))).expect("Failed to open repo");
+        let history = repo.history(Path::new("Cargo.toml"), None);
+        assert!(!history.is_empty());
+        assert!(
+            history
+                .windows(2)
+                .all(|pair| pair[0].time.seconds >= pair[1].time.seconds)
+        );
+        assert_eq!(
+            repo.last_modified(Path::new("Cargo.toml")),
+            Some(history[0].time)
+        );
+        assert_eq!(repo.history(Path::new("Cargo.toml"), Some(1)).len(), 1);
+    }
+
+    #[test]
+    fn test_open_errors() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let missing = dir.path().join("missing");
+        assert!(matches!(
+            GitRepo::open(&missing),
+            Err(Error::GitNotFound { path }) if path == missing
+        ));
+        assert!(matches!(
+            GitRepo::open(dir.path()),
+            Err(Error::GitOpen { .. })
+        ));
+        gix::init(dir.path()).expect("Failed to create repo");
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert!(matches!(repo.head_commit(10), Err(Error::GitHead { .. })));
+    }
+
+    #[test]
+    fn test_object_format() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        assert_eq!(object_format(dir.path()), None);
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert_eq!(
+            repo.head_commit(usize::MAX).map(|commit| commit.len()).ok(),
+            Some(40)
+        );
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        sha256_fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        assert_eq!(object_format(dir.path()).as_deref(), Some("sha256"));
+        let repo = GitRepo::open(dir.path()).expect("Failed to open SHA-256 repo");
+        let commit_id = repo.head_commit(usize::MAX).expect("Failed to read HEAD");
+        assert_eq!(commit_id.len(), 64);
+        assert!(commit_id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(repo.abbrev_len(), 7);
+        assert_eq!(
+            repo.head_commit(12).expect("Failed to read HEAD"),
+            commit_id[..12]
+        );
+        let cfg = crate::Config::builder()
+            .root_dir(dir.path())
+            .commit_characters(10)
+            .build();
+        let annotation = crate::Processor::default()
+            .with_providers(vec![Box::new(crate::provider::GitProvider)])
+            .collect(&cfg, dir.path())
+            .expect("Failed to collect");
+        assert_eq!(annotation.commit_id.as_deref(), Some(commit_id.as_str()));
+        assert_eq!(annotation.commit.as_deref(), Some(&commit_id[..10]));
+
+        // a format gix doesn't know
+        let config = dir.path().join(".git").join("config");
+        let contents = std::fs::read_to_string(&config)
+            .expect("Failed to read config")
+            .replace("sha256", "sha512");
+        std::fs::write(&config, contents).expect("Failed to write config");
+        assert!(matches!(
+            GitRepo::open(dir.path()),
+            Err(Error::GitObjectFormat { path, format }) if path == dir.path() && format == "sha512"
+        ));
+    }
+
+    #[test]
+    fn test_abbrev_len() {
+        for (objects, expected) in [(0, 7), (1, 7), (1 << 13, 7), (1 << 14, 8), (1 << 20, 11)] {
+            assert_eq!(auto_abbrev_len(objects), expected, "{objects} objects");
+        }
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert_eq!(repo.abbrev_len(), 7);
+
+        let config = dir.path().join(".git").join("config");
+        let without_abbrev = std::fs::read_to_string(&config).expect("Failed to read config");
+        for (abbrev, expected) in [("12", 12), ("auto", 7), ("false", 40)] {
+            std::fs::write(
+                &config,
+                format!("{without_abbrev}[core]\n\tabbrev = {abbrev}\n"),
+            )
+            .expect("Failed to write config");
+            let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+            assert_eq!(repo.abbrev_len(), expected, "core.abbrev = {abbrev}");
+        }
+    }
+
+    #[test]
+    fn test_submodules() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let workdir = dir.path().join("book");
+        let shared = workdir.join("src").join("shared");
+        std::fs::create_dir_all(shared.join("guide")).expect("Failed to create directories");
+        std::fs::write(shared.join(".git"), "gitdir: ../../.git/modules/shared\n")
+            .expect("Failed to write .git");
+
+        let mut submodules = Submodules::new(&workdir);
+        assert_eq!(submodules.find(&workdir.join("src").join("intro.md")), None);
+        assert_eq!(
+            submodules.find(&shared.join("guide").join("setup.md")),
+            Some(shared.clone())
+        );
+        assert_eq!(
+            submodules.find(&shared.join("topic.md")),
+            Some(shared.clone())
+        );
+        // the directories walked through are remembered
+        assert_eq!(
+            submodules.dirs.get(&shared.join("guide")),
+            Some(&Some(shared.clone()))
+        );
+        assert_eq!(submodules.dirs.get(&workdir.join("src")), Some(&None));
+        // files outside the working tree aren't in a submodule of it
+        assert_eq!(submodules.find(&dir.path().join("other.md")), None);
+    }
+
+    #[test]
+    fn test_default_branch() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        let branch = repo.head_branch().expect("HEAD should be on a branch");
+        assert_eq!(repo.default_branch(), Some(branch));
+
+        // detach HEAD, like a submodule's
+        let git_dir = dir.path().join(".git");
+        let head = repo.head_id().expect("no HEAD");
+        std::fs::write(git_dir.join("HEAD"), format!("{head}\n")).expect("Failed to write HEAD");
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert_eq!(repo.default_branch(), None);
+
+        let origin = git_dir.join("refs").join("remotes").join("origin");
+        std::fs::create_dir_all(&origin).expect("Failed to create refs");
+        std::fs::write(origin.join("stable"), format!("{head}\n")).expect("Failed to write ref");
+        std::fs::write(origin.join("HEAD"), "ref: refs/remotes/origin/stable\n")
+            .expect("Failed to write ref");
+        let repo = GitRepo::open(dir.path()).expect("Failed to open repo");
+        assert_eq!(repo.default_branch().as_deref(), Some("stable"));
+    }
+
+    #[test]
+    fn test_git_env() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let repo_dir = dir.path().join("repo");
+        fixture_repo(&repo_dir, &[("Alice", "alice@example.com", "intro.md")]);
+        let expected = GitRepo::open(&repo_dir)
+            .expect("Failed to open repo")
+            .head_commit(usize::MAX)
+            .expect("Failed to read HEAD");
+
+        // git_dir points nowhere, GIT_DIR wins
+        let missing = dir.path().join("missing");
+        assert!(GitRepo::open_with_env(&missing, &GitEnv::default()).is_err());
+        let env = GitEnv {
+            git_dir: Some(repo_dir.join(".git")),
+            work_tree: None,
+        };
+        let repo = GitRepo::open_with_env(&missing, &env).expect("Failed to open GIT_DIR");
+        assert_eq!(repo.head_commit(usize::MAX).ok(), Some(expected));
+        assert_eq!(repo.workdir(), Some(repo_dir.as_path()));
+
+        let work_tree = dir.path().join("checkout");
+        std::fs::create_dir(&work_tree).expect("Failed to create work tree");
+        let env = GitEnv {
+            work_tree: Some(work_tree.clone()),
+            ..env
+        };
+        let repo = GitRepo::open_with_env(&missing, &env).expect("Failed to open GIT_DIR");
+        assert_eq!(repo.workdir(), Some(work_tree.as_path()));
+
+        let env = GitEnv {
+            git_dir: Some(dir.path().join("also-missing")),
+            work_tree: None,
+        };
+        assert!(matches!(
+            GitRepo::open_with_env(&repo_dir, &env),
+            Err(Error::GitNotFound { path }) if path.ends_with("also-missing")
+        ));
+    }
+
+    #[test]
+    fn test_handle_opens_once() {
+        let handle = GitHandle::new(env!("CARGO_MANIFEST_DIR"));
+        let before = OPENS.with(Cell::get);
+        for _ in 0..3 {
+            let repo = handle.repo().expect("Failed to open repo");
+            assert!(repo.head_commit(10).is_ok());
+            assert!(repo.head_commit_date().is_some());
+            assert!(
+                repo.head_commit_time()
+                    .is_some_and(|time| humantime::parse_rfc3339_weak(&time[..19]).is_ok())
+            );
+        }
+        assert_eq!(OPENS.with(Cell::get) - before, 1);
+
+        let handle = GitHandle::new("does-not-exist");
+        assert!(handle.repo().is_none());
+        assert!(handle.repo().is_none());
+        assert!(handle.take_error().is_none());
+        assert_eq!(OPENS.with(Cell::get) - before, 2);
+
+        let handle = GitHandle::new("does-not-exist").strict(true);
+        assert!(handle.repo().is_none());
+        assert!(matches!(
+            handle.take_error(),
+            Some(Error::GitNotFound { path }) if path.is_absolute() && path.ends_with("does-not-exist")
+        ));
+        assert!(handle.take_error().is_none());
+    }
+}
@@ -0,0 +1,52 @@
+//! Relative times like "2 weeks ago", for `chapter_date_style = "relative"`.
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+const MONTH: i64 = 30 * DAY;
+const YEAR: i64 = 365 * DAY;
+
+/// How long ago something `elapsed` seconds in the past was, rounded down to the largest unit that fits, eg "59 minutes ago", "1 hour ago" or "3 weeks ago". Months are 30 days and years are 365. Anything under a minute (or in the future, which a skewed clock can give us) is "just now".
+pub fn ago(elapsed: i64) -> String {
+    let (count, unit) = match elapsed {
+        ..MINUTE => return "just now".to_string(),
+        MINUTE..HOUR => (elapsed / MINUTE, "minute"),
+        HOUR..DAY => (elapsed / HOUR, "hour"),
+        DAY..WEEK => (elapsed / DAY, "day"),
+        WEEK..MONTH => (elapsed / WEEK, "week"),
+        MONTH..YEAR => (elapsed / MONTH, "month"),
+        YEAR.. => (elapsed / YEAR, "year"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} ago")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ago_boundaries() {
+        for (elapsed, expected) in [
+            (-5, "just now"),
+            (0, "just now"),
+            (59, "just now"),
+            (MINUTE, "1 minute ago"),
+            (2 * MINUTE, "2 minutes ago"),
+            (59 * MINUTE + 59, "59 minutes ago"),
+            (HOUR, "1 hour ago"),
+            (23 * HOUR + 59 * MINUTE, "23 hours ago"),
+            (DAY, "1 day ago"),
+            (6 * DAY + 23 * HOUR, "6 days ago"),
+            (WEEK, "1 week ago"),
+            (29 * DAY, "4 weeks ago"),
+            (MONTH, "1 month ago"),
+            (364 * DAY, "12 months ago"),
+            (YEAR, "1 year ago"),
+            (3 * YEAR + 100 * DAY, "3 years ago"),
+        ] {
+            assert_eq!(ago(elapsed), expected, "{elapsed} seconds");
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! The `install` subcommand, which adds our table to a book's book.toml.
+
+use crate::Config;
+use crate::config::CONFIG_TABLE;
+use anyhow::Context;
+use mdbook_preprocessor::errors::Result;
+use std::path::Path;
+use toml_edit::{DocumentMut, Item, Table};
+
+/// What `install` did to the book.toml.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InstallOutcome {
+    Added,
+    Replaced,
+    AlreadyPresent,
+}
+
+/// Adds `[preprocessor.build-annotations]` to the book.toml in `dir`, with the defaults listed in comments. The rest of the file is left as-is. If the table's already there we leave it alone, unless `force` is set in which case it's replaced.
+pub fn install(dir: &Path, force: bool) -> Result<InstallOutcome> {
+    let book_toml = dir.join("book.toml");
+    let contents = std::fs::read_to_string(&book_toml)
+        .with_context(|| format!("Failed to read {}", book_toml.display()))?;
+    let mut doc: DocumentMut = contents
+        .parse()
+        .with_context(|| format!("Failed to parse {}", book_toml.display()))?;
+
+    let position = last_position(doc.as_table()) + 1;
+    let preprocessors = doc
+        .entry("preprocessor")
+        .or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        })
+        .as_table_mut()
+        .with_context(|| format!("preprocessor in {} isn't a table", book_toml.display()))?;
+
+    let outcome = match (preprocessors.contains_key(CONFIG_TABLE), force) {
+        (true, false) => return Ok(InstallOutcome::AlreadyPresent),
+        (true, true) => InstallOutcome::Replaced,
+        (false, _) => InstallOutcome::Added,
+    };
+    let mut table = default_table()?;
+    // put the table at the end of the file rather than next to whichever table came first
+    table.set_position(Some(position));
+    table.decor_mut().set_prefix("\n");
+    preprocessors.insert(CONFIG_TABLE, Item::Table(table));
+
+    std::fs::write(&book_toml, doc.to_string())
+        .with_context(|| format!("Failed to write {}", book_toml.display()))?;
+    Ok(outcome)
+}
+
+/// The highest document position of any table in `table`, so new tables can be written after it.
+fn last_position(table: &Table) -> isize {
+    table
+        .iter()
+        .filter_map(|(_, item)| item.as_table())
+        .map(|child| last_position(child).max(child.position().unwrap_or_default()))
+        .max()
+        .unwrap_or_default()
+}
+
+/// Our table, with `command` set and every option's default (taken from the config schema) commented out above it.
+fn default_table() -> Result<Table> {
+    let schema = Config::json_schema();
+    let mut snippet = format!("[preprocessor.{CONFIG_TABLE}]\n");
+    for key in Config::keys() {
+        let property = &schema["properties"][*key];
+        if let Some(description) = property["description"].as_str() {
+            for line in description.lines() {
+                snippet.push_str(&format!("# {line}\n"));
+            }
+        }
+        match toml::Value::try_from(&property["default"]) {
+            Ok(default) => snippet.push_str(&format!("# {key} = {default}\n")),
+            // unset options (and enums, which the schema doesn't give a default for) don't have a value to show
+            Err(_) => snippet.push_str(&format!("# {key} (unset by default)\n")),
+        }
+    }
+    snippet.push_str("command = \"mdbook-buildtime-annotations\"\n");
+
+    let mut doc: DocumentMut = snippet.parse()?;
+    doc.remove("preprocessor")
+        .and_then(|item| item.into_table().ok())
+        .and_then(|mut table| table.remove(CONFIG_TABLE))
+        .and_then(|item| item.into_table().ok())
+        .context("Failed to build the default config table")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOK_TOML: &str = "[book]\ntitle = \"Test Book\" # keep this comment\n\n[output.html]\nadditional-css = [\"footer.css\"]\n";
+
+    #[test]
+    fn test_install_is_idempotent() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let book_toml = dir.path().join("book.toml");
+        std::fs::write(&book_toml, BOOK_TOML).expect("Failed to write book.toml");
+
+        assert_eq!(
+            install(dir.path(), false).expect("install failed"),
+            InstallOutcome::Added
+        );
+        let installed = std::fs::read_to_string(&book_toml).expect("Failed to read book.toml");
+        assert!(installed.starts_with(BOOK_TOML));
+        assert!(installed.contains("[preprocessor.build-annotations]"));
+        assert!(installed.contains("# commit_characters (unset by default)\n"));
+        assert!(installed.contains("command = \"mdbook-buildtime-annotations\""));
+
+        // the result should be a config we can actually read
+        let ctx = crate::testing::context()
+            .root(dir.path())
+            .book_toml(installed.as_str())
+            .build();
+        Config::try_from(&ctx).expect("Failed to parse config");
+
+        assert_eq!(
+            install(dir.path(), false).expect("install failed"),
+            InstallOutcome::AlreadyPresent
+        );
+        assert_eq!(
+            std::fs::read_to_string(&book_toml).expect("Failed to read book.toml"),
+            installed
+        );
+    }
+
+    #[test]
+    fn test_install_force() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let book_toml = dir.path().join("book.toml");
+        std::fs::write(
+            &book_toml,
+            format!("{BOOK_TOML}\n[preprocessor.build-annotations]\ncommit_characters = 6\n"),
+        )
+        .expect("Failed to write book.toml");
+
+        assert_eq!(
+            install(dir.path(), true).expect("install failed"),
+            InstallOutcome::Replaced
+        );
+        let installed = std::fs::read_to_string(&book_toml).expect("Failed to read book.toml");
+        assert!(!installed.contains("commit_characters = 6"));
+        assert_eq!(
+            installed
+                .matches("[preprocessor.build-annotations]")
+                .count(),
+            1
+        );
+    }
+}
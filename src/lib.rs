@@ -1,18 +1,83 @@
-use mdbook_preprocessor::book::BookItem;
+use anyhow::Context;
+use mdbook_preprocessor::book::{BookItem, Chapter};
 use mdbook_preprocessor::errors::Error;
 use mdbook_preprocessor::errors::Result;
-use mdbook_preprocessor::parse_input;
+use mdbook_preprocessor::{MDBOOK_VERSION, parse_input};
 use mdbook_preprocessor::{Preprocessor, PreprocessorContext, book::Book};
-use serde::Deserialize;
-use std::path::PathBuf;
+use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use tracing::debug;
 use tracing::error;
+use tracing::info;
 use tracing::warn;
 
+pub mod annotate;
+pub mod build_info;
 pub mod cli;
+#[cfg(feature = "git")]
+mod codeowners;
+pub mod config;
+mod directive;
+mod dry_run;
+pub mod error;
+mod frontmatter;
+#[cfg(feature = "git")]
+pub mod git;
+#[cfg(feature = "git")]
+mod humanize;
+pub mod install;
+mod paths;
+pub mod provider;
+mod redact;
+pub mod report;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod timing;
+mod wildcard;
 
-pub struct Processor;
+pub use config::{Config, DateStyle, Format, HashCase, RequiredField, VersionCheck};
+
+pub struct Processor {
+    /// Config values set on the command line, which take precedence over everything else.
+    overrides: cli::ConfigOverrides,
+    /// Used by `run` instead of the book.toml when set, see [`Processor::with_config`].
+    config: Option<Config>,
+    /// Report what would change instead of changing it, see [`Processor::with_dry_run`].
+    dry_run: bool,
+    /// Where the annotation's fields come from, in the order they're added to the footer.
+    providers: Vec<Box<dyn provider::MetadataProvider>>,
+}
+
+impl Default for Processor {
+    fn default() -> Self {
+        Processor::with_overrides(cli::ConfigOverrides::default())
+    }
+}
+
+/// Wraps every annotation we inject, so we can find (and replace) our own output if the preprocessor runs more than once over the same content.
+const ANNOTATION_START: &str = "<!-- buildtime-annotations:start -->";
+const ANNOTATION_END: &str = "<!-- buildtime-annotations:end -->";
+/// Wraps the `prerelease_banner` at the top of a chapter, for the same reason.
+const BANNER_START: &str = "<!-- buildtime-annotations:banner-start -->";
+const BANNER_END: &str = "<!-- buildtime-annotations:banner-end -->";
+/// Wraps the `whats_new` section, for the same reason.
+const WHATS_NEW_START: &str = "<!-- buildtime-annotations:whats-new-start -->";
+const WHATS_NEW_END: &str = "<!-- buildtime-annotations:whats-new-end -->";
+/// Where the `whats_new` section goes in the first chapter, it's appended if this isn't there.
+const WHATS_NEW_MARKER: &str = "<!-- buildtime-whats-new -->";
+/// The key the `frontmatter` format puts the annotation under, which is replaced the same way.
+const FRONTMATTER_KEY: &str = "buildtime";
+/// The directive that tells mdbook-i18n-helpers' `mdbook-xgettext` to leave the next block out of the POT file, for `gettext_skip`.
+const GETTEXT_SKIP: &str = "<!-- i18n:skip -->";
+/// The path of the generated appendix chapter, which is fixed so the rendered URL doesn't change when the title does.
+const APPENDIX_PATH: &str = "buildtime-annotations.md";
+/// The path of the `history_appendix` chapter, for the same reason.
+const HISTORY_APPENDIX_PATH: &str = "buildtime-revision-history.md";
+/// The path of the `changelog_chapter`, for the same reason.
+const CHANGELOG_PATH: &str = "buildtime-changelog.md";
 
 #[derive(Deserialize)]
 /// Used for parsing a subset of Cargo.toml, to get the package name and version for annotation purposes. We support both the `[package]` and `[workspace]` tables, and prefer the former if both are present.
@@ -20,201 +85,6554 @@ struct CargoToml {
     #[serde(default)]
     package: Option<CargoPackage>,
     #[serde(default)]
-    workspace: Option<CargoPackage>,
+    workspace: Option<CargoWorkspace>,
 }
 
 impl CargoToml {
-    /// Falls back to worksapce name and version if package name and version are not found, which allows this to work in both workspace and non-workspace projects without any additional configuration.
-    pub fn package(&self) -> Option<&CargoPackage> {
-        self.package.as_ref().or(self.workspace.as_ref())
+    pub fn name(&self) -> Option<&str> {
+        match &self.package {
+            Some(package) => Some(package.name.as_str()),
+            None => self.workspace.as_ref()?.name.as_deref(),
+        }
     }
 
-    pub fn name(&self) -> Option<&str> {
-        self.package().map(|pkg| pkg.name.as_str())
+    /// The package's version, falling back to the workspace's if there's no package. A `version.workspace = true` is looked up in `root`, the workspace's Cargo.toml, or in this one if that's `None`.
+    pub fn version<'a>(&'a self, root: Option<&'a CargoToml>) -> Option<&'a str> {
+        let workspace = root.unwrap_or(self).workspace.as_ref();
+        let shared = || workspace?.package.as_ref()?.version.as_deref();
+        match &self.package {
+            Some(package) => match package.version.as_ref()? {
+                Inheritable::Value(version) => Some(version.as_str()),
+                Inheritable::Workspace { workspace: true } => shared(),
+                Inheritable::Workspace { workspace: false } => None,
+            },
+            None => self.workspace.as_ref()?.version.as_deref().or_else(shared),
+        }
+    }
+
+    /// Whether this is a virtual manifest, one with a `[workspace]` table and no `[package]`, so the name and version have to come from one of its members.
+    fn is_virtual(&self) -> bool {
+        self.package.is_none()
+            && self
+                .workspace
+                .as_ref()
+                .is_some_and(|workspace| workspace.name.is_none())
     }
 
-    pub fn version(&self) -> Option<&str> {
-        self.package().map(|pkg| pkg.version.as_str())
+    /// The workspace's members (relative to `dir`, where this Cargo.toml is), with `*` in the last part of a path matching any directory that has a Cargo.toml.
+    fn members(&self, dir: &Path) -> Vec<PathBuf> {
+        let mut members = Vec::new();
+        for member in self
+            .workspace
+            .iter()
+            .flat_map(|workspace| &workspace.members)
+        {
+            let member = Path::new(member);
+            let pattern = member.file_name().map(|name| name.to_string_lossy());
+            let Some((prefix, suffix)) = pattern.as_deref().and_then(|name| name.split_once('*'))
+            else {
+                members.push(member.to_path_buf());
+                continue;
+            };
+            let parent = member.parent().unwrap_or(Path::new(""));
+            let Ok(entries) = std::fs::read_dir(dir.join(parent)) else {
+                continue;
+            };
+            let mut found: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().join("Cargo.toml").is_file())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| {
+                    name.len() >= prefix.len() + suffix.len()
+                        && name.starts_with(prefix)
+                        && name.ends_with(suffix)
+                })
+                .map(|name| parent.join(name))
+                .collect();
+            found.sort();
+            members.extend(found);
+        }
+        members.dedup();
+        members
     }
 }
 
 #[derive(Deserialize)]
 struct CargoPackage {
     pub name: String,
-    pub version: String,
+    #[serde(default)]
+    pub version: Option<Inheritable>,
 }
 
-#[derive(Debug)]
-pub struct Config {
-    /// Defaults to 10 if unset, and is used to determine how many characters of the git commit hash to include in the annotation
-    pub commit_characters: usize,
-    /// Defaults to "../" if unset, and is used to determine where to look for the Cargo.toml and .git directories. This should typically be set to the root of the workspace, so that it works correctly in both workspace and non-workspace projects.
-    pub workspace_dir: PathBuf,
-    /// Defaults to "../" if unset, and is used to determine where to look for the .git directory. This should typically be set to the root of the workspace, so that it works correctly in both workspace and non-workspace projects.
-    pub git_dir: PathBuf,
-    /// Defaults to true if unset, and determines whether to include the package name in the annotation
-    pub package_name: bool,
-    /// Defaults to true if unset, and determines whether to include the package version in the annotation
-    pub package_version: bool,
-    /// Defaults to true if unset, and determines whether to include the git commit in the annotation
-    pub git_commit: bool,
-}
-
-impl TryFrom<&PreprocessorContext> for Config {
-    type Error = Error;
-
-    fn try_from(ctx: &PreprocessorContext) -> Result<Self> {
-        let cfg_key = |key| format!("preprocessor.{}.{}", "build-annotations", key);
-        Ok(Config {
-            commit_characters: ctx.config.get(&cfg_key("commit_characters"))?.unwrap_or(10),
-            workspace_dir: ctx
-                .config
-                .get(&cfg_key("workspace_dir"))?
-                .unwrap_or("../".into()),
-            git_dir: ctx.config.get(&cfg_key("git_dir"))?.unwrap_or("../".into()),
-            package_name: ctx.config.get(&cfg_key("package_name"))?.unwrap_or(true),
-            package_version: ctx.config.get(&cfg_key("package_version"))?.unwrap_or(true),
-            git_commit: ctx.config.get(&cfg_key("git_commit"))?.unwrap_or(true),
-        })
-    }
+/// A `[package]` field that can be set to `{ workspace = true }` to use the workspace's.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Inheritable {
+    Value(String),
+    Workspace { workspace: bool },
 }
 
-impl Processor {
-    /// does the actual work of modifying the book, by appending the given footer to the end of each chapter. This is called from `run` after we've determined the footer text, which includes the package name, version, and git commit.
-    fn handle_bookitem(&self, item: &mut BookItem, footer: &str) {
-        if let BookItem::Chapter(ref mut chapter) = *item {
-            chapter.content.push_str(footer);
-        }
-    }
+#[derive(Deserialize)]
+struct CargoWorkspace {
+    #[serde(default)]
+    members: Vec<String>,
+    /// The workspace's own name and version, which cargo doesn't have but we've long read.
+    name: Option<String>,
+    version: Option<String>,
+    /// `[workspace.package]`, which members can inherit from.
+    #[serde(default)]
+    package: Option<CargoWorkspacePackage>,
 }
 
-impl Preprocessor for Processor {
-    fn name(&self) -> &str {
-        "build-annotations"
+#[derive(Deserialize)]
+struct CargoWorkspacePackage {
+    version: Option<String>,
+}
+
+/// What [`read_package`] found.
+enum ManifestPackage {
+    /// The name and version from `manifest`, which is the workspace's Cargo.toml or the member's it points at.
+    Found {
+        manifest: PathBuf,
+        name: Option<String>,
+        version: Option<String>,
+    },
+    /// `manifest` is a virtual workspace manifest and `member_path` isn't set, so we can't tell which of its `members` to use.
+    Virtual {
+        manifest: PathBuf,
+        members: Vec<PathBuf>,
+    },
+}
+
+impl ManifestPackage {
+    /// Explains why a virtual manifest has no name or version to use.
+    fn virtual_message(manifest: &Path, members: &[PathBuf]) -> String {
+        let members = if members.is_empty() {
+            "no members".to_string()
+        } else {
+            format!("{} members", members.len())
+        };
+        format!(
+            "{} is a virtual workspace manifest with {members}, so it has no package name or version. Set member_path to the member (eg \"crates/core\") whose name and version should be in the annotation",
+            manifest.display()
+        )
     }
+}
 
-    fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let cfg = Config::try_from(ctx)?;
-        debug!("Config: {:?}", cfg);
+/// Everything we found out about the build, before it's rendered into a footer. [`Annotation::collect`] gathers it and [`Annotation::render`] turns it into text, so other tools can use either half (or serialize it) without going through mdbook.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Annotation {
+    /// The package (or workspace) name from the Cargo.toml.
+    pub name: Option<String>,
+    /// The package (or workspace) version from the Cargo.toml.
+    pub version: Option<String>,
+    /// The HEAD commit id, trimmed to `commit_characters`.
+    pub commit: Option<String>,
+    /// The branch HEAD points to, or if HEAD is detached the one the CI system says it's building, if any.
+    pub branch: Option<String>,
+    /// The committer date of the HEAD commit, as YYYY-MM-DD.
+    pub commit_date: Option<String>,
+    /// The full HEAD commit id.
+    pub commit_id: Option<String>,
+    /// The committer time of the HEAD commit, in RFC 3339 format.
+    pub commit_time: Option<String>,
+    /// Fields from other [`MetadataProvider`](provider::MetadataProvider)s, in the order they were provided.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extras: Vec<(String, String)>,
+    /// The second project from `code_workspace_dir` and `code_git_dir`, if either is set and anything was found there.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<CodeAnnotation>,
+}
+
+/// What was found out about the second project `code_workspace_dir` and `code_git_dir` point at, rendered as a segment of its own after the book's.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CodeAnnotation {
+    /// The package (or workspace) name from its Cargo.toml.
+    pub name: Option<String>,
+    /// The package (or workspace) version from its Cargo.toml.
+    pub version: Option<String>,
+    /// Its HEAD commit id, trimmed to `commit_characters`.
+    pub commit: Option<String>,
+    /// Its full HEAD commit id.
+    pub commit_id: Option<String>,
+}
+
+impl Annotation {
+    /// Reads the Cargo.toml and git repository the config points at, using the built-in providers. `book_root` is the book's directory, which relative paths are resolved against as described on [`Config`]. A missing Cargo.toml is an error, while anything we can't find in git is just left as `None`.
+    pub fn collect(cfg: &Config, book_root: &Path) -> Result<Self> {
+        Annotation::collect_from(&provider::default_providers(), cfg, book_root)
+    }
 
-        let cargo_file = std::fs::read_to_string(cfg.workspace_dir.join("Cargo.toml"))?;
-        let cargo_toml: CargoToml = toml::from_str(&cargo_file)?;
+    /// Gathers the fields from each provider in turn, sharing one [`provider::Context`] (and so one git repository) between them. Fields the built-in providers use go into their dedicated struct fields, everything else is kept in `extras`.
+    pub fn collect_from(
+        providers: &[Box<dyn provider::MetadataProvider>],
+        cfg: &Config,
+        book_root: &Path,
+    ) -> Result<Self> {
+        Annotation::collect_in(providers, &provider::Context::new(cfg, book_root))
+    }
 
-        let commit = determine_git_rev(&cfg.git_dir, cfg.commit_characters);
+    /// [`Annotation::collect_from`] with a context the caller keeps, so its git repository can be used again afterwards.
+    fn collect_in(
+        providers: &[Box<dyn provider::MetadataProvider>],
+        ctx: &provider::Context,
+    ) -> Result<Self> {
+        let mut annotation = Annotation::default();
+        for provider in providers {
+            for (key, value) in provider.fields(ctx)? {
+                let slot = match key.as_str() {
+                    provider::FIELD_NAME => &mut annotation.name,
+                    provider::FIELD_VERSION => &mut annotation.version,
+                    provider::FIELD_COMMIT => &mut annotation.commit,
+                    provider::FIELD_BRANCH => &mut annotation.branch,
+                    provider::FIELD_COMMIT_DATE => &mut annotation.commit_date,
+                    provider::FIELD_COMMIT_ID => &mut annotation.commit_id,
+                    provider::FIELD_COMMIT_TIME => &mut annotation.commit_time,
+                    provider::FIELD_CODE_NAME => &mut annotation.code.get_or_insert_default().name,
+                    provider::FIELD_CODE_VERSION => {
+                        &mut annotation.code.get_or_insert_default().version
+                    }
+                    provider::FIELD_CODE_COMMIT => {
+                        &mut annotation.code.get_or_insert_default().commit
+                    }
+                    provider::FIELD_CODE_COMMIT_ID => {
+                        &mut annotation.code.get_or_insert_default().commit_id
+                    }
+                    _ => {
+                        annotation.extras.push((key, value));
+                        continue;
+                    }
+                };
+                *slot = Some(value);
+            }
+        }
 
         debug!(
             "Package: {} v{} Git commit: {}",
-            cargo_toml.name().unwrap_or("unknown"),
-            cargo_toml.version().unwrap_or("unknown"),
-            commit.as_deref().unwrap_or("unknown")
+            annotation.name.as_deref().unwrap_or("unknown"),
+            annotation.version.as_deref().unwrap_or("unknown"),
+            annotation.commit.as_deref().unwrap_or("unknown")
         );
+        Ok(annotation)
+    }
+
+    /// The enabled fields joined into a single line, eg `mdbook-buildtime-annotations @0f6ad02d1c v0.0.1`, followed by the value of each of the `extras`. Empty if none of them could be found.
+    pub fn render(&self, cfg: &Config) -> String {
+        self.render_with(cfg, str::to_string, None)
+    }
+
+    /// Fails with the first field in `required` that wasn't found.
+    fn check_required(&self, cfg: &Config) -> error::Result<()> {
+        for field in &cfg.required {
+            let found = match field {
+                RequiredField::PackageName => self.name.is_some(),
+                RequiredField::PackageVersion => self.version.is_some(),
+                RequiredField::GitCommit => self.commit.is_some(),
+            };
+            if !found {
+                return Err(error::Error::RequiredMissing { field: field.key() });
+            }
+        }
+        Ok(())
+    }
 
+    /// [`Annotation::render`] with each field escaped by `escape`, and the version linked to `version_url` if there is one.
+    fn render_with(
+        &self,
+        cfg: &Config,
+        escape_text: impl Fn(&str) -> String,
+        version_url: Option<&str>,
+    ) -> String {
         let mut footer = String::new();
+        let escape = |field: &str, value: &str| {
+            let escaped = escape_text(value);
+            match max_length(cfg, field, value) {
+                Some(max) => truncate_markup(&escaped, max, &escape_text(&cfg.ellipsis)),
+                None => escaped,
+            }
+        };
+        if let Some(template) = &cfg.template {
+            return fill_template(template, &escape_text, |name| {
+                self.template_field(cfg, name)
+                    .map(|value| value.map(|value| escape(name, &value)).unwrap_or_default())
+            });
+        }
 
-        if cfg.package_name {
-            if let Some(name) = cargo_toml.name() {
-                footer.push_str(name);
+        if let Some(label) = &cfg.book_label {
+            footer.push_str(&escape(provider::FIELD_NAME, label));
+        } else if cfg.package_name {
+            if let Some(name) = &self.name {
+                footer.push_str(&escape(provider::FIELD_NAME, name));
             } else {
                 error!("Package name not found in Cargo.toml, skipping it in annotation");
             }
         }
         if cfg.git_commit {
-            if let Some(commit) = &commit {
+            if let Some(commit) = &self.commit {
                 if !footer.is_empty() {
                     footer.push(' ');
                 }
-                footer.push_str(&format!("@{}", commit));
+                footer.push_str(&escape(
+                    provider::FIELD_COMMIT,
+                    &display_commit(cfg, commit),
+                ));
             } else {
                 error!("Git commit not found, skipping it in annotation");
             }
         }
         if cfg.package_version {
-            if let Some(version) = cargo_toml.version() {
+            if let Some(version) = &self.version {
                 if !footer.is_empty() {
                     footer.push(' ');
                 }
-                footer.push_str(&format!("v{}", version));
+                let version = escape(provider::FIELD_VERSION, &format!("v{}", version));
+                match version_url {
+                    Some(url) => footer.push_str(&link(cfg, &version, url)),
+                    None => footer.push_str(&version),
+                }
             } else {
                 error!("Package version not found in Cargo.toml, skipping it in annotation");
             }
         }
+        for (field, value) in &self.extras {
+            if !footer.is_empty() {
+                footer.push(' ');
+            }
+            footer.push_str(&escape(field, value));
+        }
+        if let Some(code) = &self.code {
+            let segment = code.render_with(cfg, escape);
+            if !segment.is_empty() {
+                if !footer.is_empty() {
+                    footer.push_str(&escape_text(&cfg.segment_separator));
+                }
+                footer.push_str(&segment);
+            }
+        }
+        footer
+    }
+
+    /// The value of the field `name` for a `template`: `Some(None)` if it's a field that wasn't found, and `None` if there's no field by that name.
+    fn template_field(&self, cfg: &Config, name: &str) -> Option<Option<String>> {
+        let code = self.code.as_ref();
+        let value = match name {
+            provider::FIELD_NAME => self.name.clone(),
+            provider::FIELD_VERSION => self.version.clone(),
+            provider::FIELD_COMMIT => self
+                .commit
+                .as_deref()
+                .map(|commit| cfg.hash_case.apply(commit)),
+            provider::FIELD_COMMIT_ID => self.commit_id.clone(),
+            provider::FIELD_BRANCH => self.branch.clone(),
+            provider::FIELD_COMMIT_DATE => self.commit_date.clone(),
+            provider::FIELD_COMMIT_TIME => self.commit_time.clone(),
+            provider::FIELD_CODE_NAME => code.and_then(|code| code.name.clone()),
+            provider::FIELD_CODE_VERSION => code.and_then(|code| code.version.clone()),
+            provider::FIELD_CODE_COMMIT => code
+                .and_then(|code| code.commit.as_deref())
+                .map(|commit| cfg.hash_case.apply(commit)),
+            provider::FIELD_CODE_COMMIT_ID => code.and_then(|code| code.commit_id.clone()),
+            _ if name.starts_with(provider::FIELD_ENV_PREFIX) => {
+                let var = &name[provider::FIELD_ENV_PREFIX.len()..];
+                // they're per run, like GITHUB_RUN_ID, so a deterministic build leaves them empty
+                (!cfg.deterministic && cfg.allows_env(var))
+                    .then(|| std::env::var(var).ok())
+                    .flatten()
+            }
+            _ => {
+                let values: Vec<&str> = self
+                    .extras
+                    .iter()
+                    .filter(|(field, _)| field == name)
+                    .map(|(_, value)| value.as_str())
+                    .collect();
+                if !values.is_empty() {
+                    Some(values.join(" "))
+                } else if name.starts_with(provider::FIELD_TRAILER_PREFIX)
+                    || name == provider::FIELD_UNRELEASED
+                {
+                    None
+                } else {
+                    return None;
+                }
+            }
+        };
+        Some(value)
+    }
+
+    /// [`build_footer`], or `None` (with an error logged) if none of the fields could be found.
+    fn footer(&self, cfg: &Config) -> Option<String> {
+        if cfg.format == Format::Frontmatter {
+            return self.frontmatter(cfg);
+        }
+        self.footer_markup(cfg, None)
+            .map(|markup| wrap_markup(cfg, &markup))
+    }
+
+    /// The fields the built-in layout would show, as the entries of the `frontmatter` format's mapping (see [`frontmatter::entries`]), unescaped and never cut short. `None` (with an error logged) if none of them could be found.
+    fn frontmatter(&self, cfg: &Config) -> Option<String> {
+        let mut fields: Vec<(&str, String)> = Vec::new();
+        let name = cfg
+            .book_label
+            .as_ref()
+            .or(self.name.as_ref().filter(|_| cfg.package_name));
+        fields.extend(name.map(|name| (provider::FIELD_NAME, name.clone())));
+        if cfg.git_commit
+            && let Some(commit) = &self.commit
+        {
+            fields.push((provider::FIELD_COMMIT, cfg.hash_case.apply(commit)));
+        }
+        if cfg.package_version
+            && let Some(version) = &self.version
+        {
+            fields.push((provider::FIELD_VERSION, version.clone()));
+        }
+        // a key can only be in a mapping once
+        for (field, value) in &self.extras {
+            match fields.iter_mut().find(|(existing, _)| existing == field) {
+                Some((_, existing)) => {
+                    existing.push(' ');
+                    existing.push_str(value);
+                }
+                None => fields.push((field, value.clone())),
+            }
+        }
+        if let Some(code) = &self.code {
+            let name = cfg
+                .code_label
+                .as_ref()
+                .or(code.name.as_ref().filter(|_| cfg.package_name));
+            fields.extend(name.map(|name| (provider::FIELD_CODE_NAME, name.clone())));
+            if cfg.git_commit
+                && let Some(commit) = &code.commit
+            {
+                fields.push((provider::FIELD_CODE_COMMIT, cfg.hash_case.apply(commit)));
+            }
+            if cfg.package_version
+                && let Some(version) = &code.version
+            {
+                fields.push((provider::FIELD_CODE_VERSION, version.clone()));
+            }
+        }
+        if fields.is_empty() {
+            error!("No annotation data found, not adding frontmatter");
+            return None;
+        }
+        Some(frontmatter::entries(
+            fields.iter().map(|(field, value)| (*field, value.as_str())),
+        ))
+    }
 
-        if footer.is_empty() {
+    /// [`Annotation::render`] escaped for the footer, with the version linked to `version_url` if there is one. `None` (with an error logged) if none of the fields could be found.
+    fn footer_markup(&self, cfg: &Config, version_url: Option<&str>) -> Option<String> {
+        let markup = self.render_with(cfg, escape_html, version_url);
+        if markup.is_empty() {
             error!("No annotation data found, not adding footer");
-            return Ok(book);
+            return None;
+        }
+        Some(self.with_changelog(cfg, markup))
+    }
+
+    /// `markup` with a link to the `changelog_url` on the end, if there is one.
+    fn with_changelog(&self, cfg: &Config, mut markup: String) -> String {
+        if let Some(url) = changelog_url(cfg, self.version.as_deref()) {
+            if !markup.is_empty() {
+                markup.push(' ');
+            }
+            markup.push_str(&link(cfg, "Changelog", &url));
+        }
+        markup
+    }
+
+    /// This annotation and `cfg` with everything but `fields` (from a chapter's directive, see [`directive::FIELDS`]) taken out or turned off, for that chapter's footer. Fields can only be turned off, not on.
+    fn limit_fields(&self, cfg: &Config, fields: &[String]) -> (Config, Annotation) {
+        let has = |field: &str| fields.iter().any(|f| f == field);
+        let keep = |field: &str, value: &Option<String>| value.clone().filter(|_| has(field));
+        let mut cfg = cfg.clone();
+        cfg.package_name &= has("name");
+        if !has("name") {
+            cfg.book_label = None;
         }
-        footer = format!("<footer id=\"buildtime-annotations\">{footer}</footer>");
+        cfg.package_version &= has("version");
+        cfg.git_commit &= has("commit");
+        if !has("changelog") {
+            cfg.changelog_url = None;
+        }
+        cfg.chapter_dates &= has("date");
+        cfg.chapter_created &= has("created");
+        cfg.chapter_revisions &= has("revisions");
+        cfg.chapter_contributors &= has("contributors");
+        cfg.edit_link &= has("edit");
+        cfg.history_link &= has("history");
+        cfg.codeowners &= has("owners");
+        let annotation = Annotation {
+            name: keep("name", &self.name),
+            version: keep("version", &self.version),
+            commit: keep("commit", &self.commit),
+            branch: keep("branch", &self.branch),
+            commit_date: keep("commit_date", &self.commit_date),
+            commit_id: keep("commit", &self.commit_id),
+            commit_time: keep("commit_time", &self.commit_time),
+            extras: self
+                .extras
+                .iter()
+                .filter(|(field, _)| has(field))
+                .cloned()
+                .collect(),
+            code: self.code.clone().filter(|_| has("code")),
+        };
+        (cfg, annotation)
+    }
+}
 
-        book.for_each_mut(|item| self.handle_bookitem(item, &footer));
+impl CodeAnnotation {
+    /// The second project's segment of [`Annotation::render`], with each field escaped by `escape`: its `code_label` (or name), commit and version, in the same order as the book's.
+    fn render_with(&self, cfg: &Config, escape: impl Fn(&str, &str) -> String) -> String {
+        let label = cfg
+            .code_label
+            .as_ref()
+            .or(self.name.as_ref().filter(|_| cfg.package_name));
+        let commit = self
+            .commit
+            .as_deref()
+            .filter(|_| cfg.git_commit)
+            .map(|commit| display_commit(cfg, commit));
+        let version = self
+            .version
+            .as_ref()
+            .filter(|_| cfg.package_version)
+            .map(|version| format!("v{version}"));
+        [
+            label.map(|label| escape(provider::FIELD_CODE_NAME, label)),
+            commit.map(|commit| escape(provider::FIELD_CODE_COMMIT, &commit)),
+            version.map(|version| escape(provider::FIELD_CODE_VERSION, &version)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+}
 
-        Ok(book)
+/// `template` with each `{name}` in it replaced by `field(name)`, and the text around them passed through `text`. Braces `field` returns `None` for are kept, as text.
+fn fill_template(
+    template: &str,
+    text: impl Fn(&str) -> String,
+    field: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        filled.push_str(&text(&rest[..start]));
+        match field(&rest[start + 1..end]) {
+            Some(value) => filled.push_str(&value),
+            None => filled.push_str(&text(&rest[start..=end])),
+        }
+        rest = &rest[end + 1..];
     }
+    filled.push_str(&text(rest));
+    filled
+}
+
+/// Takes every chapter's `<!-- buildtime: ... -->` directive out of it, whether or not it's annotated, and returns them by the chapter's source path. Fails on the first one that can't be parsed. Fields a directive lists that aren't [`directive::FIELDS`] or one of `annotation`'s `extras` are warned about, since there's nothing to show for them.
+fn take_directives(
+    book: &mut Book,
+    annotation: &Annotation,
+) -> error::Result<std::collections::HashMap<PathBuf, directive::Directive>> {
+    let mut directives = std::collections::HashMap::new();
+    let mut result = Ok(());
+    book.for_each_chapter_mut(|chapter| {
+        if result.is_err() {
+            return;
+        }
+        let label = dry_run::chapter_label(chapter);
+        let directive = match directive::Directive::take(&mut chapter.content) {
+            Ok(Some(directive)) => directive,
+            Ok(None) => return,
+            Err(err) => {
+                result = Err(error::Error::Directive {
+                    chapter: label,
+                    argument: err.argument,
+                    reason: err.reason,
+                });
+                return;
+            }
+        };
+        for field in directive.fields.iter().flatten() {
+            if !directive::FIELDS.contains(&field.as_str())
+                && !annotation.extras.iter().any(|(name, _)| name == field)
+            {
+                warn!(
+                    "{label}: the buildtime directive lists the field {field}, which wasn't found, leaving it out"
+                );
+            }
+        }
+        match &chapter.source_path {
+            Some(path) => {
+                directives.insert(path.clone(), directive);
+            }
+            None => debug!("{label} has no source file, so its buildtime directive is ignored"),
+        }
+    });
+    result.map(|()| directives)
 }
 
-fn determine_git_rev(workspace_dir: &PathBuf, commit_characters: usize) -> Option<String> {
-    debug!(
-        "looking for git repository in {}",
-        workspace_dir.canonicalize().ok()?.display()
+/// Warns about each `{env.NAME}` in the `template` that `env_allowlist` doesn't allow, once per build rather than once per chapter. They're left empty.
+fn warn_blocked_env(cfg: &Config) {
+    let Some(template) = &cfg.template else {
+        return;
+    };
+    fill_template(
+        template,
+        |_| String::new(),
+        |name| {
+            if let Some(var) = name.strip_prefix(provider::FIELD_ENV_PREFIX)
+                && !cfg.allows_env(var)
+            {
+                warn!("The environment variable {var} isn't in env_allowlist, leaving it out");
+            }
+            None
+        },
     );
-    let Ok(repo) = gix::open(workspace_dir) else {
-        error!("Failed to open git repository, can't annotate it!");
+}
+
+/// `commit` as it's shown in the annotation, with the `hash_prefix` and in the `hash_case`.
+fn display_commit(cfg: &Config, commit: &str) -> String {
+    format!("{}{}", cfg.hash_prefix, cfg.hash_case.apply(commit))
+}
+
+/// The footer the preprocessor adds to each chapter: the fields [`Annotation::render`] joins together, wrapped up for the configured `format` (a `<footer id="buildtime-annotations">` element for `html`, a `<div class="buildtime-annotations">` for `epub`, a paragraph of its own for `markdown`).
+///
+/// `&`, `<`, `>`, `"` and `'` in the text are always replaced with HTML entities, whatever the format, so a field can't inject markup into the page. Nothing else is escaped, in particular Markdown emphasis characters are left as they are. The footer is built even if all the fields are missing, check `render` first if that matters.
+pub fn build_footer(cfg: &Config, annotation: &Annotation) -> String {
+    if cfg.format == Format::Frontmatter {
+        return annotation.frontmatter(cfg).unwrap_or_default();
+    }
+    wrap_footer(cfg, &annotation.render(cfg))
+}
+
+fn wrap_footer(cfg: &Config, text: &str) -> String {
+    wrap_markup(cfg, &escape_html(text))
+}
+
+/// [`wrap_footer`] for text that's already been escaped, and can have links in it.
+fn wrap_markup(cfg: &Config, markup: &str) -> String {
+    match cfg.format {
+        Format::Html => footer_element(markup),
+        Format::Markdown => format!("\n\n{markup}\n"),
+        Format::Epub => format!("<div class=\"buildtime-annotations\">{markup}</div>"),
+        // `--` can't be in a comment, and the escaped text can't have a `>` to end it early
+        Format::Comment => format!(
+            "<!-- buildtime-annotations: {} -->",
+            markup.replace("--", "&#45;&#45;")
+        ),
+        // built from the fields themselves, see `Annotation::frontmatter`
+        Format::Frontmatter => markup.to_string(),
+    }
+}
+
+fn html_footer(text: &str) -> String {
+    footer_element(&escape_html(text))
+}
+
+fn footer_element(markup: &str) -> String {
+    format!("<footer id=\"buildtime-annotations\">{markup}</footer>")
+}
+
+/// The most characters `field`'s `value` can have: its `field_max_lengths` entry, or `max_field_length` unless it's a hash or a URL, which would be useless cut short.
+fn max_length(cfg: &Config, field: &str, value: &str) -> Option<usize> {
+    if let Some(max) = cfg.field_max_lengths.get(field) {
+        return Some(*max);
+    }
+    if [
+        provider::FIELD_COMMIT,
+        provider::FIELD_COMMIT_ID,
+        provider::FIELD_CODE_COMMIT,
+        provider::FIELD_CODE_COMMIT_ID,
+    ]
+    .contains(&field)
+        || value.starts_with("https://")
+        || value.starts_with("http://")
+    {
+        return None;
+    }
+    cfg.max_field_length
+}
+
+/// `markup` cut to `max` characters with `ellipsis` on the end, if it's longer. An entity like `&amp;` counts as one character and is never cut in half.
+fn truncate_markup(markup: &str, max: usize, ellipsis: &str) -> String {
+    let mut rest = markup;
+    let mut kept = 0;
+    for _ in 0..max {
+        let Some(c) = rest.chars().next() else {
+            return markup.to_string();
+        };
+        let len = match rest.find(';') {
+            Some(end)
+                if c == '&'
+                    && end > 1
+                    && rest[1..end]
+                        .chars()
+                        .all(|c| c == '#' || c.is_ascii_alphanumeric()) =>
+            {
+                end + 1
+            }
+            _ => c.len_utf8(),
+        };
+        kept += len;
+        rest = &rest[len..];
+    }
+    if rest.is_empty() {
+        return markup.to_string();
+    }
+    format!("{}{ellipsis}", &markup[..kept])
+}
+
+/// A link to `url` around `markup` (which has already been escaped), in the configured `format`.
+fn link(cfg: &Config, markup: &str, url: &str) -> String {
+    match cfg.format {
+        Format::Html | Format::Epub | Format::Comment | Format::Frontmatter => {
+            format!("<a href=\"{}\">{markup}</a>", escape_html(url))
+        }
+        Format::Markdown => format!("[{markup}](<{url}>)"),
+    }
+}
+
+/// The `changelog_url` with the `changelog_anchor_template` for `version` on the end, if there's a version. `None` if it's unset or blank. Relative URLs are left as they are, for mdbook to resolve like any other link.
+fn changelog_url(cfg: &Config, version: Option<&str>) -> Option<String> {
+    let url = cfg.changelog_url.as_deref().map(str::trim)?;
+    if url.is_empty() {
         return None;
+    }
+    let anchor = cfg
+        .changelog_anchor_template
+        .as_deref()
+        .map(str::trim)
+        .filter(|anchor| !anchor.is_empty());
+    match (anchor, version) {
+        (Some(anchor), Some(version)) => {
+            Some(format!("{url}{}", anchor.replace("{version}", version)))
+        }
+        (Some(_), None) => {
+            debug!("No version for changelog_anchor_template, linking to the whole changelog");
+            Some(url.to_string())
+        }
+        (None, _) => Some(url.to_string()),
+    }
+}
+
+/// The `edit_url_template` or `history_url_template` for the chapter at `source_path` (relative to the book's `src` directory), with `{path}` and `{branch}` filled in and URL-encoded. `None` (and logged) if the template needs the branch and we don't know it.
+fn chapter_url(template: &str, branch: Option<&str>, source_path: &Path) -> Option<String> {
+    let mut url = template.replace("{path}", &encode_path(&paths::slash_path(source_path)));
+    if url.contains("{branch}") {
+        let Some(branch) = branch else {
+            debug!("No branch for {template}, leaving the link out");
+            return None;
+        };
+        url = url.replace("{branch}", &encode_path(branch));
+    }
+    Some(url)
+}
+
+/// Percent-encodes each component of a `/` or `\` separated path, and joins them back together with `/`.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for (index, component) in path.split(['/', '\\']).enumerate() {
+        if index > 0 {
+            encoded.push('/');
+        }
+        for byte in component.bytes() {
+            if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+                encoded.push(byte as char);
+            } else {
+                encoded.push_str(&format!("%{byte:02X}"));
+            }
+        }
+    }
+    encoded
+}
+
+/// Writes the annotation to `path` as an HTML fragment, for a theme to include. The file's only replaced if the fragment has changed, because `mdbook serve` rebuilds the book whenever something in the theme directory is touched.
+fn write_theme_partial(path: &Path, text: &str) -> Result<()> {
+    let fragment = format!("{}\n", html_footer(text));
+    if std::fs::read_to_string(path).is_ok_and(|existing| existing == fragment) {
+        debug!("{} is already up to date", path.display());
+        return Ok(());
+    }
+    let write = || {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        annotate::write_atomic(path, &fragment)
     };
+    write().with_context(|| format!("Failed to write the theme partial {}", path.display()))
+}
 
-    let mut head = repo.head().ok()?;
-    let commit = head.peel_to_commit().ok()?;
-    let mut commit_id = commit.id().to_string();
-    // Now we actually want to trim this to the first `commit_characters` chars
-    commit_id.truncate(commit_characters);
-    Some(commit_id)
+/// Replaces the characters that mean something in HTML with their entities.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
 }
 
-pub fn handle_preprocessing() -> Result<(), Error> {
-    let (ctx, book) = parse_input(std::io::stdin())?;
+/// `text` escaped for a cell of a Markdown table in one of the generated chapters: HTML escaped, and with any `|` escaped so it doesn't end the cell.
+fn table_cell(text: &str) -> String {
+    escape_html(text).replace('|', "\\|")
+}
 
-    if ctx.mdbook_version != mdbook_preprocessor::MDBOOK_VERSION {
-        warn!(
-            "Warning: The {} preprocessor was built against version \
-             {} of mdbook, but we're being called from version {}",
-            Processor.name(),
-            mdbook_preprocessor::MDBOOK_VERSION,
-            ctx.mdbook_version
-        );
+/// `url` as a Markdown autolink for a table cell, with the characters that would end the link or the cell percent-encoded.
+fn autolink(url: &str) -> String {
+    let mut encoded = String::with_capacity(url.len() + 2);
+    encoded.push('<');
+    for c in url.chars() {
+        match c {
+            '<' | '>' | '|' | '"' | ' ' => encoded.push_str(&format!("%{:02X}", c as u32)),
+            _ => encoded.push(c),
+        }
     }
+    encoded.push('>');
+    encoded
+}
 
-    let processed_book = Processor.run(&ctx, book)?;
-    serde_json::to_writer(std::io::stdout(), &processed_book)?;
+/// How one step of `check` went.
+#[derive(Debug, PartialEq, Eq)]
+enum CheckStatus {
+    Pass,
+    Fail,
+    Skipped,
+}
 
-    Ok(())
+/// One line of `check` output, naming the step and what it found (or the paths it tried).
+#[derive(Debug)]
+struct CheckStep {
+    name: &'static str,
+    status: CheckStatus,
+    detail: String,
 }
 
-pub fn handle_supports(proc: impl Preprocessor, renderer: &str) -> ! {
-    let supported = proc.supports_renderer(renderer);
+impl CheckStep {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        CheckStep {
+            name,
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+        }
+    }
 
-    // Signal whether the renderer is supported by exiting with 1 or 0.
-    if let Ok(true) = supported {
-        exit(0);
-    } else {
-        exit(1);
+    fn fail(name: &'static str, detail: impl std::fmt::Display) -> Self {
+        CheckStep {
+            name,
+            status: CheckStatus::Fail,
+            detail: format!("{detail:#}"),
+        }
+    }
+
+    fn skipped(name: &'static str, detail: impl Into<String>) -> Self {
+        CheckStep {
+            name,
+            status: CheckStatus::Skipped,
+            detail: detail.into(),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl std::fmt::Display for CheckStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self.status {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skipped => "skip",
+        };
+        write!(f, "[{status}] {}: {}", self.name, self.detail)
+    }
+}
 
-    #[test]
-    fn test_git_rev() {
-        let rev = determine_git_rev(&env!("CARGO_MANIFEST_DIR").into(), 10);
-        assert!(rev.is_some());
-        assert_eq!(rev.as_ref().unwrap().len(), 10);
+impl Processor {
+    /// A processor whose config is overridden by the given command line flags.
+    pub fn with_overrides(overrides: cli::ConfigOverrides) -> Self {
+        Processor {
+            overrides,
+            config: None,
+            dry_run: false,
+            providers: provider::default_providers(),
+        }
     }
 
-    #[test]
-    fn test_git_rev_too_long() {
-        let rev = determine_git_rev(&env!("CARGO_MANIFEST_DIR").into(), 100);
-        assert!(rev.is_some());
-        assert_eq!(rev.as_ref().unwrap().len(), 40);
+    /// A processor that always uses `cfg`, ignoring the book.toml (and any overrides), for driving it from code rather than from mdbook. Relative paths in it are still resolved against the book root mdbook passes in.
+    pub fn with_config(cfg: Config) -> Self {
+        Processor {
+            config: Some(cfg),
+            ..Processor::default()
+        }
+    }
+
+    /// Replaces the providers the annotation's fields are collected from, which are [`provider::default_providers`] unless this is called. Include those in `providers` to keep the package and git fields.
+    pub fn with_providers(mut self, providers: Vec<Box<dyn provider::MetadataProvider>>) -> Self {
+        self.providers = providers;
+        self
+    }
+
+    /// Collects the annotation from this processor's providers.
+    pub fn collect(&self, cfg: &Config, book_root: &Path) -> Result<Annotation> {
+        Annotation::collect_from(&self.providers, cfg, book_root)
+    }
+
+    /// When set, `run` describes each chapter it would change on stderr and hands the book back unmodified, and `annotate` prints the changes instead of writing the files.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Annotates the book using the given config, rather than reading it from a `PreprocessorContext`. Relative paths in the config are resolved as described on [`Config`], with `book_root` standing in for the book's directory.
+    pub fn run_with_config(&self, cfg: &Config, book_root: &Path, book: Book) -> Result<Book> {
+        self.run_in(cfg, book_root, &book_root.join("src"), book)
+    }
+
+    /// [`Processor::run_with_config`], with chapter source paths relative to `src_dir`.
+    fn run_in(&self, cfg: &Config, book_root: &Path, src_dir: &Path, book: Book) -> Result<Book> {
+        self.run_timed(cfg, book_root, src_dir, book, timing::Timings::new())
+    }
+
+    /// [`Processor::run_in`], adding each phase to `timings` and logging a summary of them (and how many chapters were annotated) at the end.
+    fn run_timed(
+        &self,
+        cfg: &Config,
+        book_root: &Path,
+        src_dir: &Path,
+        mut book: Book,
+        mut timings: timing::Timings,
+    ) -> Result<Book> {
+        let chapters = book
+            .iter()
+            .filter(|item| matches!(item, BookItem::Chapter(_)))
+            .count();
+        let mut outcome = report::Chapters::default();
+        let ctx = provider::Context::new(cfg, book_root);
+        let annotation =
+            timings.time("metadata", || Annotation::collect_in(&self.providers, &ctx))?;
+        if !cfg.annotates_branch(annotation.branch.as_deref()) {
+            match (&annotation.branch, cfg.branches_invert) {
+                (Some(branch), false) => info!(
+                    "The branch {branch} doesn't match any of {:?} in branches, not annotating",
+                    cfg.branches
+                ),
+                (Some(branch), true) => info!(
+                    "The branch {branch} matches {:?} in branches and branches_invert is set, not annotating",
+                    cfg.branches
+                ),
+                (None, _) => info!(
+                    "The branch couldn't be found to check it against branches, not annotating"
+                ),
+            }
+            return Ok(book);
+        }
+        let cfg = &*cfg.for_branch(annotation.branch.as_deref())?;
+        let cfg = &*cfg.for_determinism();
+        warn_blocked_env(cfg);
+        annotation.check_required(cfg)?;
+        let directives = take_directives(&mut book, &annotation)?;
+        if cfg.whats_new {
+            let section = timings.time("what's new", || whats_new(cfg, &ctx, src_dir));
+            add_whats_new(&mut book, section.as_deref());
+        }
+
+        let build_info = || match cfg.deterministic {
+            true => build_info::BuildInfo::reproducible(&annotation),
+            false => build_info::BuildInfo::new(&annotation),
+        };
+        if let Some(path) = &cfg.write_build_info {
+            self.write_output(cfg, &book_root.join(path), |path| build_info().write(path))?;
+        }
+        if let Some(path) = &cfg.write_js {
+            self.write_output(cfg, &book_root.join(path), |path| {
+                build_info().write_js(path)
+            })?;
+        }
+        if let Some(path) = &cfg.write_theme_partial {
+            self.write_output(cfg, &book_root.join(path), |path| {
+                write_theme_partial(path, &annotation.render(cfg))
+            })?;
+        }
+
+        let annotations = match cfg.skip_chapters {
+            true => Vec::new(),
+            false => cfg.annotation_configs()?,
+        };
+        #[cfg(not(feature = "git"))]
+        if annotations.iter().any(|cfg| cfg.chapter_history()) {
+            warn!(
+                "chapter_dates, chapter_revisions or chapter_contributors is enabled but git support was compiled out (the `git` feature), leaving them out"
+            );
+        }
+        let edit_templates: Vec<Option<&String>> = annotations
+            .iter()
+            .map(|cfg| match &cfg.edit_url_template {
+                Some(template) if cfg.edit_link => Some(template),
+                None if cfg.edit_link => {
+                    warn!("edit_link is enabled but edit_url_template isn't set, leaving the links out");
+                    None
+                }
+                _ => None,
+            })
+            .collect();
+
+        let owners = annotations
+            .iter()
+            .any(|cfg| cfg.codeowners)
+            .then(|| ChapterOwners::new(&ctx, src_dir))
+            .flatten();
+        let history_from_remote = annotations
+            .iter()
+            .any(|cfg| cfg.history_link && cfg.history_url_template.is_none());
+        #[cfg(feature = "git")]
+        if history_from_remote
+            && let Some(repo) = ctx.git()
+            && let Err(host) = forge(repo)
+        {
+            warn!(
+                "history_link is enabled but history_url_template isn't set, and we don't know where the history is on {host}, leaving the links out"
+            );
+        }
+        #[cfg(not(feature = "git"))]
+        if history_from_remote {
+            warn!(
+                "history_link is enabled but history_url_template isn't set and git support was compiled out (the `git` feature), leaving the links out"
+            );
+        }
+
+        // the chapters' histories are looked up once, for all of the annotations and the history appendix
+        let since = cfg.history_since_seconds()?;
+        let repos = timings.time("git history", || {
+            (annotations.iter().any(|cfg| cfg.chapter_history())
+                || edit_templates.iter().any(Option::is_some)
+                || annotations.iter().any(|cfg| cfg.history_link)
+                || cfg.history_appendix)
+                .then(|| ChapterRepos::new(&ctx, src_dir, &book))
+                .flatten()
+        });
+        let histories = timings.time("git history", || {
+            repos
+                .as_ref()
+                .and_then(|repos| ChapterHistories::new(cfg, &annotations, repos, since))
+        });
+
+        if cfg.skip_chapters {
+            debug!("skip_chapters is set, not annotating the chapters");
+        } else {
+            let banners: Vec<String> = [
+                dirty_banner(cfg, &ctx),
+                prerelease_banner(cfg, annotation.version.as_deref()),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            let banner = (!banners.is_empty()).then(|| banners.join("\n\n"));
+
+            for (index, (entry, edit_template)) in
+                annotations.iter().zip(edit_templates).enumerate()
+            {
+                let entry: &Config = entry;
+                let version_url = release_url(&ctx, entry, &annotation);
+                let text = match entry.format {
+                    Format::Frontmatter => annotation.frontmatter(entry),
+                    _ => annotation.footer_markup(entry, version_url.as_deref()),
+                };
+                let Some(text) = text else {
+                    continue;
+                };
+                if entry.format == Format::Frontmatter {
+                    let left_out: Vec<&str> = [
+                        (entry.template.is_some(), "template"),
+                        (entry.chapter_history(), "the per-chapter fields"),
+                        (edit_template.is_some(), "the edit links"),
+                        (entry.history_link, "the history links"),
+                        (entry.codeowners, "the owners"),
+                        (version_url.is_some(), "the release link"),
+                        (entry.changelog_url.is_some(), "the changelog link"),
+                    ]
+                    .into_iter()
+                    .filter_map(|(set, what)| set.then_some(what))
+                    .collect();
+                    if !left_out.is_empty() {
+                        warn!(
+                            "format = \"frontmatter\" only has the annotation's fields, leaving out {}",
+                            left_out.join(", ")
+                        );
+                    }
+                }
+                let branch = entry
+                    .edit_branch
+                    .as_deref()
+                    .or(annotation.branch.as_deref());
+                let footer_for = |source_path: Option<&Path>| {
+                    let directive = source_path.and_then(|path| directives.get(path));
+                    // a directive listing the fields to show gets a footer of its own
+                    let limited = directive
+                        .and_then(|directive| directive.fields.as_deref())
+                        .map(|fields| annotation.limit_fields(entry, fields));
+                    let position = directive.and_then(|directive| directive.position);
+                    if entry.format == Format::Frontmatter {
+                        let entries = match &limited {
+                            Some((cfg, annotation)) => {
+                                annotation.frontmatter(cfg).unwrap_or_default()
+                            }
+                            None => text.clone(),
+                        };
+                        return (entries, position);
+                    }
+                    let (cfg, mut markup) = match &limited {
+                        Some((cfg, annotation)) => (
+                            cfg,
+                            annotation.with_changelog(
+                                cfg,
+                                annotation.render_with(cfg, escape_html, version_url.as_deref()),
+                            ),
+                        ),
+                        None => (entry, text.clone()),
+                    };
+                    let mut push = |part: &str| {
+                        if !markup.is_empty() {
+                            markup.push(' ');
+                        }
+                        markup.push_str(part);
+                    };
+                    if let Some(path) = source_path {
+                        if let Some(details) = histories
+                            .as_ref()
+                            .and_then(|histories| histories.details(cfg, path))
+                        {
+                            push(&details);
+                        }
+                        if cfg.codeowners
+                            && let Some(owners) =
+                                owners.as_ref().and_then(|owners| owners.owners(cfg, path))
+                        {
+                            push(&owners);
+                        }
+                        let submodule_url = || {
+                            repos
+                                .as_ref()
+                                .and_then(|repos| repos.submodule_edit_url(path))
+                        };
+                        if let Some(url) =
+                            edit_template
+                                .filter(|_| cfg.edit_link)
+                                .and_then(|template| {
+                                    submodule_url()
+                                        .unwrap_or_else(|| chapter_url(template, branch, path))
+                                })
+                        {
+                            push(&link(cfg, "Edit this page", &url));
+                        }
+                        let template = cfg.history_url_template.as_deref();
+                        if cfg.history_link
+                            && let Some(url) = match &repos {
+                                Some(repos) => repos.history_url(path, branch, template),
+                                None => template
+                                    .and_then(|template| chapter_url(template, branch, path)),
+                            }
+                        {
+                            push(&link(cfg, &escape_html(&cfg.history_link_text), &url));
+                        }
+                    }
+                    (wrap_markup(cfg, &markup), position)
+                };
+                let mut found_parts = Vec::new();
+                let entry_outcome = timings.time("render", || {
+                    self.handle_items_with(
+                        &mut book.items,
+                        footer_for,
+                        &Markers::new(index),
+                        banner.as_deref(),
+                        entry,
+                        &mut found_parts,
+                    )
+                });
+                outcome.merge(entry_outcome);
+
+                for part in entry.parts.iter().flatten() {
+                    if !found_parts.contains(part) {
+                        warn!(
+                            "Configured part {:?} not found in the book, found parts: {:?}",
+                            part, found_parts
+                        );
+                    }
+                }
+            }
+        }
+
+        if cfg.title_suffix {
+            add_title_suffix(&mut book, cfg, &annotation);
+        }
+
+        if cfg.history_appendix {
+            match &histories {
+                Some(histories) => {
+                    let content = self.history_appendix(cfg, &book, histories);
+                    add_appendix(
+                        &mut book,
+                        HISTORY_APPENDIX_PATH,
+                        &cfg.history_appendix_title,
+                        content,
+                    );
+                }
+                None if cfg!(feature = "git") => warn!(
+                    "history_appendix is enabled but the chapters' git history couldn't be read, leaving it out"
+                ),
+                None => warn!(
+                    "history_appendix is enabled but git support was compiled out (the `git` feature), leaving it out"
+                ),
+            }
+        }
+
+        if cfg.changelog_chapter
+            && let Some(content) = timings.time("changelog", || changelog_chapter(cfg, &ctx))
+        {
+            add_appendix(
+                &mut book,
+                CHANGELOG_PATH,
+                &cfg.changelog_chapter_title,
+                content,
+            );
+        }
+
+        if cfg.appendix {
+            let commit = annotation
+                .commit
+                .as_deref()
+                .map(|commit| cfg.hash_case.apply(commit));
+            let code = annotation.code.clone().unwrap_or_default();
+            let code_commit = code
+                .commit
+                .as_deref()
+                .map(|commit| cfg.hash_case.apply(commit));
+            let tag = appendix_tag(&ctx);
+            let built_at = build_info().built_at;
+            // the run's page is different every time
+            let ci_url = match cfg.deterministic {
+                true => None,
+                false => provider::ci_build_url(|name| {
+                    cfg.env_var(name, |name| std::env::var(name).ok())
+                }),
+            };
+            let text = |value: Option<&str>| value.map(table_cell);
+            let content = appendix_content(
+                &cfg.appendix_title,
+                &[
+                    ("Package", text(annotation.name.as_deref())),
+                    ("Version", text(annotation.version.as_deref())),
+                    ("Commit", text(commit.as_deref())),
+                    ("Branch", text(annotation.branch.as_deref())),
+                    ("Tag", text(tag.as_deref())),
+                    ("Commit date", text(annotation.commit_date.as_deref())),
+                    ("Built at", text(built_at.as_deref())),
+                    ("CI build", ci_url.as_deref().map(autolink)),
+                    ("Code package", text(code.name.as_deref())),
+                    ("Code version", text(code.version.as_deref())),
+                    ("Code commit", text(code_commit.as_deref())),
+                ]
+                .into_iter()
+                .chain(
+                    annotation
+                        .extras
+                        .iter()
+                        .map(|(key, value)| (key.as_str(), Some(table_cell(value)))),
+                )
+                .collect::<Vec<_>>(),
+            );
+            add_appendix(&mut book, APPENDIX_PATH, &cfg.appendix_title, content);
+        }
+
+        let annotated = outcome.annotated.len();
+        info!("{}", timings.summary(annotated, chapters - annotated));
+        if let Some(path) = &cfg.report_file {
+            let path = book_root.join(path);
+            if self.dry_run {
+                debug!("Dry run, not writing {}", path.display());
+            } else if let Err(err) = report::Report::new(
+                cfg,
+                &annotation,
+                &outcome,
+                timings.elapsed(),
+                timings.phases(),
+            )
+            .write(&path)
+            {
+                warn!("{err:#}");
+            }
+        }
+        Ok(book)
+    }
+
+    /// Writes one of the files we generate alongside the book with `write`, unless this is a dry run. Failing to write it is only a warning, unless `strict` is set.
+    fn write_output(
+        &self,
+        cfg: &Config,
+        path: &Path,
+        write: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<()> {
+        if self.dry_run {
+            debug!("Dry run, not writing {}", path.display());
+            return Ok(());
+        }
+        if let Err(err) = write(path) {
+            if cfg.strict {
+                return Err(err);
+            }
+            warn!("{err:#}");
+        }
+        Ok(())
+    }
+
+    /// `supports` is only given the renderer name, so we have to find the book.toml in `root` ourselves. If there isn't one every renderer is supported, as that's the default.
+    fn supports_renderer_in(&self, root: &Path, renderer: &str) -> Result<bool> {
+        if !root.join("book.toml").exists() {
+            debug!(
+                "No book.toml found in {}, using the default renderers",
+                root.display()
+            );
+            return Ok(true);
+        }
+        Ok(self
+            .config_for_dir(root, renderer)?
+            .supports_renderer(renderer))
+    }
+
+    /// The renderers we'd annotate for the book in `root`: every renderer the book builds (mdbook builds `html` if there are no `[output]` tables) and any named in `renderers`, filtered by `supports_renderer_in` so the list always agrees with `supports <renderer>`.
+    fn supported_renderers(&self, root: &Path) -> Result<Vec<String>> {
+        let mut candidates = Vec::new();
+        let book_toml = root.join("book.toml");
+        if book_toml.exists() {
+            let config = load_book_toml(&book_toml)?;
+            candidates.extend(config.outputs::<toml::Value>()?.into_keys());
+            candidates.extend(
+                self.config_for_dir(root, "html")?
+                    .renderers
+                    .into_iter()
+                    .flatten(),
+            );
+        }
+        if candidates.is_empty() {
+            candidates.push("html".to_string());
+        }
+        candidates.sort();
+        candidates.dedup();
+
+        let mut supported = Vec::new();
+        for renderer in candidates {
+            if self.supports_renderer_in(root, &renderer)? {
+                supported.push(renderer);
+            }
+        }
+        Ok(supported)
+    }
+
+    /// Resolves the config for the book in `root`, for when we're not being run by mdbook and have to read the book.toml ourselves.
+    fn config_for_dir(&self, root: &Path, renderer: &str) -> error::Result<Config> {
+        Config::resolve(&context_for_dir(root, renderer)?, &self.overrides)
+    }
+
+    /// Loads the book in `book_root` from its book.toml and SUMMARY.md the way mdbook would, then runs the preprocessor on it for the html renderer and returns the result, without mdbook or writing anything but the files we're configured to write. No other preprocessors are run. When `book_root` has no book.toml the error is [`error::Error::NotABook`], anything else is a problem loading or annotating the book.
+    pub fn run_on_dir(&self, book_root: &Path) -> Result<Book> {
+        let ctx = context_for_dir(book_root, "html")?;
+        let book = dry_run::load_book(book_root, &ctx.config)?;
+        self.run(&ctx, book)
+    }
+
+    /// Like `config_for_dir`, except a missing book.toml just means the defaults (plus any overrides), for annotating files that aren't part of a book.
+    fn standalone_config(&self, root: &Path) -> error::Result<Config> {
+        if root.join("book.toml").exists() {
+            return self.config_for_dir(root, "html");
+        }
+        let ctx = PreprocessorContext::new(
+            root.to_path_buf(),
+            mdbook_preprocessor::config::Config::default(),
+            "html".to_string(),
+        );
+        Config::resolve(&ctx, &self.overrides)
+    }
+
+    /// Runs each step of resolving the annotation for the book in `root` in turn, using the same code as `run`, and reports what happened at each one. Steps that depend on a failed step, or on a field that's disabled, are skipped.
+    fn check(&self, root: &Path) -> Vec<CheckStep> {
+        let mut steps = Vec::new();
+        let cfg = match self.config_for_dir(root, "html") {
+            Ok(cfg) => {
+                steps.push(CheckStep::pass(
+                    "book.toml",
+                    format!("loaded {}", root.join("book.toml").display()),
+                ));
+                cfg
+            }
+            Err(err) => {
+                steps.push(CheckStep::fail("book.toml", anyhow::Error::from(err)));
+                return steps;
+            }
+        };
+
+        let ctx = provider::Context::new(&cfg, root);
+        if cfg.package_name || cfg.package_version {
+            steps.push(match read_package(ctx.workspace_path(), &cfg) {
+                Ok(ManifestPackage::Found {
+                    manifest,
+                    name: Some(name),
+                    version,
+                }) => CheckStep::pass(
+                    "Cargo.toml",
+                    format!(
+                        "read {name} v{} from {}",
+                        version.unwrap_or_default(),
+                        manifest.display()
+                    ),
+                ),
+                Ok(ManifestPackage::Found { manifest, .. }) => CheckStep::fail(
+                    "Cargo.toml",
+                    format!(
+                        "{} has no [package] or [workspace] name and version",
+                        manifest.display()
+                    ),
+                ),
+                Ok(ManifestPackage::Virtual { manifest, members }) => CheckStep::fail(
+                    "Cargo.toml",
+                    ManifestPackage::virtual_message(&manifest, &members),
+                ),
+                Err(err) => CheckStep::fail("Cargo.toml", anyhow::Error::from(err)),
+            });
+        } else {
+            steps.push(CheckStep::skipped(
+                "Cargo.toml",
+                "package_name and package_version are disabled",
+            ));
+        }
+
+        if !cfg.git_commit {
+            steps.push(CheckStep::skipped(
+                "git repository",
+                "git_commit is disabled",
+            ));
+            steps.push(CheckStep::skipped("HEAD", "git_commit is disabled"));
+            return steps;
+        }
+        if !cfg!(feature = "git") {
+            steps.push(CheckStep::skipped(
+                "git repository",
+                "git support was compiled out",
+            ));
+            steps.push(CheckStep::skipped("HEAD", "git support was compiled out"));
+            return steps;
+        }
+        #[cfg(feature = "git")]
+        self.check_git(&ctx, &mut steps);
+        steps
+    }
+
+    /// The git steps of `check`.
+    #[cfg(feature = "git")]
+    fn check_git(&self, ctx: &provider::Context, steps: &mut Vec<CheckStep>) {
+        let git_path = ctx.git_path();
+        match git::GitRepo::open(git_path) {
+            Ok(repo) => {
+                steps.push(CheckStep::pass(
+                    "git repository",
+                    format!("opened {}", git_path.display()),
+                ));
+                let commit_characters = ctx
+                    .cfg
+                    .commit_characters
+                    .unwrap_or_else(|| repo.abbrev_len());
+                steps.push(match repo.head_commit(commit_characters) {
+                    Ok(commit) => CheckStep::pass("HEAD", format!("resolved to {commit}")),
+                    Err(err) => CheckStep::fail("HEAD", anyhow::Error::from(err)),
+                });
+            }
+            Err(err) => {
+                steps.push(CheckStep::fail("git repository", anyhow::Error::from(err)));
+                steps.push(CheckStep::skipped(
+                    "HEAD",
+                    "there's no repository to read it from",
+                ));
+            }
+        }
+    }
+
+    /// Why `chapter` shouldn't get the footer, given the part it's in, or `None` if it should.
+    fn skip_reason(
+        &self,
+        chapter: &Chapter,
+        cfg: &Config,
+        part: Option<&str>,
+    ) -> Option<report::SkipReason> {
+        if let Some(parts) = &cfg.parts
+            && !part.is_some_and(|part| parts.iter().any(|p| p == part))
+        {
+            debug!(
+                "Skipping chapter outside the configured parts: {}",
+                chapter.name
+            );
+            return Some(report::SkipReason::Excluded);
+        }
+        if cfg.skip_empty && chapter.content.trim().is_empty() {
+            debug!("Skipping empty chapter: {}", chapter.name);
+            return Some(if chapter.is_draft_chapter() {
+                report::SkipReason::Draft
+            } else {
+                report::SkipReason::Empty
+            });
+        }
+        if let (Some(max_depth), Some(number)) = (cfg.max_depth, &chapter.number)
+            && number.len() > max_depth
+        {
+            debug!("Skipping chapter deeper than max_depth: {}", chapter.name);
+            return Some(report::SkipReason::Excluded);
+        }
+        if !cfg.annotates_chapter(cfg.chapter_path(chapter).as_deref()) {
+            debug!(
+                "Skipping chapter that doesn't match any of the configured chapters: {}",
+                chapter.name
+            );
+            return Some(report::SkipReason::Excluded);
+        }
+        None
+    }
+
+    /// The content of the `history_appendix` chapter: a table of every chapter that would be annotated (so not drafts, or chapters `parts`, `max_depth` or `chapters` leave out) with a link to it, when it was last changed and by whom, most recently changed first. Chapters no commit has changed go at the end, with `chapter_dates_fallback` as their date.
+    fn history_appendix(&self, cfg: &Config, book: &Book, histories: &ChapterHistories) -> String {
+        let mut rows = Vec::new();
+        let mut part = None;
+        for item in book.iter() {
+            let chapter = match item {
+                BookItem::PartTitle(title) => {
+                    part = Some(title.as_str());
+                    continue;
+                }
+                BookItem::Chapter(chapter) => chapter,
+                BookItem::Separator => continue,
+            };
+            let (Some(source_path), Some(path)) = (&chapter.source_path, &chapter.path) else {
+                continue;
+            };
+            if self.skip_reason(chapter, cfg, part).is_some() {
+                continue;
+            }
+            let (time, date, author) = histories
+                .last_change(cfg, source_path)
+                .map_or((None, None, None), |(time, date, author)| {
+                    (Some(time), Some(date), Some(author))
+                });
+            rows.push((time, &chapter.name, paths::slash_path(path), date, author));
+        }
+        // newest first, then the ones without a commit, each in book order
+        rows.sort_by_key(|(time, ..)| {
+            std::cmp::Reverse(time.map(|time| (1, time)).unwrap_or((0, 0)))
+        });
+
+        let mut content = format!(
+            "# {}\n\n| Chapter | Path | Last updated | By |\n|---|---|---|---|\n",
+            cfg.history_appendix_title
+        );
+        for (_, name, path, date, author) in rows {
+            let date = date.or_else(|| cfg.chapter_dates_fallback.clone());
+            content.push_str(&format!(
+                "| {} | [{}](<{path}>) | {} | {} |\n",
+                table_cell(name),
+                table_cell(&path),
+                table_cell(date.as_deref().unwrap_or_default()),
+                table_cell(author.as_deref().unwrap_or_default()),
+            ));
+        }
+        content
+    }
+
+    /// Adds the same footer to every chapter that should have it, see [`Processor::handle_items_with`].
+    #[cfg(test)]
+    fn handle_items(
+        &self,
+        items: &mut [BookItem],
+        footer: &str,
+        cfg: &Config,
+        found_parts: &mut Vec<String>,
+    ) {
+        self.handle_items_with(
+            items,
+            |_| (footer.to_string(), None),
+            &Markers::new(0),
+            None,
+            cfg,
+            found_parts,
+        );
+    }
+
+    /// does the actual work of modifying the book, by adding a footer to each chapter that should have it. This is called from `run` after we've determined the footer text, which includes the package name, version, and git commit. `footer_for` is given the chapter's source path and returns its footer, which is the same for every chapter unless `chapter_dates` or `chapter_contributors` is set or the chapter has a directive, and where the directive says it goes, if anywhere.
+    ///
+    /// Working out where the footer goes means scanning each chapter, so that's done for all of them up front (in parallel with the `parallel` feature) and the chapters are only changed once it's finished. The footer is wrapped in `markers`, and when they're the first annotation's the same chapters get `banner` at the top, if there is one.
+    fn handle_items_with(
+        &self,
+        items: &mut [BookItem],
+        footer_for: impl Fn(Option<&Path>) -> (String, Option<directive::Position>),
+        markers: &Markers,
+        banner: Option<&str>,
+        cfg: &Config,
+        found_parts: &mut Vec<String>,
+    ) -> report::Chapters {
+        let mut chapters = Vec::new();
+        let mut outcome = report::Chapters::default();
+        self.collect_contents(items, cfg, None, found_parts, &mut chapters, &mut outcome);
+        let (source_paths, contents): (Vec<_>, Vec<_>) = chapters.into_iter().unzip();
+        let footers: Vec<(String, Option<directive::Position>)> =
+            source_paths.into_iter().map(footer_for).collect();
+
+        let before_heading = cfg.before_heading();
+        // gettext_skip always appends, so no message moves
+        let position =
+            |position: Option<directive::Position>| position.filter(|_| !cfg.gettext_skip);
+        #[cfg(feature = "parallel")]
+        let insertions: Vec<Insertion> = {
+            use rayon::prelude::*;
+            contents
+                .par_iter()
+                .zip(footers.par_iter())
+                .map(|(content, (_, at))| {
+                    Insertion::find(content, before_heading, position(*at), markers)
+                })
+                .collect()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let insertions: Vec<Insertion> = contents
+            .iter()
+            .zip(&footers)
+            .map(|(content, (_, at))| {
+                Insertion::find(content, before_heading, position(*at), markers)
+            })
+            .collect();
+
+        for ((content, insertion), (footer, _)) in contents.into_iter().zip(insertions).zip(footers)
+        {
+            match cfg.format {
+                Format::Frontmatter => frontmatter::set(content, &markers.key, &footer),
+                _ => insertion.apply(content, &footer, cfg.gettext_skip, markers),
+            }
+            if markers.index == 0 {
+                apply_banner(content, banner);
+            }
+        }
+        outcome
+    }
+
+    /// Walks the book items depth-first, keeping track of which part (`BookItem::PartTitle`) each chapter belongs to, and pushes the source path and content of each chapter that should be annotated to `chapters`. Part titles only appear at the top level, so sub-chapters inherit the part of their parent. Every part title seen is pushed to `found_parts`, and every chapter to `outcome`'s annotated or skipped list.
+    fn collect_contents<'a>(
+        &self,
+        items: &'a mut [BookItem],
+        cfg: &Config,
+        mut part: Option<String>,
+        found_parts: &mut Vec<String>,
+        chapters: &mut Vec<(Option<&'a Path>, &'a mut String)>,
+        outcome: &mut report::Chapters,
+    ) {
+        for item in items {
+            match item {
+                BookItem::PartTitle(title) => {
+                    found_parts.push(title.clone());
+                    part = Some(title.clone());
+                }
+                BookItem::Chapter(chapter) => {
+                    let skip_reason = self.skip_reason(chapter, cfg, part.as_deref());
+                    let label = dry_run::chapter_label(chapter);
+                    let Chapter {
+                        content,
+                        sub_items,
+                        source_path,
+                        ..
+                    } = chapter;
+                    match skip_reason {
+                        None => outcome.annotated.push(label),
+                        Some(reason) => outcome.skipped.push(report::Skipped {
+                            chapter: label,
+                            reason,
+                        }),
+                    }
+                    self.collect_contents(
+                        sub_items,
+                        cfg,
+                        part.clone(),
+                        found_parts,
+                        chapters,
+                        outcome,
+                    );
+                    if skip_reason.is_none() {
+                        let source_path: &'a Option<PathBuf> = source_path;
+                        chapters.push((source_path.as_deref(), content));
+                    }
+                }
+                BookItem::Separator => {}
+            }
+        }
+    }
+}
+
+/// The comments around one of the annotations in a chapter: [`ANNOTATION_START`] and [`ANNOTATION_END`] for the first (or only) one, and the same with its number on the end for the rest of the `annotations`, so each of them can be found again. The `frontmatter` format uses the `key` instead.
+#[derive(Debug)]
+struct Markers {
+    /// Where the annotation is in the `annotations`, from 0.
+    index: usize,
+    start: String,
+    end: String,
+    /// The frontmatter key, [`FRONTMATTER_KEY`] with the number on the end for all but the first.
+    key: String,
+}
+
+impl Markers {
+    fn new(index: usize) -> Self {
+        match index {
+            0 => Markers {
+                index,
+                start: ANNOTATION_START.to_string(),
+                end: ANNOTATION_END.to_string(),
+                key: FRONTMATTER_KEY.to_string(),
+            },
+            _ => Markers {
+                index,
+                start: ANNOTATION_START.replace(":start", &format!(":start:{}", index + 1)),
+                end: ANNOTATION_END.replace(":end", &format!(":end:{}", index + 1)),
+                key: format!("{FRONTMATTER_KEY}_{}", index + 1),
+            },
+        }
+    }
+}
+
+/// Where an annotation goes in a chapter's content.
+#[derive(Debug, PartialEq, Eq)]
+enum Insertion {
+    /// Over the top of an annotation we've previously added, which is at this byte range.
+    Replace(std::ops::Range<usize>),
+    /// Above the heading starting at this byte offset.
+    Before(usize),
+    /// At the end.
+    Append,
+}
+
+impl Insertion {
+    /// Replaces an annotation we've previously added (between `markers`) in-place, otherwise puts it where the chapter's directive says (its `position`) if it has one, otherwise inserts it above `before_heading` if that heading exists, otherwise appends it.
+    fn find(
+        content: &str,
+        before_heading: Option<&str>,
+        position: Option<directive::Position>,
+        markers: &Markers,
+    ) -> Self {
+        if let Some(start) = content.find(&markers.start)
+            && let Some(end) = content[start..].find(&markers.end)
+        {
+            Insertion::Replace(start..start + end + markers.end.len())
+        } else if position == Some(directive::Position::Top) {
+            Insertion::Before(frontmatter::end(content))
+        } else if let Some(offset) = before_heading
+            .filter(|_| position.is_none())
+            .and_then(|heading| find_heading(content, heading))
+        {
+            Insertion::Before(offset)
+        } else {
+            Insertion::Append
+        }
+    }
+
+    /// Adds the annotation to `content`, which has to be the content this was found in. With `gettext_skip` the annotation is a block of its own after [`GETTEXT_SKIP`], and is only ever appended after a blank line, so no message in the chapter changes or moves.
+    fn apply(self, content: &mut String, annotation: &str, gettext_skip: bool, markers: &Markers) {
+        let Markers { start, end, .. } = markers;
+        let wrapped = if gettext_skip {
+            format!(
+                "{start}\n\n{GETTEXT_SKIP}\n{}\n\n{end}",
+                annotation.trim_matches('\n')
+            )
+        } else {
+            format!("{start}{annotation}{end}")
+        };
+        match self {
+            Insertion::Replace(range) => content.replace_range(range, &wrapped),
+            Insertion::Before(offset) => content.insert_str(offset, &format!("{wrapped}\n\n")),
+            Insertion::Append if gettext_skip => {
+                if !content.is_empty() {
+                    while !content.ends_with("\n\n") {
+                        content.push('\n');
+                    }
+                }
+                content.push_str(&wrapped);
+            }
+            Insertion::Append => content.push_str(&wrapped),
+        }
+    }
+}
+
+/// Puts `banner` at the top of `content`, over the top of one we've previously added if there is one. A previous banner is removed if there's no `banner` this time.
+fn apply_banner(content: &mut String, banner: Option<&str>) {
+    let wrapped = banner.map(|banner| format!("{BANNER_START}{banner}{BANNER_END}\n\n"));
+    if let Some(start) = content.find(BANNER_START)
+        && let Some(end) = content[start..].find(BANNER_END)
+    {
+        let mut end = start + end + BANNER_END.len();
+        end += content[end..].len() - content[end..].trim_start_matches('\n').len();
+        content.replace_range(start..end, wrapped.as_deref().unwrap_or_default());
+    } else if let Some(wrapped) = wrapped {
+        content.insert_str(frontmatter::end(content), &wrapped);
+    }
+}
+
+/// The `prerelease_banner` for the book's `version`, wrapped up for the `format`. `None` unless it's turned on and `version` is a semver version with a prerelease part, eg `2.0.0-alpha.3`.
+fn prerelease_banner(cfg: &Config, version: Option<&str>) -> Option<String> {
+    if !cfg.prerelease_banner {
+        return None;
+    }
+    let version = version?;
+    match semver::Version::parse(version) {
+        Ok(parsed) if !parsed.pre.is_empty() => {}
+        Ok(_) => {
+            debug!("{version} isn't a prerelease, leaving out the prerelease banner");
+            return None;
+        }
+        Err(err) => {
+            debug!("{version} isn't a semver version ({err}), leaving out the prerelease banner");
+            return None;
+        }
+    }
+    Some(banner_block(
+        cfg,
+        "buildtime-prerelease",
+        &cfg.prerelease_banner_template.replace("{version}", version),
+    ))
+}
+
+/// The `dirty_banner`, wrapped up for the `format`. `None` unless it's turned on and the working tree has uncommitted changes.
+#[cfg(feature = "git")]
+fn dirty_banner(cfg: &Config, ctx: &provider::Context) -> Option<String> {
+    if !cfg.dirty_banner || !ctx.git()?.is_dirty() {
+        return None;
+    }
+    warn!("The working tree has uncommitted changes, adding the dirty_banner to each chapter");
+    Some(banner_block(
+        cfg,
+        &cfg.dirty_banner_class,
+        &cfg.dirty_banner_text,
+    ))
+}
+
+/// The content of the `changelog_chapter`: a heading for the commits since the last tag and for each of the last `changelog_tags` tags (with the tagged commit's date), each listing its commits' subjects newest first, linked with `commit_url_template` if it's set. `None`, with a warning, if it's a shallow clone or there aren't any tags, as the releases can't be told apart.
+#[cfg(feature = "git")]
+fn changelog_chapter(cfg: &Config, ctx: &provider::Context) -> Option<String> {
+    let Some(repo) = ctx.git() else {
+        warn!("changelog_chapter is enabled but there's no git repository, leaving it out");
+        return None;
+    };
+    if repo.is_shallow() {
+        warn!(
+            "changelog_chapter is enabled but this is a shallow clone, leaving it out (fetch the full history and tags, eg with fetch-depth: 0)"
+        );
+        return None;
+    }
+    let Some(releases) = repo.releases(
+        cfg.changelog_tags,
+        &cfg.changelog_path_filter,
+        cfg.changelog_first_parent,
+    ) else {
+        warn!(
+            "changelog_chapter is enabled but HEAD's history doesn't have any tags, leaving it out"
+        );
+        return None;
+    };
+    let commit_characters = cfg.commit_characters.unwrap_or_else(|| repo.abbrev_len());
+
+    let mut content = format!("# {}\n", cfg.changelog_chapter_title);
+    for release in releases {
+        let heading = match (&release.tag, release.time) {
+            (None, _) => "Unreleased".to_string(),
+            (Some(tag), time) => match time.and_then(|time| format_date(time, &cfg.date_format)) {
+                Some(date) => format!("{tag} ({date})"),
+                None => tag.clone(),
+            },
+        };
+        content.push_str(&format!("\n## {}\n\n", escape_html(&heading)));
+        if release.commits.is_empty() {
+            content.push_str("No changes.\n");
+        }
+        for commit in release.commits {
+            let short = cfg
+                .hash_case
+                .apply(&commit.id.to_hex_with_len(commit_characters).to_string());
+            let id = match &cfg.commit_url_template {
+                Some(template) => format!(
+                    "[{short}](<{}>)",
+                    template.replace("{commit}", &commit.id.to_string())
+                ),
+                None => short,
+            };
+            content.push_str(&format!(
+                "- {} ({id})\n",
+                escape_html(&cfg.redact(&commit.subject))
+            ));
+        }
+    }
+    Some(content)
+}
+
+/// The `whats_new` section: `whats_new_title` and the subjects of the commits since the last tag that changed `whats_new_paths` (or the book's `src_dir`), newest first and at most `whats_new_max` of them, wrapped in markers so it can be replaced. `None` if there aren't any such commits, or (with a warning) if it's a shallow clone or there aren't any tags.
+#[cfg(feature = "git")]
+fn whats_new(cfg: &Config, ctx: &provider::Context, src_dir: &Path) -> Option<String> {
+    let Some(repo) = ctx.git() else {
+        warn!("whats_new is enabled but there's no git repository, leaving it out");
+        return None;
+    };
+    if repo.is_shallow() {
+        warn!(
+            "whats_new is enabled but this is a shallow clone, leaving it out (fetch the full history and tags, eg with fetch-depth: 0)"
+        );
+        return None;
+    }
+    let paths = match cfg.whats_new_paths.is_empty() {
+        true => {
+            let workdir = repo.workdir().and_then(|dir| paths::canonicalize(dir).ok());
+            let src = paths::canonicalize(src_dir).ok();
+            match workdir.zip(src) {
+                Some((workdir, src)) if src == workdir => Vec::new(),
+                Some((workdir, src)) if src.starts_with(&workdir) => vec![format!(
+                    "{}/*",
+                    paths::slash_path(src.strip_prefix(&workdir).ok()?)
+                )],
+                _ => {
+                    warn!(
+                        "The book's src directory isn't in the git repository, the whats_new section lists every commit"
+                    );
+                    Vec::new()
+                }
+            }
+        }
+        false => cfg.whats_new_paths.clone(),
+    };
+    // the release before is walked too, for the tag's name when there aren't any commits since it
+    let Some(releases) = repo.releases(1, &paths, false) else {
+        warn!("whats_new is enabled but HEAD's history doesn't have any tags, leaving it out");
+        return None;
+    };
+    let tag = releases.iter().find_map(|release| release.tag.as_deref())?;
+    let commits = releases
+        .first()
+        .filter(|release| release.tag.is_none())
+        .map(|release| &release.commits[..])
+        .unwrap_or_default();
+    if commits.is_empty() {
+        debug!("There aren't any commits since {tag} for the whats_new section, leaving it out");
+        return None;
+    }
+
+    let mut section = format!(
+        "{WHATS_NEW_START}\n\n## {}\n\n",
+        escape_html(&cfg.whats_new_title.replace("{tag}", tag))
+    );
+    for commit in commits.iter().take(cfg.whats_new_max) {
+        section.push_str(&format!(
+            "- {}\n",
+            escape_html(&cfg.redact(&commit.subject))
+        ));
+    }
+    if commits.len() > cfg.whats_new_max {
+        section.push_str(&format!(
+            "- …and {} more\n",
+            commits.len() - cfg.whats_new_max
+        ));
+    }
+    section.push_str(&format!("\n{WHATS_NEW_END}\n"));
+    Some(section)
+}
+
+#[cfg(not(feature = "git"))]
+fn whats_new(_cfg: &Config, _ctx: &provider::Context, _src_dir: &Path) -> Option<String> {
+    warn!(
+        "whats_new is enabled but git support was compiled out (the `git` feature), leaving it out"
+    );
+    None
+}
+
+/// Puts the `whats_new` `section` in the book's first chapter (the one mdbook makes the index page), after its [`WHATS_NEW_MARKER`] or at the end, replacing the one a previous run added. With no `section`, a previous run's is taken out.
+fn add_whats_new(book: &mut Book, section: Option<&str>) {
+    let mut first = true;
+    book.for_each_chapter_mut(|chapter| {
+        if !first || chapter.path.is_none() {
+            return;
+        }
+        first = false;
+        let content = &mut chapter.content;
+        if let Some(start) = content.find(WHATS_NEW_START)
+            && let Some(end) = content[start..].find(WHATS_NEW_END)
+        {
+            let end = start + end + WHATS_NEW_END.len();
+            let end = match content[end..].starts_with('\n') {
+                true => end + 1,
+                false => end,
+            };
+            match section {
+                Some(section) => content.replace_range(start..end, section),
+                // along with the blank line that was added before it
+                None => content.replace_range(start.saturating_sub(1)..end, ""),
+            }
+            return;
+        }
+        let Some(section) = section else {
+            return;
+        };
+        let at = content.find(WHATS_NEW_MARKER).and_then(|marker| {
+            content[marker..]
+                .find('\n')
+                .map(|newline| marker + newline + 1)
+        });
+        let at = at.unwrap_or_else(|| {
+            if !content.is_empty() && !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.len()
+        });
+        content.insert_str(at, &format!("\n{section}"));
+    });
+}
+
+#[cfg(not(feature = "git"))]
+fn changelog_chapter(_cfg: &Config, _ctx: &provider::Context) -> Option<String> {
+    warn!(
+        "changelog_chapter is enabled but git support was compiled out (the `git` feature), leaving it out"
+    );
+    None
+}
+
+#[cfg(not(feature = "git"))]
+fn dirty_banner(cfg: &Config, _ctx: &provider::Context) -> Option<String> {
+    if cfg.dirty_banner {
+        warn!(
+            "dirty_banner is enabled but git support was compiled out (the `git` feature), leaving it out"
+        );
+    }
+    None
+}
+
+/// A banner for the top of a chapter: `text` in a `<div>` with `class`, or a paragraph of its own for the `markdown` format. It's meant to be seen, so the `comment` and `frontmatter` formats get a `<div>` too.
+fn banner_block(cfg: &Config, class: &str, text: &str) -> String {
+    let text = escape_html(text);
+    match cfg.format {
+        Format::Html | Format::Epub | Format::Comment | Format::Frontmatter => {
+            format!("<div class=\"{}\">{text}</div>", escape_html(class))
+        }
+        Format::Markdown => text,
+    }
+}
+
+/// Which repository each chapter's file is in: the book's, or a submodule of it (or a repository nested in it) for chapters shared between books. Submodules are found by walking up from each chapter's file to the nearest `.git`, see [`git::Submodules`].
+#[cfg(feature = "git")]
+struct ChapterRepos<'a> {
+    repo: &'a git::GitRepo,
+    /// The working tree of each submodule a chapter is in, and its repository if it could be opened.
+    submodules: Vec<(PathBuf, Option<git::GitRepo>)>,
+    /// Each chapter's submodule (an index into `submodules`, `None` for the book's repository) and its path in that repository, by its source path.
+    chapters: std::collections::HashMap<PathBuf, (Option<usize>, PathBuf)>,
+}
+
+#[cfg(feature = "git")]
+impl<'a> ChapterRepos<'a> {
+    /// Finds the repository of every chapter in `book`, whose source paths are relative to `src_dir`. `None` if there's no repository, or it has no working tree. Chapters outside its working tree aren't in any.
+    fn new(ctx: &'a provider::Context, src_dir: &Path, book: &Book) -> Option<Self> {
+        let repo = ctx.git()?;
+        // the chapters' files might not exist yet, so only the directories are canonicalized
+        let workdir = paths::canonicalize(repo.workdir()?).ok()?;
+        let src_dir = paths::canonicalize(src_dir).ok()?;
+        let mut finder = git::Submodules::new(&workdir);
+        let mut repos = ChapterRepos {
+            repo,
+            submodules: Vec::new(),
+            chapters: std::collections::HashMap::new(),
+        };
+        for item in book.iter() {
+            let BookItem::Chapter(chapter) = item else {
+                continue;
+            };
+            let Some(source_path) = &chapter.source_path else {
+                continue;
+            };
+            let file = src_dir.join(source_path);
+            if repos.chapters.contains_key(source_path) || !file.starts_with(&workdir) {
+                continue;
+            }
+            let (index, root) = match finder.find(&file) {
+                Some(root) => (Some(repos.submodule(root.clone())), root),
+                None => (None, workdir.clone()),
+            };
+            if let Ok(repo_path) = file.strip_prefix(&root) {
+                repos
+                    .chapters
+                    .insert(source_path.clone(), (index, repo_path.to_path_buf()));
+            }
+        }
+        Some(repos)
+    }
+
+    /// The index of the submodule with the working tree `root`, opening it if it's the first chapter in it.
+    fn submodule(&mut self, root: PathBuf) -> usize {
+        if let Some(index) = self.submodules.iter().position(|(dir, _)| *dir == root) {
+            return index;
+        }
+        debug!(
+            "Reading the history of chapters in {} from its own repository",
+            root.display()
+        );
+        // GIT_DIR is the book's repository, not this one
+        let repo = match git::GitRepo::open_with_env(&root, &git::GitEnv::default()) {
+            Ok(repo) => Some(repo),
+            Err(err) => {
+                warn!(
+                    "{:#}, leaving out the history of the chapters in it",
+                    anyhow::Error::from(err)
+                );
+                None
+            }
+        };
+        self.submodules.push((root, repo));
+        self.submodules.len() - 1
+    }
+
+    /// The "Edit this page" URL for a chapter in a submodule, on the submodule's own forge and branch (`edit_url_template` is for the book's repository). `None` if it isn't in a submodule, `Some(None)` (and logged) if it is but we can't tell where to edit it.
+    fn submodule_edit_url(&self, source_path: &Path) -> Option<Option<String>> {
+        let (Some(index), path) = self.chapters.get(source_path)? else {
+            return None;
+        };
+        let (root, repo) = &self.submodules[*index];
+        let repo = repo.as_ref()?;
+        let Some(branch) = repo.default_branch() else {
+            debug!(
+                "Don't know which branch of {} to edit, leaving the edit link out",
+                root.display()
+            );
+            return Some(None);
+        };
+        let edit = match forge(repo) {
+            Ok((_, Forge::Gitea)) => "_edit",
+            _ => "edit",
+        };
+        let page = format!(
+            "{edit}/{}/{}",
+            encode_path(&branch),
+            encode_path(&paths::slash_path(path))
+        );
+        Some(forge_url(repo, &page))
+    }
+
+    /// The "View history" URL for the chapter at `source_path`: the `history_url_template` (with `branch`) if there is one, otherwise the forge's page for the file's commits, worked out from the remote. Chapters in a submodule always get the submodule's forge page, on its branch. `None` (and logged) if we can't tell where it is.
+    fn history_url(
+        &self,
+        source_path: &Path,
+        branch: Option<&str>,
+        template: Option<&str>,
+    ) -> Option<String> {
+        let from_template =
+            || template.and_then(|template| chapter_url(template, branch, source_path));
+        let Some((repo, path, submodule)) = self.get(source_path) else {
+            return from_template();
+        };
+        if !submodule && template.is_some() {
+            return from_template();
+        }
+        let branch = match submodule {
+            true => repo.default_branch(),
+            false => branch.map(str::to_string),
+        };
+        let Some(branch) = branch else {
+            debug!(
+                "Don't know which branch {} is on, leaving the history link out",
+                path.display()
+            );
+            return None;
+        };
+        let (branch, path) = (encode_path(&branch), encode_path(&paths::slash_path(path)));
+        match forge(repo) {
+            Ok((web, Forge::GitHub)) => Some(format!("{web}/commits/{branch}/{path}")),
+            Ok((web, Forge::GitLab)) => Some(format!("{web}/-/commits/{branch}/{path}")),
+            Ok((web, Forge::Gitea)) => Some(format!("{web}/commits/branch/{branch}/{path}")),
+            Err(host) => {
+                debug!("Don't know where the history of {path} is on {host}, leaving it unlinked");
+                None
+            }
+        }
+    }
+
+    /// The repository the chapter at `source_path` is in, its path there, and whether it's a submodule. `None` if it isn't in one, or its submodule couldn't be opened.
+    fn get(&self, source_path: &Path) -> Option<(&git::GitRepo, &Path, bool)> {
+        let (index, path) = self.chapters.get(source_path)?;
+        match index {
+            Some(index) => Some((self.submodules[*index].1.as_ref()?, path, true)),
+            None => Some((self.repo, path, false)),
+        }
+    }
+}
+
+/// The git history of each chapter in the book, which the per-chapter fields (`chapter_dates`, `chapter_created`, `chapter_revisions` and `chapter_contributors`) come from. It's looked up for every chapter in a repository at once, so each repository's history is only walked once however big the book is.
+#[cfg(feature = "git")]
+struct ChapterHistories<'a> {
+    repos: &'a ChapterRepos<'a>,
+    /// Each chapter's history, and whether `history_limit` or `history_since` stopped the walk of its repository early, by its source path.
+    histories: std::collections::HashMap<PathBuf, (Vec<git::FileCommit>, bool)>,
+    /// How many commits each chapter's history was looked up to.
+    limit: Option<usize>,
+}
+
+#[cfg(feature = "git")]
+impl<'a> ChapterHistories<'a> {
+    /// Looks up the history of every chapter in `repos` once, for all of the `annotations` (their configs are `annotations`) and `cfg`'s `history_appendix`, going no further back than `history_limit` commits or the `since` time. `None` if none of them have any of the per-chapter fields enabled and there's no history appendix.
+    fn new(
+        cfg: &Config,
+        annotations: &[impl std::ops::Deref<Target = Config>],
+        repos: &'a ChapterRepos<'a>,
+        since: Option<i64>,
+    ) -> Option<Self> {
+        if !annotations.iter().any(|cfg| cfg.chapter_history()) && !cfg.history_appendix {
+            return None;
+        }
+
+        // the date only needs the most recent commit, the revisions need one more than they count up to and the
+        // creation date and contributors need all of them
+        let limit = annotations
+            .iter()
+            .filter(|cfg| cfg.chapter_history())
+            .map(|cfg| {
+                if cfg.chapter_created || cfg.chapter_contributors {
+                    None
+                } else if cfg.chapter_revisions {
+                    Some(cfg.revisions_max.saturating_add(1))
+                } else {
+                    Some(1)
+                }
+            })
+            // the history appendix only needs the most recent commit
+            .chain(cfg.history_appendix.then_some(Some(1)))
+            .reduce(|a, b| a.zip(b).map(|(a, b)| a.max(b)))
+            .flatten();
+        let bounds = git::HistoryBounds {
+            max_commits: cfg.history_limit,
+            since,
+        };
+        let mut histories = std::collections::HashMap::new();
+        // the book's repository, then each submodule
+        for index in std::iter::once(None).chain((0..repos.submodules.len()).map(Some)) {
+            let repo = match index {
+                Some(index) => match &repos.submodules[index].1 {
+                    Some(repo) => repo,
+                    None => continue,
+                },
+                None => repos.repo,
+            };
+            let (source_paths, paths): (Vec<&PathBuf>, Vec<&Path>) = repos
+                .chapters
+                .iter()
+                .filter(|(_, (chapter_index, _))| *chapter_index == index)
+                .map(|(source_path, (_, path))| (source_path, path.as_path()))
+                .unzip();
+            if paths.is_empty() {
+                continue;
+            }
+            let (found, truncated) = repo.histories_within(&paths, limit, bounds);
+            if truncated {
+                debug!(
+                    "Stopped looking through the history at history_limit or history_since, chapters with older history are marked as approximate"
+                );
+            }
+            histories.extend(
+                source_paths
+                    .into_iter()
+                    .cloned()
+                    .zip(found.into_iter().map(|history| (history, truncated))),
+            );
+        }
+        Some(ChapterHistories {
+            repos,
+            histories,
+            limit,
+        })
+    }
+
+    /// The per-chapter fields for the chapter at `source_path`: "First published" and the author date of the commit that added it, "Last updated" and the date of the last commit that changed it (or `chapter_dates_fallback` if no commit has), linked to that commit if there's a `commit_url_template`, then "rev" and the number of commits that changed it, then "Contributors:" and its authors. They all come from the same history, so they always agree. When `history_limit` or `history_since` cut the chapter's history short they're marked as approximate: "or earlier" after the first published date, eg "rev 14+", "…" after the contributors and no `chapter_dates_fallback`. They're returned escaped, ready for [`wrap_markup`]. `None` if there's nothing to show.
+    fn details(&self, cfg: &Config, source_path: &Path) -> Option<String> {
+        let (repo, path, submodule) = self.repos.get(source_path)?;
+        let (history, truncated) = self.histories.get(source_path)?;
+        // the walk stopped before it got to the end of this chapter's history, so there might be older commits
+        let approximate = *truncated && self.limit.is_none_or(|limit| history.len() < limit);
+
+        let mut details = Vec::new();
+        if cfg.chapter_created
+            && let Some(first) = history.last()
+        {
+            if repo.is_shallow() {
+                debug!(
+                    "{} is in a shallow clone, its first published date is the oldest commit we have",
+                    path.display()
+                );
+            }
+            if let Some(mut date) =
+                chapter_date(cfg, first.author_time, "First published", "First published")
+            {
+                if approximate {
+                    date.push_str(" or earlier");
+                }
+                details.push(escape_html(&date));
+            }
+        }
+        if cfg.chapter_dates {
+            let date = match history.first() {
+                Some(commit) => {
+                    chapter_date(cfg, commit.time, "Last updated", "Updated").map(|date| {
+                        let date = escape_html(&date);
+                        // the template is for the book's repository, a submodule's commits are on its own forge
+                        let url = match &cfg.commit_url_template {
+                            Some(_) if submodule => {
+                                forge_url(repo, &format!("commit/{}", commit.id))
+                            }
+                            Some(template) => {
+                                Some(template.replace("{commit}", &commit.id.to_string()))
+                            }
+                            None => None,
+                        };
+                        match url {
+                            Some(url) => link(cfg, &date, &url),
+                            None => date,
+                        }
+                    })
+                }
+                // it was changed before the history we looked at, which isn't the same as never
+                None if approximate => None,
+                None => cfg.chapter_dates_fallback.as_deref().map(escape_html),
+            };
+            if let Some(mut date) = date {
+                if cfg.note_local_changes && repo.is_modified(path) {
+                    date.push_str(" (modified locally)");
+                }
+                details.push(date);
+            }
+        }
+        if cfg.chapter_revisions && !history.is_empty() {
+            if history.len() > cfg.revisions_max {
+                details.push(format!("rev {}+", cfg.revisions_max));
+            } else if approximate {
+                details.push(format!("rev {}+", history.len()));
+            } else {
+                details.push(format!("rev {}", history.len()));
+            }
+        }
+        if cfg.chapter_contributors {
+            let mut contributors: Vec<String> =
+                git::contributors(history, cfg.contributors_merges, &cfg.exclude_authors)
+                    .iter()
+                    .map(|name| cfg.redact(name))
+                    .filter(|name| !name.is_empty())
+                    .collect();
+            if let Some(max) = cfg.contributors_max {
+                contributors.truncate(max);
+            }
+            if approximate && !contributors.is_empty() {
+                contributors.push("…".to_string());
+            }
+            if !contributors.is_empty() {
+                details.push(escape_html(&format!(
+                    "Contributors: {}",
+                    contributors.join(", ")
+                )));
+            }
+        }
+        (!details.is_empty()).then(|| details.join(" "))
+    }
+
+    /// The last change to the chapter at `source_path` for the `history_appendix`: when it was committed (seconds since the epoch), that date in the `chapter_date_style`, and its author. `None` if no commit has changed it (in the history we looked at).
+    fn last_change(&self, cfg: &Config, source_path: &Path) -> Option<(i64, String, String)> {
+        let commit = self.histories.get(source_path)?.0.first()?;
+        let date = chapter_date(cfg, commit.time, "", "")?;
+        Some((
+            commit.time.seconds,
+            date.trim().to_string(),
+            cfg.redact(&commit.author),
+        ))
+    }
+}
+
+/// The owners of each chapter from the repository's CODEOWNERS file, for `codeowners`.
+#[cfg(feature = "git")]
+struct ChapterOwners {
+    owners: codeowners::CodeOwners,
+    /// Where the book's `src` directory is in the repository, as the rules are relative to the top of it.
+    src_dir: PathBuf,
+}
+
+#[cfg(feature = "git")]
+impl ChapterOwners {
+    /// Reads the CODEOWNERS file from the first of [`codeowners::LOCATIONS`] that has one. `None` (and logged) if there isn't a repository or a CODEOWNERS file, or `src_dir` isn't in the repository.
+    fn new(ctx: &provider::Context, src_dir: &Path) -> Option<Self> {
+        let Some(workdir) = ctx.git().and_then(|repo| repo.workdir()) else {
+            warn!("codeowners is enabled but there's no git repository, leaving the owners out");
+            return None;
+        };
+        let Some((location, text)) = codeowners::LOCATIONS.iter().find_map(|location| {
+            Some((
+                location,
+                std::fs::read_to_string(workdir.join(location)).ok()?,
+            ))
+        }) else {
+            warn!(
+                "codeowners is enabled but there's no CODEOWNERS file in .github/, the top of the repository or docs/, leaving the owners out"
+            );
+            return None;
+        };
+        debug!("Reading the chapters' owners from {location}");
+        let src_dir = paths::canonicalize(src_dir).ok()?;
+        let Some(relative) = paths::canonicalize(workdir)
+            .ok()
+            .and_then(|workdir| src_dir.strip_prefix(workdir).ok().map(Path::to_path_buf))
+        else {
+            warn!(
+                "codeowners is enabled but {} isn't in the git repository, leaving the owners out",
+                src_dir.display()
+            );
+            return None;
+        };
+        Some(ChapterOwners {
+            owners: codeowners::CodeOwners::parse(&text),
+            src_dir: relative,
+        })
+    }
+
+    /// "Owners:" and the owners of the chapter at `source_path`, escaped and with any email addresses redacted. `None` if no rule owns it.
+    fn owners(&self, cfg: &Config, source_path: &Path) -> Option<String> {
+        let path = paths::slash_path(&self.src_dir.join(source_path));
+        let owners: Vec<String> = self
+            .owners
+            .owners(&path)?
+            .iter()
+            .map(|owner| cfg.redact(owner))
+            .filter(|owner| !owner.is_empty())
+            .collect();
+        (!owners.is_empty()).then(|| escape_html(&format!("Owners: {}", owners.join(", "))))
+    }
+}
+
+/// Without the `git` feature there's no repository to read the CODEOWNERS file from.
+#[cfg(not(feature = "git"))]
+struct ChapterOwners;
+
+#[cfg(not(feature = "git"))]
+impl ChapterOwners {
+    fn new(_ctx: &provider::Context, _src_dir: &Path) -> Option<Self> {
+        warn!(
+            "codeowners is enabled but git support was compiled out (the `git` feature), leaving the owners out"
+        );
+        None
+    }
+
+    fn owners(&self, _cfg: &Config, _source_path: &Path) -> Option<String> {
+        None
+    }
+}
+
+/// Without the `git` feature there are no repositories to find.
+#[cfg(not(feature = "git"))]
+struct ChapterRepos;
+
+#[cfg(not(feature = "git"))]
+impl ChapterRepos {
+    fn new(_ctx: &provider::Context, _src_dir: &Path, _book: &Book) -> Option<Self> {
+        None
+    }
+
+    fn submodule_edit_url(&self, _source_path: &Path) -> Option<Option<String>> {
+        None
+    }
+
+    fn history_url(
+        &self,
+        source_path: &Path,
+        branch: Option<&str>,
+        template: Option<&str>,
+    ) -> Option<String> {
+        template.and_then(|template| chapter_url(template, branch, source_path))
+    }
+}
+
+/// Without the `git` feature there's no history to show.
+#[cfg(not(feature = "git"))]
+struct ChapterHistories;
+
+#[cfg(not(feature = "git"))]
+impl ChapterHistories {
+    fn new(
+        _cfg: &Config,
+        _annotations: &[impl std::ops::Deref<Target = Config>],
+        _repos: &ChapterRepos,
+        _since: Option<i64>,
+    ) -> Option<Self> {
+        None
+    }
+
+    fn details(&self, _cfg: &Config, _source_path: &Path) -> Option<String> {
+        None
+    }
+
+    fn last_change(&self, _cfg: &Config, _source_path: &Path) -> Option<(i64, String, String)> {
+        None
+    }
+}
+
+/// The release page for the annotation's version, if it's tagged (as eg `1.4.2` or `v1.4.2`) and `cfg`'s `release_link` is on. The page comes from `release_url_template`, or from the remote for repositories on GitHub, GitLab or Gitea.
+#[cfg(feature = "git")]
+fn release_url(ctx: &provider::Context, cfg: &Config, annotation: &Annotation) -> Option<String> {
+    if !cfg.release_link || !cfg.package_version {
+        return None;
+    }
+    let version = annotation.version.as_deref()?;
+    let repo = ctx.git()?;
+    let Some(tag) = [version.to_string(), format!("v{version}")]
+        .into_iter()
+        .find(|tag| repo.has_tag(tag))
+    else {
+        debug!("There's no tag for version {version}, leaving it unlinked");
+        return None;
+    };
+    let template = match &cfg.release_url_template {
+        Some(template) => template.clone(),
+        None => match forge(repo) {
+            Ok((web, Forge::GitHub | Forge::Gitea)) => format!("{web}/releases/tag/{{tag}}"),
+            Ok((web, Forge::GitLab)) => format!("{web}/-/releases/{{tag}}"),
+            Err(host) => {
+                debug!(
+                    "Don't know where releases are on {host}, set release_url_template to link the version"
+                );
+                return None;
+            }
+        },
+    };
+    Some(template.replace("{tag}", &encode_path(&tag)))
+}
+
+/// The forges we know the URLs of pages on.
+#[cfg(feature = "git")]
+enum Forge {
+    GitHub,
+    /// Which puts the repository's pages under `/-/`.
+    GitLab,
+    /// And Forgejo, which Codeberg runs.
+    Gitea,
+}
+
+/// The web page of the repository's remote (see [`git::GitRepo::remote_url`]) and the forge it's on. Otherwise the remote's host (or "the remote" if there isn't one), for saying where we don't know the URLs.
+#[cfg(feature = "git")]
+fn forge(repo: &git::GitRepo) -> std::result::Result<(String, Forge), String> {
+    let Some(web) = repo.remote_url().as_deref().and_then(git::web_url) else {
+        return Err("the remote".to_string());
+    };
+    let host = web
+        .trim_start_matches("https://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    if host == "github.com" {
+        Ok((web, Forge::GitHub))
+    } else if host.split('.').any(|part| part == "gitlab") {
+        Ok((web, Forge::GitLab))
+    } else if host == "codeberg.org"
+        || host
+            .split('.')
+            .any(|part| part == "gitea" || part == "forgejo")
+    {
+        Ok((web, Forge::Gitea))
+    } else {
+        Err(host.to_string())
+    }
+}
+
+/// The URL of `page` (eg `commit/<id>`) in the repository on its forge, for links to a submodule whose URLs aren't the ones the templates make. `None` (and logged) if it isn't on GitHub, GitLab or Gitea.
+#[cfg(feature = "git")]
+fn forge_url(repo: &git::GitRepo, page: &str) -> Option<String> {
+    match forge(repo) {
+        Ok((web, Forge::GitHub | Forge::Gitea)) => Some(format!("{web}/{page}")),
+        Ok((web, Forge::GitLab)) => Some(format!("{web}/-/{page}")),
+        Err(host) => {
+            debug!("Don't know where {page} is on {host}, leaving it unlinked");
+            None
+        }
+    }
+}
+
+/// Without the `git` feature there are no tags to link to.
+#[cfg(not(feature = "git"))]
+fn release_url(
+    _ctx: &provider::Context,
+    _cfg: &Config,
+    _annotation: &Annotation,
+) -> Option<String> {
+    None
+}
+
+/// `time` for the chapter's annotation in the configured `chapter_date_style`, after `absolute` (eg "Last updated 2024-05-12") or `relative` (eg "Updated 2 weeks ago").
+#[cfg(feature = "git")]
+fn chapter_date(
+    cfg: &Config,
+    time: gix::date::Time,
+    absolute: &str,
+    relative: &str,
+) -> Option<String> {
+    match cfg.chapter_date_style {
+        DateStyle::Absolute => {
+            format_date(time, &cfg.date_format).map(|date| format!("{absolute} {date}"))
+        }
+        DateStyle::Relative => {
+            let now = build_info::build_time()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() as i64);
+            Some(format!("{relative} {}", humanize::ago(now - time.seconds)))
+        }
+    }
+}
+
+/// Formats `time` in its own timezone with the strftime `format`, `None` (with a warning) if the format's invalid.
+#[cfg(feature = "git")]
+fn format_date(time: gix::date::Time, format: &str) -> Option<String> {
+    use std::fmt::Write;
+    let mut formatted = String::new();
+    let zoned = time.to_zoned().ok()?;
+    if write!(formatted, "{}", zoned.strftime(format)).is_err() {
+        warn!("Can't format chapter dates with date_format {format:?}");
+        return None;
+    }
+    Some(formatted)
+}
+
+/// Appends the annotation to the content (or inserts it above `insert_before_heading` if that heading exists), or replaces an annotation we've previously added in-place. For the `frontmatter` format it's set in the chapter's frontmatter instead.
+fn insert_annotation(content: &mut String, annotation: &str, cfg: &Config) {
+    let markers = Markers::new(0);
+    if cfg.format == Format::Frontmatter {
+        frontmatter::set(content, &markers.key, annotation);
+        return;
+    }
+    Insertion::find(content, cfg.before_heading(), None, &markers).apply(
+        content,
+        annotation,
+        cfg.gettext_skip,
+        &markers,
+    );
+}
+
+/// Finds the byte offset of the first ATX heading (`## Like this`) whose text is exactly `text`. We lean on pulldown-cmark for this so headings in code blocks are ignored.
+fn find_heading(content: &str, text: &str) -> Option<usize> {
+    let mut current: Option<(usize, String)> = None;
+    for (event, range) in Parser::new(content).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { .. }) if content[range.start..].starts_with('#') => {
+                current = Some((range.start, String::new()));
+            }
+            Event::Text(value) | Event::Code(value) => {
+                if let Some((_, heading)) = current.as_mut() {
+                    heading.push_str(&value);
+                }
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                if let Some((start, heading)) = current.take()
+                    && heading.trim() == text
+                {
+                    return Some(start);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The tag HEAD is at for the `appendix`, or the closest one before it and how many commits HEAD is ahead of it.
+#[cfg(feature = "git")]
+fn appendix_tag(ctx: &provider::Context) -> Option<String> {
+    Some(match ctx.git()?.nearest_tag()? {
+        (tag, 0) => tag,
+        (tag, 1) => format!("{tag} (1 commit since)"),
+        (tag, count) => format!("{tag} ({count} commits since)"),
+    })
+}
+
+#[cfg(not(feature = "git"))]
+fn appendix_tag(_ctx: &provider::Context) -> Option<String> {
+    None
+}
+
+/// Builds the content of the appendix chapter, a table of every piece of build information we could find. The labels are escaped, but the values have to be ready for the table already (see [`table_cell`]), so they can have links in them.
+fn appendix_content(title: &str, fields: &[(&str, Option<String>)]) -> String {
+    let mut content = format!("# {title}\n\n| | |\n|---|---|\n");
+    for (label, value) in fields {
+        if let Some(value) = value {
+            content.push_str(&format!("| {} | {value} |\n", table_cell(label)));
+        }
+    }
+    content
+}
+
+/// Appends `title_suffix_template`, filled in from `annotation`, to the names of the chapters `title_suffix` applies to, unless a name already ends with exactly that suffix (from a previous run over the same book). Nothing else is taken off, as a title that only looks like the template could be one someone wrote. The suffix is left off if any of its fields can't be found.
+fn add_title_suffix(book: &mut Book, cfg: &Config, annotation: &Annotation) {
+    let missing = std::cell::RefCell::new(Vec::new());
+    let suffix = fill_template(&cfg.title_suffix_template, str::to_string, |name| {
+        let value = annotation.template_field(cfg, name)?;
+        if value.is_none() {
+            missing.borrow_mut().push(name.to_string());
+        }
+        Some(value.unwrap_or_default())
+    });
+    let missing = missing.into_inner();
+    if !missing.is_empty() {
+        warn!(
+            "{} couldn't be found, leaving the title suffix out",
+            missing.join(", ")
+        );
+        return;
+    }
+    let mut first = true;
+    book.for_each_chapter_mut(|chapter| {
+        let applies = match cfg.title_suffix_chapters.is_empty() {
+            true => first,
+            false => cfg.chapter_path(chapter).is_some_and(|path| {
+                let path = paths::slash_path(&path);
+                cfg.title_suffix_chapters
+                    .iter()
+                    .any(|pattern| wildcard::matches(pattern, &path))
+            }),
+        };
+        first = false;
+        if applies && !chapter.name.ends_with(&suffix) {
+            chapter.name.push_str(&suffix);
+        }
+    });
+}
+
+/// Adds the appendix chapter at `path` to the end of the book, or replaces its content if a previous run already added it.
+fn add_appendix(book: &mut Book, path: &str, title: &str, content: String) {
+    let existing = book.items.iter_mut().find_map(|item| match item {
+        BookItem::Chapter(chapter) if chapter.path.as_deref() == Some(path.as_ref()) => {
+            Some(chapter)
+        }
+        _ => None,
+    });
+    if let Some(chapter) = existing {
+        chapter.name = title.to_string();
+        chapter.content = content;
+    } else {
+        let mut chapter = Chapter::new(title, content, path, vec![]);
+        // there's no file on disk for this one
+        chapter.source_path = None;
+        book.push_item(chapter);
+    }
+}
+
+impl Preprocessor for Processor {
+    fn name(&self) -> &str {
+        "build-annotations"
+    }
+
+    fn supports_renderer(&self, renderer: &str) -> Result<bool> {
+        // mdbook runs `supports` from the book root
+        self.supports_renderer_in(&std::env::current_dir()?, renderer)
+    }
+
+    fn run(&self, ctx: &PreprocessorContext, book: Book) -> Result<Book> {
+        let mut timings = timing::Timings::new();
+        let cfg = timings.time("config", || match &self.config {
+            Some(cfg) => Ok(cfg.clone()),
+            None => Config::resolve(ctx, &self.overrides),
+        })?;
+        debug!("Config: {:?}", cfg);
+        if version_mismatch(cfg.version_check, MDBOOK_VERSION, &ctx.mdbook_version) {
+            warn!(
+                "The {} preprocessor was built against version \
+                 {MDBOOK_VERSION} of mdbook, but we're being called from version {}",
+                self.name(),
+                ctx.mdbook_version
+            );
+        }
+
+        if cfg.skips_renderer(&ctx.renderer) {
+            debug!(
+                "The {} renderer is in the skip_renderers list, not annotating",
+                ctx.renderer
+            );
+            return Ok(book);
+        }
+        if !cfg.supports_renderer(&ctx.renderer) {
+            debug!(
+                "The {} renderer isn't in the renderers list, not annotating",
+                ctx.renderer
+            );
+            return Ok(book);
+        }
+
+        let src_dir = ctx.root.join(&ctx.config.book.src);
+        if self.dry_run {
+            let processed = self.run_timed(&cfg, &ctx.root, &src_dir, book.clone(), timings)?;
+            for line in dry_run::report(&book, &processed) {
+                eprintln!("{line}");
+            }
+            return Ok(book);
+        }
+
+        self.run_timed(&cfg, &ctx.root, &src_dir, book, timings)
+    }
+}
+
+/// The context mdbook would pass us for the book in `root`, from its book.toml.
+fn context_for_dir(root: &Path, renderer: &str) -> error::Result<PreprocessorContext> {
+    let book_toml = root.join("book.toml");
+    if !book_toml.is_file() {
+        return Err(error::Error::NotABook {
+            path: root.to_path_buf(),
+        });
+    }
+    let config = load_book_toml(&book_toml)?;
+    Ok(PreprocessorContext::new(
+        root.to_path_buf(),
+        config,
+        renderer.to_string(),
+    ))
+}
+
+/// Whether the mdbook we were `built` against and the one that's `running` us are different enough to warn about. With `VersionCheck::Compatible` that's when they aren't semver-compatible: a different major version, or a different minor version before 1.0. Versions that don't parse are compared as strings.
+fn version_mismatch(check: VersionCheck, built: &str, running: &str) -> bool {
+    match check {
+        VersionCheck::Off => false,
+        VersionCheck::Strict => built != running,
+        VersionCheck::Compatible => {
+            match (
+                semver::Version::parse(built),
+                semver::Version::parse(running),
+            ) {
+                (Ok(built), Ok(running)) => {
+                    built.major != running.major
+                        || (built.major == 0 && built.minor != running.minor)
+                }
+                _ => built != running,
+            }
+        }
+    }
+}
+
+/// `path` made absolute, with its directory canonicalized if it exists, so an error can say exactly where we looked for a file that isn't there.
+fn resolve_missing(path: &Path) -> PathBuf {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    match (
+        dir.map_or(std::env::current_dir(), paths::canonicalize),
+        path.file_name(),
+    ) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+/// Reads the package name and version from the Cargo.toml in `dir`. If it's a virtual workspace manifest they come from the `member_path` member, or from the only member if there's just one.
+fn read_package(dir: &Path, cfg: &Config) -> error::Result<ManifestPackage> {
+    let manifest = dir.join("Cargo.toml");
+    let root = read_manifest(&manifest, cfg)?;
+    if !root.is_virtual() {
+        return Ok(ManifestPackage::Found {
+            name: root.name().map(str::to_string),
+            version: root.version(None).map(str::to_string),
+            manifest,
+        });
+    }
+    let member = match &cfg.member_path {
+        Some(member) => member.clone(),
+        None => match root.members(dir).as_slice() {
+            [member] => {
+                debug!(
+                    "{} is a virtual workspace manifest, using its only member {}",
+                    manifest.display(),
+                    member.display()
+                );
+                member.clone()
+            }
+            members => {
+                return Ok(ManifestPackage::Virtual {
+                    manifest,
+                    members: members.to_vec(),
+                });
+            }
+        },
+    };
+    let member_manifest = dir.join(member).join("Cargo.toml");
+    let member_toml = read_manifest(&member_manifest, cfg)?;
+    Ok(ManifestPackage::Found {
+        name: member_toml.name().map(str::to_string),
+        version: member_toml.version(Some(&root)).map(str::to_string),
+        manifest: member_manifest,
+    })
+}
+
+/// Reads the Cargo.toml, explaining where we looked if it isn't there. One that isn't valid UTF-8 is read with the invalid bytes replaced, and is only an error if they're in a field we need.
+fn read_manifest(path: &Path, cfg: &Config) -> error::Result<CargoToml> {
+    let (cargo_file, invalid_at) = read_lossy(path).map_err(|source| {
+        let setting = [
+            ("workspace_dir", &cfg.workspace_dir),
+            ("root_dir", &cfg.root_dir),
+        ]
+        .into_iter()
+        .find_map(|(key, dir)| Some((key.to_string(), dir.clone()?)));
+        error::Error::ManifestNotFound {
+            path: resolve_missing(path),
+            relative_to_cwd: !cfg.resolve_from_book_root
+                && setting.as_ref().is_some_and(|(_, dir)| dir.is_relative()),
+            setting,
+            source,
+        }
+    })?;
+    let manifest: CargoToml =
+        toml::from_str(&cargo_file).map_err(|source| error::Error::ManifestParse {
+            path: path.to_path_buf(),
+            location: error::TomlLocation::find(&cargo_file, &source),
+            source: Box::new(source),
+        })?;
+    if let Some(offset) = invalid_at {
+        let damaged = |value: Option<&str>| value.is_some_and(|value| value.contains('\u{FFFD}'));
+        let members = manifest
+            .workspace
+            .iter()
+            .flat_map(|workspace| &workspace.members);
+        let field = if cfg.package_name && damaged(manifest.name()) {
+            Some("package.name")
+        } else if cfg.package_version && damaged(manifest.version(None)) {
+            Some("package.version")
+        } else if members
+            .map(String::as_str)
+            .any(|member| damaged(Some(member)))
+        {
+            Some("workspace.members")
+        } else {
+            None
+        };
+        if let Some(field) = field {
+            return Err(error::Error::ManifestEncoding {
+                path: path.to_path_buf(),
+                field,
+                offset,
+            });
+        }
+    }
+    Ok(manifest)
+}
+
+/// Reads `path` as text, replacing anything that isn't valid UTF-8 (eg a Latin-1 author name) rather than failing, and warning with the byte offset of the first invalid sequence. That offset is returned too, so callers can check the parts they need came through.
+fn read_lossy(path: &Path) -> std::io::Result<(String, Option<usize>)> {
+    match String::from_utf8(std::fs::read(path)?) {
+        Ok(content) => Ok((content, None)),
+        Err(err) => {
+            let offset = err.utf8_error().valid_up_to();
+            warn!(
+                "{} isn't valid UTF-8 (the first invalid byte is at offset {offset}), reading it with the invalid bytes replaced",
+                path.display()
+            );
+            Ok((
+                String::from_utf8_lossy(err.as_bytes()).into_owned(),
+                Some(offset),
+            ))
+        }
+    }
+}
+
+/// Loads the book.toml at `path` the way mdbook does, except that invalid UTF-8 is replaced (see [`read_lossy`]) rather than failing.
+fn load_book_toml(path: &Path) -> error::Result<mdbook_preprocessor::config::Config> {
+    let to_error = |source: Box<dyn std::error::Error + Send + Sync>| error::Error::BookToml {
+        path: path.to_path_buf(),
+        source,
+    };
+    let (content, _) = read_lossy(path).map_err(|err| to_error(err.into()))?;
+    content.parse().map_err(|err: Error| to_error(err.into()))
+}
+
+/// Reads mdbook's JSON payload from `input`, runs the preprocessor over it and writes the book to `output` as JSON. Errors say which of the three stages failed.
+pub fn handle_preprocessing(
+    processor: &Processor,
+    input: impl Read,
+    output: impl Write,
+) -> Result<(), Error> {
+    let (ctx, book) = parse_input(input).context("Failed to parse the preprocessor input")?;
+
+    let processed_book = processor
+        .run(&ctx, book)
+        .context("Failed to annotate the book")?;
+    serde_json::to_writer(output, &processed_book).context("Failed to write the annotated book")?;
+
+    Ok(())
+}
+
+/// [`handle_preprocessing`] reading from the `input` file (stdin if `None`) and writing to the `output` file (stdout if `None`). Using files means a payload can be captured once and replayed while working on the config.
+pub fn handle_preprocessing_paths(
+    processor: &Processor,
+    input: Option<&Path>,
+    output: Option<&Path>,
+) -> Result<(), Error> {
+    let input: Box<dyn Read> = match input {
+        Some(path) => Box::new(
+            std::fs::File::open(path)
+                .with_context(|| format!("Failed to open input file {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdin()),
+    };
+    let output: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            std::fs::File::create(path)
+                .with_context(|| format!("Failed to create output file {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    };
+    handle_preprocessing(processor, input, output)
+}
+
+pub fn handle_supports(proc: impl Preprocessor, renderer: &str) -> ! {
+    let supported = proc.supports_renderer(renderer);
+
+    // Signal whether the renderer is supported by exiting with 1 or 0.
+    if let Ok(true) = supported {
+        exit(0);
+    } else {
+        exit(1);
+    }
+}
+
+/// Prints a pass/fail line for each step of resolving the annotation for the book in `root`, then exits non-zero if any of them failed.
+///
+/// With `diff`, the book is then loaded from its SUMMARY.md and each change annotating it would make is printed too.
+pub fn handle_check(processor: &Processor, root: &Path, diff: bool) -> ! {
+    let steps = processor.check(root);
+    for step in &steps {
+        println!("{step}");
+    }
+    if steps.iter().any(|step| step.status == CheckStatus::Fail) {
+        exit(1);
+    }
+    if diff {
+        let changes = context_for_dir(root, "html")
+            .map_err(Error::from)
+            .and_then(|ctx| {
+                let cfg = Config::resolve(&ctx, &processor.overrides)?;
+                let book = dry_run::load_book(root, &ctx.config)?;
+                let processed = processor.run_with_config(&cfg, root, book.clone())?;
+                Ok(dry_run::report(&book, &processed))
+            });
+        match changes {
+            Ok(changes) => {
+                for line in changes {
+                    println!("{line}");
+                }
+            }
+            Err(err) => {
+                println!("{}", CheckStep::fail("diff", err));
+                exit(1);
+            }
+        }
+    }
+    exit(0);
+}
+
+/// Prints the renderers we support for the book in the current directory (see `supported_renderers`), for `supports` without a renderer name.
+pub fn handle_list_supports(processor: &Processor, format: cli::PrintFormat) -> Result<()> {
+    let renderers = processor.supported_renderers(&std::env::current_dir()?)?;
+    match format {
+        cli::PrintFormat::Text => {
+            for renderer in renderers {
+                println!("{renderer}");
+            }
+        }
+        cli::PrintFormat::Json => println!("{}", serde_json::to_string_pretty(&renderers)?),
+    }
+    Ok(())
+}
+
+/// Exit code for `print` when the book.toml or our config in it can't be loaded.
+pub const EXIT_CONFIG: i32 = 2;
+/// Exit code for `print` when the Cargo.toml can't be read.
+pub const EXIT_MANIFEST: i32 = 3;
+/// Exit code for `print` when the git commit is enabled but can't be found, or (with `strict`) the repository can't be opened.
+pub const EXIT_GIT: i32 = 4;
+
+/// The `EXIT_*` code `print` exits with when collecting the annotation fails with `err`, going by which [`error::Error`] it is. Errors that aren't one of ours, like a custom provider's, exit with 1.
+fn print_exit_code(err: &Error) -> i32 {
+    let Some(err) = err
+        .chain()
+        .find_map(|err| err.downcast_ref::<error::Error>())
+    else {
+        return 1;
+    };
+    match err {
+        error::Error::ManifestNotFound { .. }
+        | error::Error::ManifestParse { .. }
+        | error::Error::ManifestEncoding { .. } => EXIT_MANIFEST,
+        #[cfg(feature = "git")]
+        error::Error::GitNotFound { .. }
+        | error::Error::GitOpen { .. }
+        | error::Error::GitObjectFormat { .. }
+        | error::Error::GitHead { .. } => EXIT_GIT,
+        error::Error::RequiredMissing { .. }
+        | error::Error::NotABook { .. }
+        | error::Error::BookToml { .. }
+        | error::Error::ConfigInvalid { .. }
+        | error::Error::Config(_)
+        | error::Error::Directive { .. }
+        | error::Error::EnvOverride { .. } => EXIT_CONFIG,
+    }
+}
+
+/// Prints the annotation for the book in `root`, resolved the same way as when mdbook runs us, then exits with one of the `EXIT_*` codes if anything couldn't be found.
+pub fn handle_print(processor: &Processor, root: &Path, format: cli::PrintFormat) -> ! {
+    let cfg = match processor.config_for_dir(root, "html") {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            eprintln!("{:#}", Error::from(err));
+            exit(EXIT_CONFIG);
+        }
+    };
+    let annotation = match processor.collect(&cfg, root) {
+        Ok(annotation) => annotation,
+        Err(err) => {
+            eprintln!("{err:#}");
+            exit(print_exit_code(&err));
+        }
+    };
+
+    let cfg = match cfg.for_branch(annotation.branch.as_deref()) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            eprintln!("{:#}", Error::from(err));
+            exit(EXIT_CONFIG);
+        }
+    };
+    warn_blocked_env(&cfg);
+
+    match format {
+        cli::PrintFormat::Text => println!("{}", annotation.render(&cfg)),
+        cli::PrintFormat::Json => match serde_json::to_string_pretty(&annotation) {
+            Ok(json) => println!("{json}"),
+            Err(err) => {
+                eprintln!("Failed to serialize the annotation: {err}");
+                exit(1);
+            }
+        },
+    }
+
+    if cfg.git_commit && annotation.commit.is_none() {
+        eprintln!(
+            "Couldn't find the git commit in {}",
+            cfg.git_path(root).display()
+        );
+        exit(EXIT_GIT);
+    }
+    exit(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdbook_preprocessor::book::SectionNumber;
+
+    fn chapter_content(item: &BookItem) -> Option<&str> {
+        match item {
+            BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_print_exit_code() {
+        let code = |err: error::Error| print_exit_code(&Error::from(err));
+        assert_eq!(
+            code(error::Error::ManifestEncoding {
+                path: "Cargo.toml".into(),
+                field: "name",
+                offset: 3,
+            }),
+            EXIT_MANIFEST
+        );
+        assert_eq!(
+            code(error::Error::ConfigInvalid {
+                key: "preprocessor.build-annotations".to_string(),
+                reason: "isn't a table".to_string(),
+            }),
+            EXIT_CONFIG
+        );
+        assert_eq!(
+            code(error::Error::RequiredMissing { field: "commit" }),
+            EXIT_CONFIG
+        );
+        assert_eq!(
+            print_exit_code(&anyhow::anyhow!("a custom provider failed")),
+            1
+        );
+        // still found with context added
+        assert_eq!(
+            print_exit_code(
+                &Error::from(error::Error::RequiredMissing { field: "commit" })
+                    .context("Failed to collect")
+            ),
+            EXIT_CONFIG
+        );
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let collect = |cfg: config::ConfigBuilder| {
+            Processor::default()
+                .collect(&cfg.root_dir(dir.path()).build(), dir.path())
+                .expect_err("collect should fail")
+        };
+        assert_eq!(print_exit_code(&collect(Config::builder())), EXIT_MANIFEST);
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.0.0\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        #[cfg(feature = "git")]
+        assert_eq!(
+            print_exit_code(&collect(Config::builder().strict(true))),
+            EXIT_GIT
+        );
+    }
+
+    #[test]
+    fn test_skip_empty_chapter() {
+        let cfg = Config::default();
+        let mut item = BookItem::Chapter(Chapter::new(
+            "Empty",
+            " \n\n".to_string(),
+            "empty.md",
+            vec![],
+        ));
+        Processor::default().handle_items(
+            std::slice::from_mut(&mut item),
+            "<footer></footer>",
+            &cfg,
+            &mut Vec::new(),
+        );
+        assert_eq!(chapter_content(&item), Some(" \n\n"));
+    }
+
+    #[test]
+    fn test_insert_annotation_replaces_existing() {
+        let mut content = String::from("# Hello\n");
+        insert_annotation(&mut content, "<footer>old</footer>", &Config::default());
+        insert_annotation(&mut content, "<footer>new</footer>", &Config::default());
+        assert_eq!(
+            content,
+            format!("# Hello\n{ANNOTATION_START}<footer>new</footer>{ANNOTATION_END}")
+        );
+    }
+
+    #[test]
+    fn test_insert_before_heading() {
+        let original =
+            "# Chapter\n\n```markdown\n## Feedback\n```\n\nText\n\n## Feedback\n\nTell us!\n";
+        let cfg = Config::builder().insert_before_heading("Feedback").build();
+        let mut content = original.to_string();
+        insert_annotation(&mut content, "<footer></footer>", &cfg);
+        assert_eq!(
+            content,
+            format!(
+                "# Chapter\n\n```markdown\n## Feedback\n```\n\nText\n\n{ANNOTATION_START}<footer></footer>{ANNOTATION_END}\n\n## Feedback\n\nTell us!\n"
+            )
+        );
+
+        // only the fenced heading, so we fall back to appending
+        let mut content = "# Chapter\n\n```\n## Feedback\n```\n".to_string();
+        insert_annotation(&mut content, "<footer></footer>", &cfg);
+        assert!(content.ends_with(ANNOTATION_END));
+    }
+
+    /// Roughly what `mdbook-xgettext` extracts from `content`: the text of each top-level block and the line it starts on, leaving out HTML comments and the block after an `<!-- i18n:skip -->`.
+    fn gettext_messages(content: &str) -> Vec<(usize, String)> {
+        let mut messages: Vec<(usize, String)> = Vec::new();
+        let mut depth = 0;
+        let mut skip_next = false;
+        let mut skipping = false;
+        for (event, range) in Parser::new(content).into_offset_iter() {
+            if depth == 0 {
+                let block = &content[range.clone()];
+                if block.trim_start().starts_with("<!--") && block.trim_end().ends_with("-->") {
+                    skip_next |= block.contains("i18n:skip");
+                    continue;
+                }
+                skipping = std::mem::take(&mut skip_next);
+                if !skipping {
+                    let line = content[..range.start].matches('\n').count() + 1;
+                    messages.push((line, String::new()));
+                }
+            }
+            match event {
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth -= 1,
+                Event::Text(text)
+                | Event::Code(text)
+                | Event::Html(text)
+                | Event::InlineHtml(text)
+                    if !skipping =>
+                {
+                    if let Some((_, message)) = messages.last_mut() {
+                        message.push_str(&text);
+                    }
+                }
+                _ => {}
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn test_gettext_skip() {
+        let original = "# Chapter\n\nSome text.\n\n## Feedback\n\nTell us!";
+        let expected = gettext_messages(original);
+        assert_eq!(expected.len(), 4);
+
+        for format in [Format::Html, Format::Markdown, Format::Epub] {
+            let cfg = Config::builder()
+                .format(format)
+                .gettext_skip(true)
+                .insert_before_heading("Feedback")
+                .build();
+            let mut content = original.to_string();
+            for build in ["abcdef1234", "0123456789"] {
+                let footer = wrap_footer(&cfg, &format!("example @{build} v1.0.0"));
+                insert_annotation(&mut content, &footer, &cfg);
+                assert_eq!(
+                    gettext_messages(&content),
+                    expected,
+                    "{format:?}: {content}"
+                );
+                assert!(content.starts_with(original));
+                assert_eq!(content.matches(GETTEXT_SKIP).count(), 1);
+                assert!(content.contains(build));
+            }
+        }
+
+        // without it the annotation is extracted as a message, or changes the last one
+        for format in [Format::Html, Format::Markdown] {
+            let cfg = Config::builder().format(format).build();
+            let mut content = original.to_string();
+            insert_annotation(&mut content, &wrap_footer(&cfg, "example v1.0.0"), &cfg);
+            assert_ne!(gettext_messages(&content), expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn test_run_twice_single_footer() {
+        let ctx = testing::context()
+            .root(env!("CARGO_MANIFEST_DIR"))
+            .set("workspace_dir", env!("CARGO_MANIFEST_DIR"))
+            .set("git_dir", env!("CARGO_MANIFEST_DIR"))
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+
+        let book = Processor::default()
+            .run(&ctx, book)
+            .expect("first run failed");
+        let book = Processor::default()
+            .run(&ctx, book)
+            .expect("second run failed");
+
+        let content = chapter_content(&book.items[0]).expect("expected a chapter");
+        assert_eq!(content.matches("<footer").count(), 1);
+        assert_eq!(content.matches(ANNOTATION_START).count(), 1);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let cfg = Config {
+            max_depth: Some(1),
+            ..Config::default()
+        };
+        let is_annotated = |number: Option<Vec<u32>>| {
+            let mut chapter = Chapter::new("Chapter", "# Chapter\n".to_string(), "ch.md", vec![]);
+            chapter.number = number.map(SectionNumber::new);
+            let mut item = BookItem::Chapter(chapter);
+            Processor::default().handle_items(
+                std::slice::from_mut(&mut item),
+                "<footer></footer>",
+                &cfg,
+                &mut Vec::new(),
+            );
+            chapter_content(&item)
+                .map(|content| content.contains("<footer>"))
+                .unwrap_or(false)
+        };
+        assert!(is_annotated(Some(vec![1])));
+        assert!(!is_annotated(Some(vec![1, 2])));
+        assert!(is_annotated(None));
+    }
+
+    #[test]
+    fn test_parts() {
+        let cfg = Config {
+            parts: Some(vec!["Reference".to_string()]),
+            ..Config::default()
+        };
+        let chapter = |name: &str| {
+            BookItem::Chapter(Chapter::new(
+                name,
+                format!("# {name}\n"),
+                format!("{name}.md"),
+                vec![],
+            ))
+        };
+        let mut nested = Chapter::new("Nested", "# Nested\n".to_string(), "nested.md", vec![]);
+        nested.sub_items.push(chapter("Child"));
+        let mut items = vec![
+            chapter("Intro"),
+            BookItem::PartTitle("User Guide".to_string()),
+            chapter("Guide"),
+            BookItem::PartTitle("Reference".to_string()),
+            BookItem::Chapter(nested),
+        ];
+        let mut found_parts = Vec::new();
+        Processor::default().handle_items(&mut items, "<footer></footer>", &cfg, &mut found_parts);
+
+        let is_annotated = |item: &BookItem| {
+            chapter_content(item)
+                .map(|content| content.contains("<footer>"))
+                .unwrap_or(false)
+        };
+        assert!(!is_annotated(&items[0]));
+        assert!(!is_annotated(&items[2]));
+        assert!(is_annotated(&items[4]));
+        let BookItem::Chapter(nested) = &items[4] else {
+            unreachable!("expected a chapter");
+        };
+        assert!(is_annotated(&nested.sub_items[0]));
+        assert_eq!(found_parts, vec!["User Guide", "Reference"]);
+    }
+
+    #[test]
+    fn test_appendix_added_once() {
+        let mut book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+        let content = appendix_content(
+            "Build information",
+            &[("Package", Some("example".to_string())), ("Commit", None)],
+        );
+        assert_eq!(
+            content,
+            "# Build information\n\n| | |\n|---|---|\n| Package | example |\n"
+        );
+        add_appendix(
+            &mut book,
+            APPENDIX_PATH,
+            "Build information",
+            content.clone(),
+        );
+        add_appendix(&mut book, APPENDIX_PATH, "Build info", content);
+
+        assert_eq!(book.items.len(), 2);
+        let BookItem::Chapter(appendix) = &book.items[1] else {
+            unreachable!("expected a chapter");
+        };
+        assert_eq!(appendix.name, "Build info");
+        assert_eq!(appendix.path.as_deref(), Some(APPENDIX_PATH.as_ref()));
+        assert!(appendix.source_path.is_none());
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_appendix() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "intro.md"),
+            ],
+        );
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.0.0\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let repo = gix::open(dir.path()).expect("Failed to open repo");
+        let head = repo.head_commit().expect("no HEAD");
+        let parent = head.parent_ids().next().expect("no parent").detach();
+        repo.tag_reference(
+            "v1.0.0",
+            parent,
+            gix::refs::transaction::PreviousValue::MustNotExist,
+        )
+        .expect("Failed to tag");
+        let appendix = |cfg: config::ConfigBuilder| {
+            let book = testing::book().chapter("intro.md", "# Intro\n").build();
+            let book = Processor::default()
+                .run_in(
+                    &cfg.root_dir(dir.path())
+                        .appendix(true)
+                        .skip_chapters(true)
+                        .build(),
+                    dir.path(),
+                    dir.path(),
+                    book,
+                )
+                .expect("run failed");
+            chapter_content(&book.items[1])
+                .expect("not a chapter")
+                .to_string()
+        };
+        let row = |content: &str, label: &str| {
+            content
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("| {label} | ")))
+                .and_then(|value| value.strip_suffix(" |"))
+                .map(str::to_string)
+        };
+
+        let content = appendix(Config::builder());
+        assert_eq!(row(&content, "Package").as_deref(), Some("example"));
+        assert_eq!(
+            row(&content, "Tag").as_deref(),
+            Some("v1.0.0 (1 commit since)")
+        );
+        let built_at = row(&content, "Built at").expect("no build time");
+        assert!(humantime::parse_rfc3339(&built_at).is_ok(), "{built_at}");
+        let ci_url = provider::ci_build_url(|name| std::env::var(name).ok());
+        assert_eq!(row(&content, "CI build"), ci_url.as_deref().map(autolink));
+
+        // nothing that changes from one run to the next
+        let content = appendix(Config::builder().deterministic(true));
+        assert_eq!(
+            row(&content, "Built at").is_some(),
+            build_info::source_date_epoch().is_some()
+        );
+        assert_eq!(row(&content, "CI build"), None);
+
+        repo.tag_reference(
+            "v1.1.0",
+            head.id,
+            gix::refs::transaction::PreviousValue::MustNotExist,
+        )
+        .expect("Failed to tag");
+        let content = appendix(Config::builder());
+        assert_eq!(row(&content, "Tag").as_deref(), Some("v1.1.0"));
+    }
+
+    #[test]
+    fn test_appendix_escaping() {
+        struct Fields;
+
+        impl provider::MetadataProvider for Fields {
+            fn fields(&self, _ctx: &provider::Context) -> Result<Vec<(String, String)>> {
+                Ok(vec![
+                    (
+                        provider::FIELD_BRANCH.to_string(),
+                        "fix|pipes<b>".to_string(),
+                    ),
+                    ("Reviewer".to_string(), "Bob <user-1a2b3c4d>".to_string()),
+                ])
+            }
+        }
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .appendix(true)
+            .skip_chapters(true)
+            .build();
+        let book = testing::book().chapter("intro.md", "# Intro\n").build();
+        let book = Processor::default()
+            .with_providers(vec![Box::new(Fields)])
+            .run_in(&cfg, dir.path(), dir.path(), book)
+            .expect("run failed");
+        let content = chapter_content(&book.items[1]).expect("not a chapter");
+        assert!(
+            content.contains("| Branch | fix\\|pipes&lt;b&gt; |\n"),
+            "{content}"
+        );
+        assert!(
+            content.contains("| Reviewer | Bob &lt;user-1a2b3c4d&gt; |\n"),
+            "{content}"
+        );
+
+        assert_eq!(
+            autolink("https://ci.example.com/runs/1?a=<b>|c d"),
+            "<https://ci.example.com/runs/1?a=%3Cb%3E%7Cc%20d>"
+        );
+    }
+
+    #[test]
+    fn test_supports_renderer_reads_book_toml() {
+        let book = tempfile::tempdir().expect("Failed to create tempdir");
+        assert!(
+            Processor::default()
+                .supports_renderer_in(book.path(), "pdf")
+                .expect("Failed to check renderer")
+        );
+
+        std::fs::write(
+            book.path().join("book.toml"),
+            "[preprocessor.build-annotations]\nrenderers = [\"html\"]\n",
+        )
+        .expect("Failed to write book.toml");
+        let processor = Processor::default();
+        assert!(
+            processor
+                .supports_renderer_in(book.path(), "html")
+                .expect("Failed to check renderer")
+        );
+        assert!(
+            !processor
+                .supports_renderer_in(book.path(), "pdf")
+                .expect("Failed to check renderer")
+        );
+    }
+
+    #[test]
+    fn test_supported_renderers() {
+        let book = tempfile::tempdir().expect("Failed to create tempdir");
+        let processor = Processor::default();
+        assert_eq!(
+            processor
+                .supported_renderers(book.path())
+                .expect("Failed to list renderers"),
+            ["html"]
+        );
+
+        std::fs::write(
+            book.path().join("book.toml"),
+            "[output.html]\n[output.pdf]\n\n[preprocessor.build-annotations]\nrenderers = [\"html\", \"markdown\"]\n",
+        )
+        .expect("Failed to write book.toml");
+        assert_eq!(
+            processor
+                .supported_renderers(book.path())
+                .expect("Failed to list renderers"),
+            ["html", "markdown"]
+        );
+    }
+
+    #[test]
+    fn test_dry_run_leaves_book_unchanged() {
+        let ctx = testing::context()
+            .root(env!("CARGO_MANIFEST_DIR"))
+            .set("root_dir", env!("CARGO_MANIFEST_DIR"))
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+        let processed = Processor::default()
+            .with_dry_run(true)
+            .run(&ctx, book.clone())
+            .expect("run failed");
+        assert_eq!(processed, book);
+    }
+
+    #[test]
+    fn test_run_skips_unsupported_renderer() {
+        let ctx = testing::context()
+            .root(env!("CARGO_MANIFEST_DIR"))
+            .renderer("pdf")
+            .set("renderers", ["html"])
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+        let processed = Processor::default()
+            .run(&ctx, book.clone())
+            .expect("run failed");
+        assert_eq!(processed, book);
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        for (built, running, compatible, strict) in [
+            ("0.5.2", "0.5.2", false, false),
+            ("0.5.2", "0.5.3", false, true),
+            ("0.5.2", "0.5.0", false, true),
+            ("0.5.2", "0.5.2-alpha.1", false, true),
+            ("0.5.2", "0.6.0", true, true),
+            ("0.5.2", "0.4.40", true, true),
+            ("0.5.2", "1.0.0", true, true),
+            ("1.2.0", "1.9.1", false, true),
+            ("1.2.0", "2.0.0", true, true),
+            ("0.5.2", "not a version", true, true),
+            ("0.5.2", "", true, true),
+        ] {
+            assert_eq!(
+                version_mismatch(VersionCheck::Compatible, built, running),
+                compatible,
+                "compatible {built} {running}"
+            );
+            assert_eq!(
+                version_mismatch(VersionCheck::Strict, built, running),
+                strict,
+                "strict {built} {running}"
+            );
+            assert!(!version_mismatch(VersionCheck::Off, built, running));
+        }
+    }
+
+    #[test]
+    fn test_run_skips_test_renderer() {
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+        let run = |toml: &str, renderer: &str| {
+            let ctx = testing::context()
+                .root(env!("CARGO_MANIFEST_DIR"))
+                .renderer(renderer)
+                .book_toml(format!("[preprocessor.build-annotations]\n{toml}"))
+                .set("root_dir", env!("CARGO_MANIFEST_DIR"))
+                .build();
+            Processor::default()
+                .run(&ctx, book.clone())
+                .expect("run failed")
+        };
+
+        assert_eq!(run("", "test"), book);
+        assert_ne!(run("", "linkcheck"), book);
+        assert_eq!(
+            run("skip_renderers = [\"test\", \"linkcheck\"]\n", "linkcheck"),
+            book
+        );
+        assert_ne!(run("skip_renderers = []\n", "test"), book);
+    }
+
+    #[test]
+    fn test_run_uses_processor_config() {
+        // the book.toml asks for the default html footer, the processor's config wins
+        let ctx = testing::context()
+            .root(env!("CARGO_MANIFEST_DIR"))
+            .set("format", "html")
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .package_name(false)
+            .git_commit(false)
+            .format(Format::Markdown)
+            .build();
+        let processed = Processor::with_config(cfg)
+            .run(&ctx, book.clone())
+            .expect("run failed");
+        let content = chapter_content(&processed.items[0]).expect("expected a chapter");
+        assert!(content.contains(&format!("\n\nv{}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(!content.contains("<footer"));
+
+        // the renderers list in the given config still applies
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .renderers(vec!["pdf".to_string()])
+            .build();
+        let processed = Processor::with_config(cfg)
+            .run(&ctx, book.clone())
+            .expect("run failed");
+        assert_eq!(processed, book);
+    }
+
+    #[test]
+    fn test_read_manifest_missing() {
+        let err = read_manifest(
+            Path::new("does-not-exist/Cargo.toml"),
+            &Config::builder().workspace_dir("does-not-exist").build(),
+        )
+        .err()
+        .expect("manifest shouldn't exist");
+        assert!(matches!(err, error::Error::ManifestNotFound { .. }));
+        let message = format!("{:#}", Error::from(err));
+        let resolved = std::env::current_dir()
+            .expect("no current dir")
+            .join("does-not-exist/Cargo.toml");
+        assert!(
+            message.contains(&format!("Failed to read {}", resolved.display())),
+            "{message}"
+        );
+        assert!(message.contains("from workspace_dir = \"does-not-exist\""));
+        assert!(message.contains("resolve_from_book_root"));
+
+        // found by searching up from the book root, with a directory that does exist
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let err = read_manifest(&dir.path().join("Cargo.toml"), &Config::default())
+            .err()
+            .expect("manifest shouldn't exist");
+        let resolved = paths::canonicalize(dir.path())
+            .expect("Failed to canonicalize")
+            .join("Cargo.toml");
+        let message = err.to_string();
+        assert!(
+            message.starts_with(&format!(
+                "Failed to read {}, workspace_dir isn't set",
+                resolved.display()
+            )),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_read_manifest_invalid() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let manifest = dir.path().join("Cargo.toml");
+        std::fs::write(&manifest, "[package\n").expect("Failed to write Cargo.toml");
+        let err = read_manifest(&manifest, &Config::default())
+            .err()
+            .expect("manifest should be invalid");
+        assert!(matches!(err, error::Error::ManifestParse { .. }));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Failed to parse {} at line 1, column 9: unclosed table, expected `]`",
+                manifest.display()
+            )
+        );
+    }
+
+    /// Writes `files` (path and contents) under `dir`, creating directories as needed.
+    fn write_files(dir: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().expect("path has no parent"))
+                .expect("Failed to create directory");
+            std::fs::write(path, contents).expect("Failed to write file");
+        }
+    }
+
+    /// The name and version [`read_package`] finds in `dir`, or the members if it's a virtual manifest.
+    fn package_in(dir: &Path, cfg: &Config) -> std::result::Result<(String, String), Vec<PathBuf>> {
+        match read_package(dir, cfg).expect("Failed to read package") {
+            ManifestPackage::Found { name, version, .. } => {
+                Ok((name.unwrap_or_default(), version.unwrap_or_default()))
+            }
+            ManifestPackage::Virtual { members, .. } => Err(members),
+        }
+    }
+
+    #[cfg(all(unix, feature = "git"))]
+    #[test]
+    fn test_symlinked_workspace() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let real = paths::canonicalize(dir.path())
+            .expect("Failed to canonicalize")
+            .join("store")
+            .join("tree");
+        write_files(
+            &real,
+            &[
+                (
+                    "Cargo.toml",
+                    "[package]\nname = \"linked\"\nversion = \"1.0.0\"\n",
+                ),
+                ("book/book.toml", ""),
+            ],
+        );
+        git::tests::fixture_repo(&real, &[("Alice", "alice@example.com", "Cargo.toml")]);
+        let link = dir.path().join("checkout");
+        std::os::unix::fs::symlink(&real, &link).expect("Failed to create symlink");
+
+        // the Cargo.toml is found by searching up from the book through the link, and git_dir is "../" from the book
+        let cfg = Config::builder().resolve_from_book_root(true).build();
+        let book_root = link.join("book");
+        let ctx = provider::Context::new(&cfg, &book_root);
+        assert_eq!(ctx.workspace_path(), real);
+        assert_eq!(ctx.git_path(), real);
+
+        let annotation = Annotation::collect(&cfg, &book_root).expect("Failed to collect");
+        assert_eq!(annotation.name.as_deref(), Some("linked"));
+        assert!(annotation.commit.is_some());
+    }
+
+    #[test]
+    fn test_non_utf8_manifest() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let manifest = dir.path().join("Cargo.toml");
+        // "José" in Latin-1, in a field we don't read
+        let mut content =
+            b"[package]\nname = \"legacy\"\nversion = \"1.0.0\"\nauthors = [\"Jos".to_vec();
+        content.extend(b"\xe9\"]\n");
+        std::fs::write(&manifest, &content).expect("Failed to write Cargo.toml");
+        assert_eq!(
+            package_in(dir.path(), &Config::default()),
+            Ok(("legacy".to_string(), "1.0.0".to_string()))
+        );
+
+        let content = b"[package]\nname = \"caf\xe9\"\nversion = \"1.0.0\"\n";
+        std::fs::write(&manifest, content).expect("Failed to write Cargo.toml");
+        let err = read_manifest(&manifest, &Config::default())
+            .err()
+            .expect("the name is damaged");
+        assert!(
+            matches!(
+                err,
+                error::Error::ManifestEncoding {
+                    field: "package.name",
+                    offset: 21,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        // unless the name isn't wanted
+        let cfg = Config::builder().package_name(false).build();
+        assert!(read_manifest(&manifest, &cfg).is_ok());
+    }
+
+    #[test]
+    fn test_virtual_manifest() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        write_files(dir.path(), &[("Cargo.toml", "[workspace]\n")]);
+        assert_eq!(package_in(dir.path(), &Config::default()), Err(vec![]));
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        write_files(
+            dir.path(),
+            &[(
+                "Cargo.toml",
+                "[workspace]\nmembers = []\n\n[workspace.package]\nversion = \"1.2.3\"\n",
+            )],
+        );
+        assert_eq!(package_in(dir.path(), &Config::default()), Err(vec![]));
+
+        let members = [
+            (
+                "crates/core/Cargo.toml",
+                "[package]\nname = \"core\"\nversion.workspace = true\n",
+            ),
+            (
+                "crates/cli/Cargo.toml",
+                "[package]\nname = \"cli\"\nversion = \"0.1.0\"\n",
+            ),
+            ("crates/notes/README.md", "not a crate\n"),
+        ];
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        write_files(dir.path(), &members);
+        write_files(
+            dir.path(),
+            &[(
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\"]\n\n[workspace.package]\nversion = \"1.2.3\"\n",
+            )],
+        );
+        assert_eq!(
+            package_in(dir.path(), &Config::default()),
+            Err(vec![
+                PathBuf::from("crates/cli"),
+                PathBuf::from("crates/core")
+            ])
+        );
+        assert_eq!(
+            package_in(
+                dir.path(),
+                &Config::builder().member_path("crates/core").build()
+            ),
+            Ok(("core".to_string(), "1.2.3".to_string()))
+        );
+        assert_eq!(
+            package_in(
+                dir.path(),
+                &Config::builder().member_path("crates/cli").build()
+            ),
+            Ok(("cli".to_string(), "0.1.0".to_string()))
+        );
+        assert!(matches!(
+            read_package(
+                dir.path(),
+                &Config::builder().member_path("crates/notes").build()
+            ),
+            Err(error::Error::ManifestNotFound { .. })
+        ));
+
+        // The only member is used without having to set member_path.
+        write_files(
+            dir.path(),
+            &[(
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/core\"]\n\n[workspace.package]\nversion = \"1.2.3\"\n",
+            )],
+        );
+        assert_eq!(
+            package_in(dir.path(), &Config::default()),
+            Ok(("core".to_string(), "1.2.3".to_string()))
+        );
+
+        // A workspace with its own name and version isn't virtual.
+        write_files(
+            dir.path(),
+            &[(
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\"]\nname = \"example\"\nversion = \"2.0.0\"\n",
+            )],
+        );
+        assert_eq!(
+            package_in(dir.path(), &Config::default()),
+            Ok(("example".to_string(), "2.0.0".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_run_writes_build_info() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .write_build_info("build-info.json")
+            .build();
+        Processor::default()
+            .run_with_config(&cfg, dir.path(), Book::new())
+            .expect("run failed");
+        let info: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(dir.path().join("build-info.json"))
+                .expect("Failed to read build info"),
+        )
+        .expect("Failed to parse build info");
+        assert_eq!(info["schema_version"], build_info::SCHEMA_VERSION);
+        assert_eq!(info["name"], env!("CARGO_PKG_NAME"));
+
+        // the book root is a file, so the build info can't be written under it
+        let not_a_dir = dir.path().join("build-info.json");
+        Processor::default()
+            .run_with_config(&cfg, &not_a_dir, Book::new())
+            .expect("a failed write should only be a warning");
+        let err = Processor::default()
+            .run_with_config(
+                &Config {
+                    strict: true,
+                    ..cfg
+                },
+                &not_a_dir,
+                Book::new(),
+            )
+            .expect_err("a failed write should fail in strict mode");
+        assert!(format!("{err:#}").contains("Failed to write the build information"));
+    }
+
+    #[test]
+    fn test_run_writes_report() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let book = testing::book()
+            .chapter("intro.md", "# Intro\n")
+            .sub_chapter("nested.md", "# Nested\n")
+            .chapter("empty.md", "")
+            .draft("Roadmap")
+            .build();
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .max_depth(1)
+            .report_file("reports/buildtime-report.json")
+            .build();
+        Processor::default()
+            .run_with_config(&cfg, dir.path(), book.clone())
+            .expect("run failed");
+        let report: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(dir.path().join("reports/buildtime-report.json"))
+                .expect("Failed to read the report"),
+        )
+        .expect("Failed to parse the report");
+        assert_eq!(report["schema_version"], report::SCHEMA_VERSION);
+        assert_eq!(report["config"]["max_depth"], 1);
+        assert_eq!(report["annotation"]["name"], env!("CARGO_PKG_NAME"));
+        assert_eq!(report["annotated"], serde_json::json!(["intro.md"]));
+        assert_eq!(
+            report["skipped"],
+            serde_json::json!([
+                { "chapter": "nested.md", "reason": "excluded" },
+                { "chapter": "empty.md", "reason": "empty" },
+                { "chapter": "Roadmap", "reason": "draft" },
+            ])
+        );
+        let phases: Vec<&str> = report["timing"]["phases"]
+            .as_array()
+            .expect("phases should be a list")
+            .iter()
+            .filter_map(|phase| phase["phase"].as_str())
+            .collect();
+        assert!(phases.contains(&"render"), "{phases:?}");
+
+        // the book root is a file, so the report can't be written, even in strict mode that's only a warning
+        let not_a_dir = dir.path().join("reports/buildtime-report.json");
+        Processor::default()
+            .run_with_config(
+                &Config {
+                    strict: true,
+                    ..cfg
+                },
+                &not_a_dir,
+                book,
+            )
+            .expect("a failed report write should only be a warning");
+    }
+
+    #[test]
+    fn test_required_fields() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"unversioned\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .required([RequiredField::PackageName])
+            .build();
+        Processor::default()
+            .run_with_config(&cfg, dir.path(), Book::new())
+            .expect("the missing version isn't required");
+
+        let err = Processor::default()
+            .run_with_config(
+                &Config {
+                    required: vec![RequiredField::PackageName, RequiredField::PackageVersion],
+                    ..cfg
+                },
+                dir.path(),
+                Book::new(),
+            )
+            .expect_err("the version is required");
+        assert!(matches!(
+            err.downcast_ref::<error::Error>(),
+            Some(error::Error::RequiredMissing {
+                field: "package_version"
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "package_version is required, but couldn't be found"
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_chapter_dates() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .package_version(false)
+            .chapter_dates(true)
+            .date_format("%Y")
+            .chapter_dates_fallback("Unreleased")
+            .build();
+        let mut book = Book::new();
+        for (name, path) in [
+            ("Committed", Some("Cargo.toml")),
+            ("New", Some("not-committed-yet.md")),
+            ("Generated", None),
+        ] {
+            let mut chapter = Chapter::new(name, format!("# {name}\n"), "unused.md", vec![]);
+            chapter.source_path = path.map(PathBuf::from);
+            book.push_item(chapter);
+        }
+
+        let book = Processor::default()
+            .run_in(&cfg, root, root, book)
+            .expect("run failed");
+        let footers: Vec<&str> = book
+            .items
+            .iter()
+            .filter_map(chapter_content)
+            .map(|content| {
+                let start = content.find("<footer").expect("no footer");
+                &content[start..]
+            })
+            .collect();
+        assert!(
+            footers[0].starts_with(&format!(
+                "<footer id=\"buildtime-annotations\">{} Last updated 20",
+                env!("CARGO_PKG_NAME")
+            )),
+            "{}",
+            footers[0]
+        );
+        assert!(footers[1].starts_with(&format!(
+            "<footer id=\"buildtime-annotations\">{} Unreleased</footer>",
+            env!("CARGO_PKG_NAME")
+        )));
+        assert!(footers[2].starts_with(&format!(
+            "<footer id=\"buildtime-annotations\">{}</footer>",
+            env!("CARGO_PKG_NAME")
+        )));
+
+        // without a fallback, chapters that haven't been committed just get the usual footer
+        let cfg = Config {
+            chapter_dates_fallback: None,
+            ..cfg
+        };
+        let mut chapter = Chapter::new("New", "# New\n".to_string(), "unused.md", vec![]);
+        chapter.source_path = Some("not-committed-yet.md".into());
+        let mut book = Book::new();
+        book.push_item(chapter);
+        let book = Processor::default()
+            .run_in(&cfg, root, root, book)
+            .expect("run failed");
+        assert!(
+            book.items
+                .iter()
+                .filter_map(chapter_content)
+                .all(|content| !content.contains("Unreleased"))
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_chapter_contributors() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let repo = git::GitRepo::open(root).expect("Failed to open repo");
+        let history = repo.history(Path::new("Cargo.toml"), None);
+        let top = git::contributors(&history, false, &[])
+            .into_iter()
+            .next()
+            .expect("Cargo.toml should have contributors");
+        let date = format_date(history[0].time, "%Y-%m-%d").expect("Failed to format date");
+
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .package_version(false)
+            .format(Format::Markdown)
+            .chapter_dates(true)
+            .chapter_contributors(true)
+            .contributors_max(1)
+            .build();
+        let mut chapter = Chapter::new("Cargo", "# Cargo\n".to_string(), "unused.md", vec![]);
+        chapter.source_path = Some("Cargo.toml".into());
+        let mut book = Book::new();
+        book.push_item(chapter);
+        let book = Processor::default()
+            .run_in(&cfg, root, root, book)
+            .expect("run failed");
+        let content = book
+            .items
+            .iter()
+            .find_map(chapter_content)
+            .expect("no chapter");
+        assert!(
+            content.contains(&format!(
+                "\n\n{} Last updated {date} Contributors: {top}\n",
+                env!("CARGO_PKG_NAME")
+            )),
+            "{content}"
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_chapter_revisions() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let repo = git::GitRepo::open(root).expect("Failed to open repo");
+        let revisions = repo.history(Path::new("Cargo.toml"), None).len();
+        assert!(
+            revisions > 1,
+            "the test needs Cargo.toml to have changed more than once"
+        );
+
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .package_version(false)
+            .format(Format::Markdown)
+            .chapter_revisions(true)
+            .build();
+        let footer = |cfg: &Config, path: &str| {
+            let mut chapter =
+                Chapter::new("Chapter", "# Chapter\n".to_string(), "unused.md", vec![]);
+            chapter.source_path = Some(path.into());
+            let mut book = Book::new();
+            book.push_item(chapter);
+            let book = Processor::default()
+                .run_in(cfg, root, root, book)
+                .expect("run failed");
+            let content = book
+                .items
+                .iter()
+                .find_map(chapter_content)
+                .expect("no chapter");
+            content[content.rfind("\n\n").expect("no footer")..]
+                .trim_end_matches(ANNOTATION_END)
+                .trim()
+                .to_string()
+        };
+        let name = env!("CARGO_PKG_NAME");
+        assert_eq!(
+            footer(&cfg, "Cargo.toml"),
+            format!("{name} rev {revisions}")
+        );
+        assert_eq!(
+            footer(
+                &Config {
+                    revisions_max: revisions - 1,
+                    ..cfg.clone()
+                },
+                "Cargo.toml"
+            ),
+            format!("{name} rev {}+", revisions - 1)
+        );
+        // the cap doesn't change the count when the contributors walk everything anyway
+        assert_eq!(
+            footer(
+                &Config {
+                    revisions_max: revisions,
+                    chapter_contributors: true,
+                    contributors_max: Some(0),
+                    ..cfg.clone()
+                },
+                "Cargo.toml"
+            ),
+            format!("{name} rev {revisions}")
+        );
+        assert_eq!(footer(&cfg, "not-committed-yet.md"), name);
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_submodule_chapters() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let root = paths::canonicalize(dir.path()).expect("Failed to canonicalize");
+        git::tests::fixture_repo(&root, &[("Alice", "alice@example.com", "intro.md")]);
+        let shared = root.join("shared");
+        git::tests::fixture_repo(&shared, &[("Bob", "bob@example.com", "topic.md")]);
+        let config = shared.join(".git").join("config");
+        let mut contents = std::fs::read_to_string(&config).expect("Failed to read config");
+        contents.push_str("[remote \"origin\"]\n\turl = git@github.com:org/shared.git\n");
+        std::fs::write(&config, contents).expect("Failed to write config");
+        let head = |repo: &Path| {
+            let repo = git::GitRepo::open(repo).expect("Failed to open repo");
+            (
+                repo.head_commit(usize::MAX).expect("Failed to read HEAD"),
+                repo.head_branch().expect("HEAD should be on a branch"),
+            )
+        };
+        let (book_commit, _) = head(&root);
+        let (shared_commit, shared_branch) = head(&shared);
+
+        let cfg = Config::builder()
+            .root_dir(&root)
+            .format(Format::Markdown)
+            .chapter_contributors(true)
+            .chapter_dates(true)
+            .date_format("dated")
+            .commit_url_template("https://example.com/book/commit/{commit}")
+            .edit_link(true)
+            .edit_url_template("https://example.com/book/edit/{branch}/{path}")
+            .edit_branch("main")
+            .build();
+        let book = testing::book()
+            .chapter("intro.md", "# Intro\n")
+            .chapter("shared/topic.md", "# Topic\n")
+            .build();
+        let book = Processor::default()
+            .with_providers(vec![Box::new(provider::GitProvider)])
+            .run_in(&cfg, &root, &root, book)
+            .expect("run failed");
+        let contents: Vec<&str> = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+                _ => None,
+            })
+            .collect();
+        let expected = |commit_url: &str, contributor: &str, edit_url: &str| {
+            format!(
+                " [Last updated dated](<{commit_url}>) Contributors: {contributor} [Edit this page](<{edit_url}>)\n"
+            )
+        };
+        // the book's own chapters are unaffected
+        assert!(
+            contents[0].contains(&expected(
+                &format!("https://example.com/book/commit/{book_commit}"),
+                "Alice",
+                "https://example.com/book/edit/main/intro.md"
+            )),
+            "{}",
+            contents[0]
+        );
+        // the submodule's history and links are its own
+        assert!(
+            contents[1].contains(&expected(
+                &format!("https://github.com/org/shared/commit/{shared_commit}"),
+                "Bob",
+                &format!("https://github.com/org/shared/edit/{shared_branch}/topic.md")
+            )),
+            "{}",
+            contents[1]
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_chapter_contributors_mailmap() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@old.example", "intro.md"),
+                ("Bob", "bob@example.com", "intro.md"),
+                ("alice", "alice@laptop.example", "intro.md"),
+            ],
+        );
+        std::fs::write(
+            dir.path().join(".mailmap"),
+            "Alice Example <alice@example.com> <alice@old.example>\nAlice Example <alice@example.com> <alice@laptop.example>\n",
+        )
+        .expect("Failed to write .mailmap");
+
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .chapter_contributors(true)
+            .build();
+        let mut chapter = Chapter::new("Intro", "# Intro\n".to_string(), "unused.md", vec![]);
+        chapter.source_path = Some("intro.md".into());
+        let mut book = Book::new();
+        book.push_item(chapter);
+        let book = Processor::default()
+            .with_providers(vec![Box::new(provider::GitProvider)])
+            .run_in(&cfg, dir.path(), dir.path(), book)
+            .expect("run failed");
+        let content = book
+            .items
+            .iter()
+            .find_map(chapter_content)
+            .expect("no chapter");
+        assert!(
+            content.contains(" Contributors: Alice Example, Bob</footer>"),
+            "{content}"
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_history_appendix() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "setup.md"),
+                ("Carol", "carol@example.com", "intro.md"),
+                ("Dave", "dave@example.com", "other.md"),
+            ],
+        );
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .workspace_dir(env!("CARGO_MANIFEST_DIR"))
+            .skip_chapters(true)
+            .chapters(["intro.md", "setup.md", "new.md"])
+            .chapter_dates_fallback("Not committed yet")
+            .history_appendix(true)
+            .build();
+        let book = testing::book()
+            .chapter("setup.md", "# Setup | install\n")
+            .chapter("new.md", "# New\n")
+            .chapter("intro.md", "# Intro\n")
+            .chapter("other.md", "# Other\n")
+            .draft("Roadmap")
+            .build();
+        let processor = Processor::default();
+        let book = processor
+            .run_in(&cfg, dir.path(), dir.path(), book)
+            .expect("run failed");
+        let BookItem::Chapter(appendix) = book.items.last().expect("no items") else {
+            panic!("the appendix isn't a chapter");
+        };
+        assert_eq!(appendix.name, "Revision history");
+        assert_eq!(
+            appendix.content,
+            "# Revision history\n\n| Chapter | Path | Last updated | By |\n|---|---|---|---|\n\
+             | Intro | [intro.md](<intro.md>) | 2023-11-16 | Carol |\n\
+             | Setup \\| install | [setup.md](<setup.md>) | 2023-11-15 | Bob |\n\
+             | New | [new.md](<new.md>) | Not committed yet |  |\n"
+        );
+
+        // running again replaces it
+        let again = processor
+            .run_in(&cfg, dir.path(), dir.path(), book.clone())
+            .expect("run failed");
+        assert_eq!(again.items, book.items);
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_changelog_chapter() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo_with_messages(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md", "Add the intro"),
+                (
+                    "Bob",
+                    "bob@example.com",
+                    "setup.md",
+                    "Add <setup> & install",
+                ),
+                ("Carol", "carol@example.com", "intro.md", "Fix a typo"),
+            ],
+        );
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .workspace_dir(env!("CARGO_MANIFEST_DIR"))
+            .skip_chapters(true)
+            .commit_characters(7)
+            .commit_url_template("https://example.com/commit/{commit}")
+            .changelog_chapter(true)
+            .build();
+        let run = |cfg: &Config| {
+            let book = testing::book().chapter("intro.md", "# Intro\n").build();
+            Processor::default()
+                .run_in(cfg, dir.path(), dir.path(), book)
+                .expect("run failed")
+        };
+        // no tags, no changelog
+        assert_eq!(run(&cfg).items.len(), 1);
+
+        let repo = gix::open(dir.path()).expect("Failed to open repo");
+        let head = repo.head_commit().expect("no HEAD");
+        let tagged = head.parent_ids().next().expect("no parent").detach();
+        let first = repo
+            .find_commit(tagged)
+            .expect("Failed to find commit")
+            .parent_ids()
+            .next()
+            .expect("no parent")
+            .detach();
+        repo.tag_reference(
+            "v1.0.0",
+            tagged,
+            gix::refs::transaction::PreviousValue::MustNotExist,
+        )
+        .expect("Failed to tag");
+        let short = |id: gix::ObjectId| {
+            format!(
+                "[{}](<https://example.com/commit/{id}>)",
+                id.to_hex_with_len(7)
+            )
+        };
+        let book = run(&cfg);
+        let BookItem::Chapter(changelog) = book.items.last().expect("no items") else {
+            panic!("the changelog isn't a chapter");
+        };
+        assert_eq!(changelog.name, "Changelog");
+        assert_eq!(
+            changelog.content,
+            format!(
+                "# Changelog\n\n## Unreleased\n\n- Fix a typo ({})\n\n## v1.0.0 (2023-11-15)\n\n- Add &lt;setup&gt; &amp; install ({})\n- Add the intro ({})\n",
+                short(head.id),
+                short(tagged),
+                short(first),
+            )
+        );
+
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .workspace_dir(env!("CARGO_MANIFEST_DIR"))
+            .skip_chapters(true)
+            .changelog_chapter(true)
+            .commit_characters(7)
+            .changelog_path_filter(vec!["setup.*".to_string()])
+            .build();
+        let book = run(&cfg);
+        let BookItem::Chapter(changelog) = book.items.last().expect("no items") else {
+            panic!("the changelog isn't a chapter");
+        };
+        assert_eq!(
+            changelog.content,
+            format!(
+                "# Changelog\n\n## v1.0.0 (2023-11-15)\n\n- Add &lt;setup&gt; &amp; install ({})\n",
+                tagged.to_hex_with_len(7)
+            )
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_whats_new() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo_with_messages(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md", "Add the intro"),
+                (
+                    "Bob",
+                    "bob@example.com",
+                    "setup.md",
+                    "Add <setup> & install",
+                ),
+                (
+                    "Carol",
+                    "carol@example.com",
+                    "Cargo.toml",
+                    "Bump the version",
+                ),
+                ("Alice", "alice@example.com", "intro.md", "Fix a typo"),
+            ],
+        );
+        let repo = gix::open(dir.path()).expect("Failed to open repo");
+        let mut first = repo.head_id().expect("no HEAD").detach();
+        while let Some(parent) = repo
+            .find_commit(first)
+            .expect("Failed to find commit")
+            .parent_ids()
+            .next()
+        {
+            first = parent.detach();
+        }
+        use gix::refs::transaction::PreviousValue::MustNotExist;
+        repo.tag_reference("v1.4.2", first, MustNotExist)
+            .expect("Failed to tag");
+
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .workspace_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .whats_new(true)
+            .whats_new_max(1)
+            .whats_new_paths(vec!["*.md".to_string()])
+            .build();
+        let processor = Processor::default();
+        let run = |book: Book| {
+            processor
+                .run_in(&cfg, dir.path(), dir.path(), book)
+                .expect("run failed")
+        };
+        let section = "<!-- buildtime-annotations:whats-new-start -->\n\n## What&#39;s new since v1.4.2\n\n- Fix a typo\n- …and 1 more\n\n<!-- buildtime-annotations:whats-new-end -->\n";
+        let book = run(testing::book()
+            .chapter(
+                "intro.md",
+                "# Intro\n\n<!-- buildtime-whats-new -->\n\nWelcome.\n",
+            )
+            .chapter("setup.md", "# Setup\n")
+            .build());
+        let content = chapter_content(&book.items[0]).expect("expected a chapter");
+        assert!(
+            content.starts_with(&format!(
+                "# Intro\n\n<!-- buildtime-whats-new -->\n\n{section}\nWelcome.\n"
+            )),
+            "{content}"
+        );
+        assert!(
+            !chapter_content(&book.items[1])
+                .expect("expected a chapter")
+                .contains("whats-new")
+        );
+        // running again replaces it
+        let again = run(book.clone());
+        assert_eq!(again.items, book.items);
+
+        // without the marker it's appended
+        let book = run(testing::book().chapter("intro.md", "# Intro").build());
+        let content = chapter_content(&book.items[0]).expect("expected a chapter");
+        assert!(
+            content.starts_with(&format!("# Intro\n\n{section}")),
+            "{content}"
+        );
+
+        // nothing since the tag, so there's no section, and the one from before is taken out
+        let head = repo.head_id().expect("no HEAD").detach();
+        repo.tag_reference("v1.5.0", head, MustNotExist)
+            .expect("Failed to tag");
+        let book = run(testing::book()
+            .chapter(
+                "intro.md",
+                "# Intro\n\n<!-- buildtime-whats-new -->\n\nWelcome.\n",
+            )
+            .build());
+        let again = run(again);
+        assert_eq!(again.items[0], book.items[0]);
+        assert!(
+            !chapter_content(&book.items[0])
+                .expect("expected a chapter")
+                .contains("whats-new-start")
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_redact_emails() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo_with_messages(
+            dir.path(),
+            &[
+                ("bob@example.com", "bob@example.com", "intro.md", "change"),
+                (
+                    "Alice",
+                    "alice@example.com",
+                    "intro.md",
+                    "Rewrite the intro\n\nReviewed-by: Carol <carol@example.com>\nReviewed-by: dave@example.com\n",
+                ),
+            ],
+        );
+        let render = |cfg: config::ConfigBuilder| {
+            let cfg = cfg
+                .root_dir(dir.path())
+                .workspace_dir(env!("CARGO_MANIFEST_DIR"))
+                .chapter_contributors(true)
+                .trailers(["Reviewed-by"])
+                .build();
+            let book = testing::book().chapter("intro.md", "# Intro\n").build();
+            let book = Processor::default()
+                .run_in(&cfg, dir.path(), dir.path(), book)
+                .expect("run failed");
+            chapter_content(&book.items[0])
+                .expect("not a chapter")
+                .to_string()
+        };
+
+        let content = render(Config::builder());
+        assert!(!content.contains("@example.com"), "{content}");
+        assert!(content.contains(" Carol "), "{content}");
+        assert!(
+            content.contains(" Contributors: Alice</footer>"),
+            "{content}"
+        );
+
+        let content = render(Config::builder().email_redaction(config::EmailRedaction::Hash));
+        assert!(!content.contains("@example.com"), "{content}");
+        assert!(content.contains(" Carol &lt;user-"), "{content}");
+        assert!(content.contains(" Contributors: Alice, user-"), "{content}");
+
+        let content = render(Config::builder().redact_emails(false));
+        assert!(
+            content.contains("Carol &lt;carol@example.com&gt;, dave@example.com"),
+            "{content}"
+        );
+        assert!(
+            content.contains(" Contributors: Alice, bob@example.com</footer>"),
+            "{content}"
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_chapter_created() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let repo = git::GitRepo::open(root).expect("Failed to open repo");
+        let history = repo.history(Path::new("Cargo.toml"), None);
+        let created = format_date(history.last().expect("no history").author_time, "%Y-%m-%d")
+            .expect("Failed to format date");
+        let updated = format_date(history[0].time, "%Y-%m-%d").expect("Failed to format date");
+
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .package_version(false)
+            .format(Format::Markdown)
+            .chapter_created(true)
+            .chapter_dates(true)
+            .build();
+        let mut chapter = Chapter::new("Cargo", "# Cargo\n".to_string(), "unused.md", vec![]);
+        chapter.source_path = Some("Cargo.toml".into());
+        let mut book = Book::new();
+        book.push_item(chapter);
+        let book = Processor::default()
+            .run_in(&cfg, root, root, book)
+            .expect("run failed");
+        let content = book
+            .items
+            .iter()
+            .find_map(chapter_content)
+            .expect("no chapter");
+        assert!(
+            content.contains(&format!(
+                "{} First published {created} Last updated {updated}\n",
+                env!("CARGO_PKG_NAME")
+            )),
+            "{content}"
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_chapter_history_bounds() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "old.md"),
+                ("Bob", "bob@example.com", "other.md"),
+                ("Carol", "carol@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "other.md"),
+            ],
+        );
+        let footers = |cfg: &Config| {
+            let mut book = Book::new();
+            for path in ["intro.md", "old.md"] {
+                let mut chapter = Chapter::new("Chapter", "# Chapter\n".to_string(), path, vec![]);
+                chapter.source_path = Some(path.into());
+                book.push_item(chapter);
+            }
+            let book = Processor::default()
+                .with_providers(vec![Box::new(provider::GitProvider)])
+                .run_in(cfg, dir.path(), dir.path(), book)
+                .expect("run failed");
+            // everything after the commit
+            book.items
+                .iter()
+                .filter_map(chapter_content)
+                .map(|content| {
+                    let footer = content[content.rfind("\n\n").expect("no footer")..]
+                        .trim_end_matches(ANNOTATION_END)
+                        .trim();
+                    footer
+                        .split_once(' ')
+                        .map_or("", |(_, rest)| rest)
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        let builder = Config::builder()
+            .root_dir(dir.path())
+            .format(Format::Markdown)
+            .chapter_dates(true)
+            .chapter_dates_fallback("Unreleased")
+            .chapter_created(true)
+            .chapter_revisions(true)
+            .chapter_contributors(true);
+
+        let cfg = builder.clone().build();
+        assert_eq!(
+            footers(&cfg),
+            [
+                "First published 2023-11-14 Last updated 2023-11-17 rev 2 Contributors: Alice, Carol",
+                "First published 2023-11-15 Last updated 2023-11-15 rev 1 Contributors: Bob",
+            ]
+        );
+        let approximate = [
+            "First published 2023-11-17 or earlier Last updated 2023-11-17 rev 1+ Contributors: Carol, …",
+            "",
+        ];
+        assert_eq!(
+            footers(&builder.clone().history_limit(3).build()),
+            approximate
+        );
+        assert_eq!(
+            footers(&builder.clone().history_since("2023-11-17").build()),
+            approximate
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_chapter_date_relative() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .package_version(false)
+            .format(Format::Markdown)
+            .chapter_dates(true)
+            .chapter_created(true)
+            .chapter_date_style(DateStyle::Relative)
+            .build();
+        let mut chapter = Chapter::new("Cargo", "# Cargo\n".to_string(), "unused.md", vec![]);
+        chapter.source_path = Some("Cargo.toml".into());
+        let mut book = Book::new();
+        book.push_item(chapter);
+        let book = Processor::default()
+            .run_in(&cfg, root, root, book)
+            .expect("run failed");
+        let content = book
+            .items
+            .iter()
+            .find_map(chapter_content)
+            .expect("no chapter");
+        let footer = content
+            .lines()
+            .find(|line| line.starts_with(env!("CARGO_PKG_NAME")))
+            .expect("no footer");
+        let (created, updated) = footer
+            .split_once(" Updated ")
+            .expect("no relative last updated date");
+        assert!(created.contains(" First published "), "{footer}");
+        assert!(
+            created.ends_with(" ago") || created.ends_with("just now"),
+            "{footer}"
+        );
+        assert!(
+            updated.ends_with(" ago") || updated == "just now",
+            "{footer}"
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_chapter_date_link() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let repo = git::GitRepo::open(root).expect("Failed to open repo");
+        let last = &repo.history(Path::new("Cargo.toml"), Some(1))[0];
+        let date = format_date(last.time, "%Y-%m-%d").expect("Failed to format date");
+        let url = format!("https://example.com/commit/{}?a=1&b=2", last.id);
+
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .package_version(false)
+            .chapter_dates(true)
+            .commit_url_template("https://example.com/commit/{commit}?a=1&b=2")
+            // links always use the lowercase hash
+            .hash_case(HashCase::Upper)
+            .build();
+        for (format, expected) in [
+            (
+                Format::Html,
+                format!(
+                    "<a href=\"{}\">Last updated {date}</a></footer>",
+                    url.replace('&', "&amp;")
+                ),
+            ),
+            (
+                Format::Markdown,
+                format!("[Last updated {date}](<{url}>)\n"),
+            ),
+        ] {
+            let mut chapter = Chapter::new("Cargo", "# Cargo\n".to_string(), "unused.md", vec![]);
+            chapter.source_path = Some("Cargo.toml".into());
+            let mut book = Book::new();
+            book.push_item(chapter);
+            let book = Processor::default()
+                .run_in(
+                    &Config {
+                        format,
+                        ..cfg.clone()
+                    },
+                    root,
+                    root,
+                    book,
+                )
+                .expect("run failed");
+            let content = book
+                .items
+                .iter()
+                .find_map(chapter_content)
+                .expect("no chapter");
+            assert!(content.contains(&expected), "{content}");
+        }
+    }
+
+    #[test]
+    fn test_chapter_url() {
+        let template = "https://github.com/org/repo/edit/{branch}/docs/src/{path}";
+        assert_eq!(
+            chapter_url(
+                template,
+                Some("main"),
+                Path::new("guide/getting started.md")
+            )
+            .as_deref(),
+            Some("https://github.com/org/repo/edit/main/docs/src/guide/getting%20started.md")
+        );
+        assert_eq!(
+            chapter_url(template, Some("feature/docs"), Path::new("über\\naïve.md")).as_deref(),
+            Some("https://github.com/org/repo/edit/feature/docs/docs/src/%C3%BCber/na%C3%AFve.md")
+        );
+        assert_eq!(chapter_url(template, None, Path::new("intro.md")), None);
+        assert_eq!(
+            chapter_url("https://example.com/{path}", None, Path::new("a&b.md")).as_deref(),
+            Some("https://example.com/a%26b.md")
+        );
+    }
+
+    #[test]
+    fn test_changelog_link() {
+        let annotation = Annotation {
+            name: Some("example".to_string()),
+            version: Some("1.4.2".to_string()),
+            ..Default::default()
+        };
+        let cfg = Config::builder()
+            .changelog_url("https://example.com/CHANGELOG.md")
+            .changelog_anchor_template("#v{version}")
+            .build();
+        assert_eq!(
+            annotation.footer(&cfg).as_deref(),
+            Some(
+                "<footer id=\"buildtime-annotations\">example v1.4.2 <a href=\"https://example.com/CHANGELOG.md#v1.4.2\">Changelog</a></footer>"
+            )
+        );
+
+        let cfg = Config::builder()
+            .format(Format::Markdown)
+            .changelog_url(" ../changelog.md ")
+            .build();
+        assert_eq!(
+            annotation.footer(&cfg).as_deref(),
+            Some("\n\nexample v1.4.2 [Changelog](<../changelog.md>)\n")
+        );
+        assert_eq!(
+            changelog_url(&cfg, None).as_deref(),
+            Some("../changelog.md")
+        );
+
+        for blank in ["", "  "] {
+            let cfg = Config::builder()
+                .changelog_url(blank)
+                .changelog_anchor_template("#v{version}")
+                .build();
+            assert_eq!(changelog_url(&cfg, Some("1.4.2")), None);
+        }
+        let cfg = Config::builder()
+            .changelog_url("changelog.md")
+            .changelog_anchor_template(" ")
+            .build();
+        assert_eq!(
+            changelog_url(&cfg, Some("1.4.2")).as_deref(),
+            Some("changelog.md")
+        );
+    }
+
+    /// Checks `fragment` is well-formed XML, which is what an EPUB's XHTML has to be: every tag closed in order, attribute values quoted, only XML's own entities and no link inside another.
+    fn assert_well_formed_xhtml(fragment: &str) {
+        let mut open = Vec::new();
+        let mut rest = fragment;
+        while let Some(start) = rest.find(['<', '&']) {
+            rest = &rest[start..];
+            if let Some(comment) = rest.strip_prefix("<!--") {
+                let end = comment.find("-->").expect("unclosed comment");
+                rest = &comment[end + 3..];
+                continue;
+            }
+            if let Some(entity) = rest.strip_prefix('&') {
+                let end = entity.find(';').expect("unterminated entity");
+                let name = &entity[..end];
+                assert!(
+                    matches!(name, "amp" | "lt" | "gt" | "quot" | "apos")
+                        || name
+                            .strip_prefix('#')
+                            .is_some_and(|n| n.parse::<u32>().is_ok()),
+                    "&{name}; isn't an XML entity in {fragment}"
+                );
+                rest = &entity[end + 1..];
+                continue;
+            }
+            let end = rest.find('>').expect("unclosed tag");
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(open.pop(), Some(name), "mismatched </{name}> in {fragment}");
+                continue;
+            }
+            let (tag, empty) = match tag.strip_suffix('/') {
+                Some(tag) => (tag, true),
+                None => (tag, false),
+            };
+            let (name, mut attributes) = tag.split_once(' ').unwrap_or((tag, ""));
+            while let Some((attribute, value)) = attributes.trim_start().split_once('=') {
+                assert!(
+                    !attribute.is_empty() && !attribute.contains(' '),
+                    "{fragment}"
+                );
+                let value = value.strip_prefix('"').expect("unquoted attribute");
+                let end = value.find('"').expect("unterminated attribute");
+                assert!(!value[..end].contains('<'), "{fragment}");
+                attributes = &value[end + 1..];
+            }
+            assert!(attributes.trim().is_empty(), "{fragment}");
+            assert!(
+                name != "a" || !open.contains(&"a"),
+                "nested link in {fragment}"
+            );
+            if !empty {
+                open.push(name);
+            }
+        }
+        assert!(open.is_empty(), "unclosed {open:?} in {fragment}");
+    }
+
+    #[test]
+    fn test_epub_format() {
+        let annotation = Annotation {
+            name: Some("<b>demo</b>".to_string()),
+            version: Some("1.0.0".to_string()),
+            commit: Some("abcdef1234".to_string()),
+            extras: vec![("note".to_string(), "Q&A's \"quoted\"".to_string())],
+            ..Default::default()
+        };
+        let cfg = Config::builder()
+            .format(Format::Epub)
+            .changelog_url("https://example.com/CHANGELOG.md?a=1&b=2")
+            .build();
+        let footer = annotation.footer(&cfg).expect("expected a footer");
+        assert_eq!(
+            footer,
+            "<div class=\"buildtime-annotations\">&lt;b&gt;demo&lt;/b&gt; @abcdef1234 v1.0.0 Q&amp;A&#39;s &quot;quoted&quot; <a href=\"https://example.com/CHANGELOG.md?a=1&amp;b=2\">Changelog</a></div>"
+        );
+        assert_well_formed_xhtml(&footer);
+
+        // the whole annotation a chapter gets when building for the epub renderer
+        let ctx = testing::context()
+            .root(env!("CARGO_MANIFEST_DIR"))
+            .renderer("epub")
+            .set("root_dir", env!("CARGO_MANIFEST_DIR"))
+            .set("chapter_dates", true)
+            .set("chapter_contributors", true)
+            .set("commit_url_template", "https://example.com/commit/{commit}")
+            .set("edit_link", true)
+            .set(
+                "edit_url_template",
+                "https://example.com/edit/{branch}/{path}",
+            )
+            .set("edit_branch", "main")
+            .set("changelog_url", "CHANGELOG.md")
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+        let processed = Processor::default().run(&ctx, book).expect("run failed");
+        let content = chapter_content(&processed.items[0]).expect("expected a chapter");
+        let annotation = &content[content.find(ANNOTATION_START).expect("no annotation")..];
+        assert!(annotation.contains("<div class=\"buildtime-annotations\">"));
+        assert!(annotation.contains("Edit this page"));
+        assert!(!annotation.contains("<footer"));
+        assert_well_formed_xhtml(annotation);
+    }
+
+    #[test]
+    fn test_edit_link() {
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .package_version(false)
+            .edit_link(true)
+            .edit_url_template("https://example.com/edit/{branch}/src/{path}")
+            .edit_branch("main")
+            .build();
+        let mut book = Book::new();
+        for (name, path) in [("Intro", Some("intro.md")), ("Generated", None)] {
+            let mut chapter = Chapter::new(name, format!("# {name}\n"), "unused.md", vec![]);
+            chapter.source_path = path.map(PathBuf::from);
+            book.push_item(chapter);
+        }
+        let book = Processor::default()
+            .run_with_config(&cfg, Path::new(env!("CARGO_MANIFEST_DIR")), book)
+            .expect("run failed");
+        let contents: Vec<&str> = book.items.iter().filter_map(chapter_content).collect();
+        assert!(contents[0].contains(&format!(
+            "<footer id=\"buildtime-annotations\">{} <a href=\"https://example.com/edit/main/src/intro.md\">Edit this page</a></footer>",
+            env!("CARGO_PKG_NAME")
+        )));
+        assert!(!contents[1].contains("Edit this page"));
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_history_link() {
+        let footer = |remote: Option<&str>, cfg: config::ConfigBuilder| {
+            let dir = tempfile::tempdir().expect("Failed to create tempdir");
+            git::tests::fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+            if let Some(remote) = remote {
+                let mut git_config = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(dir.path().join(".git/config"))
+                    .expect("Failed to open git config");
+                writeln!(git_config, "[remote \"origin\"]\n\turl = {remote}")
+                    .expect("Failed to write git config");
+            }
+            let cfg = cfg
+                .root_dir(dir.path())
+                .workspace_dir(env!("CARGO_MANIFEST_DIR"))
+                .git_commit(false)
+                .package_version(false)
+                .edit_branch("main")
+                .history_link(true)
+                .build();
+            let mut book = testing::book()
+                .chapter("guide/getting started.md", "# Getting started\n")
+                .build();
+            let mut generated = Chapter::new("Generated", "# Generated\n".to_string(), "", vec![]);
+            generated.source_path = None;
+            book.push_item(generated);
+            let book = Processor::default()
+                .run_in(&cfg, dir.path(), dir.path(), book)
+                .expect("run failed");
+            let contents: Vec<&str> = book.items.iter().filter_map(chapter_content).collect();
+            assert!(!contents[1].contains("history"), "{}", contents[1]);
+            let content = contents[0];
+            content[content.find("<footer").expect("no footer")..]
+                .trim_end_matches(ANNOTATION_END)
+                .trim()
+                .to_string()
+        };
+        let link = |url: &str, text: &str| {
+            format!(
+                "<footer id=\"buildtime-annotations\">{} <a href=\"{url}\">{text}</a></footer>",
+                env!("CARGO_PKG_NAME")
+            )
+        };
+        for (remote, url) in [
+            (
+                "git@github.com:org/repo.git",
+                "https://github.com/org/repo/commits/main/guide/getting%20started.md",
+            ),
+            (
+                "https://gitlab.com/org/repo.git",
+                "https://gitlab.com/org/repo/-/commits/main/guide/getting%20started.md",
+            ),
+            (
+                "https://codeberg.org/org/repo.git",
+                "https://codeberg.org/org/repo/commits/branch/main/guide/getting%20started.md",
+            ),
+        ] {
+            assert_eq!(
+                footer(Some(remote), Config::builder()),
+                link(url, "View history"),
+                "{remote}"
+            );
+        }
+        assert_eq!(
+            footer(
+                Some("git@github.com:org/repo.git"),
+                Config::builder()
+                    .history_url_template("https://example.com/log/{branch}/docs/{path}")
+                    .history_link_text("Changes & fixes")
+            ),
+            link(
+                "https://example.com/log/main/docs/guide/getting%20started.md",
+                "Changes &amp; fixes"
+            )
+        );
+        // a forge we don't know the pages of
+        assert!(
+            !footer(
+                Some("https://git.example.com/org/repo.git"),
+                Config::builder()
+            )
+            .contains("<a")
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_codeowners() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        let src_dir = dir.path().join("docs");
+        std::fs::create_dir_all(dir.path().join(".github")).expect("Failed to create dir");
+        std::fs::create_dir_all(&src_dir).expect("Failed to create dir");
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .workspace_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .package_version(false)
+            .codeowners(true)
+            .build();
+        let run = || {
+            let book = testing::book()
+                .chapter("intro.md", "# Intro\n")
+                .chapter("guide/setup.md", "# Setup\n")
+                .chapter("drafts.md", "# Drafts\n")
+                .build();
+            let book = Processor::default()
+                .run_in(&cfg, dir.path(), &src_dir, book)
+                .expect("run failed");
+            book.items
+                .iter()
+                .filter_map(chapter_content)
+                .map(|content| {
+                    let footer = content
+                        .find("<footer")
+                        .map_or("", |start| &content[start..]);
+                    footer.find("Owners: ").map(|start| {
+                        let owners = &footer[start..];
+                        owners[..owners.find("</footer>").expect("no end")].to_string()
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        // no CODEOWNERS file
+        assert_eq!(run(), [None, None, None]);
+
+        std::fs::write(
+            dir.path().join(".github/CODEOWNERS"),
+            "* @org/everyone\n/docs/guide/ @alice bob@example.com\n/docs/drafts.md\n",
+        )
+        .expect("Failed to write CODEOWNERS");
+        // the one in the top of the repository isn't read when there's one in .github/
+        std::fs::write(dir.path().join("CODEOWNERS"), "* @someone-else\n")
+            .expect("Failed to write CODEOWNERS");
+        assert_eq!(
+            run(),
+            [
+                Some("Owners: @org/everyone".to_string()),
+                Some("Owners: @alice".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_branches() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        let branch = git::GitRepo::open(dir.path())
+            .expect("Failed to open repo")
+            .head_branch()
+            .expect("HEAD should be on a branch");
+        let book = || testing::book().chapter("intro.md", "# Intro\n").build();
+        let run = |cfg: config::ConfigBuilder| {
+            Processor::default()
+                .run_with_config(
+                    &cfg.git_dir(dir.path())
+                        .workspace_dir(env!("CARGO_MANIFEST_DIR"))
+                        .build(),
+                    dir.path(),
+                    book(),
+                )
+                .expect("run failed")
+        };
+
+        assert_ne!(run(Config::builder().branches([branch.as_str()])), book());
+        assert_ne!(run(Config::builder().branches(["release/*", "*"])), book());
+        assert_eq!(run(Config::builder().branches(["release/*"])), book());
+        assert_eq!(
+            run(Config::builder()
+                .branches([branch.as_str()])
+                .branches_invert(true)),
+            book()
+        );
+        assert_ne!(
+            run(Config::builder()
+                .branches(["release/*"])
+                .branches_invert(true)),
+            book()
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_release_link() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        let repo = gix::open(dir.path()).expect("Failed to open repo");
+        let head = repo.head_id().expect("no HEAD").detach();
+        repo.tag_reference(
+            "v1.4.2",
+            head,
+            gix::refs::transaction::PreviousValue::MustNotExist,
+        )
+        .expect("Failed to tag");
+        let mut git_config = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(".git/config"))
+            .expect("Failed to open git config");
+        writeln!(
+            git_config,
+            "[remote \"origin\"]\n\turl = git@github.com:org/repo.git"
+        )
+        .expect("Failed to write git config");
+
+        let footer = |cfg: &Config, version: &str| {
+            std::fs::write(
+                dir.path().join("Cargo.toml"),
+                format!("[package]\nname = \"example\"\nversion = \"{version}\"\n"),
+            )
+            .expect("Failed to write Cargo.toml");
+            let mut book = Book::new();
+            book.push_item(Chapter::new(
+                "Intro",
+                "# Intro\n".to_string(),
+                "intro.md",
+                vec![],
+            ));
+            let book = Processor::default()
+                .run_with_config(cfg, dir.path(), book)
+                .expect("run failed");
+            let content = book
+                .items
+                .iter()
+                .find_map(chapter_content)
+                .expect("no chapter")
+                .to_string();
+            content[content.find("<footer").expect("no footer")..]
+                .trim_end_matches(ANNOTATION_END)
+                .trim()
+                .to_string()
+        };
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .git_commit(false)
+            .build();
+        assert_eq!(
+            footer(&cfg, "1.4.2"),
+            "<footer id=\"buildtime-annotations\">example <a href=\"https://github.com/org/repo/releases/tag/v1.4.2\">v1.4.2</a></footer>"
+        );
+        assert_eq!(
+            footer(
+                &Config {
+                    release_url_template: Some("https://git.example.com/r/{tag}".to_string()),
+                    ..cfg.clone()
+                },
+                "1.4.2"
+            ),
+            "<footer id=\"buildtime-annotations\">example <a href=\"https://git.example.com/r/v1.4.2\">v1.4.2</a></footer>"
+        );
+        let unlinked = "<footer id=\"buildtime-annotations\">example v1.4.2</footer>";
+        assert_eq!(
+            footer(
+                &Config {
+                    release_link: false,
+                    ..cfg.clone()
+                },
+                "1.4.2"
+            ),
+            unlinked
+        );
+        // there's no tag for this version
+        assert_eq!(
+            footer(&cfg, "1.5.0"),
+            "<footer id=\"buildtime-annotations\">example v1.5.0</footer>"
+        );
+    }
+
+    #[test]
+    fn test_theme_partial() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .format(Format::Markdown)
+            .write_theme_partial("theme/buildinfo.hbs")
+            .skip_chapters(true)
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+
+        let partial = dir.path().join("theme").join("buildinfo.hbs");
+        let processed = Processor::default()
+            .run_with_config(&cfg, dir.path(), book.clone())
+            .expect("run failed");
+        assert_eq!(processed, book);
+        let written = std::fs::read_to_string(&partial).expect("Failed to read partial");
+        assert_eq!(
+            written,
+            format!(
+                "<footer id=\"buildtime-annotations\">{} v{}</footer>\n",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+
+        // an unchanged partial isn't touched, so `mdbook serve` doesn't see a change
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(86_400);
+        std::fs::File::options()
+            .write(true)
+            .open(&partial)
+            .and_then(|file| file.set_modified(modified))
+            .expect("Failed to set mtime");
+        Processor::default()
+            .run_with_config(&cfg, dir.path(), book)
+            .expect("run failed");
+        assert_eq!(
+            std::fs::read_to_string(&partial).expect("Failed to read partial"),
+            written
+        );
+        assert_eq!(
+            std::fs::metadata(&partial)
+                .and_then(|meta| meta.modified())
+                .expect("Failed to read mtime"),
+            modified
+        );
+    }
+
+    #[test]
+    fn test_write_js() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .write_js("theme/buildinfo.js")
+            .skip_chapters(true)
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+        let processed = Processor::default()
+            .run_with_config(&cfg, dir.path(), book.clone())
+            .expect("run failed");
+        assert_eq!(processed, book);
+        let script = std::fs::read_to_string(dir.path().join("theme").join("buildinfo.js"))
+            .expect("Failed to read the script");
+        assert!(
+            script.starts_with("window.MDBOOK_BUILD_INFO = {"),
+            "{script}"
+        );
+        assert!(
+            script.contains(&format!("\"version\": \"{}\"", env!("CARGO_PKG_VERSION"))),
+            "{script}"
+        );
+    }
+
+    #[test]
+    fn test_run_with_config() {
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+        let book = Processor::default()
+            .run_with_config(&cfg, Path::new(env!("CARGO_MANIFEST_DIR")), book)
+            .expect("run failed");
+        let content = chapter_content(&book.items[0]).expect("expected a chapter");
+        assert!(content.contains(&format!(
+            "<footer id=\"buildtime-annotations\">{} v{}</footer>",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    #[test]
+    fn test_build_footer() {
+        let annotation = Annotation {
+            name: Some("<b>demo</b>".to_string()),
+            version: Some("1.0.0".to_string()),
+            extras: vec![("label".to_string(), "Q&A's".to_string())],
+            ..Default::default()
+        };
+        let cfg = Config::builder().git_commit(false).build();
+        assert_eq!(
+            build_footer(&cfg, &annotation),
+            "<footer id=\"buildtime-annotations\">&lt;b&gt;demo&lt;/b&gt; v1.0.0 Q&amp;A&#39;s</footer>"
+        );
+
+        let cfg = Config::builder()
+            .git_commit(false)
+            .package_name(false)
+            .format(Format::Markdown)
+            .build();
+        assert_eq!(
+            build_footer(&cfg, &annotation),
+            "\n\nv1.0.0 Q&amp;A&#39;s\n"
+        );
+        assert_eq!(
+            annotation.footer(&cfg),
+            Some(build_footer(&cfg, &annotation))
+        );
+
+        assert_eq!(build_footer(&cfg, &Annotation::default()), "\n\n\n");
+        assert_eq!(Annotation::default().footer(&cfg), None);
+    }
+
+    #[test]
+    fn test_truncate_markup() {
+        assert_eq!(truncate_markup("abcdef", 3, "…"), "abc…");
+        assert_eq!(truncate_markup("abc", 3, "…"), "abc");
+        assert_eq!(truncate_markup("ab", 3, "…"), "ab");
+        assert_eq!(truncate_markup("héllo wörld", 4, "…"), "héll…");
+        assert_eq!(truncate_markup("wörld", 2, "..."), "wö...");
+        assert_eq!(truncate_markup("🦀🦀🦀", 2, "…"), "🦀🦀…");
+        assert_eq!(truncate_markup("日本語のテキスト", 3, "…"), "日本語…");
+        assert_eq!(truncate_markup("a&amp;b&lt;c", 2, "…"), "a&amp;…");
+        assert_eq!(truncate_markup("a&amp;b", 3, "…"), "a&amp;b");
+        assert_eq!(truncate_markup("&#39;quoted&#39;", 1, "…"), "&#39;…");
+        assert_eq!(truncate_markup("a & b;", 3, "…"), "a &…");
+        assert_eq!(truncate_markup("abc", 0, "…"), "…");
+    }
+
+    #[test]
+    fn test_field_max_length() {
+        let annotation = Annotation {
+            name: Some("example".to_string()),
+            version: Some("1.2.3".to_string()),
+            commit: Some("abcdef1234".to_string()),
+            extras: vec![
+                (
+                    "trailer.Ticket".to_string(),
+                    "DOC-1234 & DOC-5678".to_string(),
+                ),
+                (
+                    "docs".to_string(),
+                    "https://example.com/a/long/path".to_string(),
+                ),
+            ],
+            ..Default::default()
+        };
+        let cfg = Config::builder().max_field_length(6).build();
+        assert_eq!(
+            annotation.render(&cfg),
+            "exampl… @abcdef1234 v1.2.3 DOC-12… https://example.com/a/long/path"
+        );
+        assert_eq!(
+            annotation.footer_markup(&cfg, None),
+            Some("exampl… @abcdef1234 v1.2.3 DOC-12… https://example.com/a/long/path".to_string())
+        );
+
+        let cfg = Config::builder()
+            .max_field_length(10)
+            .field_max_length("commit", 4)
+            .field_max_length("trailer.Ticket", 10)
+            .ellipsis("<...>")
+            .build();
+        assert_eq!(
+            annotation.footer_markup(&cfg, None),
+            Some(
+                "example @abc&lt;...&gt; v1.2.3 DOC-1234 &amp;&lt;...&gt; https://example.com/a/long/path"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_annotation_render() {
+        let annotation = Annotation {
+            name: Some("example".to_string()),
+            version: Some("1.2.3".to_string()),
+            commit: Some("abcdef1234".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            annotation.render(&Config::default()),
+            "example @abcdef1234 v1.2.3"
+        );
+        assert_eq!(
+            annotation.render(&Config::builder().package_name(false).build()),
+            "@abcdef1234 v1.2.3"
+        );
+        assert_eq!(
+            annotation.render(&Config::builder().git_commit(false).build()),
+            "example v1.2.3"
+        );
+        assert_eq!(
+            annotation.render(&Config::builder().package_version(false).build()),
+            "example @abcdef1234"
+        );
+        assert_eq!(
+            annotation.render(
+                &Config::builder()
+                    .package_name(false)
+                    .package_version(false)
+                    .build()
+            ),
+            "@abcdef1234"
+        );
+        assert_eq!(
+            annotation.render(
+                &Config::builder()
+                    .package_name(false)
+                    .package_version(false)
+                    .git_commit(false)
+                    .build()
+            ),
+            ""
+        );
+
+        let annotation = Annotation {
+            commit: None,
+            ..annotation
+        };
+        assert_eq!(annotation.render(&Config::default()), "example v1.2.3");
+        assert_eq!(Annotation::default().render(&Config::default()), "");
+    }
+
+    #[test]
+    fn test_code_segment() {
+        let annotation = Annotation {
+            name: Some("handbook".to_string()),
+            version: Some("1.0.0".to_string()),
+            commit: Some("ab12cd".to_string()),
+            code: Some(CodeAnnotation {
+                name: Some("engine".to_string()),
+                version: Some("2.3.1".to_string()),
+                commit: Some("ff9912".to_string()),
+                commit_id: Some("ff9912abcdef".to_string()),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            annotation.render(&Config::default()),
+            "handbook @ab12cd v1.0.0 · engine @ff9912 v2.3.1"
+        );
+        assert_eq!(
+            annotation.render(
+                &Config::builder()
+                    .book_label("docs")
+                    .code_label("Engine")
+                    .segment_separator(" | ")
+                    .build()
+            ),
+            "docs @ab12cd v1.0.0 | Engine @ff9912 v2.3.1"
+        );
+        // the labels are shown even when the names aren't
+        assert_eq!(
+            annotation.render(
+                &Config::builder()
+                    .package_name(false)
+                    .package_version(false)
+                    .book_label("docs")
+                    .build()
+            ),
+            "docs @ab12cd · @ff9912"
+        );
+        let cfg = Config::builder().segment_separator(" <> ").build();
+        assert_eq!(
+            build_footer(&cfg, &annotation),
+            "<footer id=\"buildtime-annotations\">handbook @ab12cd v1.0.0 &lt;&gt; engine @ff9912 v2.3.1</footer>"
+        );
+
+        // either side can be missing without the other going too
+        let code_only = Annotation {
+            name: None,
+            version: None,
+            commit: None,
+            ..annotation.clone()
+        };
+        assert_eq!(
+            code_only.render(&Config::default()),
+            "engine @ff9912 v2.3.1"
+        );
+        let book_only = Annotation {
+            code: Some(CodeAnnotation::default()),
+            ..annotation.clone()
+        };
+        assert_eq!(
+            book_only.render(&Config::default()),
+            "handbook @ab12cd v1.0.0"
+        );
+        let json = serde_json::to_value(&annotation).expect("Failed to serialize annotation");
+        assert_eq!(json["code"]["version"], "2.3.1");
+        let json = serde_json::to_value(Annotation::default()).expect("Failed to serialize");
+        assert!(json.get("code").is_none());
+    }
+
+    #[test]
+    fn test_hash_display() {
+        let annotation = Annotation {
+            name: Some("example".to_string()),
+            commit: Some("abcdef1234".to_string()),
+            ..Default::default()
+        };
+        for (case, prefix, expected) in [
+            (HashCase::Lower, "@", "example @abcdef1234"),
+            (HashCase::Lower, "g", "example gabcdef1234"),
+            (HashCase::Lower, "", "example abcdef1234"),
+            (HashCase::Upper, "@", "example @ABCDEF1234"),
+            (HashCase::Upper, "g", "example gABCDEF1234"),
+            (HashCase::Upper, "", "example ABCDEF1234"),
+        ] {
+            let cfg = Config::builder()
+                .package_version(false)
+                .hash_case(case)
+                .hash_prefix(prefix)
+                .build();
+            assert_eq!(annotation.render(&cfg), expected);
+            assert_eq!(
+                build_footer(&cfg, &annotation),
+                format!("<footer id=\"buildtime-annotations\">{expected}</footer>")
+            );
+        }
+        // the prefix is escaped like the rest of the annotation
+        let cfg = Config::builder()
+            .package_name(false)
+            .package_version(false)
+            .hash_prefix("<")
+            .build();
+        assert_eq!(
+            build_footer(&cfg, &annotation),
+            "<footer id=\"buildtime-annotations\">&lt;abcdef1234</footer>"
+        );
+    }
+
+    #[test]
+    fn test_template() {
+        let annotation = Annotation {
+            name: Some("example".to_string()),
+            version: Some("1.4.2".to_string()),
+            commit: Some("abcdef1234".to_string()),
+            branch: Some("feature/<x>".to_string()),
+            extras: vec![("trailer.Ticket".to_string(), "DOC-7".to_string())],
+            ..Default::default()
+        };
+        for (template, expected) in [
+            ("{name} v{version}", "example v1.4.2"),
+            (
+                "PREVIEW BUILD {branch} @{commit}",
+                "PREVIEW BUILD feature/&lt;x&gt; @ABCDEF1234",
+            ),
+            ("{trailer.Ticket} {trailer.Reviewed-by}|", "DOC-7 |"),
+            ("{commit_date}|{code.name}|", "||"),
+            // not fields
+            ("{nope} {version", "{nope} {version"),
+            ("<{name}>", "&lt;example&gt;"),
+        ] {
+            let cfg = Config::builder()
+                .template(template)
+                .hash_case(HashCase::Upper)
+                .build();
+            assert_eq!(
+                build_footer(&cfg, &annotation),
+                format!("<footer id=\"buildtime-annotations\">{expected}</footer>"),
+                "{template}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_env_allowlist() {
+        let annotation = Annotation {
+            name: Some("example".to_string()),
+            ..Default::default()
+        };
+        let render = |cfg: config::ConfigBuilder| {
+            let cfg = cfg
+                .template("{name} [{env.PATH}] [{env.CARGO_PKG_NAME}]")
+                .build();
+            annotation.render(&cfg)
+        };
+        // cargo sets both of these for the tests, but neither is allowed by default
+        assert!(std::env::var("PATH").is_ok());
+        assert_eq!(render(Config::builder()), "example [] []");
+        assert_eq!(
+            render(Config::builder().env_allowlist(["CARGO_PKG_*"])),
+            format!("example [] [{}]", env!("CARGO_PKG_NAME"))
+        );
+    }
+
+    #[test]
+    fn test_prerelease_banner() {
+        let cfg = Config::builder().prerelease_banner(true).build();
+        assert_eq!(
+            prerelease_banner(&cfg, Some("2.0.0-alpha.3")).as_deref(),
+            Some(
+                "<div class=\"buildtime-prerelease\">You are reading documentation for an unreleased version (2.0.0-alpha.3).</div>"
+            )
+        );
+        assert_eq!(prerelease_banner(&cfg, Some("2.0.0")), None);
+        assert_eq!(prerelease_banner(&cfg, Some("2.0.0+build.7")), None);
+        assert_eq!(prerelease_banner(&cfg, Some("2.0")), None);
+        assert_eq!(prerelease_banner(&cfg, None), None);
+        assert_eq!(
+            prerelease_banner(&Config::default(), Some("2.0.0-alpha.3")),
+            None
+        );
+        let cfg = Config::builder()
+            .prerelease_banner(true)
+            .prerelease_banner_template("<{version}> preview")
+            .format(Format::Markdown)
+            .build();
+        assert_eq!(
+            prerelease_banner(&cfg, Some("1.0.0-rc.1")).as_deref(),
+            Some("&lt;1.0.0-rc.1&gt; preview")
+        );
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let run = |version: &str, book: Book| {
+            std::fs::write(
+                dir.path().join("Cargo.toml"),
+                format!("[package]\nname = \"example\"\nversion = \"{version}\"\n"),
+            )
+            .expect("Failed to write Cargo.toml");
+            let cfg = Config::builder()
+                .workspace_dir(dir.path())
+                .git_commit(false)
+                .prerelease_banner(true)
+                .max_depth(1)
+                .build();
+            Processor::default()
+                .run_with_config(&cfg, dir.path(), book)
+                .expect("run failed")
+        };
+        let book = testing::book()
+            .chapter("intro.md", "# Intro\n")
+            .sub_chapter("intro/details.md", "# Details\n")
+            .build();
+
+        let book = run("2.0.0-alpha.3", book);
+        let book = run("2.0.0-alpha.4", book);
+        let contents: Vec<&str> = book.iter().filter_map(chapter_content).collect();
+        assert!(
+            contents[0].starts_with(&format!(
+                "{BANNER_START}<div class=\"buildtime-prerelease\">You are reading documentation for an unreleased version (2.0.0-alpha.4).</div>{BANNER_END}\n\n# Intro\n"
+            )),
+            "{}",
+            contents[0]
+        );
+        assert_eq!(contents[0].matches(BANNER_START).count(), 1);
+        // excluded by max_depth
+        assert_eq!(contents[1], "# Details\n");
+
+        let book = run("2.0.0", book);
+        let contents: Vec<&str> = book.iter().filter_map(chapter_content).collect();
+        assert!(contents[0].starts_with("# Intro\n"), "{}", contents[0]);
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_deterministic() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.0.0\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .chapter_dates(true)
+            .chapter_date_style(DateStyle::Relative)
+            .write_build_info("build-info.json")
+            .report_file("report.json")
+            .template("{name} [{env.GITHUB_RUN_ID}] [{env.CARGO_PKG_NAME}]")
+            .env_allowlist(["CARGO_PKG_NAME"])
+            .deterministic(true)
+            .build();
+        let run = || {
+            let book = testing::book().chapter("intro.md", "# Intro\n").build();
+            let book = Processor::default()
+                .run_in(&cfg, dir.path(), dir.path(), book)
+                .expect("run failed");
+            let read = |file: &str| {
+                std::fs::read_to_string(dir.path().join(file)).expect("Failed to read output")
+            };
+            (
+                serde_json::to_string(&book).expect("Failed to serialize book"),
+                read("build-info.json"),
+                read("report.json"),
+            )
+        };
+
+        let first = run();
+        assert_eq!(run(), first);
+        assert!(first.0.contains("Last updated 2023-11-14"), "{}", first.0);
+        assert!(!first.0.contains("ago"), "{}", first.0);
+        // the environment variables are left out, even allowed ones cargo sets
+        assert!(first.0.contains("example [] []"), "{}", first.0);
+        let annotation = Annotation {
+            name: Some("example".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            annotation.render(&Config {
+                deterministic: false,
+                ..cfg.clone()
+            }),
+            format!("example [] [{}]", env!("CARGO_PKG_NAME"))
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_dirty_banner() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        git::tests::checkout(dir.path());
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"2.0.0-beta.1\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let first_line = |cfg: config::ConfigBuilder| {
+            let book = testing::book().chapter("intro.md", "# Intro\n").build();
+            let book = Processor::default()
+                .run_with_config(&cfg.root_dir(dir.path()).build(), dir.path(), book)
+                .expect("run failed");
+            chapter_content(&book.items[0])
+                .expect("not a chapter")
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        // the untracked Cargo.toml doesn't count
+        assert_eq!(first_line(Config::builder().dirty_banner(true)), "# Intro");
+
+        std::fs::write(dir.path().join("intro.md"), "# Intro, edited\n").expect("Failed to write");
+        assert_eq!(
+            first_line(Config::builder().dirty_banner(true)),
+            format!(
+                "{BANNER_START}<div class=\"buildtime-dirty\">⚠ This book was built from a modified working tree and may not match any committed state</div>{BANNER_END}"
+            )
+        );
+        assert_eq!(first_line(Config::builder()), "# Intro");
+        assert_eq!(
+            first_line(
+                Config::builder()
+                    .dirty_banner(true)
+                    .dirty_banner_text("Local build")
+                    .dirty_banner_class("warning")
+                    .prerelease_banner(true)
+            ),
+            format!("{BANNER_START}<div class=\"warning\">Local build</div>")
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_variants() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        git::tests::fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.4.2\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let branch = git::GitRepo::open(dir.path())
+            .expect("Failed to open repo")
+            .head_branch()
+            .expect("HEAD should be on a branch");
+        let footer = |cfg: config::ConfigBuilder| {
+            let book = testing::book().chapter("intro.md", "# Intro\n").build();
+            let book = Processor::default()
+                .run_with_config(&cfg.root_dir(dir.path()).build(), dir.path(), book)
+                .expect("run failed");
+            chapter_content(&book.items[0])
+                .expect("not a chapter")
+                .to_string()
+        };
+
+        let content = footer(
+            Config::builder()
+                .variant("release/*", "{name} v{version}")
+                .variant("*", "PREVIEW BUILD {branch}")
+                .variant(branch.as_str(), "never used"),
+        );
+        assert!(
+            content.contains(&format!(">PREVIEW BUILD {branch}</footer>")),
+            "{content}"
+        );
+        let content = footer(
+            Config::builder()
+                .package_version(false)
+                .variant("release/*", "{name} v{version}"),
+        );
+        assert!(content.contains(">example @"), "{content}");
+    }
+
+    #[test]
+    fn test_annotations() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.4.2\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .annotation(toml::toml! { format = "comment" })
+            .annotation(toml::toml! {
+                chapters = ["index.md"]
+                template = "{name} -- {version}"
+            })
+            .build();
+        let processor =
+            Processor::default().with_providers(vec![Box::new(provider::CargoProvider)]);
+        let book = testing::book()
+            .chapter("index.md", "# Welcome\n")
+            .chapter("guide.md", "# Guide\n")
+            .build();
+        let book = processor
+            .run_in(&cfg, dir.path(), dir.path(), book)
+            .expect("run failed");
+        let comment = "<!-- buildtime-annotations:start --><!-- buildtime-annotations: example v1.4.2 --><!-- buildtime-annotations:end -->";
+        assert_eq!(
+            chapter_content(&book.items[0]),
+            Some(
+                format!(
+                    "# Welcome\n{comment}<!-- buildtime-annotations:start:2 --><footer id=\"buildtime-annotations\">example -- 1.4.2</footer><!-- buildtime-annotations:end:2 -->"
+                )
+                .as_str()
+            )
+        );
+        assert_eq!(
+            chapter_content(&book.items[1]),
+            Some(format!("# Guide\n{comment}").as_str())
+        );
+
+        // running again replaces each of them
+        let again = processor
+            .run_in(&cfg, dir.path(), dir.path(), book.clone())
+            .expect("run failed");
+        assert_eq!(again.items, book.items);
+    }
+
+    #[test]
+    fn test_directives() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.4.2\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .format(Format::Comment)
+            .build();
+        let processor =
+            Processor::default().with_providers(vec![Box::new(provider::CargoProvider)]);
+        let book = testing::book()
+            .chapter(
+                "index.md",
+                "<!-- buildtime: fields=version position=top -->\n# Welcome\n",
+            )
+            .chapter("guide.md", "# Guide\n")
+            .build();
+        let book = processor
+            .run_in(&cfg, dir.path(), dir.path(), book)
+            .expect("run failed");
+        assert_eq!(
+            chapter_content(&book.items[0]),
+            Some(
+                "<!-- buildtime-annotations:start --><!-- buildtime-annotations: v1.4.2 --><!-- buildtime-annotations:end -->\n\n# Welcome\n"
+            )
+        );
+        assert_eq!(
+            chapter_content(&book.items[1]),
+            Some(
+                "# Guide\n<!-- buildtime-annotations:start --><!-- buildtime-annotations: example v1.4.2 --><!-- buildtime-annotations:end -->"
+            )
+        );
+
+        let book = testing::book()
+            .chapter("guide.md", "# Guide\n<!-- buildtime: position=side -->\n")
+            .build();
+        let err = processor
+            .run_in(&cfg, dir.path(), dir.path(), book)
+            .expect_err("a bad directive should fail the build")
+            .to_string();
+        assert!(err.contains("guide.md"), "{err}");
+        assert!(err.contains("position=side"), "{err}");
+    }
+
+    #[test]
+    fn test_title_suffix() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.4.2\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let processor =
+            Processor::default().with_providers(vec![Box::new(provider::CargoProvider)]);
+        let names = |book: &Book| {
+            book.iter()
+                .filter_map(|item| match item {
+                    BookItem::Chapter(chapter) => Some(chapter.name.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let run = |cfg: &Config, book: Book| {
+            processor
+                .run_in(cfg, dir.path(), dir.path(), book)
+                .expect("run failed")
+        };
+
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .skip_chapters(true)
+            .title_suffix(true)
+            .build();
+        let book = testing::book()
+            .chapter("index.md", "# Handbook\n")
+            .chapter("guide.md", "# Guide — v1\n")
+            .build();
+        let book = run(&cfg, book);
+        assert_eq!(names(&book), ["Handbook — v1.4.2", "Guide — v1"]);
+        // running again doesn't add another one
+        let again = run(&cfg, book.clone());
+        assert_eq!(again.items, book.items);
+
+        // only this run's suffix counts as already being there, titles that just look like the template are left alone
+        let book = testing::book()
+            .chapter("index.md", "# Upgrading — v1 to v2\n")
+            .build();
+        assert_eq!(names(&run(&cfg, book)), ["Upgrading — v1 to v2 — v1.4.2"]);
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .skip_chapters(true)
+            .title_suffix(true)
+            .title_suffix_template(" ({name} {version})")
+            .title_suffix_chapters(["guide/*"])
+            .build();
+        let book = testing::book()
+            .chapter("index.md", "# Handbook\n")
+            .chapter("guide/a.md", "# Limits (per user)\n")
+            .chapter("guide/b.md", "# Setup (example 1.4.2)\n")
+            .build();
+        assert_eq!(
+            names(&run(&cfg, book)),
+            [
+                "Handbook",
+                "Limits (per user) (example 1.4.2)",
+                "Setup (example 1.4.2)"
+            ]
+        );
+
+        // left out when a field is missing
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .skip_chapters(true)
+            .title_suffix(true)
+            .title_suffix_template(" @{commit}")
+            .build();
+        let book = testing::book().chapter("index.md", "# Handbook\n").build();
+        assert_eq!(names(&run(&cfg, book)), ["Handbook"]);
+    }
+
+    #[test]
+    fn test_language_dirs() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.4.2\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let book_root = dir.path().join("book");
+        std::fs::create_dir(&book_root).expect("Failed to create book");
+        let cfg = Config::builder()
+            .chapters(["reference/*"])
+            .title_suffix(true)
+            .title_suffix_chapters(["index.md"])
+            .language_dirs(["en", "de"])
+            .build();
+        let processor =
+            Processor::default().with_providers(vec![Box::new(provider::CargoProvider)]);
+        let annotated = |book: &Book| {
+            book.iter()
+                .filter_map(|item| match item {
+                    BookItem::Chapter(chapter) => Some((
+                        chapter.name.clone(),
+                        chapter.content.contains("example v1.4.2"),
+                    )),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // both translations side by side under src, matched without their language directory
+        let book = testing::book()
+            .chapter("en/index.md", "# Home\n")
+            .chapter("en/reference/api.md", "# API\n")
+            .chapter("de/index.md", "# Start\n")
+            .chapter("de/reference/api.md", "# Schnittstelle\n")
+            .chapter("english/reference/api.md", "# Other\n")
+            .build();
+        let book = processor
+            .run_in(&cfg, &book_root, &book_root.join("src"), book)
+            .expect("run failed");
+        assert_eq!(
+            annotated(&book),
+            [
+                ("Home — v1.4.2".to_string(), false),
+                ("API".to_string(), true),
+                ("Start — v1.4.2".to_string(), false),
+                ("Schnittstelle".to_string(), true),
+                ("Other".to_string(), false),
+            ]
+        );
+
+        // a build of one language from its own src directory finds the same Cargo.toml
+        let book = testing::book()
+            .chapter("index.md", "# Start\n")
+            .chapter("reference/api.md", "# Schnittstelle\n")
+            .build();
+        let book = processor
+            .run_in(&cfg, &book_root, &book_root.join("src/de"), book)
+            .expect("run failed");
+        assert_eq!(
+            annotated(&book),
+            [
+                ("Start — v1.4.2".to_string(), false),
+                ("Schnittstelle".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_frontmatter_format() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"example\"\nversion = \"1.4.2-beta.1\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .format(Format::Frontmatter)
+            .prerelease_banner(true)
+            .build();
+        let processor =
+            Processor::default().with_providers(vec![Box::new(provider::CargoProvider)]);
+        let book = testing::book()
+            .chapter("index.md", "# Welcome\n")
+            .chapter(
+                "guide.md",
+                "---\ntitle: Guide\nbuildtime:\n  version: \"0.1.0\"\n---\n# Guide\n",
+            )
+            .build();
+        let book = processor
+            .run_in(&cfg, dir.path(), dir.path(), book)
+            .expect("run failed");
+        let fields = "buildtime:\n  name: \"example\"\n  version: \"1.4.2-beta.1\"\n";
+        let banner =
+            "<!-- buildtime-annotations:banner-start --><div class=\"buildtime-prerelease\">";
+        let index = chapter_content(&book.items[0]).expect("not a chapter");
+        assert!(
+            index.starts_with(&format!("---\n{fields}---\n\n{banner}")),
+            "{index}"
+        );
+        assert!(index.ends_with("\n\n# Welcome\n"), "{index}");
+        let guide = chapter_content(&book.items[1]).expect("not a chapter");
+        assert!(
+            guide.starts_with(&format!("---\ntitle: Guide\n{fields}---\n{banner}")),
+            "{guide}"
+        );
+
+        // running again replaces it
+        let again = processor
+            .run_in(&cfg, dir.path(), dir.path(), book.clone())
+            .expect("run failed");
+        assert_eq!(again.items, book.items);
+    }
+
+    #[test]
+    fn test_comment_format() {
+        let cfg = Config::builder().format(Format::Comment).build();
+        assert_eq!(
+            wrap_footer(&cfg, "a --> b <!-- c"),
+            "<!-- buildtime-annotations: a &#45;&#45;&gt; b &lt;!&#45;&#45; c -->"
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_annotation_collect_git() {
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .commit_characters(10)
+            .build();
+        let annotation = Annotation::collect(&cfg, Path::new(env!("CARGO_MANIFEST_DIR")))
+            .expect("Failed to collect annotation");
+        assert_eq!(annotation.commit.as_ref().map(String::len), Some(10));
+        assert!(
+            annotation
+                .commit_date
+                .as_ref()
+                .is_some_and(|date| date.len() == "2024-06-01".len())
+        );
+        let json = serde_json::to_value(&annotation).expect("Failed to serialize annotation");
+        assert!(json.get("branch").is_some());
+    }
+
+    #[test]
+    fn test_config_for_dir() {
+        let book = Path::new(env!("CARGO_MANIFEST_DIR")).join("book");
+        let cfg = Processor::default()
+            .config_for_dir(&book, "html")
+            .expect("Failed to load the test book config");
+        assert_eq!(cfg.commit_characters, Some(10));
+        let annotation = Annotation::collect(&cfg, &book).expect("Failed to collect annotation");
+        assert_eq!(annotation.name.as_deref(), Some(env!("CARGO_PKG_NAME")));
+
+        assert!(
+            Processor::default()
+                .config_for_dir(&book.join("src"), "html")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_skip_empty_disabled() {
+        let cfg = Config {
+            skip_empty: false,
+            ..Config::default()
+        };
+        let mut item = BookItem::Chapter(Chapter::new("Empty", String::new(), "empty.md", vec![]));
+        Processor::default().handle_items(
+            std::slice::from_mut(&mut item),
+            "<footer></footer>",
+            &cfg,
+            &mut Vec::new(),
+        );
+        assert_eq!(
+            chapter_content(&item),
+            Some(format!("{ANNOTATION_START}<footer></footer>{ANNOTATION_END}").as_str())
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_check() {
+        let book = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            book.path().join("book.toml"),
+            format!(
+                "[preprocessor.build-annotations]\nroot_dir = {:?}\n",
+                env!("CARGO_MANIFEST_DIR")
+            ),
+        )
+        .expect("Failed to write book.toml");
+        let steps = Processor::default().check(book.path());
+        assert_eq!(steps.len(), 4);
+        assert!(steps.iter().all(|step| step.status == CheckStatus::Pass));
+
+        std::fs::write(
+            book.path().join("book.toml"),
+            format!(
+                "[preprocessor.build-annotations]\nworkspace_dir = {:?}\ngit_dir = {:?}\n",
+                env!("CARGO_MANIFEST_DIR"),
+                book.path()
+            ),
+        )
+        .expect("Failed to write book.toml");
+        let steps = Processor::default().check(book.path());
+        let statuses: Vec<_> = steps.iter().map(|step| &step.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                &CheckStatus::Pass,
+                &CheckStatus::Pass,
+                &CheckStatus::Fail,
+                &CheckStatus::Skipped
+            ]
+        );
+        let resolved = paths::canonicalize(book.path()).expect("Failed to canonicalize");
+        assert!(steps[2].detail.contains(&resolved.display().to_string()));
+
+        let steps = Processor::default().check(&book.path().join("missing"));
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].status, CheckStatus::Fail);
+    }
+
+    #[cfg(not(feature = "git"))]
+    #[test]
+    fn test_check_without_git() {
+        let book = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::write(
+            book.path().join("book.toml"),
+            format!(
+                "[preprocessor.build-annotations]\nroot_dir = {:?}\n",
+                env!("CARGO_MANIFEST_DIR")
+            ),
+        )
+        .expect("Failed to write book.toml");
+        let steps = Processor::default().check(book.path());
+        let statuses: Vec<_> = steps.iter().map(|step| &step.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                &CheckStatus::Pass,
+                &CheckStatus::Pass,
+                &CheckStatus::Skipped,
+                &CheckStatus::Skipped
+            ]
+        );
+        assert_eq!(steps[2].detail, "git support was compiled out");
+    }
+
+    #[test]
+    fn test_run_on_dir() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("book");
+        let book = Processor::default()
+            .run_on_dir(&root)
+            .expect("Failed to run on the test book");
+        let content = book
+            .items
+            .iter()
+            .find_map(chapter_content)
+            .expect("expected a chapter");
+        assert!(content.contains(&format!(
+            "<footer id=\"buildtime-annotations\">{}",
+            env!("CARGO_PKG_NAME")
+        )));
+
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let err = Processor::default()
+            .run_on_dir(dir.path())
+            .expect_err("there's no book.toml");
+        assert!(matches!(
+            err.downcast_ref::<error::Error>(),
+            Some(error::Error::NotABook { path }) if path == dir.path()
+        ));
+
+        // a book.toml makes it a book, so a missing SUMMARY.md is a problem loading it
+        std::fs::write(dir.path().join("book.toml"), "[book]\n")
+            .expect("Failed to write book.toml");
+        let err = Processor::default()
+            .run_on_dir(dir.path())
+            .expect_err("there's no SUMMARY.md");
+        assert!(err.downcast_ref::<error::Error>().is_none());
+        assert!(format!("{err:#}").contains("SUMMARY.md"));
+    }
+
+    #[test]
+    fn test_preprocess_files() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let ctx = testing::context()
+            .root(dir.path())
+            .set("root_dir", env!("CARGO_MANIFEST_DIR"))
+            .build();
+        let book = testing::book()
+            .chapter("chapter_1.md", "# Chapter 1\n")
+            .build();
+        let input = dir.path().join("input.json");
+        std::fs::write(
+            &input,
+            serde_json::to_string(&(&ctx, &book)).expect("Failed to serialize input"),
+        )
+        .expect("Failed to write input");
+
+        let output = dir.path().join("output.json");
+        handle_preprocessing_paths(&Processor::default(), Some(&input), Some(&output))
+            .expect("preprocessing failed");
+        let processed: Book =
+            serde_json::from_str(&std::fs::read_to_string(&output).expect("Failed to read output"))
+                .expect("Failed to parse output");
+        assert!(
+            chapter_content(&processed.items[0])
+                .is_some_and(|content| content.contains(ANNOTATION_START))
+        );
+
+        let err = handle_preprocessing_paths(
+            &Processor::default(),
+            Some(&dir.path().join("missing.json")),
+            Some(&output),
+        )
+        .expect_err("missing input should fail");
+        assert!(format!("{err:#}").contains("missing.json"));
+    }
+
+    #[test]
+    fn test_preprocess_fixture() {
+        let input = include_str!("../tests/fixtures/preprocess-input.json");
+        let mut output = Vec::new();
+        handle_preprocessing(&Processor::default(), input.as_bytes(), &mut output)
+            .expect("preprocessing failed");
+        let processed: Book = serde_json::from_slice(&output).expect("Failed to parse output");
+        assert_eq!(
+            chapter_content(&processed.items[0]),
+            Some(
+                format!(
+                    "# Introduction\n\nHello!\n{ANNOTATION_START}<footer id=\"buildtime-annotations\">{} v{}</footer>{ANNOTATION_END}",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION")
+                )
+                .as_str()
+            )
+        );
+
+        let err = handle_preprocessing(&Processor::default(), &b"[{}"[..], Vec::new())
+            .expect_err("truncated input should fail");
+        assert!(format!("{err:#}").starts_with("Failed to parse the preprocessor input: "));
+
+        let err = handle_preprocessing(
+            &Processor::with_overrides(cli::ConfigOverrides {
+                workspace_dir: Some("does-not-exist".into()),
+                ..Default::default()
+            }),
+            input.as_bytes(),
+            Vec::new(),
+        )
+        .expect_err("missing Cargo.toml should fail");
+        assert!(format!("{err:#}").starts_with("Failed to annotate the book: Failed to read "));
     }
 
     #[test]
@@ -230,7 +6648,7 @@ mod tests {
             env!("CARGO_PKG_NAME")
         );
         assert_eq!(
-            cargo_toml.version().expect("Package version not found"),
+            cargo_toml.version(None).expect("Package version not found"),
             env!("CARGO_PKG_VERSION")
         );
     }
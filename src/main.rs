@@ -1,21 +1,106 @@
 use clap::Parser;
 use mdbook_buildtime_annotations::{
-    Processor,
-    cli::{CliOpts, Cmd, init_logger},
-    handle_preprocessing, handle_supports,
+    Config, Processor,
+    annotate::{Destination, annotate_files},
+    cli::{CliOpts, Cmd, LogFormat, generate_man_pages, init_logger, write_completions},
+    handle_check, handle_list_supports, handle_preprocessing_paths, handle_print, handle_supports,
+    install::{InstallOutcome, install},
 };
 use mdbook_preprocessor::Preprocessor;
 use tracing::error;
 
 pub fn main() {
-    init_logger();
     let app = CliOpts::parse();
-    let processor = Processor;
+    init_logger(
+        LogFormat::select(
+            app.log_format,
+            std::env::var("MDBOOK_LOG_FORMAT").ok().as_deref(),
+        ),
+        app.quiet,
+        app.verbose,
+    );
+    let processor = Processor::with_overrides(app.overrides).with_dry_run(app.dry_run);
 
-    if let Some(Cmd::Supports { renderer }) = app.cmd {
-        handle_supports(processor, &renderer);
-    } else if let Err(e) = handle_preprocessing() {
-        error!("{} failed to handle preprocessing: {}", processor.name(), e);
-        std::process::exit(1);
+    match app.cmd {
+        Some(Cmd::Supports {
+            renderer: Some(renderer),
+            ..
+        }) => handle_supports(processor, &renderer),
+        Some(Cmd::Supports {
+            renderer: None,
+            format,
+        }) => {
+            if let Err(e) = handle_list_supports(&processor, format) {
+                error!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        Some(Cmd::Completions { shell }) => write_completions(shell, &mut std::io::stdout()),
+        Some(Cmd::Mangen { out }) => match generate_man_pages(&out) {
+            Ok(written) => {
+                for path in written {
+                    println!("{}", path.display());
+                }
+            }
+            Err(e) => {
+                error!("Failed to write man pages to {}: {e}", out.display());
+                std::process::exit(1);
+            }
+        },
+        Some(Cmd::ConfigSchema) => println!("{:#}", Config::json_schema()),
+        Some(Cmd::Print { root, format }) => handle_print(&processor, &root, format),
+        Some(Cmd::Check { root, diff }) => handle_check(&processor, &root, diff),
+        Some(Cmd::Annotate {
+            files,
+            in_place,
+            output,
+            root,
+        }) => {
+            let destination = match (in_place, output) {
+                (true, _) => Destination::InPlace,
+                (false, Some(dir)) => Destination::Dir(dir),
+                (false, None) => Destination::Stdout,
+            };
+            if let Err(e) = annotate_files(&processor, &root, &files, &destination) {
+                error!("{e:#}");
+                std::process::exit(1);
+            }
+        }
+        Some(Cmd::Install { dir, force }) => match install(&dir, force) {
+            Ok(InstallOutcome::Added) => {
+                println!(
+                    "Added [preprocessor.build-annotations] to {}",
+                    dir.join("book.toml").display()
+                )
+            }
+            Ok(InstallOutcome::Replaced) => {
+                println!(
+                    "Replaced [preprocessor.build-annotations] in {}",
+                    dir.join("book.toml").display()
+                )
+            }
+            Ok(InstallOutcome::AlreadyPresent) => println!(
+                "{} already has [preprocessor.build-annotations], not changing it (use --force to replace it)",
+                dir.join("book.toml").display()
+            ),
+            Err(e) => {
+                error!("{e:#}");
+                std::process::exit(1);
+            }
+        },
+        Some(Cmd::Preprocess { input, output }) => {
+            if let Err(e) =
+                handle_preprocessing_paths(&processor, input.as_deref(), output.as_deref())
+            {
+                error!("{} failed to handle preprocessing: {e:#}", processor.name());
+                std::process::exit(1);
+            }
+        }
+        None => {
+            if let Err(e) = handle_preprocessing_paths(&processor, None, None) {
+                error!("{} failed to handle preprocessing: {}", processor.name(), e);
+                std::process::exit(1);
+            }
+        }
     }
 }
@@ -0,0 +1,166 @@
+//! Paths the way they should look in logs, the rendered book and URLs, whichever platform the book's built on.
+
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Like [`std::fs::canonicalize`], except that on Windows the `\\?\C:\…` paths it returns are turned back into `C:\…` when that means the same thing, so they're readable in logs and compare equal to paths that were never canonicalized.
+pub(crate) fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    std::fs::canonicalize(path).map(simplify)
+}
+
+/// The real directory `path` (from the config key `key`) points at, with any symlinks in it resolved, so the Cargo.toml and the git repository are looked for in the same place however it was reached. A path that doesn't exist is returned as it is, so errors about it name the path that was configured.
+pub(crate) fn resolve_dir(key: &str, path: PathBuf) -> PathBuf {
+    match canonicalize(&path) {
+        Ok(resolved) => {
+            if resolved != path {
+                debug!(
+                    "Resolved {key} {} to {}",
+                    path.display(),
+                    resolved.display()
+                );
+            }
+            resolved
+        }
+        Err(_) => path,
+    }
+}
+
+/// `path` with `/` between its components, for putting it in the book or a URL. Only Windows separates them with `\`, everywhere else a `\` is part of the file name and is left alone.
+pub(crate) fn slash_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if cfg!(windows) {
+        path.replace('\\', "/")
+    } else {
+        path.into_owned()
+    }
+}
+
+/// `path` without its verbatim `\\?\` prefix, if it's a drive path that means the same thing without it: short enough for the old path length limit, and without any file names Windows only allows in verbatim paths (`CON`, names ending in a dot or space, and so on).
+#[cfg(windows)]
+fn simplify(path: PathBuf) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    const MAX_PATH: usize = 260;
+    const RESERVED: &[&str] = &[
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    let mut components = path.components();
+    let Some(Component::Prefix(prefix)) = components.next() else {
+        return path;
+    };
+    let Prefix::VerbatimDisk(drive) = prefix.kind() else {
+        return path;
+    };
+    let simple_name = |name: &std::ffi::OsStr| {
+        let Some(name) = name.to_str() else {
+            return false;
+        };
+        let stem = name.split('.').next().unwrap_or(name).trim_end();
+        !name.ends_with(['.', ' '])
+            && !name.contains(['/', '<', '>', ':', '"', '|', '?', '*'])
+            && !RESERVED
+                .iter()
+                .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    };
+    let mut simplified = PathBuf::from(format!("{}:\\", drive as char));
+    for component in components {
+        match component {
+            Component::RootDir => {}
+            Component::Normal(name) if simple_name(name) => simplified.push(name),
+            _ => return path,
+        }
+    }
+    if simplified.as_os_str().len() >= MAX_PATH {
+        return path;
+    }
+    simplified
+}
+
+#[cfg(not(windows))]
+fn simplify(path: PathBuf) -> PathBuf {
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonicalize() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let canonical = canonicalize(dir.path()).expect("Failed to canonicalize");
+        assert!(canonical.is_absolute());
+        assert!(!canonical.to_string_lossy().starts_with(r"\\?\"));
+        assert_eq!(
+            canonicalize(&canonical.join(".")).expect("Failed to canonicalize"),
+            canonical
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_dir() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let real = canonicalize(dir.path())
+            .expect("Failed to canonicalize")
+            .join("store")
+            .join("tree");
+        std::fs::create_dir_all(&real).expect("Failed to create directory");
+        let link = dir.path().join("checkout");
+        std::os::unix::fs::symlink(&real, &link).expect("Failed to create symlink");
+
+        assert_eq!(resolve_dir("workspace_dir", link.clone()), real);
+        assert_eq!(resolve_dir("git_dir", link.join(".")), real);
+        assert_eq!(
+            resolve_dir("git_dir", link.join("missing")),
+            link.join("missing")
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_slash_path() {
+        assert_eq!(slash_path(Path::new("guide/setup.md")), "guide/setup.md");
+        // a backslash is just part of the file name here
+        assert_eq!(slash_path(Path::new(r"guide\setup.md")), r"guide\setup.md");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_slash_path() {
+        assert_eq!(slash_path(Path::new(r"guide\setup.md")), "guide/setup.md");
+        assert_eq!(slash_path(Path::new("guide/setup.md")), "guide/setup.md");
+        assert_eq!(
+            slash_path(&Path::new("guide").join("setup.md")),
+            "guide/setup.md"
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_simplify() {
+        for (verbatim, expected) in [
+            (r"\\?\C:\Users\docs\book", r"C:\Users\docs\book"),
+            (r"\\?\d:\book", r"d:\book"),
+            (r"\\?\C:\", r"C:\"),
+            // these only mean the same thing as verbatim paths
+            (r"\\?\C:\book\CON", r"\\?\C:\book\CON"),
+            (r"\\?\C:\book\nul.txt", r"\\?\C:\book\nul.txt"),
+            (r"\\?\C:\book\trailing.", r"\\?\C:\book\trailing."),
+            (r"\\?\C:\book\trailing ", r"\\?\C:\book\trailing "),
+            // not a drive path
+            (r"\\?\UNC\server\share\book", r"\\?\UNC\server\share\book"),
+            (r"C:\already\simple", r"C:\already\simple"),
+        ] {
+            assert_eq!(
+                simplify(PathBuf::from(verbatim)),
+                PathBuf::from(expected),
+                "{verbatim}"
+            );
+        }
+        let long = format!(r"\\?\C:\{}", "a".repeat(300));
+        assert_eq!(simplify(PathBuf::from(&long)), PathBuf::from(&long));
+    }
+}
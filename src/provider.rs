@@ -0,0 +1,865 @@
+//! Where the annotation's fields come from. Each [`MetadataProvider`] returns a list of named fields, the two built-in providers read the Cargo.toml and the git repository, and library users can register their own with [`Processor::with_providers`](crate::Processor::with_providers).
+
+use crate::{Config, ManifestPackage, paths, read_package};
+use mdbook_preprocessor::errors::Result;
+use std::cell::OnceCell;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "git")]
+use tracing::error;
+use tracing::{debug, warn};
+
+/// Field names the built-in providers use, which [`Annotation`](crate::Annotation) has dedicated fields for.
+pub const FIELD_NAME: &str = "name";
+pub const FIELD_VERSION: &str = "version";
+pub const FIELD_COMMIT: &str = "commit";
+pub const FIELD_BRANCH: &str = "branch";
+pub const FIELD_COMMIT_DATE: &str = "commit_date";
+pub const FIELD_COMMIT_ID: &str = "commit_id";
+pub const FIELD_COMMIT_TIME: &str = "commit_time";
+/// The start of the field names for the HEAD commit's `trailers`, eg `trailer.Ticket`.
+pub const FIELD_TRAILER_PREFIX: &str = "trailer.";
+/// The start of the `template` fields for environment variables, eg `env.GITHUB_RUN_ID`. They're only read if `env_allowlist` allows them.
+pub const FIELD_ENV_PREFIX: &str = "env.";
+/// The `unreleased_notice`, when HEAD is ahead of the closest tag.
+pub const FIELD_UNRELEASED: &str = "unreleased";
+/// The second project's fields, from `code_workspace_dir` and `code_git_dir`.
+pub const FIELD_CODE_NAME: &str = "code.name";
+pub const FIELD_CODE_VERSION: &str = "code.version";
+pub const FIELD_CODE_COMMIT: &str = "code.commit";
+pub const FIELD_CODE_COMMIT_ID: &str = "code.commit_id";
+
+/// What the providers are given to work from. One is created for each run and shared by every provider, so anything expensive to set up (like the git repository) is only done once.
+pub struct Context<'a> {
+    pub cfg: &'a Config,
+    /// The book's directory, for resolving relative paths the way [`Config`] describes.
+    pub book_root: &'a Path,
+    workspace_path: OnceCell<PathBuf>,
+    git_path: PathBuf,
+    #[cfg(feature = "git")]
+    git: crate::git::GitHandle,
+    code_workspace_path: Option<PathBuf>,
+    code_git_path: Option<PathBuf>,
+    #[cfg(feature = "git")]
+    code_git: Option<crate::git::GitHandle>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(cfg: &'a Config, book_root: &'a Path) -> Self {
+        let git_path = paths::resolve_dir("git_dir", cfg.git_path(book_root));
+        let code_git_path = cfg
+            .code_git_path(book_root)
+            .map(|path| paths::resolve_dir("code_git_dir", path));
+        Context {
+            cfg,
+            book_root,
+            workspace_path: OnceCell::new(),
+            #[cfg(feature = "git")]
+            git: crate::git::GitHandle::new(git_path.clone()).strict(cfg.strict),
+            git_path,
+            code_workspace_path: cfg
+                .code_workspace_path(book_root)
+                .map(|path| paths::resolve_dir("code_workspace_dir", path)),
+            #[cfg(feature = "git")]
+            code_git: code_git_path.clone().map(|path| {
+                crate::git::GitHandle::new(path)
+                    .strict(cfg.strict)
+                    // GIT_DIR is the book's repository, not this one
+                    .env(crate::git::GitEnv::default())
+            }),
+            code_git_path,
+        }
+    }
+
+    /// Opens the book's git repository with `env` instead of this process's `GIT_DIR` and `GIT_WORK_TREE`. `code_git_dir` never uses them.
+    #[cfg(feature = "git")]
+    pub fn with_git_env(mut self, env: crate::git::GitEnv) -> Self {
+        self.git = crate::git::GitHandle::new(self.git_path.clone())
+            .strict(self.cfg.strict)
+            .env(env);
+        self
+    }
+
+    /// The directory the Cargo.toml is in, from [`Config::workspace_path`] with any symlinks resolved. It's only worked out the first time it's asked for, as finding it can log a warning.
+    pub fn workspace_path(&self) -> &Path {
+        self.workspace_path.get_or_init(|| {
+            paths::resolve_dir("workspace_dir", self.cfg.workspace_path(self.book_root))
+        })
+    }
+
+    /// The directory the git repository is in, from [`Config::git_path`] with any symlinks resolved.
+    pub fn git_path(&self) -> &Path {
+        &self.git_path
+    }
+
+    /// The git repository the config points at, opened the first time it's asked for. `None` (with an error logged) if it can't be opened.
+    #[cfg(feature = "git")]
+    pub fn git(&self) -> Option<&crate::git::GitRepo> {
+        self.git.repo()
+    }
+
+    /// The directory of the second project's Cargo.toml, from [`Config::code_workspace_path`] with any symlinks resolved. `None` if `code_workspace_dir` isn't set.
+    pub fn code_workspace_path(&self) -> Option<&Path> {
+        self.code_workspace_path.as_deref()
+    }
+
+    /// The directory of the second project's git repository, from [`Config::code_git_path`] with any symlinks resolved. `None` if `code_git_dir` isn't set.
+    pub fn code_git_path(&self) -> Option<&Path> {
+        self.code_git_path.as_deref()
+    }
+
+    /// The second project's git repository, opened the first time it's asked for like [`Context::git`]. `None` if `code_git_dir` isn't set or it can't be opened.
+    #[cfg(feature = "git")]
+    pub fn code_git(&self) -> Option<&crate::git::GitRepo> {
+        self.code_git.as_ref()?.repo()
+    }
+}
+
+/// A source of annotation fields. Returning an error stops the build, so only do that when the footer would be wrong without the fields, otherwise log it and leave them out.
+pub trait MetadataProvider {
+    fn fields(&self, ctx: &Context) -> Result<Vec<(String, String)>>;
+}
+
+/// The package name and version from the Cargo.toml, it's an error if the Cargo.toml can't be read.
+#[derive(Debug, Default)]
+pub struct CargoProvider;
+
+impl MetadataProvider for CargoProvider {
+    fn fields(&self, ctx: &Context) -> Result<Vec<(String, String)>> {
+        let workspace_path = ctx.workspace_path();
+        debug!("Using workspace_dir {}", workspace_path.display());
+        let (name, version) = match read_package(workspace_path, ctx.cfg)? {
+            ManifestPackage::Found { name, version, .. } => (name, version),
+            ManifestPackage::Virtual { manifest, members } => {
+                warn!("{}", ManifestPackage::virtual_message(&manifest, &members));
+                return Ok(Vec::new());
+            }
+        };
+
+        let mut fields = Vec::new();
+        if let Some(name) = name {
+            fields.push((FIELD_NAME.to_string(), name));
+        }
+        if let Some(version) = version {
+            fields.push((FIELD_VERSION.to_string(), version));
+        }
+        Ok(fields)
+    }
+}
+
+/// The branch a CI system says it's building, for when HEAD is detached (as it is for pull requests and tags on most of them), read from the environment with `var`. Tag builds don't have one.
+#[cfg(feature = "git")]
+fn ci_branch(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let var = |name: &str| var(name).filter(|value| !value.is_empty());
+    // pull and merge requests first, since some of these are set to the merge ref for them
+    var("GITHUB_HEAD_REF")
+        .or_else(|| {
+            var("GITHUB_REF_NAME").filter(|_| var("GITHUB_REF_TYPE").as_deref() == Some("branch"))
+        })
+        .or_else(|| var("CI_MERGE_REQUEST_SOURCE_BRANCH_NAME"))
+        .or_else(|| var("CI_COMMIT_BRANCH"))
+        .or_else(|| var("BUILDKITE_BRANCH"))
+        .or_else(|| var("CIRCLE_BRANCH"))
+        .or_else(|| var("BRANCH_NAME"))
+}
+
+/// The page for the CI run building the book, from the variables GitHub Actions, GitLab CI, Buildkite and CircleCI set, read with `var`. `None` when it isn't built by one of them.
+pub(crate) fn ci_build_url(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let var = |name: &str| var(name).filter(|value| !value.is_empty());
+    let github = || {
+        Some(format!(
+            "{}/{}/actions/runs/{}",
+            var("GITHUB_SERVER_URL")?.trim_end_matches('/'),
+            var("GITHUB_REPOSITORY")?,
+            var("GITHUB_RUN_ID")?
+        ))
+    };
+    github()
+        .or_else(|| var("CI_PIPELINE_URL"))
+        .or_else(|| var("BUILDKITE_BUILD_URL"))
+        .or_else(|| var("CIRCLE_BUILD_URL"))
+}
+
+/// The HEAD commit (trimmed and in full), its branch (or the CI system's, when HEAD is detached), its date and time and any `trailers` from the git repository. Anything that can't be found is logged and left out. Without the `git` feature it never finds anything.
+#[derive(Debug, Default)]
+pub struct GitProvider;
+
+#[cfg(feature = "git")]
+impl MetadataProvider for GitProvider {
+    fn fields(&self, ctx: &Context) -> Result<Vec<(String, String)>> {
+        debug!("Using git_dir {}", ctx.git_path().display());
+        let Some(repo) = ctx.git() else {
+            return match ctx.git.take_error() {
+                Some(err) => Err(err.into()),
+                None => Ok(Vec::new()),
+            };
+        };
+
+        let mut fields = Vec::new();
+        if let Some(branch) = repo
+            .head_branch()
+            .or_else(|| ci_branch(|name| ctx.cfg.env_var(name, |name| std::env::var(name).ok())))
+        {
+            fields.push((FIELD_BRANCH.to_string(), branch));
+        }
+        let commit_characters = ctx
+            .cfg
+            .commit_characters
+            .unwrap_or_else(|| repo.abbrev_len());
+        match repo.head_commit(commit_characters) {
+            Ok(commit) => fields.push((FIELD_COMMIT.to_string(), commit)),
+            Err(err) if ctx.cfg.strict => return Err(err.into()),
+            Err(err) => {
+                error!(
+                    "{:#}, leaving the commit out of the annotation",
+                    anyhow::Error::from(err)
+                );
+                return Ok(fields);
+            }
+        }
+        if let Some(date) = repo.head_commit_date() {
+            fields.push((FIELD_COMMIT_DATE.to_string(), date));
+        }
+        if let Ok(commit_id) = repo.head_commit(usize::MAX) {
+            fields.push((FIELD_COMMIT_ID.to_string(), commit_id));
+        }
+        if let Some(time) = repo.head_commit_time() {
+            fields.push((FIELD_COMMIT_TIME.to_string(), time));
+        }
+        for token in &ctx.cfg.trailers {
+            let values: Vec<String> = repo
+                .head_trailer(token)
+                .iter()
+                .map(|value| ctx.cfg.redact(value))
+                .filter(|value| !value.is_empty())
+                .collect();
+            if values.is_empty() {
+                debug!("The HEAD commit has no {token} trailer");
+                continue;
+            }
+            fields.push((
+                format!("{FIELD_TRAILER_PREFIX}{token}"),
+                values.join(&ctx.cfg.trailer_separator),
+            ));
+        }
+        if ctx.cfg.unreleased_notice {
+            match repo.nearest_tag() {
+                Some((tag, 0)) => debug!("HEAD is tagged {tag}, leaving the unreleased notice out"),
+                Some((tag, count)) => fields.push((
+                    FIELD_UNRELEASED.to_string(),
+                    unreleased_notice(&ctx.cfg.unreleased_notice_template, &tag, count),
+                )),
+                None => {
+                    debug!("There are no tags in HEAD's history, leaving the unreleased notice out")
+                }
+            }
+        }
+        Ok(fields)
+    }
+}
+
+/// `template` with the `tag` and the `count` of commits since it filled in.
+#[cfg(feature = "git")]
+fn unreleased_notice(template: &str, tag: &str, count: usize) -> String {
+    let commits = if count == 1 {
+        "1 commit".to_string()
+    } else {
+        format!("{count} commits")
+    };
+    template
+        .replace("{tag}", tag)
+        .replace("{count}", &count.to_string())
+        .replace("{commits}", &commits)
+}
+
+#[cfg(not(feature = "git"))]
+impl MetadataProvider for GitProvider {
+    fn fields(&self, ctx: &Context) -> Result<Vec<(String, String)>> {
+        if ctx.cfg.git_commit {
+            warn!(
+                "git_commit is enabled but git support was compiled out (the `git` feature), leaving the commit out"
+            );
+        }
+        if !ctx.cfg.trailers.is_empty() {
+            warn!(
+                "trailers are configured but git support was compiled out (the `git` feature), leaving them out"
+            );
+        }
+        if ctx.cfg.unreleased_notice {
+            warn!(
+                "unreleased_notice is enabled but git support was compiled out (the `git` feature), leaving it out"
+            );
+        }
+        Ok(Vec::new())
+    }
+}
+
+/// The name, version and HEAD commit of the second project `code_workspace_dir` and `code_git_dir` point at, eg the software a book in a repository of its own documents. It finds nothing unless one of them is set. The two are read independently, so the version is still found if the repository can't be opened and the other way round, and what can't be found is logged and left out (or fails the build with `strict`).
+#[derive(Debug, Default)]
+pub struct CodeProvider;
+
+impl MetadataProvider for CodeProvider {
+    fn fields(&self, ctx: &Context) -> Result<Vec<(String, String)>> {
+        let mut fields = Vec::new();
+        if let Some(workspace_path) = ctx.code_workspace_path() {
+            debug!("Using code_workspace_dir {}", workspace_path.display());
+            // member_path is for the book's workspace, not this one
+            let cfg = Config {
+                member_path: None,
+                ..ctx.cfg.clone()
+            };
+            match read_package(workspace_path, &cfg) {
+                Ok(ManifestPackage::Found { name, version, .. }) => {
+                    fields.extend(name.map(|name| (FIELD_CODE_NAME.to_string(), name)));
+                    fields.extend(version.map(|version| (FIELD_CODE_VERSION.to_string(), version)));
+                }
+                Ok(ManifestPackage::Virtual { manifest, members }) => {
+                    warn!("{}", ManifestPackage::virtual_message(&manifest, &members));
+                }
+                Err(err) if ctx.cfg.strict => return Err(err.into()),
+                Err(err) => warn!(
+                    "{:#}, leaving the code_workspace_dir name and version out",
+                    anyhow::Error::from(err)
+                ),
+            }
+        }
+        fields.extend(code_commit(ctx)?);
+        Ok(fields)
+    }
+}
+
+/// The second project's HEAD commit, trimmed and in full.
+#[cfg(feature = "git")]
+fn code_commit(ctx: &Context) -> Result<Vec<(String, String)>> {
+    let Some(git_path) = ctx.code_git_path() else {
+        return Ok(Vec::new());
+    };
+    debug!("Using code_git_dir {}", git_path.display());
+    let Some(repo) = ctx.code_git() else {
+        return match ctx.code_git.as_ref().and_then(|git| git.take_error()) {
+            Some(err) => Err(err.into()),
+            None => Ok(Vec::new()),
+        };
+    };
+    let commit_characters = ctx
+        .cfg
+        .commit_characters
+        .unwrap_or_else(|| repo.abbrev_len());
+    match repo.head_commit(commit_characters) {
+        Ok(commit) => Ok(vec![
+            (FIELD_CODE_COMMIT.to_string(), commit),
+            (
+                FIELD_CODE_COMMIT_ID.to_string(),
+                repo.head_commit(usize::MAX)?,
+            ),
+        ]),
+        Err(err) if ctx.cfg.strict => Err(err.into()),
+        Err(err) => {
+            error!(
+                "{:#}, leaving the code_git_dir commit out of the annotation",
+                anyhow::Error::from(err)
+            );
+            Ok(Vec::new())
+        }
+    }
+}
+
+#[cfg(not(feature = "git"))]
+fn code_commit(ctx: &Context) -> Result<Vec<(String, String)>> {
+    if ctx.code_git_path().is_some() {
+        warn!(
+            "code_git_dir is set but git support was compiled out (the `git` feature), leaving its commit out"
+        );
+    }
+    Ok(Vec::new())
+}
+
+/// The providers a [`Processor`](crate::Processor) uses unless it's given others: the Cargo.toml, the git repository, then the second project from `code_workspace_dir` and `code_git_dir`.
+pub fn default_providers() -> Vec<Box<dyn MetadataProvider>> {
+    vec![
+        Box::new(CargoProvider),
+        Box::new(GitProvider),
+        Box::new(CodeProvider),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Annotation, Processor};
+
+    struct BuildLabel(&'static str);
+
+    impl MetadataProvider for BuildLabel {
+        fn fields(&self, _ctx: &Context) -> Result<Vec<(String, String)>> {
+            Ok(vec![("label".to_string(), self.0.to_string())])
+        }
+    }
+
+    #[test]
+    fn test_custom_providers() {
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .git_commit(false)
+            .build();
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+        let processor = Processor::default().with_providers(vec![
+            Box::new(BuildLabel("CL-1")),
+            Box::new(CargoProvider),
+            Box::new(BuildLabel("nightly")),
+        ]);
+        let annotation = processor.collect(&cfg, root).expect("Failed to collect");
+        assert_eq!(
+            annotation.extras,
+            [
+                ("label".to_string(), "CL-1".to_string()),
+                ("label".to_string(), "nightly".to_string())
+            ]
+        );
+        assert_eq!(
+            annotation.render(&cfg),
+            format!(
+                "{} v{} CL-1 nightly",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+
+        // the default providers are the same as collecting without a processor
+        assert_eq!(
+            Processor::default()
+                .collect(&cfg, root)
+                .expect("Failed to collect"),
+            Annotation::collect(&cfg, root).expect("Failed to collect")
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_ci_branch() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(ci_branch(env(&[])), None);
+        assert_eq!(
+            ci_branch(env(&[
+                ("GITHUB_HEAD_REF", "feature/x"),
+                ("GITHUB_REF_NAME", "42/merge"),
+                ("GITHUB_REF_TYPE", "branch"),
+            ]))
+            .as_deref(),
+            Some("feature/x")
+        );
+        assert_eq!(
+            ci_branch(env(&[
+                ("GITHUB_HEAD_REF", ""),
+                ("GITHUB_REF_NAME", "main"),
+                ("GITHUB_REF_TYPE", "branch"),
+            ]))
+            .as_deref(),
+            Some("main")
+        );
+        // a tag build
+        assert_eq!(
+            ci_branch(env(&[
+                ("GITHUB_REF_NAME", "v1.4.2"),
+                ("GITHUB_REF_TYPE", "tag"),
+            ])),
+            None
+        );
+        assert_eq!(
+            ci_branch(env(&[("CI_COMMIT_BRANCH", "release/1.4")])).as_deref(),
+            Some("release/1.4")
+        );
+    }
+
+    #[test]
+    fn test_ci_build_url() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(ci_build_url(env(&[])), None);
+        assert_eq!(
+            ci_build_url(env(&[
+                ("GITHUB_SERVER_URL", "https://github.com/"),
+                ("GITHUB_REPOSITORY", "octo/handbook"),
+                ("GITHUB_RUN_ID", "1234"),
+            ]))
+            .as_deref(),
+            Some("https://github.com/octo/handbook/actions/runs/1234")
+        );
+        // not enough of GitHub's to make a link
+        assert_eq!(
+            ci_build_url(env(&[
+                ("GITHUB_REPOSITORY", "octo/handbook"),
+                ("GITHUB_RUN_ID", "1234"),
+            ])),
+            None
+        );
+        assert_eq!(
+            ci_build_url(env(&[
+                (
+                    "CI_PIPELINE_URL",
+                    "https://gitlab.com/octo/handbook/-/pipelines/77"
+                ),
+                ("CIRCLE_BUILD_URL", ""),
+            ]))
+            .as_deref(),
+            Some("https://gitlab.com/octo/handbook/-/pipelines/77")
+        );
+        assert_eq!(
+            ci_build_url(env(&[(
+                "CIRCLE_BUILD_URL",
+                "https://circleci.com/gh/octo/handbook/9"
+            )]))
+            .as_deref(),
+            Some("https://circleci.com/gh/octo/handbook/9")
+        );
+    }
+
+    /// Reads the commit each time it's asked, the way a per-chapter field would.
+    #[cfg(feature = "git")]
+    struct CommitLookups(usize);
+
+    #[cfg(feature = "git")]
+    impl MetadataProvider for CommitLookups {
+        fn fields(&self, ctx: &Context) -> Result<Vec<(String, String)>> {
+            let mut fields = Vec::new();
+            for _ in 0..self.0 {
+                if let Some(commit) = ctx.git().and_then(|repo| repo.head_commit(7).ok()) {
+                    fields.push(("lookup".to_string(), commit));
+                }
+            }
+            Ok(fields)
+        }
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_trailers() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        crate::git::tests::fixture_repo_with_messages(
+            dir.path(),
+            &[(
+                "Alice",
+                "alice@example.com",
+                "intro.md",
+                "Rewrite the intro\n\nReviewed-by: Bob <bob@example.com>\nticket: DOC-12\nReviewed-by: Carol <carol@example.com>\n",
+            )],
+        );
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .git_commit(false)
+            .trailers(["Reviewed-by", "Ticket", "Missing"])
+            .trailer_separator(" & ")
+            .redact_emails(false)
+            .build();
+        let annotation = Processor::default()
+            .with_providers(vec![Box::new(GitProvider)])
+            .collect(&cfg, dir.path())
+            .expect("Failed to collect");
+        assert_eq!(
+            annotation.extras,
+            [
+                (
+                    "trailer.Reviewed-by".to_string(),
+                    "Bob <bob@example.com> & Carol <carol@example.com>".to_string()
+                ),
+                ("trailer.Ticket".to_string(), "DOC-12".to_string()),
+            ]
+        );
+
+        // the addresses are stripped by default
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .git_commit(false)
+            .trailers(["Reviewed-by"])
+            .build();
+        let annotation = Processor::default()
+            .with_providers(vec![Box::new(GitProvider)])
+            .collect(&cfg, dir.path())
+            .expect("Failed to collect");
+        assert_eq!(
+            annotation.extras,
+            [("trailer.Reviewed-by".to_string(), "Bob, Carol".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_code_provider() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let engine = dir.path().join("engine");
+        std::fs::create_dir(&engine).expect("Failed to create engine");
+        std::fs::write(
+            engine.join("Cargo.toml"),
+            "[package]\nname = \"engine\"\nversion = \"2.3.1\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let collect = |cfg: &Config| {
+            Processor::default()
+                .with_providers(vec![Box::new(CodeProvider)])
+                .collect(cfg, dir.path())
+        };
+
+        // nothing unless it's configured
+        let annotation = collect(&Config::default()).expect("Failed to collect");
+        assert_eq!(annotation.code, None);
+
+        let cfg = Config::builder().code_workspace_dir(&engine).build();
+        let code = collect(&cfg)
+            .expect("Failed to collect")
+            .code
+            .expect("no code fields");
+        assert_eq!(code.name.as_deref(), Some("engine"));
+        assert_eq!(code.version.as_deref(), Some("2.3.1"));
+        assert_eq!(code.commit, None);
+
+        // a missing Cargo.toml leaves the rest alone, unless it's strict
+        let missing = dir.path().join("missing");
+        let cfg = Config::builder().code_workspace_dir(&missing).build();
+        assert_eq!(collect(&cfg).expect("Failed to collect").code, None);
+        let cfg = Config::builder()
+            .code_workspace_dir(&missing)
+            .strict(true)
+            .build();
+        assert!(collect(&cfg).is_err());
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_code_provider_git() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let (docs, engine) = (dir.path().join("docs"), dir.path().join("engine"));
+        crate::git::tests::fixture_repo(&docs, &[("Alice", "alice@example.com", "intro.md")]);
+        crate::git::tests::fixture_repo(&engine, &[("Bob", "bob@example.com", "Cargo.toml")]);
+        std::fs::write(
+            engine.join("Cargo.toml"),
+            "[package]\nname = \"engine\"\nversion = \"2.3.1\"\n",
+        )
+        .expect("Failed to write Cargo.toml");
+        let head = |repo: &Path| {
+            crate::git::GitRepo::open(repo)
+                .expect("Failed to open repo")
+                .head_commit(7)
+                .expect("Failed to read HEAD")
+        };
+        let collect = |cfg: &Config| {
+            Processor::default()
+                .with_providers(vec![Box::new(GitProvider), Box::new(CodeProvider)])
+                .collect(cfg, dir.path())
+        };
+
+        let cfg = Config::builder()
+            .git_dir(&docs)
+            .code_workspace_dir(&engine)
+            .code_git_dir(&engine)
+            .commit_characters(7)
+            .book_label("docs")
+            .build();
+        let annotation = collect(&cfg).expect("Failed to collect");
+        assert_eq!(
+            annotation.render(&cfg),
+            format!("docs @{} · engine @{} v2.3.1", head(&docs), head(&engine))
+        );
+
+        // the book's repository doesn't need the code's, or the other way round
+        let missing = dir.path().join("missing");
+        let annotation = collect(&Config {
+            code_git_dir: Some(missing.clone()),
+            ..cfg.clone()
+        })
+        .expect("Failed to collect");
+        assert_eq!(
+            annotation.render(&cfg),
+            format!("docs @{} · engine v2.3.1", head(&docs))
+        );
+        let annotation = collect(&Config {
+            git_dir: Some(missing.clone()),
+            ..cfg.clone()
+        })
+        .expect("Failed to collect");
+        assert_eq!(
+            annotation.render(&cfg),
+            format!("docs · engine @{} v2.3.1", head(&engine))
+        );
+        assert!(
+            collect(&Config {
+                code_git_dir: Some(missing),
+                strict: true,
+                ..cfg.clone()
+            })
+            .is_err()
+        );
+
+        // GIT_DIR points at the book's repository, which code_git_dir ignores
+        let cfg = Config {
+            git_dir: Some(dir.path().join("missing")),
+            ..cfg
+        };
+        let ctx = Context::new(&cfg, dir.path()).with_git_env(crate::git::GitEnv {
+            git_dir: Some(docs.join(".git")),
+            work_tree: None,
+        });
+        let annotation =
+            Annotation::collect_in(&[Box::new(GitProvider), Box::new(CodeProvider)], &ctx)
+                .expect("Failed to collect");
+        assert_eq!(annotation.commit, Some(head(&docs)));
+        assert_eq!(
+            annotation.code.and_then(|code| code.commit),
+            Some(head(&engine))
+        );
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_commit_characters() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        crate::git::tests::fixture_repo(dir.path(), &[("Alice", "alice@example.com", "intro.md")]);
+        let commit = |cfg: &Config| {
+            Processor::default()
+                .with_providers(vec![Box::new(GitProvider)])
+                .collect(cfg, dir.path())
+                .expect("Failed to collect")
+                .commit
+                .map(|commit| commit.len())
+        };
+        let cfg = Config::builder().root_dir(dir.path()).build();
+        assert_eq!(commit(&cfg), Some(7));
+
+        let config = dir.path().join(".git").join("config");
+        let mut contents = std::fs::read_to_string(&config).expect("Failed to read config");
+        contents.push_str("[core]\n\tabbrev = 12\n");
+        std::fs::write(&config, contents).expect("Failed to write config");
+        assert_eq!(commit(&cfg), Some(12));
+        // commit_characters wins over core.abbrev
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .commit_characters(5)
+            .build();
+        assert_eq!(commit(&cfg), Some(5));
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_unreleased_notice() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        crate::git::tests::fixture_repo(
+            dir.path(),
+            &[
+                ("Alice", "alice@example.com", "intro.md"),
+                ("Bob", "bob@example.com", "intro.md"),
+            ],
+        );
+        let notice = |cfg: &Config| {
+            Processor::default()
+                .with_providers(vec![Box::new(GitProvider)])
+                .collect(cfg, dir.path())
+                .expect("Failed to collect")
+                .extras
+        };
+        let cfg = Config::builder()
+            .root_dir(dir.path())
+            .git_commit(false)
+            .unreleased_notice(true)
+            .build();
+        // no tags yet
+        assert_eq!(notice(&cfg), []);
+
+        let repo = gix::open(dir.path()).expect("Failed to open repo");
+        let head = repo.head_id().expect("no HEAD").detach();
+        let first = repo
+            .find_commit(head)
+            .expect("Failed to find HEAD")
+            .parent_ids()
+            .next()
+            .expect("no parent")
+            .detach();
+        use gix::refs::transaction::PreviousValue::MustNotExist;
+        repo.tag_reference("v0.9.0", first, MustNotExist)
+            .expect("Failed to tag");
+        assert_eq!(
+            notice(&cfg),
+            [(
+                FIELD_UNRELEASED.to_string(),
+                "contains unreleased changes (1 commit since v0.9.0)".to_string()
+            )]
+        );
+        assert_eq!(
+            notice(&Config {
+                unreleased_notice_template: "{count} ahead of {tag}".to_string(),
+                ..cfg.clone()
+            }),
+            [(
+                FIELD_UNRELEASED.to_string(),
+                "1 ahead of v0.9.0".to_string()
+            )]
+        );
+
+        repo.tag_reference("v1.0.0", head, MustNotExist)
+            .expect("Failed to tag");
+        assert_eq!(notice(&cfg), []);
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_repository_opened_once_per_run() {
+        use crate::git::tests::OPENS;
+
+        let cfg = Config::builder()
+            .root_dir(env!("CARGO_MANIFEST_DIR"))
+            .build();
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let processor = Processor::default().with_providers(vec![
+            Box::new(GitProvider),
+            Box::new(CommitLookups(5)),
+            Box::new(GitProvider),
+        ]);
+
+        let before = OPENS.with(|opens| opens.get());
+        let annotation = processor.collect(&cfg, root).expect("Failed to collect");
+        assert_eq!(OPENS.with(|opens| opens.get()) - before, 1);
+        assert_eq!(annotation.extras.len(), 5);
+        assert!(annotation.commit.is_some());
+
+        // each run gets its own handle
+        processor.collect(&cfg, root).expect("Failed to collect");
+        assert_eq!(OPENS.with(|opens| opens.get()) - before, 2);
+    }
+
+    #[cfg(feature = "git")]
+    #[test]
+    fn test_strict_git_errors() {
+        let dir = tempfile::tempdir().expect("Failed to create tempdir");
+        let collect = |git_dir: &Path, strict: bool| {
+            let cfg = Config::builder().git_dir(git_dir).strict(strict).build();
+            Processor::default()
+                .with_providers(vec![Box::new(GitProvider)])
+                .collect(&cfg, dir.path())
+        };
+        let git_error = |git_dir: &Path| {
+            let err = collect(git_dir, true).expect_err("strict should fail");
+            match err.downcast::<crate::error::Error>() {
+                Ok(crate::error::Error::GitNotFound { .. }) => "not found",
+                Ok(crate::error::Error::GitOpen { .. }) => "not a repository",
+                Ok(crate::error::Error::GitHead { .. }) => "no HEAD",
+                other => panic!("unexpected error {other:?}"),
+            }
+        };
+
+        let missing = dir.path().join("missing");
+        assert!(collect(&missing, false).is_ok());
+        assert_eq!(git_error(&missing), "not found");
+        assert_eq!(git_error(dir.path()), "not a repository");
+        gix::init(dir.path()).expect("Failed to create repo");
+        assert!(collect(dir.path(), false).is_ok());
+        assert_eq!(git_error(dir.path()), "no HEAD");
+    }
+}
@@ -0,0 +1,111 @@
+//! Taking email addresses out of the names and trailers that end up in the annotation, see `redact_emails`.
+
+use crate::config::EmailRedaction;
+
+/// `text` with every email address in it stripped or replaced with a token, depending on `mode`. Stripping takes the `<>` around an address (and the space before them) with it, so "Bob <bob@example.com>" becomes "Bob". Tokens are made from the address's FNV-1a hash (ignoring case), so the same address always gets the same one, eg "Bob <user-1a2b3c4d>".
+pub(crate) fn redact(text: &str, mode: EmailRedaction) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut at = 0;
+    while let Some(offset) = chars[at..].iter().position(|c| *c == '@') {
+        let index = at + offset;
+        at = index + 1;
+        let mut start = index;
+        while start > copied && is_local_char(chars[start - 1]) {
+            start -= 1;
+        }
+        let mut end = index + 1;
+        while end < chars.len() && is_domain_char(chars[end]) {
+            end += 1;
+        }
+        // a sentence can end with an address
+        while end > index + 1 && chars[end - 1] == '.' {
+            end -= 1;
+        }
+        let domain: String = chars[index + 1..end].iter().collect();
+        if start == index
+            || !domain.contains('.')
+            || domain.starts_with('.')
+            || domain.contains("..")
+        {
+            continue;
+        }
+        let address: String = chars[start..end].iter().collect();
+        let bracketed = start > copied && chars[start - 1] == '<' && chars.get(end) == Some(&'>');
+        match mode {
+            EmailRedaction::Strip => {
+                let (mut start, end) = match bracketed {
+                    true => (start - 1, end + 1),
+                    false => (start, end),
+                };
+                while start > copied && chars[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                out.extend(&chars[copied..start]);
+                copied = end;
+            }
+            EmailRedaction::Hash => {
+                out.extend(&chars[copied..start]);
+                out.push_str(&token(&address));
+                copied = end;
+            }
+        }
+        at = copied.max(at);
+    }
+    out.extend(&chars[copied..]);
+    out.trim().to_string()
+}
+
+/// A short token for `address` that's the same on every run, eg "user-1a2b3c4d".
+fn token(address: &str) -> String {
+    let hash = address
+        .to_lowercase()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("user-{:08x}", hash >> 32)
+}
+
+fn is_local_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "._%+-".contains(c)
+}
+
+fn is_domain_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || ".-".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let strip = |text| redact(text, EmailRedaction::Strip);
+        assert_eq!(strip("Bob <bob@example.com>"), "Bob");
+        assert_eq!(strip("bob@example.com"), "");
+        assert_eq!(
+            strip("Bob <bob@example.com>, Carol <carol.c+docs@mail.example.org>"),
+            "Bob, Carol"
+        );
+        assert_eq!(strip("ask bob@example.com."), "ask.");
+        // not addresses
+        assert_eq!(strip("DOC-12"), "DOC-12");
+        assert_eq!(strip("@alice"), "@alice");
+        assert_eq!(strip("alice@localhost"), "alice@localhost");
+
+        let hash = |text| redact(text, EmailRedaction::Hash);
+        let bob = hash("Bob <bob@example.com>");
+        assert!(bob.starts_with("Bob <user-"), "{bob}");
+        assert!(!bob.contains('@'), "{bob}");
+        assert_eq!(bob.len(), "Bob <user-12345678>".len());
+        // the same address always gets the same token, whatever its case
+        assert_eq!(hash("Bob <BOB@example.com>"), bob);
+        assert_eq!(
+            hash("bob@example.com"),
+            bob.trim_start_matches("Bob <").trim_end_matches('>')
+        );
+        assert_ne!(hash("carol@example.com"), hash("bob@example.com"));
+    }
+}
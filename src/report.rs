@@ -0,0 +1,227 @@
+//! The `report_file`, a record of what a run did for CI and audit tooling to archive. It looks like this, with `config` being every key's resolved value and `annotation` the fields as `print --format json` prints them:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "config": { "commit_characters": 7, "...": "..." },
+//!   "annotation": { "name": "mdbook-buildtime-annotations", "version": "0.0.1", "...": "..." },
+//!   "annotated": ["intro.md", "guide/setup.md"],
+//!   "skipped": [
+//!     { "chapter": "Roadmap", "reason": "draft" },
+//!     { "chapter": "appendix/empty.md", "reason": "empty" }
+//!   ],
+//!   "timing": {
+//!     "total_ms": 1812.4,
+//!     "phases": [{ "phase": "config", "ms": 2.0 }, { "phase": "render", "ms": 200.3 }]
+//!   }
+//! }
+//! ```
+//!
+//! With `deterministic` set, `timing` is `null`, since it's different every time.
+//!
+//! Chapters are named by their source path, or their name for drafts, which don't have one. A chapter is `excluded` when `parts`, `max_depth` or `chapters` leave it out. With more than one of the `annotations`, a chapter's `annotated` if any of them were added to it. Neither list has anything in it when `skip_chapters` is set. [`SCHEMA_VERSION`] goes up whenever a field is renamed, removed or changes meaning, adding fields doesn't change it.
+
+use crate::{Annotation, Config};
+use anyhow::Context;
+use mdbook_preprocessor::errors::Result;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+/// The version of the file's shape, written as `schema_version`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Why a chapter wasn't annotated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SkipReason {
+    /// A draft chapter, one in the SUMMARY.md without a file.
+    Draft,
+    /// Outside the configured `parts` or `chapters`, or deeper than `max_depth`.
+    Excluded,
+    /// Only whitespace, with `skip_empty` set.
+    Empty,
+}
+
+/// A chapter that wasn't annotated, and why.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Skipped {
+    pub chapter: String,
+    pub reason: SkipReason,
+}
+
+/// The chapters one run annotated and skipped, in the order they're in the book.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Chapters {
+    pub annotated: Vec<String>,
+    pub skipped: Vec<Skipped>,
+}
+
+impl Chapters {
+    /// Adds the chapters another of the `annotations` was added to, after the ones already annotated. Chapters are only left skipped if none of the annotations were added to them.
+    pub fn merge(&mut self, other: Chapters) {
+        if self.annotated.is_empty() && self.skipped.is_empty() {
+            *self = other;
+            return;
+        }
+        for chapter in other.annotated {
+            if !self.annotated.contains(&chapter) {
+                self.skipped.retain(|skipped| skipped.chapter != chapter);
+                self.annotated.push(chapter);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Phase {
+    phase: &'static str,
+    ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Timing {
+    total_ms: f64,
+    phases: Vec<Phase>,
+}
+
+/// What's written to the `report_file`, see the module docs for what it looks like.
+#[derive(Debug, Serialize)]
+pub(crate) struct Report<'a> {
+    schema_version: u32,
+    config: &'a Config,
+    annotation: &'a Annotation,
+    annotated: &'a [String],
+    skipped: &'a [Skipped],
+    timing: Option<Timing>,
+}
+
+impl<'a> Report<'a> {
+    pub(crate) fn new(
+        cfg: &'a Config,
+        annotation: &'a Annotation,
+        chapters: &'a Chapters,
+        total: Duration,
+        phases: &[(&'static str, Duration)],
+    ) -> Self {
+        Report {
+            schema_version: SCHEMA_VERSION,
+            config: cfg,
+            annotation,
+            annotated: &chapters.annotated,
+            skipped: &chapters.skipped,
+            timing: (!cfg.deterministic).then(|| Timing {
+                total_ms: millis(total),
+                phases: phases
+                    .iter()
+                    .map(|&(phase, elapsed)| Phase {
+                        phase,
+                        ms: millis(elapsed),
+                    })
+                    .collect(),
+            }),
+        }
+    }
+
+    /// Writes the report to `path`, atomically like the `write_build_info` file.
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        let write = || {
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create {}", parent.display()))?;
+            }
+            crate::annotate::write_atomic(path, &json)
+        };
+        write().with_context(|| format!("Failed to write the report to {}", path.display()))
+    }
+}
+
+/// `duration` in milliseconds, to a tenth of one.
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 10_000.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let skipped = |chapter: &str| Skipped {
+            chapter: chapter.to_string(),
+            reason: SkipReason::Excluded,
+        };
+        let mut chapters = Chapters::default();
+        chapters.merge(Chapters {
+            annotated: vec!["intro.md".to_string()],
+            skipped: vec![skipped("index.md"), skipped("faq.md")],
+        });
+        chapters.merge(Chapters {
+            annotated: vec!["index.md".to_string(), "intro.md".to_string()],
+            skipped: vec![skipped("faq.md")],
+        });
+        assert_eq!(
+            chapters,
+            Chapters {
+                annotated: vec!["intro.md".to_string(), "index.md".to_string()],
+                skipped: vec![skipped("faq.md")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_report_json() {
+        let cfg = Config::default();
+        let annotation = Annotation {
+            name: Some("demo".to_string()),
+            ..Default::default()
+        };
+        let chapters = Chapters {
+            annotated: vec!["intro.md".to_string()],
+            skipped: vec![Skipped {
+                chapter: "Roadmap".to_string(),
+                reason: SkipReason::Draft,
+            }],
+        };
+        let report = Report::new(
+            &cfg,
+            &annotation,
+            &chapters,
+            Duration::from_micros(1_812_449),
+            &[("config", Duration::from_micros(2_040))],
+        );
+        let json = serde_json::to_value(&report).expect("Failed to serialize");
+        assert_eq!(json["schema_version"], 1);
+        assert!(json["config"]["commit_characters"].is_null());
+        assert_eq!(json["config"]["format"], "html");
+        assert_eq!(json["annotation"]["name"], "demo");
+        assert_eq!(json["annotated"], serde_json::json!(["intro.md"]));
+        assert_eq!(
+            json["skipped"],
+            serde_json::json!([{ "chapter": "Roadmap", "reason": "draft" }])
+        );
+        assert_eq!(
+            json["timing"],
+            serde_json::json!({
+                "total_ms": 1812.4,
+                "phases": [{ "phase": "config", "ms": 2.0 }]
+            })
+        );
+
+        let cfg = Config::builder().deterministic(true).build();
+        let report = Report::new(
+            &cfg,
+            &annotation,
+            &chapters,
+            Duration::from_micros(1_812_449),
+            &[("config", Duration::from_micros(2_040))],
+        );
+        let json = serde_json::to_value(&report).expect("Failed to serialize");
+        assert!(json["timing"].is_null());
+    }
+}
@@ -0,0 +1,239 @@
+//! Builders for the `PreprocessorContext` and `Book` mdbook hands a preprocessor, for testing a book's config against this preprocessor without writing mdbook's JSON protocol by hand. Needs the `testing` feature.
+//!
+//! ```
+//! use mdbook_buildtime_annotations::{Processor, testing};
+//! use mdbook_preprocessor::Preprocessor;
+//!
+//! let ctx = testing::context()
+//!     .root(env!("CARGO_MANIFEST_DIR"))
+//!     .set("git_commit", false)
+//!     .set("format", "markdown")
+//!     .build();
+//! let book = testing::book().chapter("intro.md", "# Intro\n").build();
+//! let book = Processor::default().run(&ctx, book).expect("run failed");
+//! # let _ = book;
+//! ```
+
+use mdbook_preprocessor::PreprocessorContext;
+use mdbook_preprocessor::book::{Book, BookItem, Chapter, SectionNumber};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Starts a [`ContextBuilder`] for the html renderer, with an empty book.toml and the current directory as the book root.
+pub fn context() -> ContextBuilder {
+    ContextBuilder {
+        root: PathBuf::new(),
+        renderer: "html".to_string(),
+        book_toml: String::new(),
+        values: Vec::new(),
+    }
+}
+
+/// Starts a [`BookBuilder`] with no chapters.
+pub fn book() -> BookBuilder {
+    BookBuilder { items: Vec::new() }
+}
+
+/// Builds the `PreprocessorContext` mdbook would pass us, see [`context`].
+#[derive(Clone, Debug)]
+pub struct ContextBuilder {
+    root: PathBuf,
+    renderer: String,
+    book_toml: String,
+    values: Vec<(String, toml::Value)>,
+}
+
+impl ContextBuilder {
+    /// The book's root directory, which relative paths in the config are resolved against.
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// The renderer mdbook is running us for.
+    pub fn renderer(mut self, renderer: impl Into<String>) -> Self {
+        self.renderer = renderer.into();
+        self
+    }
+
+    /// The whole book.toml, which [`ContextBuilder::set`] values are applied on top of. Panics in [`ContextBuilder::build`] if it isn't valid.
+    pub fn book_toml(mut self, book_toml: impl Into<String>) -> Self {
+        self.book_toml = book_toml.into();
+        self
+    }
+
+    /// Sets `key` in our `[preprocessor.build-annotations]` table. Panics if `value` can't be represented in TOML.
+    pub fn set(self, key: &str, value: impl Serialize) -> Self {
+        self.set_book_key(&format!("preprocessor.build-annotations.{key}"), value)
+    }
+
+    /// Sets a dotted `key` anywhere in the book.toml, eg `book.src`. Panics if `value` can't be represented in TOML.
+    pub fn set_book_key(mut self, key: &str, value: impl Serialize) -> Self {
+        let value = toml::Value::try_from(value)
+            .unwrap_or_else(|err| panic!("{key} can't be represented in TOML: {err}"));
+        self.values.push((key.to_string(), value));
+        self
+    }
+
+    pub fn build(self) -> PreprocessorContext {
+        let mut config: mdbook_preprocessor::config::Config = self
+            .book_toml
+            .parse()
+            .unwrap_or_else(|err| panic!("Failed to parse the book.toml: {err:#}"));
+        for (key, value) in self.values {
+            config
+                .set(&key, value)
+                .unwrap_or_else(|err| panic!("Failed to set {key}: {err:#}"));
+        }
+        PreprocessorContext::new(self.root, config, self.renderer)
+    }
+}
+
+/// Builds a `Book`, numbering its chapters in order like mdbook numbers the chapters of a SUMMARY.md. See [`book`].
+#[derive(Clone, Debug)]
+pub struct BookBuilder {
+    items: Vec<BookItem>,
+}
+
+impl BookBuilder {
+    /// Adds a chapter read from `path` (relative to the book's source directory). It's named after its first `# ` heading, or its file name if it hasn't got one.
+    pub fn chapter(mut self, path: impl AsRef<Path>, content: &str) -> Self {
+        let number = self.chapter_count() + 1;
+        self.items.push(BookItem::Chapter(new_chapter(
+            path.as_ref(),
+            content,
+            vec![number],
+        )));
+        self
+    }
+
+    /// Adds a chapter under the last chapter added with [`BookBuilder::chapter`] or [`BookBuilder::draft`]. Panics if there isn't one.
+    pub fn sub_chapter(mut self, path: impl AsRef<Path>, content: &str) -> Self {
+        let Some(BookItem::Chapter(parent)) = self
+            .items
+            .iter_mut()
+            .rfind(|item| matches!(item, BookItem::Chapter(_)))
+        else {
+            panic!("sub_chapter needs a chapter to go under");
+        };
+        let mut number = parent
+            .number
+            .as_ref()
+            .map(|number| number.to_vec())
+            .unwrap_or_default();
+        number.push(parent.sub_items.len() as u32 + 1);
+        let mut chapter = new_chapter(path.as_ref(), content, number);
+        chapter.parent_names = vec![parent.name.clone()];
+        parent.sub_items.push(BookItem::Chapter(chapter));
+        self
+    }
+
+    /// Adds a draft chapter, one that's in the SUMMARY.md without a file.
+    pub fn draft(mut self, name: &str) -> Self {
+        let mut chapter = Chapter::new_draft(name, vec![]);
+        chapter.number = Some(SectionNumber::new(vec![self.chapter_count() + 1]));
+        self.items.push(BookItem::Chapter(chapter));
+        self
+    }
+
+    /// Starts a part, eg `# Reference` in the SUMMARY.md, which the chapters added after it are in.
+    pub fn part(mut self, title: &str) -> Self {
+        self.items.push(BookItem::PartTitle(title.to_string()));
+        self
+    }
+
+    pub fn build(self) -> Book {
+        Book::new_with_items(self.items)
+    }
+
+    fn chapter_count(&self) -> u32 {
+        self.items
+            .iter()
+            .filter(|item| matches!(item, BookItem::Chapter(_)))
+            .count() as u32
+    }
+}
+
+fn new_chapter(path: &Path, content: &str, number: Vec<u32>) -> Chapter {
+    let name = content
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(|heading| heading.trim().to_string())
+        .or_else(|| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default();
+    let mut chapter = Chapter::new(&name, content.to_string(), path, vec![]);
+    chapter.number = Some(SectionNumber::new(number));
+    chapter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let ctx = context()
+            .root("book")
+            .renderer("epub")
+            .book_toml(
+                "[book]\ntitle = \"Demo\"\n[preprocessor.build-annotations]\nstrict = true\n",
+            )
+            .set("commit_characters", 7)
+            .set("renderers", ["epub"])
+            .set_book_key("book.src", "content")
+            .build();
+        assert_eq!(ctx.root, PathBuf::from("book"));
+        assert_eq!(ctx.renderer, "epub");
+        assert_eq!(ctx.config.book.title.as_deref(), Some("Demo"));
+        assert_eq!(ctx.config.book.src, PathBuf::from("content"));
+        let cfg = crate::Config::try_from(&ctx).expect("Failed to parse config");
+        assert_eq!(cfg.commit_characters, Some(7));
+        assert!(cfg.strict);
+        assert_eq!(cfg.renderers, Some(vec!["epub".to_string()]));
+    }
+
+    #[test]
+    fn test_book() {
+        let book = book()
+            .chapter("intro.md", "# Introduction\n")
+            .sub_chapter("intro/setup.md", "Setting up\n")
+            .part("Reference")
+            .draft("Roadmap")
+            .build();
+        let chapters: Vec<(&str, Option<&Path>, String)> = book
+            .iter()
+            .filter_map(|item| match item {
+                BookItem::Chapter(chapter) => Some((
+                    chapter.name.as_str(),
+                    chapter.source_path.as_deref(),
+                    chapter
+                        .number
+                        .as_ref()
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            chapters,
+            [
+                (
+                    "Introduction",
+                    Some(Path::new("intro.md")),
+                    "1.".to_string()
+                ),
+                (
+                    "setup",
+                    Some(Path::new("intro/setup.md")),
+                    "1.1.".to_string()
+                ),
+                ("Roadmap", None, "2.".to_string()),
+            ]
+        );
+        assert_eq!(book.items[1], BookItem::PartTitle("Reference".to_string()));
+    }
+}
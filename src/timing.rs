@@ -0,0 +1,108 @@
+//! How long each phase of a run took, for the summary logged at the end of it.
+
+use std::time::{Duration, Instant};
+
+/// The phases of one run and how long each took, in the order they ran.
+pub(crate) struct Timings {
+    start: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub(crate) fn new() -> Self {
+        Timings {
+            start: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Runs `f` in a tracing span for `phase`, adding how long it took to the phase's total.
+    pub(crate) fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let span = tracing::info_span!("phase", phase);
+        let start = Instant::now();
+        let result = span.in_scope(f);
+        let elapsed = start.elapsed();
+        tracing::debug!("{phase} took {elapsed:.1?}");
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+        result
+    }
+
+    /// How long it's been since the run started.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Each phase and its total, in the order they first ran.
+    pub(crate) fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    /// Eg "annotated 214 chapters (3 skipped) in 1.8s (config: 2.0ms, metadata: 40.1ms, git history: 1.1s, render: 200.0ms)".
+    pub(crate) fn summary(&self, annotated: usize, skipped: usize) -> String {
+        summary(annotated, skipped, self.start.elapsed(), &self.phases)
+    }
+}
+
+fn summary(
+    annotated: usize,
+    skipped: usize,
+    total: Duration,
+    phases: &[(&'static str, Duration)],
+) -> String {
+    let chapters = if annotated == 1 {
+        "chapter"
+    } else {
+        "chapters"
+    };
+    let mut summary =
+        format!("annotated {annotated} {chapters} ({skipped} skipped) in {total:.1?}");
+    if !phases.is_empty() {
+        let phases: Vec<String> = phases
+            .iter()
+            .map(|(phase, elapsed)| format!("{phase}: {elapsed:.1?}"))
+            .collect();
+        summary.push_str(&format!(" ({})", phases.join(", ")));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(
+            summary(
+                214,
+                3,
+                Duration::from_millis(1812),
+                &[
+                    ("config", Duration::from_micros(2040)),
+                    ("git history", Duration::from_millis(1100)),
+                    ("render", Duration::from_millis(200)),
+                ]
+            ),
+            "annotated 214 chapters (3 skipped) in 1.8s (config: 2.0ms, git history: 1.1s, render: 200.0ms)"
+        );
+        assert_eq!(
+            summary(1, 0, Duration::from_millis(5), &[]),
+            "annotated 1 chapter (0 skipped) in 5.0ms"
+        );
+    }
+
+    #[test]
+    fn test_phases_add_up() {
+        let mut timings = Timings::new();
+        assert_eq!(timings.time("render", || 1), 1);
+        let first = timings.phases[0].1;
+        timings.time("config", || ());
+        timings.time("render", || ());
+        let phases: Vec<&str> = timings.phases.iter().map(|(phase, _)| *phase).collect();
+        assert_eq!(phases, ["render", "config"]);
+        assert!(timings.phases[0].1 >= first);
+    }
+}
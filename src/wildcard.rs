@@ -0,0 +1,31 @@
+//! The `*` and `?` patterns used by `exclude_authors` and `branches`.
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters (including none, and including `/`) and `?` matches any one character. Everything else has to match exactly, including case.
+pub(crate) fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // where to carry on from if the match fails after the last `*`: the pattern after it, and how much of the text it's taken
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, taken)) => {
+                    star = Some((after, taken + 1));
+                    p = after;
+                    n = taken + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}